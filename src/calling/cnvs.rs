@@ -3,33 +3,46 @@
 // This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cmp;
 use std::collections::{BTreeMap, HashMap};
 use std::iter;
-use std::mem;
 use std::path::Path;
 
 use anyhow::Result;
 use bio::stats::{bayesian::bayes_factors::BayesFactor, hmm, hmm::Model, LogProb, PHREDProb, Prob};
 use derive_builder::Builder;
-use itertools::join;
 use itertools::Itertools;
 use itertools_num::linspace;
 use rayon::prelude::*;
 use rgsl::randist::binomial::binomial_pdf;
-use rgsl::randist::poisson::poisson_pdf;
 use rust_htslib::bcf;
 use rust_htslib::bcf::record::Numeric;
 use rust_htslib::bcf::Read;
 
-use crate::model::modes::tumor::TumorNormalPairView;
-use crate::model::AlleleFreq;
+use crate::errors;
 use crate::utils;
+use crate::variants::model::modes::tumor::TumorNormalPair;
+use crate::variants::model::AlleleFreq;
 
 const MIN_DEPTH: u32 = 10;
 const MAX_GAIN: i32 = 21;
 
-pub(crate) fn depth_pmf(observed_depth: u32, true_depth: f64) -> LogProb {
-    LogProb(poisson_pdf(observed_depth, true_depth).ln())
+/// Probability to observe the given tumor depth, given the total depth of tumor and normal
+/// and the expected ratio between tumor and normal depth.
+///
+/// METHOD: if both depths are Poisson distributed with rates sharing the same locus specific
+/// biases (e.g. GC content or mappability), the tumor depth conditioned on the total depth
+/// follows a binomial distribution. Unlike comparing the tumor depth to the normal depth
+/// directly, this accounts for the sampling noise of both samples.
+pub(crate) fn depth_pmf(depth_tumor: u32, depth_normal: u32, depth_ratio: f64) -> LogProb {
+    LogProb(
+        binomial_pdf(
+            depth_tumor,
+            depth_ratio / (1.0 + depth_ratio),
+            depth_tumor + depth_normal,
+        )
+        .ln(),
+    )
 }
 
 pub(crate) fn allele_freq_pdf(
@@ -48,15 +61,25 @@ pub(crate) struct Caller {
     bcf_reader: bcf::Reader,
     #[builder(private)]
     bcf_writer: bcf::Writer,
+    /// Indices of tumor and normal sample in the input calls.
+    #[builder(private)]
+    samples: TumorNormalPair<usize>,
     min_bayes_factor: f64,
     purity: f64,
     max_dist: u64,
+    threads: usize,
     #[builder(private)]
-    contig_lens: HashMap<Vec<u8>, u32>,
+    contig_lens: HashMap<Vec<u8>, u64>,
 }
 
 impl CallerBuilder {
-    pub(crate) fn bcfs<P: AsRef<Path>>(mut self, in_path: Option<P>, out_path: Option<P>) -> Result<Self> {
+    /// Input calls (as generated by varlociraptor call variants tumor-normal) and output
+    /// path. If omitted, read from STDIN and write to STDOUT, respectively.
+    pub(crate) fn bcfs<P: AsRef<Path>>(
+        mut self,
+        in_path: Option<P>,
+        out_path: Option<P>,
+    ) -> Result<Self> {
         self = self.bcf_reader(if let Some(path) = in_path {
             bcf::Reader::from_path(path)?
         } else {
@@ -65,14 +88,26 @@ impl CallerBuilder {
 
         let bcf_reader = self.bcf_reader.as_ref().unwrap();
 
+        let sample_id = |name: &str| {
+            bcf_reader
+                .header()
+                .sample_id(name.as_bytes())
+                .ok_or_else(|| errors::Error::MissingCallsSample {
+                    name: name.to_owned(),
+                })
+        };
+        let samples = TumorNormalPair {
+            tumor: sample_id("tumor")?,
+            normal: sample_id("normal")?,
+        };
+
         let mut header = bcf::Header::new();
-        for sample in bcf_reader.header().samples() {
-            header.push_sample(sample);
-        }
+        header.push_sample(b"tumor");
+        header.push_sample(b"normal");
 
         header.push_record(
             "##INFO=<ID=IMPRECISE,Number=0,Type=Flag,Description=\"Imprecise structural variation\">"
-                .as_bytes()
+                .as_bytes(),
         );
         header.push_record(
             "##INFO=<ID=CN,Number=1,Type=Integer,Description=\"Copy number in tumor sample\">"
@@ -101,7 +136,7 @@ impl CallerBuilder {
             "##INFO=<ID=SVLEN,Number=1,Type=Integer,Description=\"CNV length.\">".as_bytes(),
         );
         header.push_record(
-            "##INFO=<ID=SVTYPE,Number=1,Type=Integer,Description=\"SV type.\">".as_bytes(),
+            "##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"SV type.\">".as_bytes(),
         );
         header.push_record(
             "##INFO=<ID=LOCI,Number=1,Type=Integer,Description=\"Number of contained loci.\">"
@@ -109,8 +144,8 @@ impl CallerBuilder {
         );
         header.push_record(
             "##INFO=<ID=OBS,Number=1,Type=String,Description=\"Bayes factors for per-locus \
-             support for no CNV, given as Kass Raftery scores: \
-             N: none, B: barely, P: positive, S: strong, V: very strong \">"
+             support for the CNV over no CNV, given as Kass Raftery scores: \
+             E: equal, N: none, B: barely, P: positive, S: strong, V: very strong \">"
                 .as_bytes(),
         );
         header.push_record(
@@ -118,25 +153,22 @@ impl CallerBuilder {
                 .as_bytes(),
         );
         header.push_record(
-            "##FORMAT=<ID=LOCI_VAF,Number=.,Type=Integer,Description=\"VAFs of contained loci.\">"
+            "##FORMAT=<ID=LOCI_VAF,Number=.,Type=Float,Description=\"VAFs of contained loci.\">"
                 .as_bytes(),
         );
 
         let mut contig_lens = HashMap::new();
         // register sequences
         for rec in bcf_reader.header().header_records() {
-            match rec {
-                bcf::header::HeaderRecord::Contig { values, .. } => {
-                    let name = values.get("ID").unwrap();
-                    let len = values.get("length").unwrap();
-                    contig_lens.insert(name.clone().into_bytes(), len.parse()?);
-                    header.push_record(format!("##contig=<ID={},length={}>", name, len).as_bytes());
-                }
-                _ => (),
+            if let bcf::header::HeaderRecord::Contig { values, .. } = rec {
+                let name = values.get("ID").unwrap();
+                let len = values.get("length").unwrap();
+                contig_lens.insert(name.clone().into_bytes(), len.parse()?);
+                header.push_record(format!("##contig=<ID={},length={}>", name, len).as_bytes());
             }
         }
 
-        self = self.contig_lens(contig_lens);
+        self = self.contig_lens(contig_lens).samples(samples);
 
         Ok(self.bcf_writer(if let Some(path) = out_path {
             bcf::Writer::from_path(path, &header, false, bcf::Format::BCF)?
@@ -149,115 +181,123 @@ impl CallerBuilder {
 impl Caller {
     pub(crate) fn call(&mut self) -> Result<()> {
         // obtain records
-
         let calls = {
             let mut record = self.bcf_reader.empty_record();
-            let mut last_call: Option<&Call> = None;
-            let mut curr_region = None;
             let mut _calls = Vec::new();
             loop {
-                if !self.bcf_reader.read(&mut record)? {
-                    break;
+                match self.bcf_reader.read(&mut record) {
+                    None => break,
+                    Some(res) => res?,
                 }
 
-                if let Some(call) = Call::new(&mut record)? {
+                if let Some(call) = Call::new(&mut record, &self.samples)? {
                     if call.depth_normal >= MIN_DEPTH {
                         _calls.push(call);
                     }
                 }
             }
-            // add next and prev pos to calls
+            // add next and prev pos on the same contig to calls
             for i in 0.._calls.len() {
-                if i > 0 {
+                if i > 0 && _calls[i - 1].rid == _calls[i].rid {
                     _calls[i].prev_start = Some(_calls[i - 1].start);
                 }
-                if i < _calls.len() - 1 {
+                if i + 1 < _calls.len() && _calls[i + 1].rid == _calls[i].rid {
                     _calls[i].next_start = Some(_calls[i + 1].start);
                 }
             }
 
-            let mut calls = HashMap::new();
-
+            // group calls into regions of loci that are at most max_dist apart
+            let mut calls = BTreeMap::new();
+            let mut last: Option<(Region, u32, u64)> = None;
             for call in _calls {
-                let region = if let Some(last_call) = last_call {
-                    if call.rid == last_call.rid && (call.start - last_call.start) <= self.max_dist
+                let region = match last {
+                    Some((region, rid, start))
+                        if call.rid == rid
+                            && call.start >= start
+                            && call.start - start <= self.max_dist =>
                     {
-                        curr_region.unwrap()
-                    } else {
-                        Region {
-                            rid: call.rid,
-                            start: call.start,
-                        }
+                        region
                     }
-                } else {
-                    Region {
+                    _ => Region {
                         rid: call.rid,
                         start: call.start,
-                    }
+                    },
                 };
-                curr_region = Some(region);
+                last = Some((region, call.rid, call.start));
                 calls.entry(region).or_insert_with(Vec::new).push(call);
-                last_call = Some(calls.get(&region).unwrap().last().unwrap());
             }
             calls
         };
 
+        if calls.is_empty() {
+            return Ok(());
+        }
+
         // normalization
-        let mean_depth = |filter: &dyn Fn(&Call) -> u32| {
-            calls.values().flatten().map(filter).sum::<u32>() as f64 / calls.len() as f64
+        // METHOD: the ratio of the median depths is robust against CNVs affecting
+        // a minority of the loci (unlike the ratio of the mean depths).
+        let median_depth = |depth: &dyn Fn(&Call) -> u32| {
+            let mut depths = calls.values().flatten().map(depth).collect_vec();
+            depths.sort_unstable();
+            depths[depths.len() / 2] as f64
         };
-        let mean_depth_tumor = mean_depth(&|call: &Call| call.depth_tumor);
-        let mean_depth_normal = mean_depth(&|call: &Call| call.depth_normal);
-        let depth_norm_factor = mean_depth_tumor / mean_depth_normal;
+        let median_depth_tumor = median_depth(&|call: &Call| call.depth_tumor);
+        let median_depth_normal = median_depth(&|call: &Call| call.depth_normal);
+        let depth_norm_factor = median_depth_tumor / median_depth_normal;
 
         let min_bayes_factor = self.min_bayes_factor;
         let purity = self.purity;
-        let cnv_calls: BTreeMap<_, _> = calls
-            .par_iter()
-            .map(|(region, calls)| {
-                let hmm = HMM::new(depth_norm_factor, min_bayes_factor, purity);
-
-                let (states, _prob) = hmm::viterbi(&hmm, calls);
-
-                (
-                    region,
-                    states
-                        .iter()
-                        .zip(calls.iter())
-                        .group_by(|item| item.0)
-                        .into_iter()
-                        .filter_map(|(&state, group)| {
-                            let cnv = hmm.states[*state];
-                            if cnv.gain == 0 {
-                                return None;
-                            }
-                            let group = group.into_iter().map(|item| item.1).collect_vec();
-                            let first_call = group[0];
-                            if group.len() > 1 {
-                                let last_call = group[group.len() - 1];
-
-                                // calculate posterior probability of no CNV
-                                let prob_no_cnv = hmm.prob_no_cnv(&group);
-                                let bayes_factors = hmm.bayes_factors(state, &group);
-
-                                Some(CNVCall {
-                                    prev_pos: first_call.prev_start,
-                                    next_pos: last_call.next_start,
-                                    pos: first_call.start,
-                                    end: last_call.start + 1,
-                                    cnv: cnv,
-                                    prob_no_cnv,
-                                    calls: group,
-                                    bayes_factors: bayes_factors,
-                                })
-                            } else {
-                                None
-                            }
-                        })
-                        .collect_vec(),
-                )
-            })
-            .collect();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()?;
+        let cnv_calls: BTreeMap<_, _> = pool.install(|| {
+            calls
+                .par_iter()
+                .map(|(region, calls)| {
+                    let hmm = Hmm::new(depth_norm_factor, min_bayes_factor, purity);
+
+                    let (states, _prob) = hmm::viterbi(&hmm, calls);
+
+                    (
+                        region,
+                        states
+                            .iter()
+                            .zip(calls.iter())
+                            .group_by(|item| item.0)
+                            .into_iter()
+                            .filter_map(|(&state, group)| {
+                                let cnv = hmm.states[*state];
+                                if cnv.gain == 0 {
+                                    return None;
+                                }
+                                let group = group.into_iter().map(|item| item.1).collect_vec();
+                                let first_call = group[0];
+                                if group.len() > 1 {
+                                    let last_call = group[group.len() - 1];
+
+                                    // calculate posterior probability of no CNV
+                                    let prob_no_cnv = hmm.prob_no_cnv(&group);
+                                    let bayes_factors = hmm.bayes_factors(state, &group);
+
+                                    Some(CNVCall {
+                                        prev_pos: first_call.prev_start,
+                                        next_pos: last_call.next_start,
+                                        pos: first_call.start,
+                                        end: last_call.start + 1,
+                                        cnv,
+                                        prob_no_cnv,
+                                        calls: group,
+                                        bayes_factors,
+                                    })
+                                } else {
+                                    None
+                                }
+                            })
+                            .collect_vec(),
+                    )
+                })
+                .collect()
+        });
 
         for (region, calls) in cnv_calls {
             let contig = self.bcf_reader.header().rid2name(region.rid)?;
@@ -288,7 +328,7 @@ pub(crate) struct CNVCall<'a> {
     next_pos: Option<u64>,
     pos: u64,
     end: u64,
-    cnv: CNV,
+    cnv: Cnv,
     prob_no_cnv: LogProb,
     calls: Vec<&'a Call>,
     bayes_factors: Vec<BayesFactor>,
@@ -304,7 +344,7 @@ impl<'a> CNVCall<'a> {
     ) -> Result<()> {
         record.set_rid(Some(rid));
         record.set_pos(self.pos as i64);
-        record.set_alleles(&[b"N", b"<CNV>"])?;
+        record.set_alleles(&[b"N".as_ref(), b"<CNV>".as_ref()])?;
         record.push_info_integer(b"END", &[self.end as i32])?;
         record.push_info_integer(b"SVLEN", &[self.len() as i32])?;
         record.push_info_integer(b"CN", &[2 + self.cnv.gain])?;
@@ -354,31 +394,30 @@ impl<'a> CNVCall<'a> {
         record.push_format_float(b"LOCI_VAF", &loci_vaf)?;
         record.set_qual(*PHREDProb::from(self.prob_no_cnv) as f32);
 
-        let obs = join(
-            self.bayes_factors
-                .iter()
-                .map(|bf| utils::evidence_kass_raftery_to_letter(bf.evidence_kass_raftery())),
-            "",
-        );
+        let obs: String = self
+            .bayes_factors
+            .iter()
+            .map(|bf| utils::bayes_factor_to_letter(*bf))
+            .collect();
         record.push_info_string(b"OBS", &[obs.as_bytes()])?;
 
         Ok(())
     }
 
-    pub(crate) fn len(&self) -> u32 {
-        self.end - self.pos + 1
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.pos
     }
 }
 
-pub(crate) struct HMM {
-    states: Vec<CNV>,
+pub(crate) struct Hmm {
+    states: Vec<Cnv>,
     state_by_gain: HashMap<i32, Vec<hmm::State>>,
     depth_norm_factor: f64,
     prob_keep_state: LogProb,
     prob_change_state: LogProb,
 }
 
-impl HMM {
+impl Hmm {
     #[allow(clippy::float_cmp)]
     fn new(depth_norm_factor: f64, min_bayes_factor: f64, purity: f64) -> Self {
         let n_allele_freqs = 10;
@@ -387,8 +426,8 @@ impl HMM {
         for allele_freq in linspace(0.1, 1.0, n_allele_freqs) {
             for gain in -2..MAX_GAIN {
                 if gain != 0 || allele_freq == 1.0 {
-                    let cnv = CNV {
-                        gain: gain,
+                    let cnv = Cnv {
+                        gain,
                         allele_freq: AlleleFreq(allele_freq),
                         purity,
                     };
@@ -414,7 +453,7 @@ impl HMM {
         let prob_keep_state = LogProb::from(Prob((1.0 + epsilon) / denominator));
         let prob_change_state = LogProb::from(Prob(1.0 / denominator));
 
-        HMM {
+        Hmm {
             states,
             state_by_gain,
             depth_norm_factor,
@@ -423,39 +462,48 @@ impl HMM {
         }
     }
 
+    /// Posterior probability of no CNV, given the observations.
+    ///
+    /// METHOD: we assume a uniform prior over the possible gains. For each gain, the likelihood
+    /// is averaged over the allele frequency spectrum of the corresponding states.
     pub(crate) fn prob_no_cnv(&self, observations: &[&Call]) -> LogProb {
         let likelihood_no_cnv = likelihood(
             self,
-            iter::repeat(self.state_by_gain.get(&0).unwrap()[0]),
+            iter::repeat(self.null_state()),
             observations.iter().cloned(),
         );
         let mut likelihoods = vec![likelihood_no_cnv];
         for gain in -2..MAX_GAIN {
             if gain != 0 {
                 let af_spectrum = self.state_by_gain.get(&gain).unwrap();
-                likelihoods.push(LogProb::ln_simpsons_integrate_exp(
-                    |i, _| {
-                        let state = af_spectrum[i];
-                        likelihood(self, iter::repeat(state), observations.iter().cloned())
-                    },
-                    0.0,
-                    1.0,
-                    af_spectrum.len() - 1,
-                ));
+                let af_likelihoods = af_spectrum
+                    .iter()
+                    .map(|state| {
+                        likelihood(self, iter::repeat(*state), observations.iter().cloned())
+                    })
+                    .collect_vec();
+                likelihoods.push(
+                    LogProb::ln_sum_exp(&af_likelihoods) - LogProb((af_spectrum.len() as f64).ln()),
+                );
             }
         }
 
-        LogProb::ln_sum_exp(&likelihoods)
+        (likelihood_no_cnv - LogProb::ln_sum_exp(&likelihoods))
+            .cap_numerical_overshoot(utils::NUMERICAL_EPSILON)
     }
 
     pub(crate) fn null_state(&self) -> hmm::State {
         self.state_by_gain.get(&0).unwrap()[0]
     }
 
-    pub(crate) fn bayes_factors(&self, state: hmm::State, observations: &[&Call]) -> Vec<BayesFactor> {
+    pub(crate) fn bayes_factors(
+        &self,
+        state: hmm::State,
+        observations: &[&Call],
+    ) -> Vec<BayesFactor> {
         let null_state = self.null_state();
         observations
-            .into_iter()
+            .iter()
             .map(|obs| {
                 BayesFactor::new(
                     self.observation_prob(state, obs),
@@ -482,14 +530,13 @@ impl HMM {
         };
 
         // handle depth changes
-        let prob_depth = call.prob_depth_tumor(
-            call.depth_normal as f64 * self.depth_norm_factor * cnv.expected_depth_factor(),
-        );
+        let prob_depth =
+            call.prob_depth_tumor(self.depth_norm_factor * cnv.expected_depth_factor());
         prob_af + prob_depth
     }
 }
 
-impl hmm::Model<Call> for HMM {
+impl hmm::Model<Call> for Hmm {
     fn num_states(&self) -> usize {
         self.states.len()
     }
@@ -535,34 +582,6 @@ pub(crate) fn likelihood<'a, O: 'a>(
     p
 }
 
-pub(crate) fn marginal<'a, O: 'a>(
-    hmm: &dyn hmm::Model<O>,
-    observations: impl IntoIterator<Item = &'a O>,
-) -> LogProb {
-    let mut prev = vec![LogProb::ln_zero(); hmm.num_states()];
-    let mut curr = prev.clone();
-
-    for (i, obs) in observations.into_iter().enumerate() {
-        for to in hmm.states() {
-            let prob_obs = hmm.observation_prob(to, obs);
-            curr[*to] = if i == 0 {
-                hmm.initial_prob(to)
-            } else {
-                prob_obs
-                    + LogProb::ln_sum_exp(
-                        &hmm.states()
-                            .map(|from| prev[*from] + hmm.transition_prob(from, to))
-                            .collect_vec(),
-                    )
-            };
-        }
-        mem::swap(&mut prev, &mut curr);
-    }
-
-    LogProb::ln_sum_exp(&prev.into_iter().collect_vec())
-        .cap_numerical_overshoot(utils::NUMERICAL_EPSILON)
-}
-
 #[derive(Debug)]
 pub(crate) struct Call {
     prob_germline_het: LogProb,
@@ -577,75 +596,104 @@ pub(crate) struct Call {
 }
 
 impl Call {
-    pub(crate) fn new(record: &mut bcf::Record) -> Result<Option<Self>> {
-        let pos = record.pos();
-        let prob_germline_het = record.info(b"PROB_GERMLINE_HET").float()?;
-        if let Some(_prob_germline_het) = prob_germline_het {
-            if !_prob_germline_het[0].is_missing() && !_prob_germline_het[0].is_nan() {
-                let prob_germline_het = LogProb::from(PHREDProb(_prob_germline_het[0] as f64));
-                assert!(
-                    *prob_germline_het <= 0.0,
-                    "invalid prob_germline_het: {}, POS: {}",
-                    _prob_germline_het[0],
-                    pos
-                );
-                let depths = record
-                    .format(b"DP")
-                    .integer()?
-                    .into_iter()
-                    .map(|d| d[0] as u32)
-                    .collect_vec();
-                let allele_freqs = record.format(b"AF").float()?;
-                if prob_germline_het >= LogProb::from(Prob(0.5)) {
-                    return Ok(Some(Call {
-                        allele_freq_tumor: AlleleFreq(allele_freqs.tumor()[0] as f64),
-                        allele_freq_normal: AlleleFreq(allele_freqs.normal()[0] as f64),
-                        depth_tumor: *depths.tumor(),
-                        depth_normal: *depths.normal(),
-                        prob_germline_het: prob_germline_het,
-                        start: record.pos() as u64,
-                        rid: record.rid().unwrap(),
-                        prev_start: None,
-                        next_start: None,
-                    }));
-                }
+    /// Germline heterozygous SNV call from the given record, or `None` if the record is
+    /// not a biallelic SNV, unlikely to be germline heterozygous, or lacks depth or allele
+    /// frequency information.
+    pub(crate) fn new(
+        record: &mut bcf::Record,
+        samples: &TumorNormalPair<usize>,
+    ) -> Result<Option<Self>> {
+        let alleles = record.alleles();
+        if alleles.len() != 2 || alleles[0].len() != 1 || alleles[1].len() != 1 {
+            return Ok(None);
+        }
+
+        let prob_germline_het = match record.info(b"PROB_GERMLINE_HET").float()? {
+            Some(values) if !values[0].is_missing() && !values[0].is_nan() => values[0],
+            _ => return Ok(None),
+        };
+        if prob_germline_het < 0.0 {
+            return Err(errors::Error::InvalidBCFRecord {
+                msg: format!(
+                    "invalid PROB_GERMLINE_HET {} at position {}",
+                    prob_germline_het,
+                    record.pos() + 1
+                ),
             }
+            .into());
+        }
+        let prob_germline_het = LogProb::from(PHREDProb(prob_germline_het as f64));
+        if prob_germline_het < LogProb::from(Prob(0.5)) {
+            return Ok(None);
         }
 
-        Ok(None)
+        let (depth_tumor, depth_normal) = {
+            let depths = record.format(b"DP").integer()?;
+            (depths[samples.tumor][0], depths[samples.normal][0])
+        };
+        let (allele_freq_tumor, allele_freq_normal) = {
+            let allele_freqs = record.format(b"AF").float()?;
+            (
+                allele_freqs[samples.tumor][0],
+                allele_freqs[samples.normal][0],
+            )
+        };
+        if depth_tumor.is_missing()
+            || depth_normal.is_missing()
+            || allele_freq_tumor.is_missing()
+            || allele_freq_normal.is_missing()
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(Call {
+            allele_freq_tumor: AlleleFreq(allele_freq_tumor as f64),
+            allele_freq_normal: AlleleFreq(allele_freq_normal as f64),
+            depth_tumor: depth_tumor as u32,
+            depth_normal: depth_normal as u32,
+            prob_germline_het,
+            start: record.pos() as u64,
+            rid: record.rid().unwrap(),
+            prev_start: None,
+            next_start: None,
+        }))
     }
 
     pub(crate) fn prob_allele_freq_tumor(&self, true_allele_freq: AlleleFreq) -> LogProb {
         allele_freq_pdf(self.allele_freq_tumor, true_allele_freq, self.depth_tumor)
     }
 
-    pub(crate) fn prob_allele_freq_normal_het(&self) -> LogProb {
-        allele_freq_pdf(self.allele_freq_normal, AlleleFreq(0.5), self.depth_normal)
-    }
-
-    pub(crate) fn prob_depth_tumor(&self, true_depth: f64) -> LogProb {
-        depth_pmf(self.depth_tumor, true_depth)
+    pub(crate) fn prob_depth_tumor(&self, depth_ratio: f64) -> LogProb {
+        depth_pmf(self.depth_tumor, self.depth_normal, depth_ratio)
     }
 }
 
 #[derive(PartialEq, Copy, Clone, Debug)]
-pub(crate) struct CNV {
+pub(crate) struct Cnv {
     gain: i32,
     allele_freq: AlleleFreq,
     purity: f64,
 }
 
-impl CNV {
+impl Cnv {
+    /// Fraction of all cells in the sample that are affected by the CNV.
+    fn affected_fraction(&self) -> f64 {
+        self.purity * *self.allele_freq
+    }
+
+    /// Expected allele frequency at a germline heterozygous locus if the CNV affects the
+    /// haplotype carrying the alt allele.
+    ///
+    /// METHOD: affected cells carry 2 + gain copies, of which 1 + gain carry the alt allele.
+    /// Unaffected (tumor or normal) cells carry one alt and one ref copy. The allele frequency
+    /// is the fraction of alt copies over all copies. If no copies are left (complete loss
+    /// in all cells), there is no allele frequency.
     pub(crate) fn expected_allele_freq_alt_affected(&self) -> Option<AlleleFreq> {
-        if self.gain > -2 {
-            Some(AlleleFreq(
-                *self.allele_freq * (1.0 + self.gain as f64) / (2.0 + self.gain as f64)
-                    + (1.0 - *self.allele_freq) * 0.5,
-            ))
-        } else if self.purity < 1.0 {
-            // gain = -2: all lost in tumor cells, hence 100% normal cells at this locus.
-            // Therefore VAF=0.5.
-            Some(AlleleFreq(0.5))
+        let affected = self.affected_fraction();
+        let alt_copies = affected * cmp::max(1 + self.gain, 0) as f64 + (1.0 - affected);
+        let total_copies = 2.0 * self.expected_depth_factor();
+        if total_copies > 0.0 {
+            Some(AlleleFreq(alt_copies / total_copies))
         } else {
             None
         }
@@ -656,9 +704,10 @@ impl CNV {
             .map(|af| AlleleFreq(1.0) - af)
     }
 
+    /// Expected depth relative to a diploid sample.
     pub(crate) fn expected_depth_factor(&self) -> f64 {
-        self.purity * (*self.allele_freq * (2.0 + self.gain as f64) / 2.0 + 1.0 - *self.allele_freq)
-            + (1.0 - self.purity)
+        let affected = self.affected_fraction();
+        (affected * (2 + self.gain) as f64 + 2.0 * (1.0 - affected)) / 2.0
     }
 }
 
@@ -678,23 +727,86 @@ mod tests {
         );
     }
 
-    // #[test]
-    // fn test_call() {
-    //     let call = Call {
-    //         prob_germline_het: LogProb::ln_one(),
-    //         allele_freq_tumor: AlleleFreq(1.0),
-    //         allele_freq_normal: AlleleFreq(0.5),
-    //         depth_tumor: 12,
-    //         depth_normal: 10,
-    //         start: 0,
-    //         rid: 0,
-    //     };
-    //
-    //     let cnv = CNV {
-    //         gain: 1,
-    //         allele_freq: AlleleFreq(1.0),
-    //         purity: 0.15,
-    //     };
-    //     // TODO add assertion
-    // }
+    fn call(depth_tumor: u32, allele_freq_tumor: f64) -> Call {
+        Call {
+            prob_germline_het: LogProb::from(Prob(0.99)),
+            allele_freq_tumor: AlleleFreq(allele_freq_tumor),
+            allele_freq_normal: AlleleFreq(0.5),
+            depth_tumor,
+            depth_normal: 40,
+            start: 0,
+            rid: 0,
+            prev_start: None,
+            next_start: None,
+        }
+    }
+
+    #[test]
+    fn test_cnv() {
+        let cnv = Cnv {
+            gain: -1,
+            allele_freq: AlleleFreq(1.0),
+            purity: 1.0,
+        };
+        assert_relative_eq!(cnv.expected_depth_factor(), 0.5);
+        assert_relative_eq!(*cnv.expected_allele_freq_alt_affected().unwrap(), 0.0);
+        assert_relative_eq!(*cnv.expected_allele_freq_ref_affected().unwrap(), 1.0);
+
+        let cnv = Cnv {
+            gain: 1,
+            allele_freq: AlleleFreq(1.0),
+            purity: 0.5,
+        };
+        assert_relative_eq!(cnv.expected_depth_factor(), 1.25);
+        assert_relative_eq!(*cnv.expected_allele_freq_alt_affected().unwrap(), 0.6);
+
+        // complete loss in a pure sample leaves no allele frequency information
+        let cnv = Cnv {
+            gain: -2,
+            allele_freq: AlleleFreq(1.0),
+            purity: 1.0,
+        };
+        assert!(cnv.expected_allele_freq_alt_affected().is_none());
+        assert_relative_eq!(cnv.expected_depth_factor(), 0.0);
+
+        // reads after complete loss in a subclone stem from unaffected cells
+        let cnv = Cnv {
+            gain: -2,
+            allele_freq: AlleleFreq(0.5),
+            purity: 1.0,
+        };
+        assert_relative_eq!(*cnv.expected_allele_freq_alt_affected().unwrap(), 0.5);
+        assert_relative_eq!(cnv.expected_depth_factor(), 0.5);
+    }
+
+    #[test]
+    fn test_loss() {
+        let hmm = Hmm::new(1.0, 100.0, 1.0);
+        // one copy lost: half the depth, and only one allele left at heterozygous loci
+        let calls = (0..10)
+            .map(|i| call(20, if i % 2 == 0 { 1.0 } else { 0.0 }))
+            .collect_vec();
+
+        let (states, _) = hmm::viterbi(&hmm, &calls);
+        for state in states {
+            let cnv = hmm.states[*state];
+            assert_eq!(cnv.gain, -1);
+            assert_relative_eq!(*cnv.allele_freq, 1.0);
+        }
+
+        let calls = calls.iter().collect_vec();
+        assert!(hmm.prob_no_cnv(&calls) < LogProb::from(Prob(0.01)));
+    }
+
+    #[test]
+    fn test_no_cnv() {
+        let hmm = Hmm::new(1.0, 100.0, 1.0);
+        let calls = (0..10).map(|_| call(40, 0.5)).collect_vec();
+
+        let (states, _) = hmm::viterbi(&hmm, &calls);
+        assert!(states.into_iter().all(|state| state == hmm.null_state()));
+
+        let calls = calls.iter().collect_vec();
+        assert!(hmm.prob_no_cnv(&calls) > LogProb::from(Prob(0.5)));
+    }
 }
@@ -1,2 +1,2 @@
-//pub(crate) mod cnvs;
+pub(crate) mod cnvs;
 pub(crate) mod variants;
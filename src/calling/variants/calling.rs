@@ -9,9 +9,12 @@ use derive_builder::Builder;
 use itertools::Itertools;
 use rust_htslib::bcf::{self, Read};
 
+use crate::calling::variants::phasing::Phaser;
 use crate::calling::variants::preprocessing::{
-    read_observations, remove_observation_header_entries, OBSERVATION_FORMAT_VERSION,
+    has_umi_families, read_observations, remove_observation_header_entries,
+    OBSERVATION_FORMAT_VERSION,
 };
+use crate::calling::variants::representation::RepresentationBuffer;
 use crate::calling::variants::SampleInfo;
 use crate::calling::variants::{
     chrom, event_tag_name, Call, CallBuilder, SampleInfoBuilder, VariantBuilder,
@@ -19,13 +22,17 @@ use crate::calling::variants::{
 use crate::errors;
 use crate::grammar;
 use crate::utils;
+use crate::utils::regions::{RegionReader, Regions};
+use crate::variants::evidence::depth::DepthObservation;
 use crate::variants::evidence::observation::{Observation, ReadPosition};
 use crate::variants::model;
+use crate::variants::model::bias::{Biases, DuplexRecoveryEstimator};
+use crate::variants::model::likelihood::{FragmentLengthModel, FragmentLengthModelEstimator};
 use crate::variants::model::modes::generic::{
     self, GenericLikelihood, GenericModelBuilder, GenericPosterior,
 };
+use crate::variants::model::AlleleFreq;
 use crate::variants::model::Contamination;
-use crate::variants::model::{bias::Biases, AlleleFreq};
 use crate::variants::types::breakends::BreakendIndex;
 
 pub(crate) type AlleleFreqCombination = Vec<model::likelihood::Event>;
@@ -33,6 +40,12 @@ pub(crate) type AlleleFreqCombination = Vec<model::likelihood::Event>;
 pub(crate) type Model<Pr> =
     bayesian::Model<GenericLikelihood, Pr, GenericPosterior, generic::Cache>;
 
+/// Fragment length models and duplex recovery rates of all samples.
+type SampleProperties = (
+    grammar::SampleInfo<Option<FragmentLengthModel>>,
+    grammar::SampleInfo<Option<LogProb>>,
+);
+
 #[derive(Builder)]
 #[builder(pattern = "owned")]
 pub(crate) struct Caller<Pr>
@@ -44,13 +57,17 @@ where
     omit_strand_bias: bool,
     omit_read_orientation_bias: bool,
     omit_read_position_bias: bool,
+    omit_duplex_bias: bool,
     scenario: grammar::Scenario,
     outbcf: Option<PathBuf>,
     contaminations: grammar::SampleInfo<Option<Contamination>>,
+    fragment_length_evidence: grammar::SampleInfo<bool>,
     resolutions: grammar::SampleInfo<usize>,
     prior: Pr,
     breakend_index: BreakendIndex,
     #[builder(default)]
+    regions: Option<Regions>,
+    #[builder(default)]
     breakend_results: RwLock<HashMap<Vec<u8>, BreakendResult>>,
 }
 
@@ -98,6 +115,12 @@ where
             b"##INFO=<ID=PROB_ABSENT,Number=A,Type=Float,\
              Description=\"Posterior probability for not having a variant (PHRED)\">",
         );
+        header.push_record(
+            b"##INFO=<ID=REPRESENTATION,Number=1,Type=String,\
+             Description=\"Representation supported by the reads for MNVs obtained by merging \
+             adjacent SNVs (MERGED_SNVS): MNV if the substitutions likely occur together on the same \
+             reads (in cis), SNVS if they rather occur separately.\">",
+        );
 
         // register sample specific tags
         header.push_record(
@@ -143,6 +166,36 @@ where
               Probability for read orientation bias is captured by the ARTIFACT \
               event (PROB_ARTIFACT).\">",
         );
+        header.push_record(
+            b"##FORMAT=<ID=DB,Number=A,Type=String,\
+              Description=\"Duplex bias estimate: ! indicates that ALT allele is only supported by \
+              single strands of duplex sequenced molecules (UMI families), . indicates no duplex bias. \
+              Duplex bias is indicative of damage or errors that occurred before or during the first \
+              amplification of one strand of the original molecule. Probability for duplex bias is \
+              captured by the ARTIFACT event (PROB_ARTIFACT).\">",
+        );
+        header.push_record(
+            b"##FORMAT=<ID=PS,Number=1,Type=Integer,\
+              Description=\"Phase set of the call, given as the position of the first call in the set. \
+              Calls of the same phase set are linked via shared fragments (read-backed phasing, \
+              requires preprocessing with --phasing).\">",
+        );
+        header.push_record(
+            b"##FORMAT=<ID=HP,Number=1,Type=Integer,\
+              Description=\"Haplotype of the phase set (1 or 2) carrying the ALT allele. Calls of the \
+              same phase set with the same haplotype are in cis, otherwise they are in trans.\">",
+        );
+        header.push_record(
+            b"##FORMAT=<ID=PQ,Number=1,Type=Integer,\
+              Description=\"Phasing quality: probability that the ALT allele is not located on the \
+              given haplotype of the phase set (PHRED).\">",
+        );
+        header.push_record(
+            b"##FORMAT=<ID=RCP,Number=R,Type=Float,\
+              Description=\"Posterior probability (PHRED) that the sample does not carry the repeat \
+              count of the respective allele (RN units for REF, the given number of units for ALT). \
+              Only given for tandem repeats.\">",
+        );
 
         Ok(header)
     }
@@ -159,21 +212,25 @@ where
         })
     }
 
-    fn model(&self) -> Model<Pr> {
+    fn model(
+        &self,
+        fragment_length_models: &grammar::SampleInfo<Option<FragmentLengthModel>>,
+    ) -> Model<Pr> {
         GenericModelBuilder::default()
             // TODO allow to define prior in the grammar
             .prior(self.prior.clone())
             .contaminations(self.contaminations.clone())
+            .fragment_length_models(fragment_length_models.clone())
             .resolutions(self.resolutions.clone())
             .build()
             .unwrap()
     }
 
-    fn observations(&self) -> Result<grammar::SampleInfo<Option<bcf::Reader>>> {
+    fn observations(&self) -> Result<grammar::SampleInfo<Option<RegionReader>>> {
         let mut observations = grammar::SampleInfo::default();
         for path in self.observations.iter() {
             if let Some(path) = path {
-                let mut reader = bcf::Reader::from_path(path)?;
+                let mut reader = RegionReader::new(path, self.regions.as_ref())?;
                 reader.set_threads(1)?;
                 observations.push(Some(reader));
             } else {
@@ -183,6 +240,76 @@ where
         Ok(observations)
     }
 
+    /// Learn the fragment length models of all samples for which fragment lengths shall be
+    /// considered as evidence, and the duplex recovery rates of all samples that have been
+    /// preprocessed with UMI families if duplex bias shall be considered. Both are obtained
+    /// in a single pass over the observations.
+    fn sample_properties(&self) -> Result<SampleProperties> {
+        let mut models = grammar::SampleInfo::default();
+        let mut duplex_recovery_rates = grammar::SampleInfo::default();
+        for ((path, fragment_length_evidence), sample_name) in self
+            .observations
+            .iter()
+            .zip(self.fragment_length_evidence.iter())
+            .zip(self.samplenames.iter())
+        {
+            let (model, duplex_recovery_rate) = match path {
+                Some(path) => {
+                    let mut reader = RegionReader::new(path, self.regions.as_ref())?;
+                    let learn_fragment_lengths = *fragment_length_evidence;
+                    // METHOD: duplex recovery is only learned (and hence duplex bias only
+                    // considered) for samples that have been preprocessed with UMI families.
+                    // Otherwise, there are no families and the pass would be in vain.
+                    let learn_duplex_recovery =
+                        !self.omit_duplex_bias && has_umi_families(reader.header());
+                    if !learn_fragment_lengths && !learn_duplex_recovery {
+                        models.push(None);
+                        duplex_recovery_rates.push(None);
+                        continue;
+                    }
+                    // METHOD: both properties are learned once per sample, pooled over all of
+                    // its loci. By this, the fragments of a single locus have a negligible
+                    // influence on the distributions they are evaluated against.
+                    let mut fragment_length_estimator = FragmentLengthModelEstimator::default();
+                    let mut duplex_recovery_estimator = DuplexRecoveryEstimator::default();
+                    loop {
+                        let mut record = reader.empty_record();
+                        match reader.read(&mut record) {
+                            None => break,
+                            Some(res) => res?,
+                        }
+                        let observations = read_observations(&mut record)?;
+                        if learn_fragment_lengths {
+                            fragment_length_estimator.register(&observations);
+                        }
+                        if learn_duplex_recovery {
+                            duplex_recovery_estimator.register(&observations);
+                        }
+                    }
+                    let model = if learn_fragment_lengths {
+                        let model = fragment_length_estimator.estimate();
+                        if model.is_none() {
+                            warn!(
+                                "Too few fragments of known length for learning the fragment \
+                                 length distributions of sample {}. Fragment lengths will not \
+                                 be considered as evidence for this sample.",
+                                sample_name
+                            );
+                        }
+                        model
+                    } else {
+                        None
+                    };
+                    (model, duplex_recovery_estimator.estimate())
+                }
+                None => (None, None),
+            };
+            models.push(model);
+            duplex_recovery_rates.push(duplex_recovery_rate);
+        }
+        Ok((models, duplex_recovery_rates))
+    }
+
     pub(crate) fn call(&self) -> Result<()> {
         let mut observations = self.observations()?;
         let mut bcf_writer = self.writer()?;
@@ -205,11 +332,15 @@ where
             }
         }
 
+        let (fragment_length_models, duplex_recovery_rates) = self.sample_properties()?;
+
         // data structures
         // For SNVs and MNVs we need a special model as here read orientation bias and read position bias needs to be considered.
         let mut models = HashMap::new();
         let mut events = Vec::new();
         let mut last_rids = HashMap::new();
+        let mut phaser = Phaser::new(self.n_samples());
+        let mut representations = RepresentationBuffer::default();
 
         // process calls
         let mut i = 0;
@@ -230,6 +361,9 @@ where
             }
 
             if eof.iter().all(|v| *v) {
+                for call in representations.flush() {
+                    call.write_final_record(&mut bcf_writer)?;
+                }
                 return Ok(());
             } else if !eof.iter().all(|v| !v) {
                 // only some are EOF, this is an error
@@ -252,7 +386,8 @@ where
                 }
             }
 
-            let mut work_item = self.preprocess_record(&mut records, i, &observations)?;
+            let mut work_item =
+                self.preprocess_record(&mut records, i, &observations, &duplex_recovery_rates)?;
 
             // process work item
             let contig = str::from_utf8(work_item.call.chrom()).unwrap();
@@ -263,27 +398,26 @@ where
                 work_item.check_read_orientation_bias,
                 work_item.check_read_position_bias,
             );
-            _model = models.entry(model_mode).or_insert_with(|| self.model());
+            _model = models
+                .entry(model_mode)
+                .or_insert_with(|| self.model(&fragment_length_models));
             {
                 let entry = last_rids.entry(model_mode).or_insert(None);
                 _last_rid = (*entry).clone();
                 *entry = Some(work_item.rid);
             }
 
-            self.configure_model(
-                work_item.rid,
-                _last_rid,
-                _model,
-                &mut events,
-                contig,
-                work_item.check_read_orientation_bias,
-                work_item.check_strand_bias,
-                work_item.check_read_position_bias,
-            )?;
+            self.configure_model(&work_item, _last_rid, _model, &mut events, contig)?;
 
             self.call_record(&mut work_item, _model, &events);
 
-            work_item.call.write_final_record(&mut bcf_writer)?;
+            if let Some(ref mut variant) = work_item.call.variant {
+                phaser.phase(work_item.rid, work_item.call.pos, &mut variant.sample_info);
+            }
+
+            for call in representations.push(work_item.call) {
+                call.write_final_record(&mut bcf_writer)?;
+            }
 
             if (i + 1) % 100 == 0 {
                 info!("{} records processed.", i + 1);
@@ -297,12 +431,13 @@ where
         &self,
         records: &mut grammar::SampleInfo<Option<bcf::Record>>,
         index: usize,
-        observations: &grammar::SampleInfo<Option<bcf::Reader>>,
+        observations: &grammar::SampleInfo<Option<RegionReader>>,
+        duplex_recovery_rates: &grammar::SampleInfo<Option<LogProb>>,
     ) -> Result<WorkItem> {
         let (call, snv, bnd_event, rid, is_snv_or_mnv) = {
             let first_record = records.first_not_none_mut()?;
             let start = first_record.pos() as u64;
-            let chrom = chrom(observations.first_not_none()?, first_record);
+            let chrom = chrom(observations.first_not_none()?.header(), first_record);
 
             let call = CallBuilder::default()
                 .chrom(chrom.to_owned())
@@ -359,6 +494,7 @@ where
             rid,
             call,
             pileups: None,
+            depth_observations: Vec::new(),
             snv,
             bnd_event,
             variant_builder,
@@ -366,6 +502,9 @@ where
             check_read_orientation_bias: is_snv_or_mnv && !self.omit_read_orientation_bias,
             check_strand_bias: !self.omit_strand_bias,
             check_read_position_bias: is_snv_or_mnv && !self.omit_read_position_bias,
+            // Duplex recovery rates are only known for samples with UMI families.
+            check_duplex_bias: !self.omit_duplex_bias
+                && duplex_recovery_rates.iter().any(|rate| rate.is_some()),
         };
 
         if let Some(ref event) = work_item.bnd_event {
@@ -378,9 +517,16 @@ where
 
         // obtain pileups
         let mut pileups = Vec::new();
-        for record in records.iter_mut() {
+        let mut depth_observations = Vec::new();
+        for (record, duplex_recovery_rate) in records.iter_mut().zip(duplex_recovery_rates.iter()) {
+            depth_observations.push(record.as_mut().and_then(DepthObservation::read));
             let pileup = if let Some(record) = record {
                 let mut pileup = read_observations(record)?;
+                if let Some(duplex_recovery_rate) = duplex_recovery_rate {
+                    for observation in &mut pileup {
+                        observation.prob_duplex_recovery = *duplex_recovery_rate;
+                    }
+                }
                 if is_snv_or_mnv {
                     // METHOD: adjust MAPQ to get rid of stochastically inflated ones
                     //Observation::adjust_prob_mapping(&mut pileup);
@@ -402,22 +548,20 @@ where
         }
 
         work_item.pileups = Some(pileups);
+        work_item.depth_observations = depth_observations;
 
         Ok(work_item)
     }
 
     fn configure_model(
         &self,
-        current_rid: u32,
+        work_item: &WorkItem,
         rid: Option<u32>,
         model: &mut Model<Pr>,
         events: &mut Vec<model::Event>,
         contig: &str,
-        consider_read_orientation_bias: bool,
-        consider_strand_bias: bool,
-        consider_read_position_bias: bool,
     ) -> Result<()> {
-        if !rid.map_or(false, |rid: u32| current_rid == rid) {
+        if !rid.map_or(false, |rid: u32| work_item.rid == rid) {
             // rid is not the same as before, obtain event universe
             // clear old events
             events.clear();
@@ -438,9 +582,10 @@ where
                 });
 
                 let biases: Vec<_> = Biases::all_artifact_combinations(
-                    consider_read_orientation_bias,
-                    consider_strand_bias,
-                    consider_read_position_bias,
+                    work_item.check_read_orientation_bias,
+                    work_item.check_strand_bias,
+                    work_item.check_read_position_bias,
+                    work_item.check_duplex_bias,
                 )
                 .collect();
                 if !biases.is_empty() {
@@ -499,6 +644,7 @@ where
         if work_item.pileups.is_some() {
             let data = model::modes::generic::Data::new(
                 work_item.pileups.take().unwrap(),
+                std::mem::take(&mut work_item.depth_observations),
                 work_item.snv.clone(),
             );
 
@@ -602,10 +748,12 @@ struct WorkItem {
     call: Call,
     variant_builder: VariantBuilder,
     pileups: Option<Vec<Vec<Observation<ReadPosition>>>>,
+    depth_observations: Vec<Option<DepthObservation>>,
     snv: Option<model::modes::generic::SNV>,
     bnd_event: Option<Vec<u8>>,
     index: usize,
     check_read_orientation_bias: bool,
     check_strand_bias: bool,
     check_read_position_bias: bool,
+    check_duplex_bias: bool,
 }
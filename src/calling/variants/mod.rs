@@ -4,28 +4,34 @@
 // except according to those terms.
 
 pub(crate) mod calling;
+pub(crate) mod phasing;
 pub(crate) mod preprocessing;
+pub(crate) mod representation;
 
 use std::collections::HashMap;
 use std::str;
 use std::u8;
 
 use anyhow::Result;
-use bio::stats::{LogProb, PHREDProb};
+use bio::stats::{LogProb, PHREDProb, Prob};
 use bio_types::sequence::SequenceReadPairOrientation;
 use derive_builder::Builder;
 use itertools::Itertools;
-use rust_htslib::bcf::{self, record::Numeric, Read};
+use rust_htslib::bcf::{self, record::Numeric};
 use vec_map::VecMap;
 
 use crate::calling::variants::preprocessing::write_observations;
 use crate::utils;
+use crate::variants::evidence::depth::DepthObservation;
 use crate::variants::evidence::observation::expected_depth;
 use crate::variants::evidence::observation::{Observation, ReadPosition, Strand};
 use crate::variants::model;
 use crate::variants::model::{
-    bias::Biases, bias::ReadOrientationBias, bias::ReadPositionBias, bias::StrandBias, AlleleFreq,
+    bias::Biases, bias::DuplexBias, bias::ReadOrientationBias, bias::ReadPositionBias,
+    bias::StrandBias, AlleleFreq,
 };
+use crate::variants::types::insertion;
+use crate::variants::types::tandem_repeat::prob_repeat_counts_absent;
 
 pub(crate) use crate::calling::variants::calling::CallerBuilder;
 
@@ -49,7 +55,11 @@ impl CallBuilder {
 }
 
 impl Call {
-    pub(crate) fn write_preprocessed_record(&self, bcf_writer: &mut bcf::Writer) -> Result<()> {
+    pub(crate) fn write_preprocessed_record(
+        &self,
+        bcf_writer: &mut bcf::Writer,
+        fragment_ids: bool,
+    ) -> Result<()> {
         let rid = bcf_writer.header().name2rid(&self.chrom)?;
 
         let variant = self.variant.as_ref().unwrap();
@@ -81,13 +91,22 @@ impl Call {
         if let Some(ref mateid) = self.mateid {
             record.push_info_string(b"MATEID", &[mateid])?;
         }
+        if variant.merged_snvs {
+            record.push_info_flag(b"MERGED_SNVS")?;
+        }
+        variant.write_insertion_flanks(&mut record)?;
+        variant.write_repeat_info(&mut record)?;
+        variant.write_refined_breakpoints(&mut record)?;
 
         // set qual
         record.set_qual(f32::missing());
 
         // add raw observations
         if let Some(ref obs) = variant.observations {
-            write_observations(obs, &mut record)?;
+            write_observations(obs, &mut record, fragment_ids)?;
+        }
+        if let Some(ref depth_observation) = variant.depth_observation {
+            depth_observation.write(&mut record)?;
         }
 
         bcf_writer.write(&record)?;
@@ -116,6 +135,11 @@ impl Call {
         let mut strand_bias = VecMap::new();
         let mut read_orientation_bias = VecMap::new();
         let mut read_position_bias = VecMap::new();
+        let mut duplex_bias = VecMap::new();
+        let mut phase_sets = VecMap::new();
+        let mut haplotypes = VecMap::new();
+        let mut phase_quals = VecMap::new();
+        let mut repeat_count_probs = VecMap::new();
         let mut alleles = Vec::new();
         let mut svlens = Vec::new();
         let mut events = Vec::new();
@@ -163,6 +187,45 @@ impl Call {
                         ReadPositionBias::Some => b'^',
                     },
                 );
+                duplex_bias.insert(
+                    i,
+                    match sample_info.biases.duplex_bias() {
+                        DuplexBias::None => b'.',
+                        DuplexBias::SingleStrand => b'!',
+                    },
+                );
+
+                if let Some(ref phase) = sample_info.phase {
+                    phase_sets.insert(i, phase.set as i32);
+                    haplotypes.insert(i, phase.haplotype as i32);
+                    // The first call of a phase set defines its haplotypes, hence there is
+                    // no phasing error to report.
+                    phase_quals.insert(
+                        i,
+                        if phase.prob_error == LogProb::ln_zero() {
+                            i32::missing()
+                        } else {
+                            PHREDProb::from(phase.prob_error).abs().round() as i32
+                        },
+                    );
+                } else {
+                    phase_sets.insert(i, i32::missing());
+                    haplotypes.insert(i, i32::missing());
+                    phase_quals.insert(i, i32::missing());
+                }
+
+                if variant.repeat_unit.is_some() {
+                    repeat_count_probs.insert(
+                        i,
+                        match prob_repeat_counts_absent(&sample_info.observations) {
+                            Some((prob_ref_absent, prob_alt_absent)) => vec![
+                                PHREDProb::from(prob_ref_absent).abs() as f32,
+                                PHREDProb::from(prob_alt_absent).abs() as f32,
+                            ],
+                            None => vec![f32::missing(); 2],
+                        },
+                    );
+                }
 
                 allelefreq_estimates.insert(i, *sample_info.allelefreq_estimate as f32);
 
@@ -243,6 +306,17 @@ impl Call {
         // set qual
         record.set_qual(f32::missing());
 
+        variant.write_insertion_flanks(&mut record)?;
+        variant.write_repeat_info(&mut record)?;
+        variant.write_refined_breakpoints(&mut record)?;
+
+        if variant.merged_snvs {
+            record.push_info_flag(b"MERGED_SNVS")?;
+        }
+        if let Some(representation) = variant.representation {
+            record.push_info_string(b"REPRESENTATION", &[representation.as_bytes()])?;
+        }
+
         // set event probabilities
         // determine whether marginal probability is zero (prob becomes NaN)
         // this is a missing data case, which we want to present accordingly
@@ -291,6 +365,23 @@ impl Call {
                 .map(|rpb| vec![*rpb])
                 .collect_vec();
             record.push_format_string(b"RPB", &rpb)?;
+
+            let db = duplex_bias.values().map(|db| vec![*db]).collect_vec();
+            record.push_format_string(b"DB", &db)?;
+
+            if phase_sets.values().any(|ps| !ps.is_missing()) {
+                let ps = phase_sets.values().cloned().collect_vec();
+                record.push_format_integer(b"PS", &ps)?;
+                let hp = haplotypes.values().cloned().collect_vec();
+                record.push_format_integer(b"HP", &hp)?;
+                let pq = phase_quals.values().cloned().collect_vec();
+                record.push_format_integer(b"PQ", &pq)?;
+            }
+
+            if !repeat_count_probs.is_empty() {
+                let rcp = repeat_count_probs.values().flatten().cloned().collect_vec();
+                record.push_format_float(b"RCP", &rcp)?;
+            }
         } else {
             record.push_format_integer(b"DP", &vec![i32::missing(); variant.sample_info.len()])?;
             record.push_format_float(b"AF", &vec![f32::missing(); variant.sample_info.len()])?;
@@ -298,6 +389,7 @@ impl Call {
             record.push_format_string(b"SB", &vec![b".".to_vec(); variant.sample_info.len()])?;
             record.push_format_string(b"ROB", &vec![b".".to_vec(); variant.sample_info.len()])?;
             record.push_format_string(b"RPB", &vec![b".".to_vec(); variant.sample_info.len()])?;
+            record.push_format_string(b"DB", &vec![b".".to_vec(); variant.sample_info.len()])?;
         }
 
         bcf_writer.write(&record)?;
@@ -319,16 +411,82 @@ pub(crate) struct Variant {
     event: Option<Vec<u8>>,
     #[builder(private, default = "None")]
     end: Option<u64>,
+    /// Known left flank of a symbolic insertion.
+    #[builder(private, default = "None")]
+    left_svinsseq: Option<Vec<u8>>,
+    /// Known right flank of a symbolic insertion.
+    #[builder(private, default = "None")]
+    right_svinsseq: Option<Vec<u8>>,
+    /// Repeat unit of a tandem repeat.
+    #[builder(private, default = "None")]
+    repeat_unit: Option<Vec<u8>>,
+    /// Number of repeat units of a tandem repeat in the reference.
+    #[builder(private, default = "None")]
+    ref_repeat_count: Option<i32>,
+    /// Whether this is an MNV that has been obtained by merging adjacent SNV candidates.
+    #[builder(default = "false")]
+    merged_snvs: bool,
+    /// Representation supported by the reads for an MNV obtained by merging adjacent SNVs.
+    /// It is determined after calling, once the posteriors of the merged SNVs are known.
+    #[builder(private, default = "None")]
+    representation: Option<representation::Representation>,
+    /// Refined position (1-based) of an imprecise breakpoint.
+    #[builder(default = "None")]
+    refined_pos: Option<u64>,
+    /// Refined end position (1-based, inclusive) of an imprecise structural variant.
+    #[builder(default = "None")]
+    refined_end: Option<u64>,
+    /// Posterior probability of the refined breakpoints.
+    #[builder(default = "None")]
+    refined_prob: Option<LogProb>,
     #[builder(private, default = "None")]
     #[getset(get = "pub(crate)")]
     event_probs: Option<HashMap<String, LogProb>>,
     #[builder(default = "None")]
     observations: Option<Vec<Observation<ReadPosition>>>,
+    /// Read depth within a large deletion or duplication.
+    #[builder(default = "None")]
+    depth_observation: Option<DepthObservation>,
     #[builder(default)]
     #[getset(get = "pub(crate)")]
     sample_info: Vec<Option<SampleInfo>>,
 }
 
+impl Variant {
+    fn write_insertion_flanks(&self, record: &mut bcf::Record) -> Result<()> {
+        if let Some(ref left) = self.left_svinsseq {
+            record.push_info_string(b"LEFT_SVINSSEQ", &[left])?;
+        }
+        if let Some(ref right) = self.right_svinsseq {
+            record.push_info_string(b"RIGHT_SVINSSEQ", &[right])?;
+        }
+        Ok(())
+    }
+
+    fn write_repeat_info(&self, record: &mut bcf::Record) -> Result<()> {
+        if let Some(ref unit) = self.repeat_unit {
+            record.push_info_string(b"RU", &[unit])?;
+        }
+        if let Some(ref_count) = self.ref_repeat_count {
+            record.push_info_integer(b"RN", &[ref_count])?;
+        }
+        Ok(())
+    }
+
+    fn write_refined_breakpoints(&self, record: &mut bcf::Record) -> Result<()> {
+        if let Some(refined_pos) = self.refined_pos {
+            record.push_info_integer(b"REFINED_POS", &[refined_pos as i32])?;
+        }
+        if let Some(refined_end) = self.refined_end {
+            record.push_info_integer(b"REFINED_END", &[refined_end as i32])?;
+        }
+        if let Some(refined_prob) = self.refined_prob {
+            record.push_info_float(b"REFINED_PROB", &[*Prob::from(refined_prob) as f32])?;
+        }
+        Ok(())
+    }
+}
+
 impl VariantBuilder {
     /// Build the variant from a single-allele bcf record.
     pub(crate) fn record(&mut self, record: &mut bcf::Record) -> Result<&mut Self> {
@@ -339,7 +497,57 @@ impl VariantBuilder {
             .svlen(record.info(b"SVLEN").integer()?.map(|v| v[0]))
             .event(utils::info_tag_event(record)?.map(|e| e.to_vec()))
             .svtype(utils::info_tag_svtype(record)?.map(|s| s.to_vec()))
-            .end(record.info(b"END").integer()?.map(|v| v[0] as u64)))
+            .end(record.info(b"END").integer()?.map(|v| v[0] as u64))
+            .left_svinsseq(
+                record
+                    .info(b"LEFT_SVINSSEQ")
+                    .string()
+                    .ok()
+                    .flatten()
+                    .map(|v| v[0].to_owned()),
+            )
+            .right_svinsseq(
+                record
+                    .info(b"RIGHT_SVINSSEQ")
+                    .string()
+                    .ok()
+                    .flatten()
+                    .map(|v| v[0].to_owned()),
+            )
+            .repeat_unit(
+                record
+                    .info(b"RU")
+                    .string()
+                    .ok()
+                    .flatten()
+                    .map(|v| v[0].to_owned()),
+            )
+            .ref_repeat_count(record.info(b"RN").integer().ok().flatten().map(|v| v[0]))
+            .merged_snvs(record.info(b"MERGED_SNVS").flag().unwrap_or(false))
+            .refined_pos(
+                record
+                    .info(b"REFINED_POS")
+                    .integer()
+                    .ok()
+                    .flatten()
+                    .map(|v| v[0] as u64),
+            )
+            .refined_end(
+                record
+                    .info(b"REFINED_END")
+                    .integer()
+                    .ok()
+                    .flatten()
+                    .map(|v| v[0] as u64),
+            )
+            .refined_prob(
+                record
+                    .info(b"REFINED_PROB")
+                    .float()
+                    .ok()
+                    .flatten()
+                    .map(|v| LogProb::from(Prob(v[0] as f64))),
+            ))
     }
 
     pub(crate) fn variant(
@@ -368,13 +576,30 @@ impl VariantBuilder {
             model::Variant::Insertion(ref seq) => {
                 let svlen = seq.len() as i32;
                 let ref_allele = vec![chrom_seq.unwrap()[start]];
-                let mut alt_allele = ref_allele.clone();
-                alt_allele.extend(seq);
+                if let Some((left, right)) = insertion::known_flanks(seq) {
+                    // insertion with unknown sequence, report the known flanks
+                    let flank = |seq: &[u8]| {
+                        if seq.is_empty() {
+                            None
+                        } else {
+                            Some(seq.to_owned())
+                        }
+                    };
+                    self.ref_allele(ref_allele.to_ascii_uppercase())
+                        .alt_allele(b"<INS>".to_vec())
+                        .svlen(Some(svlen))
+                        .svtype(Some(b"INS".to_vec()))
+                        .left_svinsseq(flank(left))
+                        .right_svinsseq(flank(right))
+                } else {
+                    let mut alt_allele = ref_allele.clone();
+                    alt_allele.extend(seq);
 
-                self.ref_allele(ref_allele.to_ascii_uppercase())
-                    .alt_allele(alt_allele.to_ascii_uppercase())
-                    .svlen(Some(svlen))
-                    .svtype(Some(b"INS".to_vec()))
+                    self.ref_allele(ref_allele.to_ascii_uppercase())
+                        .alt_allele(alt_allele.to_ascii_uppercase())
+                        .svlen(Some(svlen))
+                        .svtype(Some(b"INS".to_vec()))
+                }
             }
             model::Variant::SNV(base) => self
                 .ref_allele(chrom_seq.unwrap()[start..start + 1].to_ascii_uppercase())
@@ -407,6 +632,24 @@ impl VariantBuilder {
             } => self
                 .ref_allele(ref_allele.to_owned())
                 .alt_allele(alt_allele.to_owned()),
+            model::Variant::MobileElementInsertion { family, len } => {
+                let mut alt_allele = b"<INS:ME:".to_vec();
+                alt_allele.extend(family);
+                alt_allele.push(b'>');
+                self.ref_allele(chrom_seq.unwrap()[start..start + 1].to_ascii_uppercase())
+                    .alt_allele(alt_allele)
+                    .svlen(Some(*len as i32))
+                    .svtype(Some(b"INS".to_vec()))
+            }
+            model::Variant::TandemRepeat {
+                unit,
+                ref_count,
+                alt_count,
+            } => self
+                .ref_allele(chrom_seq.unwrap()[start..start + 1].to_ascii_uppercase())
+                .alt_allele(format!("<STR{}>", alt_count).into_bytes())
+                .repeat_unit(Some(unit.to_ascii_uppercase()))
+                .ref_repeat_count(Some(*ref_count as i32)),
             model::Variant::None => self
                 .ref_allele(chrom_seq.unwrap()[start..start + 1].to_ascii_uppercase())
                 .alt_allele(b"<REF>".to_ascii_uppercase()),
@@ -420,6 +663,8 @@ pub(crate) struct SampleInfo {
     #[builder(default = "Vec::new()")]
     observations: Vec<Observation<ReadPosition>>,
     biases: Biases,
+    #[builder(default = "None")]
+    phase: Option<phasing::Phase>,
 }
 
 /// Wrapper for comparing alleles for compatibility in BCF files.
@@ -440,8 +685,8 @@ impl<'a> PartialEq for BCFGrouper<'a> {
     }
 }
 
-fn chrom<'a>(inbcf: &'a bcf::Reader, record: &bcf::Record) -> &'a [u8] {
-    inbcf.header().rid2name(record.rid().unwrap()).unwrap()
+fn chrom<'a>(header: &'a bcf::header::HeaderView, record: &bcf::Record) -> &'a [u8] {
+    header.rid2name(record.rid().unwrap()).unwrap()
 }
 
 pub(crate) fn event_tag_name(event: &str) -> String {
@@ -0,0 +1,355 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Read-backed phasing of nearby calls. Calls are phased against each other if they share
+//! fragments, which have been recorded for each observation during preprocessing.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use bio::stats::{LogProb, Prob};
+
+use crate::calling::variants::SampleInfo;
+use crate::variants::evidence::observation::{Observation, ReadPosition};
+
+/// Probability that a fragment supports an allele it does not carry (e.g. due to
+/// sequencing errors or wrongly assigned fragments).
+const PROB_FRAGMENT_ERROR: f64 = 0.01;
+
+/// Minimum probability for a fragment to be assigned to the alt or the ref allele of a call.
+const MIN_PROB_ASSIGNMENT: f64 = 0.95;
+
+/// Maximum probability of a phasing error for a call to assign its fragments to haplotypes.
+/// Fragments of less certainly phased calls would propagate errors to subsequent calls.
+const MAX_PROB_PHASE_ERROR: f64 = 0.05;
+
+/// Maximum distance between two calls that can be covered by the same fragment.
+/// Fragments are forgotten once they are further away from the current call.
+const MAX_FRAGMENT_SPAN: u64 = 100_000;
+
+/// Phase of a call in a sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Phase {
+    /// Identifier of the phase set (the 1-based position of its first call).
+    pub(crate) set: u64,
+    /// Haplotype of the phase set (1 or 2) that carries the alt allele.
+    pub(crate) haplotype: u8,
+    /// Probability that the alt allele is not carried by the given haplotype.
+    pub(crate) prob_error: LogProb,
+}
+
+#[derive(Debug, Default)]
+struct AssignedFragment {
+    /// Number of calls supporting that the fragment stems from the first or the second
+    /// haplotype.
+    votes: [u32; 2],
+    last_pos: u64,
+}
+
+impl AssignedFragment {
+    fn vote(&mut self, haplotype: u8, pos: u64) {
+        self.votes[haplotype as usize - 1] += 1;
+        self.last_pos = pos;
+    }
+
+    /// Haplotype the fragment is assigned to, or `None` if calls disagree equally.
+    fn haplotype(&self) -> Option<u8> {
+        match self.votes[0].cmp(&self.votes[1]) {
+            Ordering::Greater => Some(1),
+            Ordering::Less => Some(2),
+            Ordering::Equal => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PhaseSet {
+    rid: u32,
+    id: u64,
+    fragments: HashMap<u64, AssignedFragment>,
+}
+
+/// Phaser that processes calls in the order of the genome and keeps an active phase set
+/// per sample.
+#[derive(Debug)]
+pub(crate) struct Phaser {
+    phase_sets: Vec<Option<PhaseSet>>,
+}
+
+impl Phaser {
+    pub(crate) fn new(n_samples: usize) -> Self {
+        Phaser {
+            phase_sets: (0..n_samples).map(|_| None).collect(),
+        }
+    }
+
+    /// Phase the call at the given position against previous calls and record the phase
+    /// in the given sample info.
+    pub(crate) fn phase(&mut self, rid: u32, pos: u64, sample_info: &mut [Option<SampleInfo>]) {
+        for (phase_set, sample_info) in self.phase_sets.iter_mut().zip(sample_info.iter_mut()) {
+            if let Some(sample_info) = sample_info {
+                // METHOD: only heterozygous calls can be phased. Absent calls do not carry
+                // an alt allele, and homozygous calls occur on both haplotypes.
+                let af = *sample_info.allelefreq_estimate;
+                if af <= 0.0 || af >= 1.0 {
+                    continue;
+                }
+
+                let fragments = fragment_probs(&sample_info.observations);
+
+                if let Some(ref mut current) = phase_set {
+                    if current.rid == rid {
+                        current
+                            .fragments
+                            .retain(|_, fragment| fragment.last_pos + MAX_FRAGMENT_SPAN >= pos);
+                    } else {
+                        current.fragments.clear();
+                    }
+                }
+
+                let phase = phase_set
+                    .as_ref()
+                    .and_then(|current| phase_against(current, &fragments))
+                    .or_else(|| {
+                        // METHOD: no fragments are shared with the active phase set, hence we
+                        // start a new one if this call can be linked to subsequent ones.
+                        if fragments.iter().any(|(_, prob)| is_assignable(*prob)) {
+                            *phase_set = Some(PhaseSet {
+                                rid,
+                                id: pos + 1,
+                                fragments: HashMap::new(),
+                            });
+                            Some(Phase {
+                                set: pos + 1,
+                                haplotype: 1,
+                                prob_error: LogProb::ln_zero(),
+                            })
+                        } else {
+                            None
+                        }
+                    });
+
+                if let (Some(phase), Some(current)) = (phase, phase_set.as_mut()) {
+                    // METHOD: fragments with clear evidence vote for the haplotype they
+                    // support, if the call itself is confidently phased. Fragments seen
+                    // at multiple calls are assigned by majority, such that an earlier
+                    // assignment can be revised by subsequent calls.
+                    if phase.prob_error <= LogProb::from(Prob(MAX_PROB_PHASE_ERROR)) {
+                        for (fragment_id, prob_alt) in fragments {
+                            if !is_assignable(prob_alt) {
+                                continue;
+                            }
+                            let haplotype = if prob_alt >= MIN_PROB_ASSIGNMENT {
+                                phase.haplotype
+                            } else {
+                                3 - phase.haplotype
+                            };
+                            current
+                                .fragments
+                                .entry(fragment_id)
+                                .or_default()
+                                .vote(haplotype, pos);
+                        }
+                    }
+                }
+
+                sample_info.phase = phase;
+            }
+        }
+    }
+}
+
+fn is_assignable(prob_alt: f64) -> bool {
+    prob_alt >= MIN_PROB_ASSIGNMENT || prob_alt <= 1.0 - MIN_PROB_ASSIGNMENT
+}
+
+/// Phase a call against the given phase set. Returns `None` if the call does not share
+/// fragments with the phase set.
+fn phase_against(phase_set: &PhaseSet, fragments: &[(u64, f64)]) -> Option<Phase> {
+    // likelihoods for the alt allele being located on the first or the second haplotype
+    let mut likelihood_first = LogProb::ln_one();
+    let mut likelihood_second = LogProb::ln_one();
+    let mut shared = false;
+    for (fragment_id, prob_alt) in fragments {
+        if let Some(haplotype) = phase_set
+            .fragments
+            .get(fragment_id)
+            .and_then(|fragment| fragment.haplotype())
+        {
+            shared = true;
+            // METHOD: fragments from the haplotype carrying the alt allele are expected
+            // to support the alt allele, fragments from the other haplotype the ref allele.
+            let prob_alt = prob_alt.clamp(PROB_FRAGMENT_ERROR, 1.0 - PROB_FRAGMENT_ERROR);
+            let prob_carrying = LogProb::from(Prob(prob_alt));
+            let prob_not_carrying = LogProb::from(Prob(1.0 - prob_alt));
+            if haplotype == 1 {
+                likelihood_first += prob_carrying;
+                likelihood_second += prob_not_carrying;
+            } else {
+                likelihood_first += prob_not_carrying;
+                likelihood_second += prob_carrying;
+            }
+        }
+    }
+    if !shared {
+        return None;
+    }
+
+    // METHOD: with a uniform prior over both haplotypes, the posterior of the less
+    // likely haplotype is the probability for a phasing error.
+    let marginal = likelihood_first.ln_add_exp(likelihood_second);
+    Some(if likelihood_first >= likelihood_second {
+        Phase {
+            set: phase_set.id,
+            haplotype: 1,
+            prob_error: likelihood_second - marginal,
+        }
+    } else {
+        Phase {
+            set: phase_set.id,
+            haplotype: 2,
+            prob_error: likelihood_first - marginal,
+        }
+    })
+}
+
+/// Probability of each fragment to support the alt allele, considering mapping uncertainty.
+fn fragment_probs(observations: &[Observation<ReadPosition>]) -> Vec<(u64, f64)> {
+    observations
+        .iter()
+        .filter_map(|obs| {
+            obs.fragment_id.map(|fragment_id| {
+                let prob_alt = obs.prob_alt - obs.prob_alt.ln_add_exp(obs.prob_ref);
+                let prob_alt = (obs.prob_mapping() + prob_alt)
+                    .ln_add_exp(obs.prob_mismapping() + LogProb::from(Prob(0.5)));
+                (fragment_id, prob_alt.exp())
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::calling::variants::SampleInfoBuilder;
+    use crate::variants::model;
+    use crate::variants::model::bias::Biases;
+    use crate::variants::model::AlleleFreq;
+
+    fn observation(fragment_id: u64, alt: bool) -> Observation<ReadPosition> {
+        let (prob_alt, prob_ref): (f64, f64) = if alt { (0.99, 0.01) } else { (0.01, 0.99) };
+        let mut obs = model::tests::observation(
+            LogProb(0.999f64.ln()),
+            LogProb(prob_alt.ln()),
+            LogProb(prob_ref.ln()),
+        );
+        obs.fragment_id = Some(fragment_id);
+        obs
+    }
+
+    fn sample_info(alt_fragments: &[u64], ref_fragments: &[u64]) -> Vec<Option<SampleInfo>> {
+        let observations = alt_fragments
+            .iter()
+            .map(|id| observation(*id, true))
+            .chain(ref_fragments.iter().map(|id| observation(*id, false)))
+            .collect();
+        vec![Some(
+            SampleInfoBuilder::default()
+                .allelefreq_estimate(AlleleFreq(0.5))
+                .observations(observations)
+                .biases(Biases::none())
+                .build()
+                .unwrap(),
+        )]
+    }
+
+    fn phase_of(sample_info: &[Option<SampleInfo>]) -> Option<Phase> {
+        sample_info[0].as_ref().unwrap().phase
+    }
+
+    #[test]
+    fn test_phasing() {
+        let mut phaser = Phaser::new(1);
+
+        let mut first = sample_info(&[1, 2], &[3, 4]);
+        phaser.phase(0, 99, &mut first);
+        let phase = phase_of(&first).unwrap();
+        assert_eq!(phase.set, 100);
+        assert_eq!(phase.haplotype, 1);
+
+        // in cis with the first call
+        let mut cis = sample_info(&[1, 2, 5], &[3, 4]);
+        phaser.phase(0, 149, &mut cis);
+        let phase = phase_of(&cis).unwrap();
+        assert_eq!(phase.set, 100);
+        assert_eq!(phase.haplotype, 1);
+        assert!(phase.prob_error < LogProb(0.001f64.ln()));
+
+        // in trans, only linked via fragment 5 of the second call
+        let mut trans = sample_info(&[6], &[5]);
+        phaser.phase(0, 199, &mut trans);
+        let phase = phase_of(&trans).unwrap();
+        assert_eq!(phase.set, 100);
+        assert_eq!(phase.haplotype, 2);
+
+        // the previous call is confidently phased, hence fragment 6 is assigned to the second
+        // haplotype
+        let mut cis_with_trans = sample_info(&[6], &[]);
+        phaser.phase(0, 249, &mut cis_with_trans);
+        let phase = phase_of(&cis_with_trans).unwrap();
+        assert_eq!(phase.set, 100);
+        assert_eq!(phase.haplotype, 2);
+
+        // no shared fragments, hence a new phase set
+        let mut unlinked = sample_info(&[7], &[8]);
+        phaser.phase(0, 299, &mut unlinked);
+        assert_eq!(phase_of(&unlinked).unwrap().set, 300);
+
+        // homozygous calls are not phased
+        let mut hom = sample_info(&[7, 8], &[]);
+        hom[0].as_mut().unwrap().allelefreq_estimate = AlleleFreq(1.0);
+        phaser.phase(0, 349, &mut hom);
+        assert!(phase_of(&hom).is_none());
+    }
+
+    #[test]
+    fn test_reassignment() {
+        let mut phaser = Phaser::new(1);
+
+        let mut first = sample_info(&[1, 2], &[3]);
+        phaser.phase(0, 99, &mut first);
+
+        // fragment 3 is wrongly assigned to the second haplotype by the first call,
+        // but the following calls place it on the first haplotype.
+        for pos in &[149, 199] {
+            let mut call = sample_info(&[1, 2, 3], &[]);
+            phaser.phase(0, *pos, &mut call);
+            assert_eq!(phase_of(&call).unwrap().haplotype, 1);
+        }
+        let fragment = &phaser.phase_sets[0].as_ref().unwrap().fragments[&3];
+        assert_eq!(fragment.haplotype(), Some(1));
+    }
+
+    #[test]
+    fn test_uncertain_phase() {
+        let mut phaser = Phaser::new(1);
+
+        let mut first = sample_info(&[1], &[2]);
+        phaser.phase(0, 99, &mut first);
+
+        // fragments 1 and 2 contradict each other, hence the phase is uncertain and
+        // the new fragment 3 is not assigned
+        let mut uncertain = sample_info(&[1, 2, 3], &[]);
+        phaser.phase(0, 149, &mut uncertain);
+        let phase = phase_of(&uncertain).unwrap();
+        assert!(phase.prob_error > LogProb::from(Prob(MAX_PROB_PHASE_ERROR)));
+        assert!(!phaser.phase_sets[0]
+            .as_ref()
+            .unwrap()
+            .fragments
+            .contains_key(&3));
+    }
+}
@@ -5,6 +5,7 @@
 
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::{Arc, Mutex, RwLock};
@@ -16,7 +17,8 @@ use bio_types::sequence::SequenceReadPairOrientation;
 use bv::BitVec;
 use byteorder::{ByteOrder, LittleEndian};
 use itertools::Itertools;
-use rust_htslib::bam::{self, Read as BAMRead};
+use rayon::prelude::*;
+use rust_htslib::bam::Read as BAMRead;
 use rust_htslib::bcf::{self, Read as BCFRead};
 
 use crate::calling::variants::{chrom, Call, CallBuilder, VariantBuilder};
@@ -25,16 +27,23 @@ use crate::errors;
 use crate::estimation::alignment_properties::AlignmentProperties;
 use crate::reference;
 use crate::utils;
+use crate::utils::collect_variants::AdjacentSnvIndex;
+use crate::utils::regions::{RegionReader, Regions};
 use crate::utils::MiniLogProb;
 use crate::variants;
+use crate::variants::evidence::breakpoints::{self, ConfidenceInterval, RefinedBreakpoints};
+use crate::variants::evidence::depth::{DepthControls, DepthEvidence, MIN_DEPTH_EVIDENCE_LEN};
 use crate::variants::evidence::observation::{
     Observation, ObservationBuilder, ReadPosition, Strand,
 };
 use crate::variants::evidence::realignment;
+use crate::variants::evidence::realignment::haplotypes::{HaplotypeIndex, HaplotypeVariant};
 use crate::variants::model;
 use crate::variants::sample::Sample;
 use crate::variants::sample::{ProtocolStrandedness, SampleBuilder};
 use crate::variants::types::breakends::{Breakend, BreakendIndex};
+use crate::variants::types::mobile_element_insertion::MobileElementLibrary;
+use crate::variants::types::tandem_repeat::is_full_tract;
 
 #[derive(TypedBuilder)]
 pub(crate) struct ObservationProcessor<R: realignment::Realigner + Clone> {
@@ -42,19 +51,40 @@ pub(crate) struct ObservationProcessor<R: realignment::Realigner + Clone> {
     max_depth: usize,
     protocol_strandedness: ProtocolStrandedness,
     reference_buffer: Arc<reference::Buffer>,
+    reference: PathBuf,
     realigner: R,
     inbcf: PathBuf,
     outbcf: Option<PathBuf>,
-    inbam: PathBuf,
+    inbams: Vec<PathBuf>,
     min_bam_refetch_distance: u64,
+    #[builder(default)]
+    umi_families: bool,
+    #[builder(default)]
+    phasing: bool,
+    #[builder(default = 1)]
+    threads: usize,
+    #[builder(default)]
+    regions: Option<Regions>,
     options: cli::Varlociraptor,
     breakend_index: BreakendIndex,
     #[builder(default)]
+    haplotype_index: HaplotypeIndex,
+    #[builder(default)]
+    adjacent_snv_index: AdjacentSnvIndex,
+    #[builder(default)]
+    mobile_elements: MobileElementLibrary,
+    /// BAM files of control samples (a matched normal or a panel of normals) for read depth
+    /// evidence of large deletions and duplications.
+    #[builder(default)]
+    depth_controls: Arc<DepthControls>,
+    #[builder(default)]
     breakend_group_builders: RwLock<
         HashMap<Vec<u8>, Mutex<Option<variants::types::breakends::BreakendGroupBuilder<R>>>>,
     >,
     #[builder(default)]
     breakend_groups: RwLock<HashMap<Vec<u8>, Mutex<variants::types::breakends::BreakendGroup<R>>>>,
+    #[builder(default)]
+    refined_breakends: RwLock<HashMap<Vec<u8>, RefinedBreakpoints>>,
 }
 
 impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
@@ -84,6 +114,42 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
             b"##INFO=<ID=MATEID,Number=1,Type=String,\
               Description=\"ID of mate breakend\">",
         );
+        header.push_record(
+            b"##INFO=<ID=LEFT_SVINSSEQ,Number=1,Type=String,\
+              Description=\"Known left flank of an insertion with partially unknown sequence\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=RIGHT_SVINSSEQ,Number=1,Type=String,\
+              Description=\"Known right flank of an insertion with partially unknown sequence\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=RU,Number=1,Type=String,\
+              Description=\"Repeat unit of a tandem repeat\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=RN,Number=1,Type=Integer,\
+              Description=\"Number of repeat units of a tandem repeat in the reference\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=MERGED_SNVS,Number=0,Type=Flag,\
+              Description=\"MNV candidate obtained by merging adjacent SNV candidates\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=REFINED_POS,Number=1,Type=Integer,\
+              Description=\"Refined position of the imprecise breakpoint at POS\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=REFINED_END,Number=1,Type=Integer,\
+              Description=\"Refined end position of the imprecise structural variant \
+              (inclusive, 1-based)\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=REFINED_PROB,Number=1,Type=Float,\
+              Description=\"Posterior probability of the refined breakpoints \
+              (REFINED_POS, REFINED_END) among all evaluated candidates, given that the \
+              variant is present. Since the breakpoints are selected with the same reads that \
+              are used for calling, it is not calibrated against that selection.\">",
+        );
 
         // register sequences
         for sequence in self.reference_buffer.sequences() {
@@ -106,12 +172,37 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
             "READ_POSITION",
             "SOFTCLIPPED",
             "PAIRED",
+            "FRAGMENT_LENGTH",
+            "FAMILY_SIZE",
+            "DUPLEX",
+            "FRAGMENT_ID",
         ] {
             header.push_record(
                 format!("##INFO=<ID={},Number=.,Type=Integer,Description=\"Varlociraptor observations (binary encoded, meant for internal use only).\"", name).as_bytes()
             );
         }
 
+        // store read depth observations
+        header.push_record(
+            b"##INFO=<ID=DEPTH_OBS,Number=1,Type=Integer,\
+              Description=\"Number of reads within a large deletion or duplication\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=DEPTH_EXP,Number=1,Type=Float,\
+              Description=\"Expected number of reads within a large deletion or duplication \
+              without the variant, as derived from control samples\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=DEPTH_VAR,Number=1,Type=Float,\
+              Description=\"Variance of the number of reads within a large deletion or \
+              duplication without the variant\">",
+        );
+        header.push_record(
+            b"##INFO=<ID=DEPTH_ALT_RATIO,Number=1,Type=Float,\
+              Description=\"Read depth of the alt allele relative to the reference allele \
+              within a large deletion or duplication\">",
+        );
+
         // store options
         header.push_record(
             format!(
@@ -121,6 +212,10 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
             .as_bytes(),
         );
 
+        // store whether observations stem from UMI families
+        header
+            .push_record(format!("##varlociraptor_umi_families={}", self.umi_families).as_bytes());
+
         // store observation format version
         header.push_record(
             format!(
@@ -139,65 +234,187 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
         })
     }
 
-    pub(crate) fn process(&mut self) -> Result<()> {
-        let mut bcf_reader = bcf::Reader::from_path(&self.inbcf)?;
-        bcf_reader.set_threads(1)?;
-        let mut skips = utils::SimpleCounter::default();
-        let mut bcf_writer = self.writer()?;
-        bcf_writer.set_threads(1)?;
-        let mut processed = 0;
+    fn sample(&self) -> Result<Sample> {
+        let mut bam_readers = Vec::with_capacity(self.inbams.len());
+        for inbam in &self.inbams {
+            let mut bam_reader = utils::alignments::indexed_reader(inbam, &self.reference)?;
+            bam_reader.set_threads(1)?;
+            bam_readers.push(bam_reader);
+        }
 
-        let mut bam_reader =
-            bam::IndexedReader::from_path(&self.inbam).context("Unable to read BAM/CRAM file.")?;
-        bam_reader.set_threads(1)?;
+        let depth_evidence = if self.depth_controls.is_empty() {
+            None
+        } else {
+            Some(DepthEvidence::new(
+                &self.inbams,
+                Arc::clone(&self.depth_controls),
+                &self.reference,
+            )?)
+        };
 
-        let mut sample = SampleBuilder::default()
+        Ok(SampleBuilder::default()
             .max_depth(self.max_depth)
             .protocol_strandedness(self.protocol_strandedness)
+            .depth_evidence(depth_evidence)
             .alignments(
-                bam_reader,
-                self.alignment_properties,
+                bam_readers,
+                self.alignment_properties.clone(),
                 self.min_bam_refetch_distance,
-            )
+                self.umi_families,
+            )?
             .build()
-            .unwrap();
+            .unwrap())
+    }
+
+    /// Read the next record from the given BCF reader and turn it into a work item.
+    /// Returns `None` if there are no records left, and a work item without variants
+    /// if the record has been skipped.
+    fn next_work_item(
+        &self,
+        bcf_reader: &mut RegionReader,
+        record_index: usize,
+        skips: &mut utils::SimpleCounter<utils::collect_variants::SkipReason>,
+    ) -> Result<Option<WorkItem>> {
+        let mut record = bcf_reader.empty_record();
+        match bcf_reader.read(&mut record) {
+            None => return Ok(None),
+            Some(res) => res?,
+        }
 
-        let display_skips = |skips: &utils::SimpleCounter<utils::collect_variants::SkipReason>| {
-            for (reason, &count) in skips.iter() {
-                if count > 0 && count % 100 == 0 {
-                    info!("Skipped {} {}.", count, reason);
+        let imprecise = record.info(b"IMPRECISE").flag().unwrap_or(false);
+        // METHOD: breakpoints of imprecise records can only be refined for structural
+        // variants given with SVTYPE. Other imprecise records are skipped.
+        let is_structural = matches!(record.info(b"SVTYPE").string(), Ok(Some(_)));
+        let mut variants = utils::collect_variants(&mut record, false, skips)?;
+        variants.retain(|variant| match variant {
+            model::Variant::MobileElementInsertion { family, .. }
+                if self.mobile_elements.consensus(family).is_none() =>
+            {
+                skips.incr(utils::collect_variants::SkipReason::MobileElementUnknownFamily);
+                false
+            }
+            model::Variant::Deletion(_)
+            | model::Variant::Duplication(_)
+            | model::Variant::Inversion(_)
+            | model::Variant::Breakend { .. }
+                if is_structural =>
+            {
+                true
+            }
+            _ if imprecise => {
+                skips.incr(utils::collect_variants::SkipReason::Imprecise);
+                false
+            }
+            _ => true,
+        });
+        if variants.iter().any(|variant| variant.is_breakend()) {
+            if let Some(event) = self.breakend_index.event(&mut record)? {
+                if !self.breakend_index.is_complete(&event) {
+                    // METHOD: without all of its breakends, the event cannot be genotyped.
+                    skips.incr(utils::collect_variants::SkipReason::BreakendIncompleteEvent);
+                    variants.retain(|variant| !variant.is_breakend());
+                }
+                for variant in &mut variants {
+                    if let model::Variant::Breakend {
+                        event: ref mut variant_event,
+                        ..
+                    } = variant
+                    {
+                        *variant_event = event.clone();
+                    }
                 }
             }
+        }
+        let start = record.pos() as u64;
+        let chrom = String::from_utf8(chrom(bcf_reader.header(), &record).to_owned()).unwrap();
+        if variants
+            .iter()
+            .any(|variant| matches!(variant, model::Variant::TandemRepeat { .. }))
+        {
+            let ref_seq = self.reference_buffer.seq(&chrom)?;
+            for variant in &mut variants {
+                if let model::Variant::TandemRepeat {
+                    unit,
+                    ref_count,
+                    alt_count,
+                } = variant
+                {
+                    // METHOD: if the given repeat units do not span the full repeat tract of
+                    // the reference (e.g. because the REF allele only contains part of it),
+                    // the repeat counts are unknown. Then, we fall back to the equivalent indel.
+                    // The repeat tract starts behind the leading reference base.
+                    if !is_full_tract(&ref_seq, start + 1, unit, *ref_count) {
+                        *variant = if alt_count > ref_count {
+                            model::Variant::Insertion(
+                                unit.repeat((*alt_count - *ref_count) as usize),
+                            )
+                        } else {
+                            model::Variant::Deletion(unit.len() as u64 * (*ref_count - *alt_count))
+                        };
+                    }
+                }
+            }
+        }
+        let merged_mnv = if variants
+            .iter()
+            .any(|variant| matches!(variant, model::Variant::SNV(_)))
+        {
+            self.adjacent_snv_index
+                .mnv(&chrom, start, &self.reference_buffer.seq(&chrom)?)
+        } else {
+            None
         };
+        let confidence_intervals = if imprecise {
+            Some((
+                ConfidenceInterval::from_record(&mut record, b"CIPOS").unwrap_or_default(),
+                ConfidenceInterval::from_record(&mut record, b"CIEND").unwrap_or_default(),
+            ))
+        } else {
+            None
+        };
+        Ok(Some(WorkItem {
+            start,
+            chrom,
+            variants,
+            merged_mnv,
+            confidence_intervals,
+            record_id: record.id(),
+            record_mateid: utils::info_tag_mateid(&mut record)
+                .map_or(None, |mateid| mateid.map(|mateid| mateid.to_owned())),
+            record_index,
+        }))
+    }
+
+    pub(crate) fn process(&mut self) -> Result<()> {
+        if self.threads > 1 {
+            return self.process_parallel();
+        }
+
+        let mut bcf_reader = RegionReader::new(&self.inbcf, self.regions.as_ref())?;
+        bcf_reader.set_threads(1)?;
+        let mut skips = utils::SimpleCounter::default();
+        let mut bcf_writer = self.writer()?;
+        bcf_writer.set_threads(1)?;
+        let mut processed = 0;
+
+        let mut sample = self.sample()?;
 
         let mut i = 0;
         loop {
-            let mut record = bcf_reader.empty_record();
-            match bcf_reader.read(&mut record) {
+            let work_item = match self.next_work_item(&mut bcf_reader, i, &mut skips)? {
                 None => {
                     display_skips(&skips);
                     return Ok(());
                 }
-                Some(res) => res?,
-            }
+                Some(work_item) => work_item,
+            };
 
-            let variants = utils::collect_variants(&mut record, true, &mut skips)?;
-            if !variants.is_empty() {
+            if !work_item.variants.is_empty() {
                 // process record
-                let work_item = WorkItem {
-                    start: record.pos() as u64,
-                    chrom: String::from_utf8(chrom(&bcf_reader, &record).to_owned()).unwrap(),
-                    variants,
-                    record_id: record.id(),
-                    record_mateid: utils::info_tag_mateid(&mut record)
-                        .map_or(None, |mateid| mateid.map(|mateid| mateid.to_owned())),
-                    record_index: i,
-                };
-
                 let calls = self.process_record(work_item, &mut sample)?;
 
                 for call in calls.iter() {
-                    call.write_preprocessed_record(&mut bcf_writer)?;
+                    call.write_preprocessed_record(&mut bcf_writer, self.phasing)?;
                     processed += 1;
 
                     if processed % 100 == 0 {
@@ -214,6 +431,104 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
         }
     }
 
+    /// Process candidates with multiple threads.
+    ///
+    /// METHOD: The candidate BCF is read in consecutive (hence genomic) chunks of records.
+    /// Each chunk is processed by an independent worker with its own BAM reader and record buffer.
+    /// Breakends are processed sequentially in input order, because breakend groups may span
+    /// multiple chunks. A batch ends once there is a chunk for each thread or enough breakends
+    /// have been collected, such that memory usage stays bounded. Once all chunks of a batch are
+    /// done, calls are written in input order.
+    fn process_parallel(&self) -> Result<()> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()?;
+
+        let mut bcf_reader = RegionReader::new(&self.inbcf, self.regions.as_ref())?;
+        bcf_reader.set_threads(1)?;
+        let mut skips = utils::SimpleCounter::default();
+        let mut bcf_writer = self.writer()?;
+        bcf_writer.set_threads(1)?;
+        let mut processed = 0;
+
+        let mut breakend_sample = self.sample()?;
+
+        let mut i = 0;
+        let mut exhausted = false;
+        while !exhausted {
+            // Read a batch of chunks, one per thread.
+            let mut chunks: Vec<Vec<WorkItem>> = Vec::with_capacity(self.threads);
+            let mut chunk = Vec::with_capacity(PARALLEL_CHUNK_SIZE);
+            let mut breakend_work_items = Vec::new();
+            while chunks.len() < self.threads && breakend_work_items.len() < PARALLEL_CHUNK_SIZE {
+                let work_item = match self.next_work_item(&mut bcf_reader, i, &mut skips)? {
+                    None => {
+                        exhausted = true;
+                        break;
+                    }
+                    Some(work_item) => work_item,
+                };
+                i += 1;
+
+                if work_item.variants.is_empty() {
+                    continue;
+                }
+                if work_item
+                    .variants
+                    .iter()
+                    .any(|variant| variant.is_breakend())
+                {
+                    breakend_work_items.push(work_item);
+                } else {
+                    chunk.push(work_item);
+                    if chunk.len() >= PARALLEL_CHUNK_SIZE {
+                        chunks.push(chunk);
+                        chunk = Vec::with_capacity(PARALLEL_CHUNK_SIZE);
+                    }
+                }
+            }
+            if !chunk.is_empty() {
+                chunks.push(chunk);
+            }
+
+            let chunk_calls: Result<Vec<Vec<Calls>>> = pool.install(|| {
+                chunks
+                    .into_par_iter()
+                    .map(|chunk| {
+                        let mut sample = self.sample()?;
+                        chunk
+                            .into_iter()
+                            .map(|work_item| self.process_record(work_item, &mut sample))
+                            .collect()
+                    })
+                    .collect()
+            });
+            let mut all_calls = chunk_calls?.into_iter().flatten().collect_vec();
+
+            for work_item in breakend_work_items {
+                all_calls.push(self.process_record(work_item, &mut breakend_sample)?);
+            }
+
+            // Restore input order.
+            all_calls.sort_by_key(|calls| calls.index);
+
+            for calls in all_calls {
+                for call in calls.iter() {
+                    call.write_preprocessed_record(&mut bcf_writer, self.phasing)?;
+                    processed += 1;
+
+                    if processed % 100 == 0 {
+                        info!("{} records processed.", processed);
+                    }
+                }
+            }
+
+            display_skips(&skips);
+        }
+
+        Ok(())
+    }
+
     fn process_record(&self, work_item: WorkItem, sample: &mut Sample) -> Result<Calls> {
         if work_item.variants.is_empty() {
             return Ok(Calls::new(work_item.record_index, vec![]));
@@ -238,10 +553,12 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
         {
             let mut calls = Vec::new();
 
-            for variant in work_item
+            for (variant, merged_snvs) in work_item
                 .variants
                 .iter()
                 .filter(|variant| !variant.is_breakend())
+                .map(|variant| (variant, false))
+                .chain(work_item.merged_mnv.iter().map(|variant| (variant, true)))
             {
                 let mut call = call_builder(
                     work_item.chrom.as_bytes().to_owned(),
@@ -252,16 +569,49 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
                 .unwrap();
 
                 let chrom_seq = self.reference_buffer.seq(&work_item.chrom)?;
-                let pileup = self.process_variant(&variant, &work_item, sample)?.unwrap(); // only breakends can lead to None, and they are handled below
+                let refined = if let Some(intervals) = work_item.confidence_intervals {
+                    self.refine_breakpoints(variant, &work_item, intervals, sample)?
+                } else {
+                    None
+                };
+                let (pileup, refined_breakpoints) = if let Some((pileup, refined)) = refined {
+                    (pileup, Some(refined))
+                } else {
+                    // only breakends can lead to None, and they are handled below
+                    (
+                        self.process_variant(&variant, &work_item, sample)?.unwrap(),
+                        None,
+                    )
+                };
+                let depth_observation = {
+                    let (start, variant) = if let Some(ref refined) = refined_breakpoints {
+                        shift_breakpoints(variant, work_item.start, &refined.offsets).unwrap()
+                    } else {
+                        (work_item.start, variant.clone())
+                    };
+                    if let Some((region, alt_depth_ratio)) = depth_region(&variant, start) {
+                        sample.depth_observation(&work_item.chrom, region, alt_depth_ratio)?
+                    } else {
+                        None
+                    }
+                };
 
                 // add variant information
-                call.variant = Some(
-                    VariantBuilder::default()
-                        .variant(&variant, work_item.start as usize, Some(chrom_seq.as_ref()))
-                        .observations(Some(pileup))
-                        .build()
-                        .unwrap(),
-                );
+                let mut variant_builder = VariantBuilder::default();
+                variant_builder
+                    .variant(&variant, work_item.start as usize, Some(chrom_seq.as_ref()))
+                    .merged_snvs(merged_snvs)
+                    .observations(Some(pileup))
+                    .depth_observation(depth_observation);
+                if let Some(refined) = refined_breakpoints {
+                    let (start, refined_variant) =
+                        shift_breakpoints(variant, work_item.start, &refined.offsets).unwrap();
+                    variant_builder
+                        .refined_pos(Some(start + 1))
+                        .refined_end(Some(sv_end(&refined_variant, start)))
+                        .refined_prob(Some(refined.posterior));
+                }
+                call.variant = Some(variant_builder.build().unwrap());
                 calls.push(call);
             }
 
@@ -272,7 +622,8 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
                 if let model::Variant::Breakend { event, .. } = variant {
                     if let Some(pileup) = self.process_variant(variant, &work_item, sample)? {
                         let pileup = Some(pileup);
-                        for breakend in self
+                        let refined = self.refined_breakends.write().unwrap().remove(event);
+                        for (i, breakend) in self
                             .breakend_groups
                             .read()
                             .unwrap()
@@ -282,6 +633,7 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
                             .lock()
                             .unwrap()
                             .breakends()
+                            .enumerate()
                         {
                             let mut call = call_builder(
                                 breakend.locus().contig().as_bytes().to_owned(),
@@ -293,17 +645,23 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
                             .unwrap();
 
                             // add variant information
-                            call.variant = Some(
-                                VariantBuilder::default()
-                                    .variant(
-                                        &breakend.to_variant(event),
-                                        breakend.locus().pos() as usize,
-                                        None,
-                                    )
-                                    .observations(pileup.clone())
-                                    .build()
-                                    .unwrap(),
-                            );
+                            let mut variant_builder = VariantBuilder::default();
+                            variant_builder
+                                .variant(
+                                    &breakend.to_variant(event),
+                                    breakend.locus().pos() as usize,
+                                    None,
+                                )
+                                .observations(pileup.clone());
+                            if let Some(ref refined) = refined {
+                                variant_builder
+                                    .refined_pos(Some(
+                                        (breakend.locus().pos() as i64 + refined.offsets[i] + 1)
+                                            as u64,
+                                    ))
+                                    .refined_prob(Some(refined.posterior));
+                            }
+                            call.variant = Some(variant_builder.build().unwrap());
                             calls.push(call);
                         }
                         // As all records a written, the breakend group can be discarded.
@@ -315,6 +673,56 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
         }
     }
 
+    /// Refine the breakpoints of an imprecise deletion, duplication or inversion within the
+    /// given confidence intervals around POS and END. Returns the observations for the refined
+    /// variant, or `None` if the variant cannot be refined.
+    fn refine_breakpoints(
+        &self,
+        variant: &model::Variant,
+        work_item: &WorkItem,
+        (cipos, ciend): (ConfidenceInterval, ConfidenceInterval),
+        sample: &mut Sample,
+    ) -> Result<Option<(Vec<Observation<ReadPosition>>, RefinedBreakpoints)>> {
+        if !matches!(
+            variant,
+            model::Variant::Deletion(_)
+                | model::Variant::Duplication(_)
+                | model::Variant::Inversion(_)
+        ) {
+            return Ok(None);
+        }
+        let chrom_len = self.reference_buffer.seq(&work_item.chrom)?.len() as u64;
+
+        let refined = breakpoints::refine(&[cipos, ciend], |offsets| {
+            match shift_breakpoints(variant, work_item.start, offsets) {
+                Some((start, shifted)) if sv_end(&shifted, start) < chrom_len => {
+                    let shifted_work_item = WorkItem {
+                        start,
+                        ..work_item.clone()
+                    };
+                    self.process_variant(&shifted, &shifted_work_item, sample)
+                }
+                _ => Ok(None),
+            }
+        })?;
+
+        Ok(refined.map(|(refined, pileup)| (pileup, refined)))
+    }
+
+    /// Realigner for the given variant, knowing about nearby candidate variants
+    /// (if haplotype-aware realignment is enabled).
+    fn realigner(&self, variant: &model::Variant, work_item: &WorkItem) -> R {
+        let mut realigner = self.realigner.clone();
+        if let Some(variant) = HaplotypeVariant::from_variant(variant, work_item.start) {
+            realigner.set_haplotype_variants(self.haplotype_index.neighbors(
+                &work_item.chrom,
+                &variant,
+                realigner.ref_window() as u64,
+            ));
+        }
+        realigner
+    }
+
     fn process_variant(
         &self,
         variant: &model::Variant,
@@ -346,11 +754,38 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
                 self.reference_buffer.seq(&work_item.chrom)?[start],
             ))?,
             model::Variant::Deletion(l) => sample.extract_observations(
-                &variants::types::Deletion::new(interval(*l), self.realigner.clone()),
-            )?,
-            model::Variant::Insertion(seq) => sample.extract_observations(
-                &variants::types::Insertion::new(locus(), seq.to_owned(), self.realigner.clone()),
+                &variants::types::Deletion::new(interval(*l), self.realigner(variant, work_item)),
             )?,
+            model::Variant::Insertion(seq) => {
+                sample.extract_observations(&variants::types::Insertion::new(
+                    locus(),
+                    seq.to_owned(),
+                    self.realigner(variant, work_item),
+                ))?
+            }
+            model::Variant::MobileElementInsertion { family, len } => {
+                let consensus = self.mobile_elements.consensus(family).unwrap();
+                let contigs = Arc::clone(sample.contigs());
+                sample.extract_observations(&variants::types::MobileElementInsertion::new(
+                    locus(),
+                    *len,
+                    Arc::clone(consensus),
+                    contigs,
+                    self.realigner.clone(),
+                ))?
+            }
+            model::Variant::TandemRepeat {
+                unit,
+                ref_count,
+                alt_count,
+            } => sample.extract_observations(&variants::types::TandemRepeat::new(
+                // the repeat tract starts behind the leading reference base
+                genome::Locus::new(work_item.chrom.clone(), work_item.start + 1),
+                unit.to_owned(),
+                *ref_count,
+                *alt_count,
+                self.realigner(variant, work_item),
+            ))?,
             model::Variant::Inversion(len) => {
                 sample.extract_observations(&variants::types::Inversion::new(
                     interval(*len),
@@ -406,27 +841,51 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
                         &work_item.record_id,
                         work_item.record_mateid.clone(),
                     )? {
+                        if let Some((cipos, _)) = work_item.confidence_intervals {
+                            group.confidence_interval(breakend.locus().clone(), cipos);
+                        }
                         group.push_breakend(breakend);
 
                         if self.breakend_index.last_record_index(event).unwrap()
                             == work_item.record_index
                         {
                             // METHOD: last record of the breakend event. Hence, we can extract observations.
-                            let breakend_group = Mutex::new(group.build());
+                            let breakend_group = group.build();
+                            let refined =
+                                if let Some(intervals) = breakend_group.confidence_intervals() {
+                                    // METHOD: imprecise breakends are moved jointly within their
+                                    // confidence intervals, keeping joins between them consistent.
+                                    breakpoints::refine(&intervals, |offsets| {
+                                        breakend_group
+                                            .shifted(offsets, &self.reference_buffer)?
+                                            .map(|shifted| sample.extract_observations(&shifted))
+                                            .transpose()
+                                    })?
+                                } else {
+                                    None
+                                };
                             self.breakend_groups
                                 .write()
                                 .unwrap()
-                                .insert(event.to_owned(), breakend_group);
-                            sample.extract_observations(
-                                &*self
-                                    .breakend_groups
-                                    .read()
+                                .insert(event.to_owned(), Mutex::new(breakend_group));
+                            if let Some((refined, pileup)) = refined {
+                                self.refined_breakends
+                                    .write()
                                     .unwrap()
-                                    .get(event)
-                                    .unwrap()
-                                    .lock()
-                                    .unwrap(),
-                            )?
+                                    .insert(event.to_owned(), refined);
+                                pileup
+                            } else {
+                                sample.extract_observations(
+                                    &*self
+                                        .breakend_groups
+                                        .read()
+                                        .unwrap()
+                                        .get(event)
+                                        .unwrap()
+                                        .lock()
+                                        .unwrap(),
+                                )?
+                            }
                         } else {
                             return Ok(None);
                         }
@@ -447,7 +906,75 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
     }
 }
 
-pub(crate) static OBSERVATION_FORMAT_VERSION: &str = "7";
+/// Move the breakpoints (POS and END) of the given deletion, duplication or inversion, starting
+/// at the given position, by the given offsets. Returns the new start position and variant, or
+/// `None` if the moved variant would be empty or start before the contig.
+fn shift_breakpoints(
+    variant: &model::Variant,
+    start: u64,
+    offsets: &[i64],
+) -> Option<(u64, model::Variant)> {
+    let shifted_start = start as i64 + offsets[0];
+    let shifted_len = |len: u64| {
+        let len = len as i64 + offsets[1] - offsets[0];
+        if len > 0 {
+            Some(len as u64)
+        } else {
+            None
+        }
+    };
+    if shifted_start < 1 {
+        return None;
+    }
+    let shifted = match variant {
+        model::Variant::Deletion(len) => model::Variant::Deletion(shifted_len(*len)?),
+        model::Variant::Duplication(len) => model::Variant::Duplication(shifted_len(*len)?),
+        model::Variant::Inversion(len) => model::Variant::Inversion(shifted_len(*len)?),
+        _ => return None,
+    };
+    Some((shifted_start as u64, shifted))
+}
+
+/// Region (0-based, half-open) of the given deletion or duplication starting at the given
+/// position, together with the depth of alt haplotypes relative to reference haplotypes within
+/// it. Returns `None` for other variants and for variants too short for read depth evidence.
+fn depth_region(variant: &model::Variant, start: u64) -> Option<(Range<u64>, f64)> {
+    match variant {
+        // deleted bases start behind the leading reference base
+        model::Variant::Deletion(len) if *len >= MIN_DEPTH_EVIDENCE_LEN => {
+            Some((start + 1..start + 1 + len, 0.0))
+        }
+        model::Variant::Duplication(len) if *len >= MIN_DEPTH_EVIDENCE_LEN => {
+            Some((start..start + len, 2.0))
+        }
+        _ => None,
+    }
+}
+
+/// End position (1-based, inclusive) of the given deletion, duplication or inversion,
+/// starting at the given position.
+fn sv_end(variant: &model::Variant, start: u64) -> u64 {
+    match variant {
+        // deleted bases start behind the leading reference base
+        model::Variant::Deletion(len) => start + 1 + len,
+        model::Variant::Duplication(len) | model::Variant::Inversion(len) => start + len,
+        _ => start + 1,
+    }
+}
+
+pub(crate) static OBSERVATION_FORMAT_VERSION: &str = "10";
+
+/// Number of candidate records per chunk when preprocessing with multiple threads.
+/// This is also the maximum number of breakend records processed per batch.
+const PARALLEL_CHUNK_SIZE: usize = 1000;
+
+fn display_skips(skips: &utils::SimpleCounter<utils::collect_variants::SkipReason>) {
+    for (reason, &count) in skips.iter() {
+        if count > 0 && count % 100 == 0 {
+            info!("Skipped {} {}.", count, reason);
+        }
+    }
+}
 
 /// Read observations from BCF record.
 pub(crate) fn read_observations(
@@ -492,6 +1019,15 @@ pub(crate) fn read_observations(
     let read_position: Vec<ReadPosition> = read_values(record, b"READ_POSITION")?;
     let softclipped: BitVec<u8> = read_values(record, b"SOFTCLIPPED")?;
     let paired: BitVec<u8> = read_values(record, b"PAIRED")?;
+    let fragment_length: Vec<Option<u32>> = read_values(record, b"FRAGMENT_LENGTH")?;
+    let family_size: Vec<u32> = read_values(record, b"FAMILY_SIZE")?;
+    let duplex: BitVec<u8> = read_values(record, b"DUPLEX")?;
+    // Fragment IDs are only recorded if preprocessing was run with --phasing.
+    let fragment_id: Vec<Option<u64>> = if record.info(b"FRAGMENT_ID").integer()?.is_some() {
+        read_values(record, b"FRAGMENT_ID")?
+    } else {
+        vec![None; prob_mapping.len()]
+    };
 
     let obs = (0..prob_mapping.len())
         .map(|i| {
@@ -508,6 +1044,10 @@ pub(crate) fn read_observations(
                 .read_position(read_position[i])
                 .softclipped(softclipped[i as u64])
                 .paired(paired[i as u64])
+                .fragment_length(fragment_length[i])
+                .family_size(family_size[i])
+                .duplex(duplex[i as u64])
+                .fragment_id(fragment_id[i])
                 .build()
                 .unwrap()
         })
@@ -516,9 +1056,12 @@ pub(crate) fn read_observations(
     Ok(obs)
 }
 
+/// Write observations to BCF record. Fragment IDs (only needed for phasing) are only written
+/// if `fragment_ids` is true.
 pub(crate) fn write_observations(
     observations: &[Observation<ReadPosition>],
     record: &mut bcf::Record,
+    fragment_ids: bool,
 ) -> Result<()> {
     let vec = || Vec::with_capacity(observations.len());
     let mut prob_mapping = vec();
@@ -533,6 +1076,10 @@ pub(crate) fn write_observations(
     let mut paired: BitVec<u8> = BitVec::with_capacity(observations.len() as u64);
     let mut read_position = Vec::with_capacity(observations.len());
     let mut prob_hit_base = vec();
+    let mut fragment_length = Vec::with_capacity(observations.len());
+    let mut family_size = Vec::with_capacity(observations.len());
+    let mut duplex: BitVec<u8> = BitVec::with_capacity(observations.len() as u64);
+    let mut fragment_id = Vec::with_capacity(observations.len());
     let encode_logprob = |prob: LogProb| utils::MiniLogProb::new(prob);
     for obs in observations {
         prob_mapping.push(encode_logprob(obs.prob_mapping_orig()));
@@ -547,6 +1094,10 @@ pub(crate) fn write_observations(
         softclipped.push(obs.softclipped);
         paired.push(obs.paired);
         read_position.push(obs.read_position);
+        fragment_length.push(obs.fragment_length);
+        family_size.push(obs.family_size);
+        duplex.push(obs.duplex);
+        fragment_id.push(obs.fragment_id);
     }
 
     fn push_values<T>(record: &mut bcf::Record, tag: &[u8], values: &T) -> Result<()>
@@ -585,6 +1136,12 @@ pub(crate) fn write_observations(
     push_values(record, b"PAIRED", &paired)?;
     push_values(record, b"READ_POSITION", &read_position)?;
     push_values(record, b"PROB_HIT_BASE", &prob_hit_base)?;
+    push_values(record, b"FRAGMENT_LENGTH", &fragment_length)?;
+    push_values(record, b"FAMILY_SIZE", &family_size)?;
+    push_values(record, b"DUPLEX", &duplex)?;
+    if fragment_ids {
+        push_values(record, b"FRAGMENT_ID", &fragment_id)?;
+    }
 
     Ok(())
 }
@@ -602,6 +1159,14 @@ pub(crate) fn remove_observation_header_entries(header: &mut bcf::Header) {
     header.remove_info(b"PAIRED");
     header.remove_info(b"PROB_HIT_BASE");
     header.remove_info(b"READ_POSITION");
+    header.remove_info(b"FRAGMENT_LENGTH");
+    header.remove_info(b"FAMILY_SIZE");
+    header.remove_info(b"DUPLEX");
+    header.remove_info(b"FRAGMENT_ID");
+    header.remove_info(b"DEPTH_OBS");
+    header.remove_info(b"DEPTH_EXP");
+    header.remove_info(b"DEPTH_VAR");
+    header.remove_info(b"DEPTH_ALT_RATIO");
 }
 
 pub(crate) fn read_preprocess_options<P: AsRef<Path>>(bcfpath: P) -> Result<cli::Varlociraptor> {
@@ -619,10 +1184,180 @@ pub(crate) fn read_preprocess_options<P: AsRef<Path>>(bcfpath: P) -> Result<cli:
     .into())
 }
 
+/// Return true if the observations of the given observation BCF header have been obtained
+/// from UMI families (i.e., preprocessing was run with --umi-families).
+pub(crate) fn has_umi_families(header: &bcf::header::HeaderView) -> bool {
+    header.header_records().into_iter().any(|record| {
+        matches!(
+            record,
+            bcf::header::HeaderRecord::Generic { key, value }
+                if key == "varlociraptor_umi_families" && value == "true"
+        )
+    })
+}
+
+/// Read the observation format version from the header of the given observation BCF.
+fn observation_format_version(header: &bcf::header::HeaderView) -> Option<String> {
+    for record in header.header_records() {
+        if let bcf::header::HeaderRecord::Generic { key, value } = record {
+            if key == "varlociraptor_observation_format_version" {
+                return Some(value);
+            }
+        }
+    }
+    None
+}
+
+/// Read preprocess options of the given observation BCF, omitting those that are expected
+/// to differ between shards of the same preprocessing run (output, regions, threads).
+fn shard_preprocess_options<P: AsRef<Path>>(bcfpath: P) -> Result<serde_json::Value> {
+    let mut options = serde_json::to_value(read_preprocess_options(bcfpath)?)?;
+    if let Some(variants_options) = options
+        .pointer_mut("/Preprocess/kind/Variants")
+        .and_then(|options| options.as_object_mut())
+    {
+        for key in &["output", "regions", "threads"] {
+            variants_options.remove(*key);
+        }
+    }
+    Ok(options)
+}
+
+/// Merge observation BCFs that have been obtained by preprocessing disjoint regions
+/// of the same candidate BCF (e.g. with --regions) into a single observation BCF.
+/// Records are copied as they are, observations are not decoded or re-encoded.
+pub(crate) fn merge_observations<P: AsRef<Path>>(
+    inbcfs: &[P],
+    outbcf: Option<&PathBuf>,
+) -> Result<()> {
+    if inbcfs.is_empty() {
+        return Err(errors::Error::EmptyObservations.into());
+    }
+
+    let mut shards = Vec::new();
+    let mut first_options = None;
+    for path in inbcfs {
+        let path = path.as_ref();
+        let mut reader = bcf::Reader::from_path(path).context(format!(
+            "Unable to read observations from {}.",
+            path.display()
+        ))?;
+
+        // Check observation format.
+        if observation_format_version(reader.header()).as_deref()
+            != Some(OBSERVATION_FORMAT_VERSION)
+        {
+            return Err(errors::Error::InvalidObservations {
+                path: path.to_owned(),
+            }
+            .into());
+        }
+
+        // Check that all shards stem from the same preprocessing run.
+        let options = shard_preprocess_options(path)?;
+        if let Some(ref first_options) = first_options {
+            if options != *first_options {
+                return Err(errors::Error::InconsistentPreprocessOptions {
+                    path: path.to_owned(),
+                }
+                .into());
+            }
+        } else {
+            first_options = Some(options);
+        }
+
+        let mut first_record = reader.empty_record();
+        let first_record = match reader.read(&mut first_record) {
+            None => None,
+            Some(res) => {
+                res?;
+                Some(first_record)
+            }
+        };
+        shards.push((path.to_owned(), reader, first_record));
+    }
+
+    // Check that all shards refer to the same contigs in the same order.
+    let contigs = |header: &bcf::header::HeaderView| {
+        (0..header.contig_count())
+            .map(|rid| header.rid2name(rid).map(|name| name.to_owned()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+    };
+    let first_contigs = contigs(shards[0].1.header())?;
+    for (path, reader, _) in &shards[1..] {
+        if contigs(reader.header())? != first_contigs {
+            return Err(errors::Error::InconsistentContigs {
+                path: path.to_owned(),
+            }
+            .into());
+        }
+    }
+
+    // Sort shards by their first record. Empty shards are omitted.
+    let mut shards = shards
+        .into_iter()
+        .filter(|(_, _, first_record)| first_record.is_some())
+        .collect_vec();
+    shards.sort_by_key(|(_, _, first_record)| {
+        let record = first_record.as_ref().unwrap();
+        (record.rid(), record.pos())
+    });
+
+    let header = bcf::Header::from_template(bcf::Reader::from_path(inbcfs[0].as_ref())?.header());
+    let mut bcf_writer = if let Some(path) = outbcf {
+        bcf::Writer::from_path(path, &header, false, bcf::Format::BCF)
+            .context(format!("Unable to write BCF to {}.", path.display()))?
+    } else {
+        bcf::Writer::from_stdout(&header, false, bcf::Format::BCF)
+            .context("Unable to write BCF to STDOUT.")?
+    };
+
+    let mut last = None;
+    let mut merged = 0;
+    for (path, mut reader, first_record) in shards {
+        let mut record = first_record.unwrap();
+        let mut shard_start = true;
+        loop {
+            // METHOD: shards must be disjoint and sorted, hence, positions may never decrease
+            // within a shard and must increase from one shard to the next.
+            // This includes breakends, since events that are only partially contained in the
+            // regions of a shard are skipped during preprocessing.
+            let current = (record.rid(), record.pos());
+            let overlapping =
+                last.is_some_and(|last| current < last || (shard_start && current == last));
+            if overlapping {
+                return Err(errors::Error::OverlappingObservations { path }.into());
+            }
+            last = Some(current);
+            shard_start = false;
+
+            bcf_writer.translate(&mut record);
+            bcf_writer.write(&record)?;
+            merged += 1;
+
+            if merged % 100000 == 0 {
+                info!("{} records merged.", merged);
+            }
+
+            match reader.read(&mut record) {
+                None => break,
+                Some(res) => res?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone)]
 struct WorkItem {
     start: u64,
     chrom: String,
     variants: Vec<model::Variant>,
+    /// MNV combining the SNV of this record with adjacent SNVs (if any).
+    merged_mnv: Option<model::Variant>,
+    /// Confidence intervals around POS and END (CIPOS, CIEND) of imprecise records.
+    confidence_intervals: Option<(ConfidenceInterval, ConfidenceInterval)>,
     record_id: Vec<u8>,
     record_mateid: Option<Vec<u8>>,
     record_index: usize,
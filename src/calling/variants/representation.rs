@@ -0,0 +1,248 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Representation of MNV candidates that have been obtained by merging adjacent SNV
+//! candidates. Whether the reads support the MNV or the separate SNVs can only be decided
+//! once both the MNV and its SNVs have been called.
+
+use std::collections::HashMap;
+
+use bio::stats::LogProb;
+use itertools::Itertools;
+
+use crate::calling::variants::{Call, Variant};
+
+/// Representation supported by the reads for an MNV obtained by merging adjacent SNVs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Representation {
+    /// The substitutions occur together on the same reads (in cis).
+    Mnv,
+    /// The substitutions occur separately.
+    Snvs,
+}
+
+impl Representation {
+    pub(crate) fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            Representation::Mnv => b"MNV",
+            Representation::Snvs => b"SNVS",
+        }
+    }
+}
+
+/// Buffer of calls that holds back merged MNVs until the calls of all their SNVs are known.
+/// Calls are released in the order they have been added.
+#[derive(Debug, Default)]
+pub(crate) struct RepresentationBuffer {
+    calls: Vec<Call>,
+    /// End position (exclusive) of the region covered by the buffered calls.
+    end: u64,
+}
+
+impl RepresentationBuffer {
+    /// Add the given call. Returns the calls that are complete and can be written.
+    pub(crate) fn push(&mut self, call: Call) -> Vec<Call> {
+        let complete = if self
+            .calls
+            .first()
+            .is_some_and(|first| first.chrom != call.chrom || call.pos >= self.end)
+        {
+            self.flush()
+        } else {
+            Vec::new()
+        };
+
+        // METHOD: calls at the same position are always kept together, since the SNV at the
+        // start of a merged MNV precedes it. Merged MNVs additionally keep all calls up to
+        // their end.
+        let len = call
+            .variant
+            .as_ref()
+            .filter(|variant| variant.merged_snvs)
+            .map_or(1, |variant| variant.ref_allele.len() as u64);
+        self.end = self.end.max(call.pos + len);
+        self.calls.push(call);
+
+        complete
+    }
+
+    /// Determine the representation of all buffered merged MNVs and release all calls.
+    pub(crate) fn flush(&mut self) -> Vec<Call> {
+        let mut calls = std::mem::take(&mut self.calls);
+        self.end = 0;
+
+        let snv_probs: HashMap<(u64, u8), LogProb> = calls
+            .iter()
+            .filter_map(|call| {
+                let variant = call.variant.as_ref()?;
+                if !variant.merged_snvs
+                    && variant.ref_allele.len() == 1
+                    && variant.alt_allele.len() == 1
+                {
+                    Some((
+                        (call.pos, variant.alt_allele[0].to_ascii_uppercase()),
+                        prob_present(variant)?,
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        for call in &mut calls {
+            let pos = call.pos;
+            if let Some(variant) = call.variant.as_mut().filter(|variant| variant.merged_snvs) {
+                let probs = variant
+                    .ref_allele
+                    .iter()
+                    .zip(variant.alt_allele.iter())
+                    .enumerate()
+                    .filter(|(_, (ref_base, alt_base))| !ref_base.eq_ignore_ascii_case(alt_base))
+                    .filter_map(|(i, (_, alt_base))| {
+                        snv_probs
+                            .get(&(pos + i as u64, alt_base.to_ascii_uppercase()))
+                            .cloned()
+                    })
+                    .collect_vec();
+                variant.representation =
+                    prob_present(variant).and_then(|prob_mnv| representation(prob_mnv, &probs));
+            }
+        }
+
+        calls
+    }
+}
+
+/// Posterior probability of the variant being present, or `None` in case of missing data.
+fn prob_present(variant: &Variant) -> Option<LogProb> {
+    let probs = variant.event_probs.as_ref()?;
+    let prob_absent = probs.get("absent")?;
+    let prob_artifact = probs.get("artifact")?;
+    let prob_present = LogProb::ln_sum_exp(&[*prob_absent, *prob_artifact]).ln_one_minus_exp();
+    if prob_present.is_nan() {
+        None
+    } else {
+        Some(prob_present)
+    }
+}
+
+/// Representation given the posterior probabilities of the MNV and its SNVs being present.
+/// Returns `None` if neither of them is likely present.
+fn representation(prob_mnv: LogProb, prob_snvs: &[LogProb]) -> Option<Representation> {
+    let prob_threshold = LogProb(0.5f64.ln());
+    let prob_snv =
+        prob_snvs.iter().cloned().fold(
+            LogProb::ln_zero(),
+            |max, prob| if prob > max { prob } else { max },
+        );
+    if prob_mnv < prob_threshold && prob_snv < prob_threshold {
+        return None;
+    }
+
+    // METHOD: reads carrying all substitutions (in cis) support both the MNV and each
+    // of the SNVs. Hence, the probability of the substitutions occurring separately is
+    // the excess of the most likely SNV over the MNV. The more likely of both is reported.
+    let prob_separate = if prob_snv > prob_mnv {
+        prob_snv.ln_sub_exp(prob_mnv)
+    } else {
+        LogProb::ln_zero()
+    };
+    Some(if prob_mnv >= prob_separate {
+        Representation::Mnv
+    } else {
+        Representation::Snvs
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bio::stats::Prob;
+
+    use crate::calling::variants::{CallBuilder, VariantBuilder};
+
+    fn call(pos: u64, ref_allele: &[u8], alt_allele: &[u8], prob_present: f64) -> Call {
+        let prob_present = LogProb::from(Prob(prob_present));
+        let mut event_probs = HashMap::new();
+        event_probs.insert("present".to_owned(), prob_present);
+        event_probs.insert("absent".to_owned(), prob_present.ln_one_minus_exp());
+        event_probs.insert("artifact".to_owned(), LogProb::ln_zero());
+        CallBuilder::default()
+            .chrom(b"chr1".to_vec())
+            .pos(pos)
+            .variant(Some(
+                VariantBuilder::default()
+                    .ref_allele(ref_allele.to_vec())
+                    .alt_allele(alt_allele.to_vec())
+                    .merged_snvs(ref_allele.len() > 1)
+                    .event_probs(Some(event_probs))
+                    .build()
+                    .unwrap(),
+            ))
+            .build()
+            .unwrap()
+    }
+
+    fn representations(calls: Vec<Call>) -> Vec<Option<Representation>> {
+        let mut buffer = RepresentationBuffer::default();
+        let mut complete = Vec::new();
+        for call in calls {
+            complete.extend(buffer.push(call));
+        }
+        complete.extend(buffer.flush());
+        complete
+            .iter()
+            .map(|call| call.variant.as_ref().unwrap().representation)
+            .collect()
+    }
+
+    #[test]
+    fn test_representation() {
+        let prob = |p: f64| LogProb::from(Prob(p));
+
+        // substitutions in cis
+        assert_eq!(
+            representation(prob(0.99), &[prob(0.999), prob(0.999)]),
+            Some(Representation::Mnv)
+        );
+        // substitutions in trans
+        assert_eq!(
+            representation(prob(0.01), &[prob(0.999), prob(0.999)]),
+            Some(Representation::Snvs)
+        );
+        // neither present
+        assert_eq!(representation(prob(0.01), &[prob(0.1), prob(0.2)]), None);
+        // SNVs have not been called
+        assert_eq!(representation(prob(0.9), &[]), Some(Representation::Mnv));
+    }
+
+    #[test]
+    fn test_representation_buffer() {
+        let calls = vec![
+            call(9, b"A", b"G", 0.999),
+            call(10, b"C", b"A", 0.999),
+            call(10, b"CTG", b"ATT", 0.01),
+            call(12, b"G", b"T", 0.999),
+            call(20, b"C", b"T", 0.999),
+            call(30, b"C", b"A", 0.01),
+            call(30, b"CG", b"AT", 0.02),
+            call(31, b"G", b"T", 0.1),
+        ];
+        assert_eq!(
+            representations(calls),
+            vec![
+                None,
+                None,
+                Some(Representation::Snvs),
+                None,
+                None,
+                None,
+                None,
+                None,
+            ]
+        );
+    }
+}
@@ -21,19 +21,24 @@ use crate::calling;
 use crate::conversion;
 use crate::errors;
 use crate::estimation;
-use crate::estimation::alignment_properties::AlignmentProperties;
+use crate::estimation::alignment_properties::{AlignmentProperties, ErrorRates};
 use crate::filtration;
 use crate::grammar;
 use crate::reference;
 use crate::testcase;
+use crate::utils::collect_variants::AdjacentSnvIndex;
+use crate::utils::regions;
+use crate::variants::evidence::depth::DepthControls;
 use crate::variants::evidence::realignment;
+use crate::variants::evidence::realignment::haplotypes::HaplotypeIndex;
 use crate::variants::evidence::realignment::pairhmm::GapParams;
 use crate::variants::model::modes::generic::FlatPrior;
 use crate::variants::model::prior::CheckablePrior;
 use crate::variants::model::prior::{Inheritance, Prior};
 use crate::variants::model::{Contamination, VariantType};
-use crate::variants::sample::{estimate_alignment_properties, ProtocolStrandedness};
+use crate::variants::sample::{estimate_alignment_properties, Platform, ProtocolStrandedness};
 use crate::variants::types::breakends::BreakendIndex;
+use crate::variants::types::mobile_element_insertion::MobileElementLibrary;
 use crate::SimpleEvent;
 
 #[derive(Debug, StructOpt, Serialize, Deserialize, Clone)]
@@ -52,6 +57,28 @@ pub enum Varlociraptor {
         #[structopt(subcommand)]
         kind: PreprocessKind,
     },
+    #[structopt(
+        name = "merge-observations",
+        about = "Merge observation BCFs obtained by preprocessing disjoint regions of the same \
+                 candidate variants (e.g. via 'varlociraptor preprocess variants --regions').",
+        usage = "varlociraptor merge-observations chr1.observations.bcf chr2.observations.bcf > sample.observations.bcf",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    MergeObservations {
+        #[structopt(
+            parse(from_os_str),
+            required = true,
+            help = "Observation BCF files obtained with 'varlociraptor preprocess variants'. \
+                    All files have to stem from the same preprocessing options and must not overlap."
+        )]
+        observations: Vec<PathBuf>,
+        #[structopt(
+            parse(from_os_str),
+            long,
+            help = "BCF file that shall contain the results (if omitted, write to STDOUT)."
+        )]
+        output: Option<PathBuf>,
+    },
     #[structopt(
         name = "call",
         about = "Call variants.",
@@ -98,7 +125,7 @@ pub enum Varlociraptor {
 
 pub struct PreprocessInput {
     reference: PathBuf,
-    bam: PathBuf,
+    bams: Vec<PathBuf>,
 }
 
 impl Varlociraptor {
@@ -107,14 +134,14 @@ impl Varlociraptor {
             kind:
                 PreprocessKind::Variants {
                     ref reference,
-                    ref bam,
+                    ref bams,
                     ..
                 },
         } = &self
         {
             PreprocessInput {
                 reference: reference.to_owned(),
-                bam: bam.to_owned(),
+                bams: bams.to_owned(),
             }
         } else {
             panic!("bug: these are not preprocess options.");
@@ -134,6 +161,29 @@ fn default_min_bam_refetch_distance() -> u64 {
     1
 }
 
+fn default_threads() -> usize {
+    1
+}
+
+/// Deserialize BAM files from preprocessing options. Options of older versions
+/// contain only a single BAM file.
+fn deserialize_bams<'de, D>(deserializer: D) -> std::result::Result<Vec<PathBuf>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Bams {
+        Single(PathBuf),
+        Multiple(Vec<PathBuf>),
+    }
+
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        Bams::Single(bam) => vec![bam],
+        Bams::Multiple(bams) => bams,
+    })
+}
+
 #[derive(Debug, StructOpt, Serialize, Deserialize, Clone)]
 pub enum PreprocessKind {
     #[structopt(
@@ -160,11 +210,16 @@ pub enum PreprocessKind {
         )]
         candidates: PathBuf,
         #[structopt(
-            long,
+            long = "bam",
             required = true,
-            help = "BAM file with aligned reads from a single sample."
+            help = "BAM or CRAM file(s) with aligned reads from a single sample. Multiple files (e.g. \
+                    from different lanes or libraries of the same sample) can be given and are treated \
+                    as one sample. Alignment properties are then estimated from each file separately \
+                    and combined. CRAM files are decoded with the given reference genome, which has \
+                    to be the one the CRAM files have been created with."
         )]
-        bam: PathBuf,
+        #[serde(alias = "bam", deserialize_with = "deserialize_bams")]
+        bams: Vec<PathBuf>,
         #[structopt(
             long = "reference-buffer-size",
             short = "b",
@@ -198,30 +253,42 @@ pub enum PreprocessKind {
             help = "BCF file that shall contain the results (if omitted, write to STDOUT)."
         )]
         output: Option<PathBuf>,
+        #[structopt(
+            long = "platform",
+            default_value = "illumina",
+            possible_values = &Platform::iter().map(|v| v.into()).collect_vec(),
+            help = "Sequencing platform. Determines the default rates of spurious insertions and \
+                    deletions (see --spurious-ins-rate etc.) in case they cannot be estimated \
+                    from the alignments. For nanopore and ion-torrent, \
+                    indel errors are in addition modeled to depend on the length of the \
+                    homopolymer they occur in."
+        )]
+        #[serde(default)]
+        platform: Platform,
         #[structopt(
             long = "spurious-ins-rate",
-            default_value = "2.8e-6",
-            help = "Rate of spuriously inserted bases by the sequencer (Illumina: 2.8e-6, see Schirmer et al. BMC Bioinformatics 2016)."
+            help = "Rate of spuriously inserted bases by the sequencer (default: estimated from \
+                    the data or given by --platform, Illumina: 2.8e-6, see Schirmer et al. BMC Bioinformatics 2016)."
         )]
-        spurious_ins_rate: f64,
+        spurious_ins_rate: Option<f64>,
         #[structopt(
             long = "spurious-del-rate",
-            default_value = "5.1e-6",
-            help = "Rate of spuriosly deleted bases by the sequencer (Illumina: 5.1e-6, see Schirmer et al. BMC Bioinformatics 2016)."
+            help = "Rate of spuriosly deleted bases by the sequencer (default: estimated from \
+                    the data or given by --platform, Illumina: 5.1e-6, see Schirmer et al. BMC Bioinformatics 2016)."
         )]
-        spurious_del_rate: f64,
+        spurious_del_rate: Option<f64>,
         #[structopt(
             long = "spurious-insext-rate",
-            default_value = "0.0",
-            help = "Extension rate of spurious insertions by the sequencer (Illumina: 0.0, see Schirmer et al. BMC Bioinformatics 2016)"
+            help = "Extension rate of spurious insertions by the sequencer (default: estimated from \
+                    the data or given by --platform, Illumina: 0.0, see Schirmer et al. BMC Bioinformatics 2016)"
         )]
-        spurious_insext_rate: f64,
+        spurious_insext_rate: Option<f64>,
         #[structopt(
             long = "spurious-delext-rate",
-            default_value = "0.0",
-            help = "Extension rate of spurious deletions by the sequencer (Illumina: 0.0, see Schirmer et al. BMC Bioinformatics 2016)"
+            help = "Extension rate of spurious deletions by the sequencer (default: estimated from \
+                    the data or given by --platform, Illumina: 0.0, see Schirmer et al. BMC Bioinformatics 2016)"
         )]
-        spurious_delext_rate: f64,
+        spurious_delext_rate: Option<f64>,
         #[structopt(
             long = "strandedness",
             default_value = "opposite",
@@ -233,8 +300,8 @@ pub enum PreprocessKind {
             long = "indel-window",
             default_value = "64",
             help = "Number of bases to consider left and right of breakpoint when \
-                    calculating read support. Currently implemented maximum \
-                    value is 64."
+                    calculating read support. For long reads (e.g. Oxford Nanopore or \
+                    PacBio HiFi), consider using larger windows of several hundred bases."
         )]
         realignment_window: u64,
         #[structopt(
@@ -251,20 +318,114 @@ pub enum PreprocessKind {
         )]
         #[serde(default)]
         omit_insert_size: bool,
+        #[structopt(
+            long = "empirical-insert-size",
+            help = "Estimate an empirical insert size distribution (a histogram) instead of assuming \
+                    a normal distribution. Use this flag for libraries with skewed or multimodal \
+                    insert sizes (e.g. after size selection or for cell-free DNA). Only used if \
+                    alignment properties are not provided via --alignment-properties."
+        )]
+        #[serde(default)]
+        empirical_insert_size: bool,
+        #[structopt(
+            long = "umi-families",
+            help = "Group reads into UMI families via their MI tag (e.g. as assigned by fgbio), \
+                    instead of discarding reads marked as duplicates. Each family yields a single \
+                    observation that combines the evidence of all its members. Family size and \
+                    duplex status (MI tags ending with /A and /B) are recorded."
+        )]
+        #[serde(default)]
+        umi_families: bool,
+        #[structopt(
+            long = "phasing",
+            help = "Record which fragments support which allele, such that 'varlociraptor call' \
+                    can phase nearby calls via shared fragments (FORMAT/PS). Without this flag, \
+                    no phase sets are reported. Note that this increases the size of the \
+                    observation files."
+        )]
+        #[serde(default)]
+        phasing: bool,
+        #[structopt(
+            long = "joint-realignment",
+            help = "Realign reads against local haplotypes that combine each indel candidate with \
+                    nearby candidate variants (at most 3, within the realignment window). \
+                    Otherwise, reads carrying a neighboring variant are penalized on both alleles. \
+                    Use this flag for mismatch dense regions (e.g. HLA) or clustered somatic \
+                    variants. Note that the number of realignments per read grows exponentially \
+                    with the number of considered neighbors."
+        )]
+        #[serde(default)]
+        joint_realignment: bool,
+        #[structopt(
+            long = "merge-adjacent-snvs",
+            help = "Additionally consider SNV candidates that are at most the given number of bases \
+                    apart as a combined MNV candidate (e.g. 2 for substitutions within a codon). \
+                    MNV candidates are marked with MERGED_SNVS. After calling, the REPRESENTATION \
+                    tag reports whether the reads support the MNV or the separate SNVs."
+        )]
+        #[serde(default)]
+        merge_adjacent_snvs: Option<u64>,
+        #[structopt(
+            long = "mobile-elements",
+            parse(from_os_str),
+            help = "FASTA file with consensus sequences of mobile element families (e.g. ALU, L1, \
+                    SVA). Mobile element insertion candidates (e.g. <INS:ME:ALU>) are genotyped \
+                    by realigning reads against the consensus of their family (matched by the \
+                    FASTA record ID) and by considering discordant read pairs with the mate \
+                    mapped to a copy of the element. Candidates of families without consensus \
+                    sequence are skipped."
+        )]
+        #[serde(default)]
+        mobile_elements: Option<PathBuf>,
+        #[structopt(
+            long = "depth-controls",
+            parse(from_os_str),
+            help = "BAM or CRAM files of control samples (one per sample, e.g. a matched normal \
+                    or a panel of normals) for considering read depth as evidence for deletions \
+                    and duplications of at least 1000 bases. The number of reads within such \
+                    variants is compared to the number expected from the controls (normalized by \
+                    the flanks of the variant). Controls should be free of the variants of \
+                    interest, hence, do not use this when preprocessing the normal sample itself."
+        )]
+        #[serde(default)]
+        depth_controls: Vec<PathBuf>,
         #[structopt(
             long = "pairhmm-mode",
-            possible_values = &["fast", "exact"],
+            possible_values = &["fast", "exact", "vectorized"],
             default_value = "exact",
-            help = "PairHMM computation mode (either fast or exact). Fast mode means that only the best \
+            help = "PairHMM computation mode (either fast, exact or vectorized). Fast mode means that only the best \
                     alignment path is considered for probability calculation. In rare cases, this can lead \
                     to wrong results for single reads. Hence, we advice to not use it when \
                     discrete allele frequences are of interest (0.5, 1.0). For continuous \
                     allele frequencies, fast mode should cause almost no deviations from the \
                     exact results. Also, if per sample allele frequencies are irrelevant (e.g. \
-                    in large cohorts), fast mode can be safely used."
+                    in large cohorts), fast mode can be safely used. Vectorized mode computes the \
+                    same probabilities as exact mode (up to numerical precision), but uses SIMD \
+                    instructions (SSE or AVX2, depending on the CPU) and is therefore considerably \
+                    faster, in particular for long reads."
         )]
         #[serde(default = "default_pairhmm_mode")]
         pairhmm_mode: String,
+        #[structopt(
+            long = "threads",
+            short = "t",
+            default_value = "1",
+            help = "Number of threads to use. With more than one thread, candidates are split into \
+                    consecutive genomic chunks that are processed independently. Output is written \
+                    in the same order as the input."
+        )]
+        #[serde(default = "default_threads")]
+        threads: usize,
+        #[structopt(
+            long = "regions",
+            help = "Restrict preprocessing to the given regions. Regions are either given as paths \
+                    to BED files or as CHROM or CHROM:START-END (1-based, inclusive). \
+                    Requires the candidate BCF/VCF to be indexed. This can be used to scatter \
+                    preprocessing over multiple jobs. Breakend events are skipped unless all their \
+                    breakends are contained in the regions."
+        )]
+        #[serde(default)]
+        regions: Vec<String>,
     },
 }
 
@@ -367,6 +528,15 @@ pub enum CallKind {
         )]
         #[serde(default)]
         omit_read_position_bias: bool,
+        #[structopt(
+            long = "omit-duplex-bias",
+            help = "Do not consider duplex bias (ALT allele only supported by a single strand of \
+                    duplex sequenced molecules) when calculating the probability of an artifact. \
+                    The bias is only considered if observations have been preprocessed with \
+                    --umi-families and reads carry duplex strand information in their MI tags."
+        )]
+        #[serde(default)]
+        omit_duplex_bias: bool,
         #[structopt(
             long = "testcase-locus",
             help = "Create a test case for the given locus. Locus must be given in the form \
@@ -387,47 +557,63 @@ pub enum CallKind {
             help = "Output variant calls to given path (in BCF format). If omitted, prints calls to STDOUT."
         )]
         output: Option<PathBuf>,
+        #[structopt(
+            long = "regions",
+            help = "Restrict calling to the given regions. Regions are either given as paths \
+                    to BED files or as CHROM or CHROM:START-END (1-based, inclusive). \
+                    Requires the observation BCFs to be indexed."
+        )]
+        #[serde(default)]
+        regions: Vec<String>,
+    },
+    #[structopt(
+        name = "cnvs",
+        about = "Call CNVs in tumor-normal sample pairs, using germline heterozygous variants \
+                 from 'varlociraptor call variants tumor-normal'. This is experimental.",
+        usage = "varlociraptor call cnvs --purity 0.75 --calls calls.bcf > cnvs.bcf",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    CNVs {
+        #[structopt(
+            parse(from_os_str),
+            long,
+            help = "VCF/BCF file (generated by varlociraptor call variants tumor-normal) to process \
+                    (if omitted, read from STDIN)."
+        )]
+        calls: Option<PathBuf>,
+        #[structopt(
+            parse(from_os_str),
+            long,
+            help = "BCF file that shall contain the results (if omitted, write to STDOUT)."
+        )]
+        output: Option<PathBuf>,
+        #[structopt(long, short = "p", help = "Tumor purity.")]
+        purity: f64,
+        #[structopt(
+            long = "min-bayes-factor",
+            default_value = "100",
+            help = "Minimum bayes factor (> 1.0) between likelihoods of CNV and no CNV to consider. \
+                    The higher this value, the fewer candidate CNVs will be investigated, and the \
+                    longer the considered segments become. Note that this can be usually left \
+                    unchanged, because every CNV is provided with a posterior probability (QUAL) \
+                    that can be used for filtering."
+        )]
+        min_bayes_factor: f64,
+        #[structopt(
+            long,
+            default_value = "1000",
+            help = "Maximum distance between supporting loci in a CNV."
+        )]
+        max_dist: u64,
+        #[structopt(
+            long,
+            short = "t",
+            default_value = "1",
+            help = "Number of threads to use."
+        )]
+        #[serde(default = "default_threads")]
+        threads: usize,
     },
-    // #[structopt(
-    //     name = "cnvs",
-    //     about = "Call CNVs in tumor-normal sample pairs. This is experimental (do not use it yet).",
-    //     setting = structopt::clap::AppSettings::ColoredHelp,
-    // )]
-    // CNVs {
-    //     #[structopt(
-    //         parse(from_os_str),
-    //         long,
-    //         help = "VCF/BCF file (generated by varlociraptor call-tumor-normal) to process \
-    //                 (if omitted, read from STDIN)."
-    //     )]
-    //     calls: Option<PathBuf>,
-    //     #[structopt(
-    //         parse(from_os_str),
-    //         long,
-    //         help = "BCF file that shall contain the results (if omitted, write to STDOUT)."
-    //     )]
-    //     output: Option<PathBuf>,
-    //     #[structopt(long, short = "p", help = "Tumor purity.")]
-    //     purity: f64,
-    //     #[structopt(
-    //         long = "min-bayes-factor",
-    //         default_value = "1.01",
-    //         help = "Minimum bayes factor (> 1.0) between likelihoods of CNV and no CNV to consider. \
-    //                 The higher this value, the fewer candidate CNVs will be investigated. \
-    //                 Note that this can be usually left unchanged, because every CNV is provided \
-    //                 with a posterior probability that can be used for filtering, e.g., via \
-    //                 'varlociraptor filter-calls control-fdr'."
-    //     )]
-    //     min_bayes_factor: f64,
-    //     #[structopt(
-    //         long,
-    //         default_value = "1000",
-    //         help = "Maximum distance between supporting loci in a CNV."
-    //     )]
-    //     max_dist: u32,
-    //     #[structopt(long, short = "t", help = "Number of threads to use.")]
-    //     threads: usize,
-    // },
 }
 
 #[derive(Debug, StructOpt, Serialize, Deserialize, Clone)]
@@ -556,51 +742,88 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                 PreprocessKind::Variants {
                     reference,
                     candidates,
-                    bam,
+                    bams,
                     alignment_properties,
                     output,
                     spurious_ins_rate,
                     spurious_del_rate,
                     spurious_insext_rate,
                     spurious_delext_rate,
+                    platform,
                     protocol_strandedness,
                     realignment_window,
                     max_depth,
                     omit_insert_size,
+                    empirical_insert_size,
+                    umi_families,
+                    phasing,
+                    joint_realignment,
+                    merge_adjacent_snvs,
+                    mobile_elements,
+                    depth_controls,
                     reference_buffer_size,
                     min_bam_refetch_distance,
                     pairhmm_mode,
+                    threads,
+                    regions,
                 } => {
                     // TODO: handle testcases
 
-                    let spurious_ins_rate = Prob::checked(spurious_ins_rate)?;
-                    let spurious_del_rate = Prob::checked(spurious_del_rate)?;
-                    let spurious_insext_rate = Prob::checked(spurious_insext_rate)?;
-                    let spurious_delext_rate = Prob::checked(spurious_delext_rate)?;
-                    if realignment_window > (128 / 2) {
-                        return Err(
-                            structopt::clap::Error::with_description(
-                                "Command-line option --indel-window requires a value <= 64 with the current implementation.", 
-                                structopt::clap::ErrorKind::ValueValidation
-                            ).into()
-                        );
-                    };
-
                     // If we omit the insert size information for calculating the evidence, we can savely allow hardclips here.
                     let allow_hardclips = omit_insert_size;
                     let alignment_properties = est_or_load_alignment_properties(
                         &alignment_properties,
-                        &bam,
+                        &bams,
+                        &reference,
                         omit_insert_size,
                         allow_hardclips,
+                        empirical_insert_size,
                     )?;
 
-                    let gap_params = GapParams {
-                        prob_insertion_artifact: LogProb::from(spurious_ins_rate),
-                        prob_deletion_artifact: LogProb::from(spurious_del_rate),
-                        prob_insertion_extend_artifact: LogProb::from(spurious_insext_rate),
-                        prob_deletion_extend_artifact: LogProb::from(spurious_delext_rate),
+                    // METHOD: start from the platform preset, replace it with the error rates
+                    // estimated from the data (if available) and override with explicitly given rates.
+                    // Read groups with their own error rate estimates get their own gap parameters.
+                    let gap_params = |error_rates: Option<&ErrorRates>| -> Result<GapParams> {
+                        let mut gap_params = GapParams::preset(platform);
+                        if let Some(error_rates) = error_rates {
+                            gap_params.prob_insertion_artifact =
+                                LogProb::from(Prob(error_rates.prob_insertion));
+                            gap_params.prob_deletion_artifact =
+                                LogProb::from(Prob(error_rates.prob_deletion));
+                            gap_params.prob_insertion_extend_artifact =
+                                LogProb::from(Prob(error_rates.prob_insertion_extend));
+                            gap_params.prob_deletion_extend_artifact =
+                                LogProb::from(Prob(error_rates.prob_deletion_extend));
+                            gap_params.prob_mismatch_artifact = error_rates
+                                .prob_mismatch
+                                .map(|prob| LogProb::from(Prob(prob)));
+                        }
+                        if let Some(rate) = spurious_ins_rate {
+                            gap_params.prob_insertion_artifact =
+                                LogProb::from(Prob::checked(rate)?);
+                        }
+                        if let Some(rate) = spurious_del_rate {
+                            gap_params.prob_deletion_artifact = LogProb::from(Prob::checked(rate)?);
+                        }
+                        if let Some(rate) = spurious_insext_rate {
+                            gap_params.prob_insertion_extend_artifact =
+                                LogProb::from(Prob::checked(rate)?);
+                        }
+                        if let Some(rate) = spurious_delext_rate {
+                            gap_params.prob_deletion_extend_artifact =
+                                LogProb::from(Prob::checked(rate)?);
+                        }
+                        Ok(gap_params)
                     };
+                    let read_group_gap_params = alignment_properties
+                        .read_group_error_rates()
+                        .map(|(rg, error_rates)| {
+                            Ok((rg.to_owned(), gap_params(Some(error_rates))?))
+                        })
+                        .collect::<Result<HashMap<_, _>>>()?;
+                    let gap_params = gap_params(alignment_properties.error_rates.as_ref())?;
+
+                    let regions = regions::regions(&regions)?;
 
                     let reference_buffer = Arc::new(reference::Buffer::new(
                         fasta::IndexedReader::from_file(&reference)
@@ -608,23 +831,98 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                         reference_buffer_size,
                     ));
 
+                    let haplotype_index = if joint_realignment {
+                        HaplotypeIndex::new(&candidates, regions.as_ref())?
+                    } else {
+                        HaplotypeIndex::default()
+                    };
+
+                    let adjacent_snv_index = if let Some(max_distance) = merge_adjacent_snvs {
+                        AdjacentSnvIndex::new(&candidates, regions.as_ref(), max_distance)?
+                    } else {
+                        AdjacentSnvIndex::default()
+                    };
+
+                    let mobile_elements = if let Some(ref path) = mobile_elements {
+                        MobileElementLibrary::new(path)
+                            .context("Unable to read mobile element consensus sequences.")?
+                    } else {
+                        MobileElementLibrary::default()
+                    };
+
+                    let depth_controls = Arc::new(
+                        DepthControls::new(&depth_controls, &reference)
+                            .context("Unable to open control BAM files for read depth evidence.")?,
+                    );
+
                     if pairhmm_mode == "fast" {
                         let mut processor =
                             calling::variants::preprocessing::ObservationProcessor::builder()
                                 .alignment_properties(alignment_properties)
                                 .protocol_strandedness(protocol_strandedness)
                                 .max_depth(max_depth)
-                                .inbam(bam)
+                                .inbams(bams.clone())
                                 .min_bam_refetch_distance(min_bam_refetch_distance)
+                                .umi_families(umi_families)
+                                .phasing(phasing)
+                                .threads(threads)
                                 .reference_buffer(Arc::clone(&reference_buffer))
-                                .breakend_index(BreakendIndex::new(&candidates)?)
+                                .reference(reference.clone())
+                                .breakend_index(BreakendIndex::new(&candidates, regions.as_ref())?)
+                                .haplotype_index(haplotype_index)
+                                .adjacent_snv_index(adjacent_snv_index)
+                                .mobile_elements(mobile_elements)
+                                .depth_controls(depth_controls)
+                                .regions(regions.clone())
                                 .inbcf(candidates)
                                 .options(opt_clone)
                                 .outbcf(output)
-                                .realigner(realignment::PathHMMRealigner::new(
+                                .realigner(realignment::ReadGroupRealigner::new(
                                     gap_params,
-                                    realignment_window,
-                                    reference_buffer,
+                                    read_group_gap_params,
+                                    |gap_params| {
+                                        realignment::PathHMMRealigner::new(
+                                            gap_params,
+                                            realignment_window,
+                                            Arc::clone(&reference_buffer),
+                                        )
+                                    },
+                                ))
+                                .build();
+
+                        processor.process()?;
+                    } else if pairhmm_mode == "vectorized" {
+                        let mut processor =
+                            calling::variants::preprocessing::ObservationProcessor::builder()
+                                .alignment_properties(alignment_properties)
+                                .protocol_strandedness(protocol_strandedness)
+                                .max_depth(max_depth)
+                                .inbams(bams.clone())
+                                .min_bam_refetch_distance(min_bam_refetch_distance)
+                                .umi_families(umi_families)
+                                .phasing(phasing)
+                                .threads(threads)
+                                .reference_buffer(Arc::clone(&reference_buffer))
+                                .reference(reference.clone())
+                                .breakend_index(BreakendIndex::new(&candidates, regions.as_ref())?)
+                                .haplotype_index(haplotype_index)
+                                .adjacent_snv_index(adjacent_snv_index)
+                                .mobile_elements(mobile_elements)
+                                .depth_controls(depth_controls)
+                                .regions(regions.clone())
+                                .inbcf(candidates)
+                                .options(opt_clone)
+                                .outbcf(output)
+                                .realigner(realignment::ReadGroupRealigner::new(
+                                    gap_params,
+                                    read_group_gap_params,
+                                    |gap_params| {
+                                        realignment::SimdPairHMMRealigner::new(
+                                            Arc::clone(&reference_buffer),
+                                            gap_params,
+                                            realignment_window,
+                                        )
+                                    },
                                 ))
                                 .build();
 
@@ -635,17 +933,32 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                                 .alignment_properties(alignment_properties)
                                 .protocol_strandedness(protocol_strandedness)
                                 .max_depth(max_depth)
-                                .inbam(bam)
+                                .inbams(bams.clone())
                                 .min_bam_refetch_distance(min_bam_refetch_distance)
+                                .umi_families(umi_families)
+                                .phasing(phasing)
+                                .threads(threads)
                                 .reference_buffer(Arc::clone(&reference_buffer))
-                                .breakend_index(BreakendIndex::new(&candidates)?)
+                                .reference(reference.clone())
+                                .breakend_index(BreakendIndex::new(&candidates, regions.as_ref())?)
+                                .haplotype_index(haplotype_index)
+                                .adjacent_snv_index(adjacent_snv_index)
+                                .mobile_elements(mobile_elements)
+                                .depth_controls(depth_controls)
+                                .regions(regions.clone())
                                 .inbcf(candidates)
                                 .options(opt_clone)
                                 .outbcf(output)
-                                .realigner(realignment::PairHMMRealigner::new(
-                                    reference_buffer,
+                                .realigner(realignment::ReadGroupRealigner::new(
                                     gap_params,
-                                    realignment_window,
+                                    read_group_gap_params,
+                                    |gap_params| {
+                                        realignment::PairHMMRealigner::new(
+                                            Arc::clone(&reference_buffer),
+                                            gap_params,
+                                            realignment_window,
+                                        )
+                                    },
                                 ))
                                 .build();
 
@@ -661,10 +974,14 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                     omit_strand_bias,
                     omit_read_orientation_bias,
                     omit_read_position_bias,
+                    omit_duplex_bias,
                     testcase_locus,
                     testcase_prefix,
                     output,
+                    regions,
                 } => {
+                    let regions = regions::regions(&regions)?;
+
                     let testcase_builder = if let Some(testcase_locus) = testcase_locus {
                         if let Some(testcase_prefix) = testcase_prefix {
                             // TODO obtain sample information from input bcfs?
@@ -706,8 +1023,10 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                             }
                         }
 
-                        let breakend_index =
-                            BreakendIndex::new(sample_observations.first_not_none()?)?;
+                        let breakend_index = BreakendIndex::new(
+                            sample_observations.first_not_none()?,
+                            regions.as_ref(),
+                        )?;
 
                         let prior = Prior::builder()
                             .ploidies(None)
@@ -736,11 +1055,14 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                             .omit_strand_bias(omit_strand_bias)
                             .omit_read_orientation_bias(omit_read_orientation_bias)
                             .omit_read_position_bias(omit_read_position_bias)
+                            .omit_duplex_bias(omit_duplex_bias)
                             .scenario(scenario)
                             .prior(prior)
                             .contaminations(sample_infos.contaminations)
+                            .fragment_length_evidence(sample_infos.fragment_length_evidence)
                             .resolutions(sample_infos.resolutions)
                             .breakend_index(breakend_index)
+                            .regions(regions.clone())
                             .outbcf(output)
                             .build()
                             .unwrap();
@@ -767,7 +1089,7 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                                         let preprocess_input = options.preprocess_input();
                                         testcase_builder = testcase_builder.register_sample(
                                             &sample_name,
-                                            preprocess_input.bam,
+                                            preprocess_input.bams,
                                             &options,
                                         )?;
                                         if i == 0 {
@@ -814,12 +1136,12 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                                     .reference(tumor_options.preprocess_input().reference)?
                                     .register_sample(
                                         "tumor",
-                                        tumor_options.preprocess_input().bam,
+                                        tumor_options.preprocess_input().bams,
                                         &tumor_options,
                                     )?
                                     .register_sample(
                                         "normal",
-                                        normal_options.preprocess_input().bam,
+                                        normal_options.preprocess_input().bams,
                                         &normal_options,
                                     )?
                                     .scenario(None)
@@ -863,33 +1185,37 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                             call_generic(scenario, observations)?;
                         }
                     }
-                } // CallKind::CNVs {
-                  //     calls,
-                  //     output,
-                  //     min_bayes_factor,
-                  //     threads,
-                  //     purity,
-                  //     max_dist,
-                  // } => {
-                  //     rayon::ThreadPoolBuilder::new()
-                  //         .num_threads(threads)
-                  //         .build_global()?;
-
-                  //     if min_bayes_factor <= 1.0 {
-                  //         Err(errors::Error::InvalidMinBayesFactor)?
-                  //     }
+                }
+                CallKind::CNVs {
+                    calls,
+                    output,
+                    min_bayes_factor,
+                    threads,
+                    purity,
+                    max_dist,
+                } => {
+                    if min_bayes_factor <= 1.0 {
+                        return Err(errors::Error::InvalidMinBayesFactor.into());
+                    }
 
-                  //     let mut caller = calling::cnvs::CallerBuilder::default()
-                  //         .bcfs(calls.as_ref(), output.as_ref())?
-                  //         .min_bayes_factor(min_bayes_factor)
-                  //         .purity(purity)
-                  //         .max_dist(max_dist)
-                  //         .build()
-                  //         .unwrap();
-                  //     caller.call()?;
-                  // }
+                    let mut caller = calling::cnvs::CallerBuilder::default()
+                        .bcfs(calls.as_ref(), output.as_ref())?
+                        .min_bayes_factor(min_bayes_factor)
+                        .purity(purity)
+                        .max_dist(max_dist)
+                        .threads(threads)
+                        .build()
+                        .unwrap();
+                    caller.call()?;
+                }
             }
         }
+        Varlociraptor::MergeObservations {
+            observations,
+            output,
+        } => {
+            calling::variants::preprocessing::merge_observations(&observations, output.as_ref())?;
+        }
         Varlociraptor::FilterCalls { method } => match method {
             FilterMethod::ControlFDR {
                 calls,
@@ -1002,22 +1328,31 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
 
 pub(crate) fn est_or_load_alignment_properties(
     alignment_properties_file: &Option<impl AsRef<Path>>,
-    bam_file: impl AsRef<Path>,
+    bam_files: &[impl AsRef<Path>],
+    reference: &Path,
     omit_insert_size: bool,
     allow_hardclips: bool,
+    empirical_insert_size: bool,
 ) -> Result<AlignmentProperties> {
     if let Some(alignment_properties_file) = alignment_properties_file {
         Ok(serde_json::from_reader(File::open(
             alignment_properties_file,
         )?)?)
     } else {
-        estimate_alignment_properties(bam_file, omit_insert_size, allow_hardclips)
+        estimate_alignment_properties(
+            bam_files,
+            reference,
+            omit_insert_size,
+            allow_hardclips,
+            empirical_insert_size,
+        )
     }
 }
 
 struct SampleInfos {
     uniform_prior: grammar::SampleInfo<bool>,
     contaminations: grammar::SampleInfo<Option<Contamination>>,
+    fragment_length_evidence: grammar::SampleInfo<bool>,
     resolutions: grammar::SampleInfo<usize>,
     germline_mutation_rates: grammar::SampleInfo<Option<f64>>,
     somatic_effective_mutation_rates: grammar::SampleInfo<Option<f64>>,
@@ -1030,6 +1365,7 @@ impl<'a> TryFrom<&'a grammar::Scenario> for SampleInfos {
 
     fn try_from(scenario: &grammar::Scenario) -> Result<Self> {
         let mut contaminations = scenario.sample_info();
+        let mut fragment_length_evidence = scenario.sample_info();
         let mut resolutions = scenario.sample_info();
         let mut sample_names = scenario.sample_info();
         let mut germline_mutation_rates = scenario.sample_info();
@@ -1053,6 +1389,8 @@ impl<'a> TryFrom<&'a grammar::Scenario> for SampleInfos {
             };
             uniform_prior = uniform_prior.push(sample_name, sample.has_uniform_prior());
             contaminations = contaminations.push(sample_name, contamination);
+            fragment_length_evidence =
+                fragment_length_evidence.push(sample_name, *sample.fragment_length_evidence());
             resolutions = resolutions.push(sample_name, *sample.resolution());
             sample_names = sample_names.push(sample_name, sample_name.to_owned());
             germline_mutation_rates = germline_mutation_rates.push(
@@ -1103,6 +1441,7 @@ impl<'a> TryFrom<&'a grammar::Scenario> for SampleInfos {
         Ok(SampleInfos {
             uniform_prior: uniform_prior.build(),
             contaminations: contaminations.build(),
+            fragment_length_evidence: fragment_length_evidence.build(),
             resolutions: resolutions.build(),
             germline_mutation_rates: germline_mutation_rates.build(),
             somatic_effective_mutation_rates: somatic_effective_mutation_rates.build(),
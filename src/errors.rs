@@ -20,6 +20,10 @@ pub(crate) enum Error {
     InvalidIndex,
     #[error("invalid locus for --testcase-locus. Use CHROM:POS syntax")]
     InvalidLocus,
+    #[error(
+        "invalid region {spec}, use CHROM or CHROM:START-END syntax or give a path to a BED file"
+    )]
+    InvalidRegion { spec: String },
     #[error("no candidate variant at the given locus")]
     NoCandidateFound,
     #[error("testcase prefix must be given with --testcase-prefix")]
@@ -38,6 +42,14 @@ pub(crate) enum Error {
     RecordMissingChrom { i: usize },
     #[error("inconsistent observations: input observation BCF files do not contain exactly the same records")]
     InconsistentObservations,
+    #[error("inconsistent observations: {path} has been preprocessed with different options than the other given observation BCF files")]
+    InconsistentPreprocessOptions { path: PathBuf },
+    #[error("inconsistent observations: contigs of {path} differ from those of the other given observation BCF files")]
+    InconsistentContigs { path: PathBuf },
+    #[error("inconsistent alignments: BAM files of the same sample have to contain the same reference sequences (@SQ) in the same order")]
+    InconsistentBamContigs,
+    #[error("overlapping observations: records of {path} overlap with those of another given observation BCF file")]
+    OverlappingObservations { path: PathBuf },
     #[error("no observations given for sample {name}")]
     InvalidObservationSampleName { name: String },
     #[error("invalid observations: varlociraptor cannot be parsed from given observations ({path}); either the file has not been preprocessed with varlociraptor or with a too old version")]
@@ -60,4 +72,12 @@ pub(crate) enum Error {
     InvalidStrandInfo { value: char },
     #[error("invalid read orientation information '{value}', must be 'F1R2', 'F2R1', etc.")]
     InvalidReadOrientationInfo { value: String },
+    #[error("reference sequence {contig} of the given FASTA does not match the one CRAM file {path} has been created with (MD5 mismatch); please provide the original reference genome")]
+    CramReferenceMismatch { path: String, contig: String },
+    #[error("reference sequence {contig} of CRAM file {path} is missing in the given FASTA; please provide the reference genome the CRAM file has been created with")]
+    CramReferenceMissingContig { path: String, contig: String },
+    #[error("sample {name} not found in given calls; CNV calling requires calls from 'varlociraptor call variants tumor-normal'")]
+    MissingCallsSample { name: String },
+    #[error("minimum bayes factor must be greater than 1.0")]
+    InvalidMinBayesFactor,
 }
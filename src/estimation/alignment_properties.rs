@@ -4,25 +4,286 @@
 // except according to those terms.
 
 use std::cmp;
+use std::collections::HashMap;
 use std::f64;
+use std::ops::Range;
 use std::u32;
 
 use anyhow::Result;
+use bio::stats::LogProb;
 use itertools::Itertools;
 use ordered_float::NotNan;
 use rust_htslib::bam::{self, record::Cigar};
 use statrs::statistics::{OrderStatistics, Statistics};
 
-#[derive(Clone, Debug, Copy, Deserialize, Serialize)]
+use crate::variants::evidence::insert_size::read_group;
+use crate::variants::sampling_bias::fragments::isize_pmf;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct AlignmentProperties {
     pub(crate) insert_size: Option<InsertSize>,
     pub(crate) max_del_cigar_len: u32,
     pub(crate) max_ins_cigar_len: u32,
     pub(crate) frac_max_softclip: f64,
     pub(crate) max_read_len: u32,
+    /// Properties of the individual read groups (libraries) of the sample.
+    /// Read groups without an entry (e.g. reads without RG tag) fall back to the global
+    /// properties above.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) read_groups: HashMap<String, ReadGroupProperties>,
+    /// Empirical sequencing error rates. If present, they are used instead of the
+    /// platform specific defaults during realignment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error_rates: Option<ErrorRates>,
+}
+
+/// Sequencing error rates, estimated from the CIGAR strings (indels) and MD tags
+/// (mismatches) of the alignments.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct ErrorRates {
+    /// Rate of spuriously inserted bases (i.e., insertion opening) per aligned base.
+    pub(crate) prob_insertion: f64,
+    /// Rate of spuriously deleted bases (i.e., deletion opening) per aligned base.
+    pub(crate) prob_deletion: f64,
+    /// Probability to extend a spurious insertion.
+    pub(crate) prob_insertion_extend: f64,
+    /// Probability to extend a spurious deletion.
+    pub(crate) prob_deletion_extend: f64,
+    /// Rate of mismatches per aligned base (if MD tags are available).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) prob_mismatch: Option<f64>,
+    /// Number of aligned bases the rates are based on (zero if unknown).
+    #[serde(default)]
+    pub(crate) count: u64,
+}
+
+impl ErrorRates {
+    /// Average of the rates of this and the other file, weighted by the number of aligned
+    /// bases they have been estimated from. Without counts (e.g. manually specified
+    /// properties), both are weighted equally.
+    fn merge(&self, other: &ErrorRates) -> Self {
+        let weight = if self.count + other.count > 0 {
+            self.count as f64 / (self.count + other.count) as f64
+        } else {
+            0.5
+        };
+        let mean = |a: f64, b: f64| weight * a + (1.0 - weight) * b;
+        ErrorRates {
+            prob_insertion: mean(self.prob_insertion, other.prob_insertion),
+            prob_deletion: mean(self.prob_deletion, other.prob_deletion),
+            prob_insertion_extend: mean(self.prob_insertion_extend, other.prob_insertion_extend),
+            prob_deletion_extend: mean(self.prob_deletion_extend, other.prob_deletion_extend),
+            prob_mismatch: match (self.prob_mismatch, other.prob_mismatch) {
+                (Some(a), Some(b)) => Some(mean(a, b)),
+                (Some(p), None) | (None, Some(p)) => Some(p),
+                (None, None) => None,
+            },
+            count: self.count + other.count,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ErrorEventKind {
+    Insertion(u32),
+    Deletion(u32),
+    Mismatch,
+}
+
+/// Collects potential sequencing errors (indels and mismatches) from alignments in order to
+/// estimate error rates.
+#[derive(Debug, Default)]
+struct ErrorRateEstimator {
+    /// Number of reads supporting each event, by contig, position, and kind, together with
+    /// the read group of the first supporting read.
+    events: HashMap<(i32, i64, ErrorEventKind), (usize, Option<String>)>,
+    /// Number of aligned bases and of those considered for mismatches (i.e., with MD tag),
+    /// by read group.
+    n_bases: HashMap<Option<String>, (u64, u64)>,
+}
+
+impl ErrorRateEstimator {
+    fn register_event(&mut self, event: (i32, i64, ErrorEventKind), read_group: Option<&str>) {
+        self.events
+            .entry(event)
+            .or_insert_with(|| (0, read_group.map(|rg| rg.to_owned())))
+            .0 += 1;
+    }
+
+    fn register(&mut self, record: &bam::Record, read_group: Option<&str>) {
+        let tid = record.tid();
+        let mut pos = record.pos();
+        let mut n_aligned_bases = 0;
+        for c in record.cigar().iter() {
+            match *c {
+                Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => {
+                    pos += l as i64;
+                    n_aligned_bases += l as u64;
+                }
+                Cigar::Ins(l) => {
+                    self.register_event((tid, pos, ErrorEventKind::Insertion(l)), read_group);
+                    n_aligned_bases += l as u64;
+                }
+                Cigar::Del(l) => {
+                    self.register_event((tid, pos, ErrorEventKind::Deletion(l)), read_group);
+                    pos += l as i64;
+                }
+                Cigar::RefSkip(l) => pos += l as i64,
+                _ => (),
+            }
+        }
+
+        let has_md = if let Some(bam::record::Aux::String(md)) = record.aux(b"MD") {
+            // MD tags consist of numbers (matching bases), letters (mismatches)
+            // and deletions (^ followed by the deleted reference bases).
+            let mut pos = record.pos();
+            let mut matches = 0;
+            let mut in_deletion = false;
+            for c in md {
+                if c.is_ascii_digit() {
+                    matches = matches * 10 + (c - b'0') as i64;
+                    in_deletion = false;
+                } else {
+                    pos += matches;
+                    matches = 0;
+                    if *c == b'^' {
+                        in_deletion = true;
+                    } else if !in_deletion {
+                        self.register_event((tid, pos, ErrorEventKind::Mismatch), read_group);
+                    }
+                    pos += if *c == b'^' { 0 } else { 1 };
+                }
+            }
+            true
+        } else {
+            false
+        };
+
+        let n_bases = self
+            .n_bases
+            .entry(read_group.map(|rg| rg.to_owned()))
+            .or_insert((0, 0));
+        n_bases.0 += n_aligned_bases;
+        if has_md {
+            n_bases.1 += n_aligned_bases;
+        }
+    }
+
+    /// Estimate error rates from all registered reads.
+    fn estimate(&self) -> Option<ErrorRates> {
+        self.estimate_filtered(|_| true)
+    }
+
+    /// Estimate error rates from the registered reads of the given read group.
+    fn estimate_read_group(&self, read_group: &str) -> Option<ErrorRates> {
+        self.estimate_filtered(|rg| rg == Some(read_group))
+    }
+
+    fn estimate_filtered<F>(&self, is_included: F) -> Option<ErrorRates>
+    where
+        F: Fn(Option<&str>) -> bool,
+    {
+        let (n_aligned_bases, n_mismatch_considered_bases) = self
+            .n_bases
+            .iter()
+            .filter(|(rg, _)| is_included(rg.as_deref()))
+            .fold((0, 0), |(a, m), (_, (n_a, n_m))| (a + n_a, m + n_m));
+        if n_aligned_bases == 0 {
+            return None;
+        }
+        // METHOD: genuine variation (germline or somatic) is expected to occur in multiple
+        // overlapping reads, whereas sequencing errors occur at random positions.
+        // Hence, only events that are supported by a single read are considered to be
+        // sequencing errors.
+        let mut n_ins = 0;
+        let mut n_ins_bases = 0;
+        let mut n_del = 0;
+        let mut n_del_bases = 0;
+        let mut n_mismatches = 0;
+        for ((_, _, kind), (count, rg)) in &self.events {
+            // METHOD: events are counted over all read groups, such that genuine variants
+            // are recognized even if each read group has only a single supporting read.
+            if *count > 1 || !is_included(rg.as_deref()) {
+                continue;
+            }
+            match kind {
+                ErrorEventKind::Insertion(l) => {
+                    n_ins += 1;
+                    n_ins_bases += *l as u64;
+                }
+                ErrorEventKind::Deletion(l) => {
+                    n_del += 1;
+                    n_del_bases += *l as u64;
+                }
+                ErrorEventKind::Mismatch => n_mismatches += 1,
+            }
+        }
+
+        // METHOD: a pseudo count of one ensures that gaps remain possible even if none
+        // has been observed. Extension probabilities follow from the mean length of
+        // geometrically distributed gap lengths.
+        let n = n_aligned_bases as f64 + 1.0;
+        let prob_extend = |n_events: u64, n_bases: u64| {
+            if n_bases == 0 {
+                0.0
+            } else {
+                1.0 - n_events as f64 / n_bases as f64
+            }
+        };
+        Some(ErrorRates {
+            prob_insertion: (n_ins as f64 + 1.0) / n,
+            prob_deletion: (n_del as f64 + 1.0) / n,
+            prob_insertion_extend: prob_extend(n_ins, n_ins_bases),
+            prob_deletion_extend: prob_extend(n_del, n_del_bases),
+            prob_mismatch: if n_mismatch_considered_bases > 0 {
+                Some((n_mismatches as f64 + 1.0) / (n_mismatch_considered_bases as f64 + 1.0))
+            } else {
+                None
+            },
+            count: n_aligned_bases,
+        })
+    }
+}
+
+/// Alignment properties that are specific to a read group.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ReadGroupProperties {
+    pub(crate) insert_size: Option<InsertSize>,
+    pub(crate) max_read_len: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error_rates: Option<ErrorRates>,
 }
 
 impl AlignmentProperties {
+    /// Insert size distribution of the given read group. Falls back to the insert size of the
+    /// whole sample if the read group is unknown, not given, or has no insert size estimate
+    /// (e.g. because too few of its fragments were usable).
+    pub(crate) fn read_group_insert_size(&self, read_group: Option<&str>) -> Option<&InsertSize> {
+        read_group
+            .and_then(|rg| self.read_groups.get(rg))
+            .and_then(|props| props.insert_size.as_ref())
+            .or(self.insert_size.as_ref())
+    }
+
+    /// Iterate over the insert size distributions of the whole sample and of all read groups.
+    pub(crate) fn insert_sizes(&self) -> impl Iterator<Item = &InsertSize> {
+        self.insert_size.iter().chain(
+            self.read_groups
+                .values()
+                .filter_map(|props| props.insert_size.as_ref()),
+        )
+    }
+
+    /// Error rates of the read groups that have their own estimate, by read group.
+    pub(crate) fn read_group_error_rates(&self) -> impl Iterator<Item = (&str, &ErrorRates)> {
+        self.read_groups.iter().filter_map(|(rg, props)| {
+            props
+                .error_rates
+                .as_ref()
+                .map(|error_rates| (rg.as_str(), error_rates))
+        })
+    }
+
     /// Update maximum observed cigar operation lengths. Return whether any D, I, S, or H operation
     /// was found in the cigar string.
     pub(crate) fn update_max_cigar_ops_len(
@@ -61,12 +322,63 @@ impl AlignmentProperties {
         (is_regular, has_soft_clip)
     }
 
+    /// Combine with the properties of another BAM file of the same sample.
+    /// Maximum lengths are combined by taking the maximum, such that windows
+    /// derived from them cover all files.
+    pub(crate) fn merge(&mut self, other: &AlignmentProperties) {
+        self.max_del_cigar_len = cmp::max(self.max_del_cigar_len, other.max_del_cigar_len);
+        self.max_ins_cigar_len = cmp::max(self.max_ins_cigar_len, other.max_ins_cigar_len);
+        self.frac_max_softclip = self.frac_max_softclip.max(other.frac_max_softclip);
+        self.max_read_len = cmp::max(self.max_read_len, other.max_read_len);
+        self.insert_size = match (&self.insert_size, &other.insert_size) {
+            (Some(a), Some(b)) => {
+                // METHOD: pool both insert size distributions as a mixture, weighted by the
+                // number of fragments they have been estimated from. Without counts
+                // (e.g. manually specified properties), both are weighted equally.
+                let weight = if a.count + b.count > 0 {
+                    a.count as f64 / (a.count + b.count) as f64
+                } else {
+                    0.5
+                };
+                let mean = weight * a.mean + (1.0 - weight) * b.mean;
+                let second_moment = weight * (a.sd.powi(2) + a.mean.powi(2))
+                    + (1.0 - weight) * (b.sd.powi(2) + b.mean.powi(2));
+                let histogram = match (&a.histogram, &b.histogram) {
+                    (Some(a), Some(b)) => Some(a.mixture(b, weight)),
+                    _ => None,
+                };
+                Some(InsertSize {
+                    mean,
+                    sd: (second_moment - mean.powi(2)).sqrt(),
+                    histogram,
+                    count: a.count + b.count,
+                })
+            }
+            (Some(isize), None) | (None, Some(isize)) => Some(isize.clone()),
+            (None, None) => None,
+        };
+        self.read_groups.extend(
+            other
+                .read_groups
+                .iter()
+                .map(|(rg, props)| (rg.clone(), props.clone())),
+        );
+        self.error_rates = match (&self.error_rates, &other.error_rates) {
+            (Some(a), Some(b)) => Some(a.merge(b)),
+            (Some(rates), None) | (None, Some(rates)) => Some(rates.clone()),
+            (None, None) => None,
+        };
+    }
+
     /// Estimate `AlignmentProperties` from first 10000 fragments of bam file.
     /// Only reads that are mapped, not duplicates and where quality checks passed are taken.
+    /// If `empirical_insert_size` is set, a histogram of the observed insert sizes is recorded
+    /// in addition to mean and standard deviation.
     pub(crate) fn estimate<R: bam::Read>(
         bam: &mut R,
         omit_insert_size: bool,
         allow_hardclips: bool,
+        empirical_insert_size: bool,
     ) -> Result<Self> {
         let mut properties = AlignmentProperties {
             insert_size: None,
@@ -74,10 +386,16 @@ impl AlignmentProperties {
             max_ins_cigar_len: 0,
             frac_max_softclip: 0.0,
             max_read_len: 0,
+            read_groups: HashMap::new(),
+            error_rates: None,
         };
 
         let mut record = bam::Record::new();
+        let mut error_rate_estimator = ErrorRateEstimator::default();
+        let mut error_rate_candidates = Vec::new();
         let mut tlens = Vec::new();
+        let mut read_group_tlens: HashMap<String, Vec<f64>> = HashMap::new();
+        let mut read_group_max_read_len: HashMap<String, u32> = HashMap::new();
         let mut max_read_len = 0;
         let mut max_mapq = 0;
         let mut i = 0;
@@ -113,9 +431,17 @@ impl AlignmentProperties {
 
             max_mapq = cmp::max(max_mapq, record.mapq());
             max_read_len = cmp::max(max_read_len, record.seq().len() as u32);
+            let rg = read_group(&record).map(|rg| rg.to_owned());
+            if let Some(ref rg) = rg {
+                let rg_max_read_len = read_group_max_read_len.entry(rg.clone()).or_insert(0);
+                *rg_max_read_len = cmp::max(*rg_max_read_len, record.seq().len() as u32);
+            }
 
             let (is_regular, has_soft_clip) =
                 properties.update_max_cigar_ops_len(&record, allow_hardclips);
+            // Keep the record for error rate estimation. Whether it will be used depends on
+            // the maximum MAPQ, which is only known at the end.
+            error_rate_candidates.push(record.clone());
 
             // If we are not using the insert size, we do not need to estimate it
             if omit_insert_size {
@@ -140,7 +466,11 @@ impl AlignmentProperties {
                 }
             } else {
                 // record insert size
-                tlens.push(record.insert_size().abs() as f64);
+                let tlen = record.insert_size().abs() as f64;
+                tlens.push(tlen);
+                if let Some(rg) = rg {
+                    read_group_tlens.entry(rg).or_default().push(tlen);
+                }
             }
 
             i += 1;
@@ -148,6 +478,34 @@ impl AlignmentProperties {
 
         properties.max_read_len = max_read_len;
 
+        // METHOD: error rates are estimated from reads with maximum MAPQ only, such that
+        // mismapped reads do not inflate them.
+        for record in error_rate_candidates
+            .iter()
+            .filter(|record| record.mapq() == max_mapq)
+        {
+            error_rate_estimator.register(record, read_group(record));
+        }
+        properties.error_rates = error_rate_estimator.estimate();
+
+        properties.read_groups = read_group_max_read_len
+            .into_iter()
+            .map(|(rg, max_read_len)| {
+                let insert_size = read_group_tlens.remove(&rg).and_then(|tlens| {
+                    estimate_insert_size_distribution(tlens, empirical_insert_size)
+                });
+                let error_rates = error_rate_estimator.estimate_read_group(&rg);
+                (
+                    rg,
+                    ReadGroupProperties {
+                        insert_size,
+                        max_read_len,
+                        error_rates,
+                    },
+                )
+            })
+            .collect();
+
         if tlens.is_empty() {
             warn!(
                 "\nFound no records to use for estimating the insert size. Will assume\n\
@@ -178,39 +536,213 @@ impl AlignmentProperties {
             properties.insert_size = None;
             Ok(properties)
         } else {
-            let upper = tlens.percentile(95);
-            let lower = tlens.percentile(5);
-            let valid = tlens
-                .into_iter()
-                .filter(|l| *l <= upper && *l >= lower)
-                .collect_vec();
-
-            properties.insert_size = Some(InsertSize {
-                mean: valid.iter().sum::<f64>() / valid.len() as f64,
-                sd: valid.iter().std_dev(),
-            });
+            properties.insert_size =
+                estimate_insert_size_distribution(tlens, empirical_insert_size);
             Ok(properties)
         }
     }
 }
 
+/// Estimate mean and standard deviation of the given insert sizes, ignoring the
+/// lowest and highest 5% as outliers. Optionally, also record an empirical histogram.
+fn estimate_insert_size_distribution(
+    mut tlens: Vec<f64>,
+    empirical_insert_size: bool,
+) -> Option<InsertSize> {
+    if tlens.is_empty() {
+        return None;
+    }
+    let histogram = if empirical_insert_size {
+        // METHOD: only the most extreme outliers (e.g. chimeric pairs) are removed here,
+        // because the tails are exactly what the histogram shall capture.
+        let upper = tlens.percentile(99);
+        let lower = tlens.percentile(1);
+        Some(InsertSizeHistogram::new(
+            tlens
+                .iter()
+                .filter(|l| **l <= upper && **l >= lower)
+                .map(|l| *l as u64),
+        ))
+    } else {
+        None
+    };
+
+    let upper = tlens.percentile(95);
+    let lower = tlens.percentile(5);
+    let valid = tlens
+        .into_iter()
+        .filter(|l| *l <= upper && *l >= lower)
+        .collect_vec();
+
+    Some(InsertSize {
+        mean: valid.iter().sum::<f64>() / valid.len() as f64,
+        sd: valid.iter().std_dev(),
+        histogram,
+        count: valid.len() as u64,
+    })
+}
+
 /// Expected insert size in terms of mean and standard deviation.
 /// This should be estimated from unsorted(!) bam files to avoid positional biases.
-#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub(crate) struct InsertSize {
     pub(crate) mean: f64,
     pub(crate) sd: f64,
+    /// Empirical distribution, used instead of the normal distribution if present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) histogram: Option<InsertSizeHistogram>,
+    /// Number of fragments the estimate is based on (zero if unknown).
+    #[serde(default)]
+    pub(crate) count: u64,
+}
+
+impl InsertSize {
+    /// Range of insert sizes with probability above zero.
+    /// For the normal distribution, we use 6 SDs around the mean.
+    pub(crate) fn range(&self) -> Range<u64> {
+        if let Some(ref histogram) = self.histogram {
+            histogram.range()
+        } else {
+            let m = self.mean.round() as u64;
+            let s = self.sd.ceil() as u64 * 6;
+            m.saturating_sub(s)..m + s
+        }
+    }
+
+    /// Probability of the given insert size under the distribution shifted by the given value.
+    pub(crate) fn pmf(&self, insert_size: u64, shift: f64) -> LogProb {
+        if let Some(ref histogram) = self.histogram {
+            histogram.pmf(insert_size as f64 - shift)
+        } else {
+            isize_pmf(insert_size as f64, self.mean + shift, self.sd)
+        }
+    }
+}
+
+/// Empirical insert size distribution, given as the probabilities of all insert sizes
+/// between the smallest and the largest observed one, and geometric tails beyond them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct InsertSizeHistogram {
+    offset: u64,
+    probs: Vec<f64>,
+    /// Probability mass below the smallest observed insert size.
+    #[serde(default)]
+    left_tail_prob: f64,
+    /// Probability mass above the largest observed insert size.
+    #[serde(default)]
+    right_tail_prob: f64,
+    /// Factor by which the probability decreases with each base along the tails.
+    #[serde(default)]
+    tail_decay: f64,
+}
+
+impl InsertSizeHistogram {
+    /// Create histogram from the given insert sizes. Insert sizes that fall between the
+    /// observed ones get a pseudo count of one, such that bimodal distributions do not
+    /// assign a probability of zero to the valley between the modes.
+    ///
+    /// METHOD: insert sizes beyond the observed ones are not impossible, they are just rare.
+    /// Otherwise, a reference fragment in the tail would become certain evidence for any
+    /// allele that changes the expected insert size. Hence, each tail gets a pseudo count of
+    /// one, distributed geometrically with a mean distance equal to the standard deviation of
+    /// the observed insert sizes.
+    pub(crate) fn new(insert_sizes: impl Iterator<Item = u64>) -> Self {
+        let insert_sizes = insert_sizes.collect_vec();
+        let offset = insert_sizes.iter().min().cloned().unwrap_or(0);
+        let end = insert_sizes.iter().max().map_or(offset, |max| max + 1);
+
+        let mut counts = vec![1.0; (end - offset) as usize];
+        for insert_size in &insert_sizes {
+            counts[(insert_size - offset) as usize] += 1.0;
+        }
+        let tail_count = 1.0;
+        let total: f64 = counts.iter().sum::<f64>() + 2.0 * tail_count;
+        let sd = insert_sizes.iter().map(|l| *l as f64).std_dev();
+        let sd = if sd.is_nan() { 0.0 } else { sd };
+
+        InsertSizeHistogram {
+            offset,
+            probs: counts.into_iter().map(|count| count / total).collect(),
+            left_tail_prob: tail_count / total,
+            right_tail_prob: tail_count / total,
+            tail_decay: sd / (sd + 1.0),
+        }
+    }
+
+    /// Range of the observed insert sizes. Beyond, only the tails remain.
+    pub(crate) fn range(&self) -> Range<u64> {
+        self.offset..self.offset + self.probs.len() as u64
+    }
+
+    /// Tail probability mass below the given insert size (which must not exceed the offset).
+    fn left_tail_mass(&self, insert_size: u64) -> f64 {
+        self.left_tail_prob * self.tail_decay.powi((self.offset - insert_size) as i32)
+    }
+
+    /// Tail probability mass from the given insert size on (which must not be below the end
+    /// of the range).
+    fn right_tail_mass(&self, insert_size: u64) -> f64 {
+        self.right_tail_prob
+            * self
+                .tail_decay
+                .powi((insert_size - self.range().end) as i32)
+    }
+
+    fn prob(&self, insert_size: u64) -> f64 {
+        if self.range().contains(&insert_size) {
+            self.probs[(insert_size - self.offset) as usize]
+        } else if insert_size < self.offset {
+            self.left_tail_mass(insert_size + 1) * (1.0 - self.tail_decay)
+        } else {
+            self.right_tail_mass(insert_size) * (1.0 - self.tail_decay)
+        }
+    }
+
+    /// Probability of the given (real valued) insert size, rounded to the nearest integer.
+    pub(crate) fn pmf(&self, insert_size: f64) -> LogProb {
+        if insert_size < 0.0 {
+            LogProb::ln_zero()
+        } else {
+            LogProb(self.prob(insert_size.round() as u64).ln())
+        }
+    }
+
+    /// Mixture of this and the other histogram, with the given weight for this one.
+    /// The tails of the mixture carry the combined mass of both tails beyond the joint
+    /// range, decaying like the heavier of both.
+    pub(crate) fn mixture(&self, other: &InsertSizeHistogram, weight: f64) -> Self {
+        let offset = cmp::min(self.offset, other.offset);
+        let end = cmp::max(self.range().end, other.range().end);
+        InsertSizeHistogram {
+            offset,
+            probs: (offset..end)
+                .map(|insert_size| {
+                    weight * self.prob(insert_size) + (1.0 - weight) * other.prob(insert_size)
+                })
+                .collect(),
+            left_tail_prob: weight * self.left_tail_mass(offset)
+                + (1.0 - weight) * other.left_tail_mass(offset),
+            right_tail_prob: weight * self.right_tail_mass(end)
+                + (1.0 - weight) * other.right_tail_mass(end),
+            tail_decay: self.tail_decay.max(other.tail_decay),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Total probability mass of the given histogram, including its tails.
+    fn total_prob(histogram: &InsertSizeHistogram) -> f64 {
+        histogram.probs.iter().sum::<f64>() + histogram.left_tail_prob + histogram.right_tail_prob
+    }
+
     #[test]
     fn test_estimate() {
         let mut bam = bam::Reader::from_path("tests/resources/tumor-first30000.bam").unwrap();
 
-        let props = AlignmentProperties::estimate(&mut bam, false, false).unwrap();
+        let props = AlignmentProperties::estimate(&mut bam, false, false, false).unwrap();
         println!("{:?}", props);
 
         if let Some(isize) = props.insert_size {
@@ -224,13 +756,239 @@ mod tests {
         assert_eq!(props.frac_max_softclip, 0.63);
     }
 
+    #[test]
+    fn test_estimate_histogram() {
+        let mut bam = bam::Reader::from_path("tests/resources/tumor-first30000.bam").unwrap();
+
+        let props = AlignmentProperties::estimate(&mut bam, false, false, true).unwrap();
+        let isize = props.insert_size.unwrap();
+        let histogram = isize.histogram.as_ref().unwrap();
+
+        assert_relative_eq!(total_prob(histogram), 1.0, epsilon = 1e-9);
+        assert!(histogram.range().contains(&(isize.mean.round() as u64)));
+        // tails beyond the observed insert sizes are unlikely but not impossible
+        let end = histogram.range().end;
+        let start = histogram.range().start;
+        assert!(isize.pmf(end, 0.0) > LogProb::ln_zero());
+        assert!(isize.pmf(end, 0.0) < isize.pmf(isize.mean.round() as u64, 0.0));
+        assert!(isize.pmf(end + 10, 0.0) < isize.pmf(end, 0.0));
+        assert!(isize.pmf(start - 1, 0.0) > LogProb::ln_zero());
+        assert!(isize.pmf(start - 10, 0.0) < isize.pmf(start - 1, 0.0));
+    }
+
+    #[test]
+    fn test_estimate_error_rates() {
+        let mut bam = bam::Reader::from_path("tests/resources/tumor-first30000.bam").unwrap();
+
+        let props = AlignmentProperties::estimate(&mut bam, false, false, false).unwrap();
+        let error_rates = props.error_rates.unwrap();
+
+        for prob in &[error_rates.prob_insertion, error_rates.prob_deletion] {
+            assert!(*prob > 0.0 && *prob < 0.01);
+        }
+        for prob in &[
+            error_rates.prob_insertion_extend,
+            error_rates.prob_deletion_extend,
+        ] {
+            assert!(*prob >= 0.0 && *prob < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_histogram_mixture() {
+        let a = InsertSizeHistogram::new(vec![100, 100, 102].into_iter());
+        let b = InsertSizeHistogram::new(vec![200, 201].into_iter());
+        let mixture = a.mixture(&b, 0.5);
+
+        assert_eq!(mixture.range(), 100..202);
+        assert_relative_eq!(total_prob(&mixture), 1.0, epsilon = 1e-9);
+        // pseudo count between the observed insert sizes
+        assert_relative_eq!(mixture.prob(101), 1.0 / 8.0 / 2.0);
+        // tails between the modes
+        assert!(mixture.prob(150) > 0.0);
+        assert!(mixture.prob(150) < mixture.prob(103));
+        assert!(mixture.prob(99) > 0.0);
+        assert!(mixture.prob(202) > 0.0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut props = AlignmentProperties {
+            insert_size: Some(InsertSize {
+                mean: 300.0,
+                sd: 10.0,
+                histogram: None,
+                count: 0,
+            }),
+            max_del_cigar_len: 10,
+            max_ins_cigar_len: 2,
+            frac_max_softclip: 0.1,
+            max_read_len: 150,
+            read_groups: HashMap::new(),
+            error_rates: None,
+        };
+        let other = AlignmentProperties {
+            insert_size: Some(InsertSize {
+                mean: 500.0,
+                sd: 10.0,
+                histogram: None,
+                count: 0,
+            }),
+            max_del_cigar_len: 5,
+            max_ins_cigar_len: 4,
+            frac_max_softclip: 0.2,
+            max_read_len: 250,
+            read_groups: HashMap::new(),
+            error_rates: None,
+        };
+        props.merge(&other);
+
+        assert_eq!(props.max_read_len, 250);
+        assert_eq!(props.max_del_cigar_len, 10);
+        assert_eq!(props.max_ins_cigar_len, 4);
+        assert_relative_eq!(props.frac_max_softclip, 0.2);
+        let isize = props.insert_size.unwrap();
+        assert_relative_eq!(isize.mean, 400.0);
+        assert_relative_eq!(isize.sd, (100.0f64 + 10000.0).sqrt());
+    }
+
+    #[test]
+    fn test_merge_weighted() {
+        let props = |mean, count| AlignmentProperties {
+            insert_size: Some(InsertSize {
+                mean,
+                sd: 10.0,
+                histogram: Some(InsertSizeHistogram::new(vec![mean as u64].into_iter())),
+                count,
+            }),
+            max_del_cigar_len: 10,
+            max_ins_cigar_len: 2,
+            frac_max_softclip: 0.1,
+            max_read_len: 150,
+            read_groups: HashMap::new(),
+            error_rates: None,
+        };
+        let mut merged = props(300.0, 3000);
+        merged.merge(&props(500.0, 1000));
+
+        let isize = merged.insert_size.unwrap();
+        assert_eq!(isize.count, 4000);
+        assert_relative_eq!(isize.mean, 350.0);
+        assert_relative_eq!(isize.sd, (100.0f64 + 0.75 * 0.25 * 40000.0).sqrt());
+        let histogram = isize.histogram.unwrap();
+        assert_relative_eq!(histogram.prob(300), 0.75);
+        assert_relative_eq!(histogram.prob(500), 0.25);
+    }
+
+    #[test]
+    fn test_merge_error_rates() {
+        let error_rates = |prob, prob_mismatch, count| ErrorRates {
+            prob_insertion: prob,
+            prob_deletion: prob,
+            prob_insertion_extend: 0.1,
+            prob_deletion_extend: 0.1,
+            prob_mismatch,
+            count,
+        };
+        let merged = error_rates(1e-4, Some(1e-3), 3000).merge(&error_rates(5e-4, None, 1000));
+        assert_eq!(merged.count, 4000);
+        assert_relative_eq!(merged.prob_insertion, 2e-4);
+        assert_relative_eq!(merged.prob_deletion, 2e-4);
+        assert_relative_eq!(merged.prob_insertion_extend, 0.1);
+        assert_relative_eq!(merged.prob_mismatch.unwrap(), 1e-3);
+
+        // without counts, both are weighted equally
+        let merged = error_rates(1e-4, None, 0).merge(&error_rates(5e-4, None, 0));
+        assert_relative_eq!(merged.prob_insertion, 3e-4);
+    }
+
+    #[test]
+    fn test_read_group_fallback() {
+        // properties written before the introduction of read groups
+        let props: AlignmentProperties = serde_json::from_str(
+            r#"{"insert_size":{"mean":300.0,"sd":10.0},"max_del_cigar_len":10,
+            "max_ins_cigar_len":2,"frac_max_softclip":0.1,"max_read_len":150}"#,
+        )
+        .unwrap();
+        assert!(props.read_groups.is_empty());
+        assert_relative_eq!(
+            props.read_group_insert_size(Some("lib1")).unwrap().mean,
+            300.0
+        );
+
+        let mut props = props;
+        props.read_groups.insert(
+            "lib1".to_owned(),
+            ReadGroupProperties {
+                insert_size: Some(InsertSize {
+                    mean: 500.0,
+                    sd: 20.0,
+                    histogram: None,
+                    count: 0,
+                }),
+                max_read_len: 100,
+                error_rates: None,
+            },
+        );
+        // read group without usable fragments for insert size estimation
+        props.read_groups.insert(
+            "lib2".to_owned(),
+            ReadGroupProperties {
+                insert_size: None,
+                max_read_len: 100,
+                error_rates: None,
+            },
+        );
+        assert_relative_eq!(
+            props.read_group_insert_size(Some("lib1")).unwrap().mean,
+            500.0
+        );
+        assert_relative_eq!(
+            props.read_group_insert_size(Some("lib2")).unwrap().mean,
+            300.0
+        );
+        assert_relative_eq!(props.read_group_insert_size(None).unwrap().mean, 300.0);
+    }
+
+    #[test]
+    fn test_read_group_error_rates() {
+        use std::convert::TryFrom;
+
+        let mut estimator = ErrorRateEstimator::default();
+        let mut register = |cigar: &str, pos: i64, rg: &str| {
+            let mut record = bam::Record::new();
+            let cigar = bam::record::CigarString::try_from(cigar).unwrap();
+            // all CIGAR strings below span 100 read bases
+            record.set(b"read", Some(&cigar), &[b'A'; 100], &[30; 100]);
+            record.set_pos(pos);
+            estimator.register(&record, Some(rg));
+        };
+        // a deletion in a single read of lib1
+        register("50M2D50M", 0, "lib1");
+        register("100M", 0, "lib1");
+        // an insertion in reads of both read groups, i.e. a genuine variant
+        register("50M1I49M", 200, "lib1");
+        register("50M1I49M", 200, "lib2");
+        register("100M", 0, "lib2");
+
+        let lib1 = estimator.estimate_read_group("lib1").unwrap();
+        let lib2 = estimator.estimate_read_group("lib2").unwrap();
+        let all = estimator.estimate().unwrap();
+        assert_relative_eq!(lib1.prob_deletion, 2.0 / 301.0);
+        assert_relative_eq!(lib2.prob_deletion, 1.0 / 201.0);
+        assert_relative_eq!(all.prob_deletion, 2.0 / 501.0);
+        assert_relative_eq!(lib1.prob_insertion, 1.0 / 301.0);
+        assert_relative_eq!(lib2.prob_insertion, 1.0 / 201.0);
+        assert!(estimator.estimate_read_group("lib3").is_none());
+    }
+
     #[test]
     fn test_estimate_all_reads_have_short_clips() {
         let mut bam =
             bam::Reader::from_path("tests/resources/tumor-first30000.reads_with_soft_clips.bam")
                 .unwrap();
 
-        let props = AlignmentProperties::estimate(&mut bam, false, false).unwrap();
+        let props = AlignmentProperties::estimate(&mut bam, false, false, false).unwrap();
         println!("{:?}", props);
 
         assert!(props.insert_size.is_none());
@@ -247,7 +1005,7 @@ mod tests {
         )
         .unwrap();
 
-        let props = AlignmentProperties::estimate(&mut bam, false, false).unwrap();
+        let props = AlignmentProperties::estimate(&mut bam, false, false, false).unwrap();
         println!("{:?}", props);
 
         assert!(props.insert_size.is_none());
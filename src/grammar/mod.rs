@@ -343,6 +343,11 @@ pub(crate) struct Sample {
     inheritance: Option<Inheritance>,
     #[serde(default)]
     sex: Option<Sex>,
+    /// consider the length of sequenced fragments as evidence (e.g. for cell-free DNA);
+    /// the alt fragment length distribution is learned from likely somatic loci only
+    #[serde(default, rename = "fragment-length-evidence")]
+    #[get = "pub(crate)"]
+    fragment_length_evidence: bool,
 }
 
 impl Sample {
@@ -1,5 +1,5 @@
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::io::Write;
@@ -18,6 +18,7 @@ use rust_htslib::{bam, bcf, bcf::Read};
 use crate::cli;
 use crate::errors;
 use crate::utils;
+use crate::utils::alignments;
 use crate::variants::model::Variant;
 use crate::variants::sample;
 use crate::variants::types::breakends::BreakendIndex;
@@ -64,9 +65,11 @@ pub struct Testcase {
     idx: usize,
     #[builder(private)]
     reference_reader: fasta::IndexedReader<File>,
+    #[builder(private)]
+    reference_path: PathBuf,
     candidates: PathBuf,
     #[builder(private)]
-    bams: HashMap<String, PathBuf>,
+    bams: HashMap<String, Vec<PathBuf>>,
     scenario: Option<PathBuf>,
     #[builder(private)]
     options: HashMap<String, String>,
@@ -77,7 +80,9 @@ pub struct Testcase {
 
 impl TestcaseBuilder {
     pub(crate) fn reference(self, path: impl AsRef<Path> + std::fmt::Debug) -> Result<Self> {
-        Ok(self.reference_reader(fasta::IndexedReader::from_file(&path)?))
+        Ok(self
+            .reference_reader(fasta::IndexedReader::from_file(&path)?)
+            .reference_path(path.as_ref().to_owned()))
     }
 
     pub(crate) fn locus(self, locus: &str) -> Result<Self> {
@@ -108,17 +113,14 @@ impl TestcaseBuilder {
     pub(crate) fn register_sample(
         mut self,
         name: &str,
-        bam: impl AsRef<Path>,
+        bams: Vec<PathBuf>,
         options: &cli::Varlociraptor,
     ) -> Result<Self> {
         if self.bams.is_none() {
             self = self.bams(HashMap::new());
         }
 
-        self.bams
-            .as_mut()
-            .unwrap()
-            .insert(name.to_owned(), bam.as_ref().to_owned());
+        self.bams.as_mut().unwrap().insert(name.to_owned(), bams);
 
         if self.options.is_none() {
             self = self.options(HashMap::new());
@@ -173,20 +175,18 @@ impl Testcase {
 
                 for rec in &mut found {
                     if utils::is_bnd(rec)? {
-                        if let Some(event) =
-                            utils::info_tag_event(rec)?.map(|event| event.to_owned())
-                        {
-                            // METHOD: for breakend events, collect all the other breakends.
-                            if breakend_index.is_none() {
-                                breakend_index = Some(BreakendIndex::new(&self.candidates)?);
-                            }
-                            let breakend_index = breakend_index.as_ref().unwrap();
+                        // METHOD: for breakend events, collect all the other breakends.
+                        if breakend_index.is_none() {
+                            breakend_index = Some(BreakendIndex::new(&self.candidates, None)?);
+                        }
+                        let breakend_index = breakend_index.as_ref().unwrap();
+                        if let Some(event) = breakend_index.event(rec)? {
                             let last_idx = breakend_index.last_record_index(&event).unwrap();
 
                             let mut candidate_reader = self.candidate_reader()?;
                             for (i, res) in candidate_reader.records().enumerate() {
                                 let mut other_rec = res?;
-                                if let Some(other_event) = utils::info_tag_event(&mut other_rec)? {
+                                if let Some(other_event) = breakend_index.event(&mut other_rec)? {
                                     if event == other_event
                                         && (other_rec.contig() != rec.contig()
                                             || other_rec.pos() != rec.pos())
@@ -201,7 +201,7 @@ impl Testcase {
                                 }
                             }
                         } else {
-                            info!("Skipping collection of mate breakends because EVENT tag is not specified, which is unsupported at the moment.")
+                            info!("Skipping collection of mate breakends because neither EVENT nor MATEID tag is specified.")
                         }
                     }
                 }
@@ -270,14 +270,28 @@ impl Testcase {
                 pos.saturating_sub(1000),
                 pos + ref_allele.len() as u64 + 1000,
             ),
+            (Variant::MobileElementInsertion { len, .. }, _) => {
+                (pos.saturating_sub(1000), pos + len + 1000)
+            }
+            (
+                Variant::TandemRepeat {
+                    ref unit,
+                    ref_count,
+                    alt_count,
+                },
+                _,
+            ) => (
+                pos.saturating_sub(1000),
+                pos + unit.len() as u64 * cmp::max(ref_count, alt_count) + 1000,
+            ),
             (Variant::None, _) => (pos.saturating_sub(100), pos + 1 + 100),
         };
 
         let mut ref_start = start;
         let mut ref_end = end;
         // first pass, extend reference interval
-        for path in self.bams.values() {
-            let mut bam_reader = bam::IndexedReader::from_path(path)?;
+        for path in self.bams.values().flatten() {
+            let mut bam_reader = alignments::indexed_reader(path, &self.reference_path)?;
 
             let tid = bam_reader.header().tid(chrom_name).unwrap();
             bam_reader.fetch((tid, start, end))?;
@@ -291,25 +305,40 @@ impl Testcase {
 
         // second pass, write samples
         let mut samples = HashMap::new();
-        for (name, path) in &self.bams {
-            let properties = sample::estimate_alignment_properties(path, false, false)?;
-            let mut bam_reader = bam::IndexedReader::from_path(path)?;
+        for (name, paths) in &self.bams {
+            let properties = sample::estimate_alignment_properties(
+                paths,
+                &self.reference_path,
+                false,
+                false,
+                false,
+            )?;
             let filename = Path::new(name).with_extension("bam");
             let mut bam_writer = bam::Writer::from_path(
                 self.prefix.join(&filename),
-                &bam::Header::from_template(bam_reader.header()),
+                &combined_header(paths, &self.reference_path)?,
                 bam::Format::BAM,
             )?;
 
-            let tid = bam_reader.header().tid(chrom_name).unwrap();
-
-            bam_reader.fetch((tid, start, end))?;
-            for res in bam_reader.records() {
-                let mut rec = res?;
-                // update mapping position to interval
-                rec.set_pos(rec.pos() - ref_start as i64);
-                rec.set_mpos(rec.mpos() - ref_start as i64);
-                bam_writer.write(&rec)?;
+            // METHOD: records of multiple BAM files of the same sample are combined into
+            // a single, sorted testcase BAM file.
+            let mut records = Vec::new();
+            for path in paths {
+                let mut bam_reader = alignments::indexed_reader(path, &self.reference_path)?;
+                let tid = bam_reader.header().tid(chrom_name).unwrap();
+
+                bam_reader.fetch((tid, start, end))?;
+                for res in bam_reader.records() {
+                    let mut rec = res?;
+                    // update mapping position to interval
+                    rec.set_pos(rec.pos() - ref_start as i64);
+                    rec.set_mpos(rec.mpos() - ref_start as i64);
+                    records.push(rec);
+                }
+            }
+            records.sort_by_key(|rec| rec.pos());
+            for rec in &records {
+                bam_writer.write(rec)?;
             }
             samples.insert(
                 name.to_owned(),
@@ -384,3 +413,44 @@ impl Testcase {
         Ok(())
     }
 }
+
+/// Header of the first of the given BAM files, extended by the read groups (@RG lines) of all
+/// others, such that the read groups of all combined records are declared.
+fn combined_header(paths: &[PathBuf], reference: &Path) -> Result<bam::Header> {
+    let rg_id = |line: &[u8]| {
+        line.split(|c| *c == b'\t')
+            .find(|field| field.starts_with(b"ID:"))
+            .map(|field| field.to_owned())
+    };
+
+    let mut text = alignments::indexed_reader(&paths[0], reference)?
+        .header()
+        .as_bytes()
+        .to_owned();
+    let mut rg_ids: HashSet<_> = text
+        .split(|c| *c == b'\n')
+        .filter(|line| line.starts_with(b"@RG"))
+        .filter_map(rg_id)
+        .collect();
+    for path in &paths[1..] {
+        let reader = alignments::indexed_reader(path, reference)?;
+        for line in reader
+            .header()
+            .as_bytes()
+            .split(|c| *c == b'\n')
+            .filter(|line| line.starts_with(b"@RG"))
+        {
+            if rg_id(line).is_some_and(|id| rg_ids.insert(id)) {
+                if !text.ends_with(b"\n") {
+                    text.push(b'\n');
+                }
+                text.extend_from_slice(line);
+                text.push(b'\n');
+            }
+        }
+    }
+
+    Ok(bam::Header::from_template(&bam::HeaderView::from_bytes(
+        &text,
+    )))
+}
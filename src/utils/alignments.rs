@@ -0,0 +1,180 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use bio::io::fasta;
+use rust_htslib::bam::{self, Read as BamRead};
+
+use crate::errors;
+
+lazy_static! {
+    /// MD5 checksums of reference sequences that have already been computed,
+    /// by path of the FASTA file and contig name.
+    static ref REFERENCE_MD5: Mutex<HashMap<(PathBuf, String), String>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Return true if the given alignment file is a CRAM file.
+pub(crate) fn is_cram<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let mut file = fs::File::open(&path).context(format!(
+        "Unable to read BAM/CRAM file {}.",
+        path.as_ref().display()
+    ))?;
+    let mut magic = [0; 4];
+    Ok(file.read_exact(&mut magic).is_ok() && &magic == b"CRAM")
+}
+
+/// Open the given BAM/CRAM file for random access.
+/// CRAM files are decoded with the given reference genome.
+pub(crate) fn indexed_reader<P: AsRef<Path>>(
+    path: P,
+    reference: &Path,
+) -> Result<bam::IndexedReader> {
+    let path = path.as_ref();
+    let mut reader = bam::IndexedReader::from_path(path)
+        .context(format!("Unable to read BAM/CRAM file {}.", path.display()))?;
+    if is_cram(path)? {
+        check_reference(reader.header(), path, reference)?;
+        reader.set_reference(reference)?;
+    }
+    Ok(reader)
+}
+
+/// Open the given BAM/CRAM file for sequential access.
+/// CRAM files are decoded with the given reference genome.
+pub(crate) fn reader<P: AsRef<Path>>(path: P, reference: &Path) -> Result<bam::Reader> {
+    let path = path.as_ref();
+    let mut reader = bam::Reader::from_path(path)
+        .context(format!("Unable to read BAM/CRAM file {}.", path.display()))?;
+    if is_cram(path)? {
+        check_reference(reader.header(), path, reference)?;
+        reader.set_reference(reference)?;
+    }
+    Ok(reader)
+}
+
+/// Ensure that the given reference genome is the one the CRAM file has been created with,
+/// by comparing the MD5 checksums given in the @SQ header records (M5 tag).
+/// Otherwise, htslib would fail with a cryptic error message when decoding the first
+/// record, or, worse, silently decode wrong sequences.
+fn check_reference(header: &bam::HeaderView, path: &Path, reference: &Path) -> Result<()> {
+    let header = bam::Header::from_template(header).to_hashmap();
+    let mut fasta_reader = None;
+    for sq in header.get("SQ").into_iter().flatten() {
+        let (contig, expected_md5) = match (sq.get("SN"), sq.get("M5")) {
+            (Some(contig), Some(md5)) => (contig, md5),
+            // Without a checksum, there is nothing to check.
+            _ => continue,
+        };
+
+        let key = (reference.to_owned(), contig.to_owned());
+        let cached = REFERENCE_MD5.lock().unwrap().get(&key).cloned();
+        let md5 = if let Some(md5) = cached {
+            md5
+        } else {
+            if fasta_reader.is_none() {
+                fasta_reader = Some(
+                    fasta::IndexedReader::from_file(&reference)
+                        .context("Unable to read genome reference.")?,
+                );
+            }
+            let fasta_reader = fasta_reader.as_mut().unwrap();
+            if fasta_reader.fetch_all(contig).is_err() {
+                return Err(errors::Error::CramReferenceMissingContig {
+                    path: path.display().to_string(),
+                    contig: contig.to_owned(),
+                }
+                .into());
+            }
+            let mut seq = Vec::new();
+            fasta_reader.read(&mut seq)?;
+            let md5 = sequence_md5(&seq);
+            REFERENCE_MD5.lock().unwrap().insert(key, md5.clone());
+            md5
+        };
+
+        if !md5.eq_ignore_ascii_case(expected_md5) {
+            return Err(errors::Error::CramReferenceMismatch {
+                path: path.display().to_string(),
+                contig: contig.to_owned(),
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// MD5 checksum of a reference sequence as defined by the SAM specification, i.e.,
+/// computed over the uppercase sequence without any characters outside of the
+/// printable ASCII range.
+fn sequence_md5(seq: &[u8]) -> String {
+    let normalized: Vec<u8> = seq
+        .iter()
+        .filter(|c| (33..=126).contains(*c))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    format!("{:x}", md5::compute(normalized))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_md5() {
+        assert_eq!(
+            sequence_md5(b"ACGTNACGT"),
+            "1614297c8c8fab5c60fa10e5f27127a5"
+        );
+        assert_eq!(
+            sequence_md5(b"acgtn\nACGT"),
+            "1614297c8c8fab5c60fa10e5f27127a5"
+        );
+    }
+
+    #[test]
+    fn test_is_cram() {
+        assert!(!is_cram("tests/resources/tumor-first30000.bam").unwrap());
+    }
+
+    #[test]
+    fn test_cram_decoding() {
+        let path = "tests/resources/cram/reads.cram";
+        assert!(is_cram(path).unwrap());
+
+        let mut reader =
+            indexed_reader(path, Path::new("tests/resources/cram/reference.fa")).unwrap();
+        reader.fetch((0, 0, 1000)).unwrap();
+        let records: Vec<_> = reader.records().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 10);
+
+        let mut reference =
+            fasta::IndexedReader::from_file(&"tests/resources/cram/reference.fa").unwrap();
+        let mut seq = Vec::new();
+        reference.fetch("chr1", 0, 50).unwrap();
+        reference.read(&mut seq).unwrap();
+        assert_eq!(records[0].seq().as_bytes(), seq);
+    }
+
+    #[test]
+    fn test_cram_reference_mismatch() {
+        let err = indexed_reader(
+            "tests/resources/cram/reads.cram",
+            Path::new("tests/resources/cram/wrong-reference.fa"),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::CramReferenceMismatch { contig, .. }) if contig == "chr1"
+        ));
+    }
+}
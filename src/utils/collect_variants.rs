@@ -1,16 +1,24 @@
+use std::collections::HashMap;
+use std::path::Path;
+
 use anyhow::Result;
 use itertools::Itertools;
 use rust_htslib::{bcf, bcf::record::Numeric};
 
 use crate::errors;
+use crate::utils::regions::{RegionReader, Regions};
 use crate::utils::SimpleCounter;
 use crate::variants::model;
+use crate::variants::types::breakends;
+use crate::variants::types::insertion::partially_known_seq;
+use crate::variants::types::mobile_element_insertion;
+use crate::variants::types::tandem_repeat::{repeat_count, symbolic_repeat_count};
 
 #[derive(
     Hash, PartialEq, Eq, EnumString, EnumIter, IntoStaticStr, EnumVariantNames, Display, Debug,
 )]
 pub(crate) enum SkipReason {
-    #[strum(serialize = "imprecise variants (will be supported in a future release)")]
+    #[strum(serialize = "imprecise variants")]
     Imprecise,
     #[strum(serialize = "inversions with missing END tag")]
     InversionMissingEndTag,
@@ -20,8 +28,18 @@ pub(crate) enum SkipReason {
     InversionInvalidAlt,
     #[strum(serialize = "duplication with more than a single <DUP> allele")]
     DuplicationInvalidAlt,
-    #[strum(serialize = "breakend without EVENT tag (will be supported in a future release)")]
+    #[strum(serialize = "breakend without EVENT and MATEID tag")]
     BreakendNoEvent,
+    #[strum(serialize = "breakends of events that are only partially contained in the regions")]
+    BreakendIncompleteEvent,
+    #[strum(serialize = "symbolic insertions with missing or inconsistent SVLEN")]
+    InsertionInvalidLength,
+    #[strum(serialize = "mobile element insertions of a family without consensus sequence")]
+    MobileElementUnknownFamily,
+    #[strum(
+        serialize = "tandem repeat alleles with the same number of repeat units as the reference"
+    )]
+    TandemRepeatUnchangedCount,
 }
 
 /// Collect variants from a given ´bcf::Record`.
@@ -71,6 +89,38 @@ pub(crate) fn collect_variants(
         _ => None,
     };
 
+    let info_seq = |tag: &[u8]| match record.info(tag).string() {
+        Ok(Some(seq)) => Some(seq[0].to_ascii_uppercase()),
+        _ => None,
+    };
+    let ins_seq = info_seq(b"SVINSSEQ");
+    let left_ins_seq = info_seq(b"LEFT_SVINSSEQ");
+    let right_ins_seq = info_seq(b"RIGHT_SVINSSEQ");
+    let repeat_unit = info_seq(b"RU");
+    // METHOD: the number of repeat units in the reference is given by RN, or by REF as
+    // written by ExpansionHunter. Otherwise, it is inferred from the REF allele below.
+    let info_count = |tag: &[u8]| match record.info(tag).integer() {
+        Ok(Some(values)) if !values[0].is_missing() => Some(values[0] as u64),
+        _ => None,
+    };
+    let ref_repeat_count = info_count(b"RN").or_else(|| info_count(b"REF"));
+    // METHOD: symbolic insertions are represented by their assembled sequence if available.
+    // Otherwise, only the flanks of the inserted sequence are known, and the bases in between
+    // are unknown. Without flanks, the entire inserted sequence is unknown.
+    let symbolic_insertion_seq = |svlen: Option<u64>| {
+        if ins_seq.is_some() {
+            ins_seq.clone()
+        } else {
+            svlen.and_then(|svlen| {
+                partially_known_seq(
+                    svlen as usize,
+                    left_ins_seq.as_deref().unwrap_or(b""),
+                    right_ins_seq.as_deref().unwrap_or(b""),
+                )
+            })
+        }
+    };
+
     let is_valid_insertion_alleles = |ref_allele: &[u8], alt_allele: &[u8]| {
         alt_allele == b"<INS>"
             || (ref_allele.len() < alt_allele.len()
@@ -83,9 +133,43 @@ pub(crate) fn collect_variants(
                 && &ref_allele[..alt_allele.len()] == alt_allele)
     };
 
+    // Tandem repeat: explicit alleles consist of the leading base and the repeat tract,
+    // symbolic alleles (<STRn>) give the number of repeat units.
+    // METHOD: records are only treated as tandem repeats if the number of repeat units is known
+    // for the reference and all alt alleles. Otherwise, they are handled like any other record.
+    // A reference count inferred from the REF allele is only valid if the REF allele spans the
+    // full repeat tract. This is checked against the reference sequence during preprocessing.
+    let tandem_repeat_counts = repeat_unit.and_then(|unit| {
+        let alleles = record.alleles();
+        let ref_count = ref_repeat_count.or_else(|| repeat_count(&alleles[0][1..], &unit))?;
+        let alt_counts = alleles[1..]
+            .iter()
+            .map(|alt_allele| {
+                if alt_allele[0] == b'<' {
+                    symbolic_repeat_count(alt_allele)
+                } else {
+                    repeat_count(&alt_allele[1..], &unit)
+                }
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some((unit, ref_count, alt_counts))
+    });
+
     let mut variants = Vec::new();
 
-    if let Some(svtype) = svtype {
+    if let Some((unit, ref_count, alt_counts)) = tandem_repeat_counts {
+        for alt_count in alt_counts {
+            if alt_count != ref_count {
+                variants.push(model::Variant::TandemRepeat {
+                    unit: unit.clone(),
+                    ref_count,
+                    alt_count,
+                })
+            } else {
+                skips.incr(SkipReason::TandemRepeatUnchangedCount);
+            }
+        }
+    } else if let Some(svtype) = svtype {
         if svtype == b"INV" {
             let alleles = record.alleles();
             if alleles.len() != 2 {
@@ -107,6 +191,17 @@ pub(crate) fn collect_variants(
                 skips.incr(SkipReason::DuplicationMissingEndTag)
             }
         } else if svtype == b"BND" {
+            // METHOD: breakends without EVENT tag are grouped via chains of MATEID references.
+            // Then, the record ID (as an inferred event name) serves as a placeholder until the
+            // event is resolved by the BreakendIndex.
+            let has_mateid = matches!(record.info(b"MATEID").string(), Ok(Some(_)));
+            let event = event.or_else(|| {
+                if has_mateid && record.id() != b"." {
+                    Some(breakends::mate_event_name(&record.id()))
+                } else {
+                    None
+                }
+            });
             let alleles = record.alleles();
             if let Some(ref event) = event {
                 for spec in &alleles[1..] {
@@ -131,13 +226,27 @@ pub(crate) fn collect_variants(
             let ref_allele = alleles[0];
             let alt_allele = alleles[1];
 
-            if alt_allele != b"<INS>" {
-                // don't support insertions without exact sequence
-                if is_valid_insertion_alleles(ref_allele, alt_allele) {
-                    variants.push(model::Variant::Insertion(
-                        alt_allele[ref_allele.len()..].to_owned(),
-                    ));
+            if alt_allele == b"<INS>" {
+                let svlen = svlens.as_ref().and_then(|svlens| svlens[0]);
+                if let Some(seq) = symbolic_insertion_seq(svlen) {
+                    variants.push(model::Variant::Insertion(seq));
+                } else {
+                    skips.incr(SkipReason::InsertionInvalidLength);
                 }
+            } else if let Some(family) = mobile_element_insertion::family(alt_allele) {
+                let svlen = svlens.as_ref().and_then(|svlens| svlens[0]);
+                if let Some(len) = svlen {
+                    variants.push(model::Variant::MobileElementInsertion {
+                        family: family.to_owned(),
+                        len,
+                    });
+                } else {
+                    skips.incr(SkipReason::InsertionInvalidLength);
+                }
+            } else if is_valid_insertion_alleles(ref_allele, alt_allele) {
+                variants.push(model::Variant::Insertion(
+                    alt_allele[ref_allele.len()..].to_owned(),
+                ));
             }
         } else if svtype == b"DEL" {
             let svlen = match (svlens, end) {
@@ -186,6 +295,23 @@ pub(crate) fn collect_variants(
                     }
                     // TODO fail with an error in else case
                 }
+            } else if alt_allele == b"<INS>" {
+                let svlen = svlens.as_ref().and_then(|svlens| svlens[i]);
+                if let Some(seq) = symbolic_insertion_seq(svlen) {
+                    variants.push(model::Variant::Insertion(seq));
+                } else {
+                    skips.incr(SkipReason::InsertionInvalidLength);
+                }
+            } else if let Some(family) = mobile_element_insertion::family(alt_allele) {
+                let svlen = svlens.as_ref().and_then(|svlens| svlens[i]);
+                if let Some(len) = svlen {
+                    variants.push(model::Variant::MobileElementInsertion {
+                        family: family.to_owned(),
+                        len,
+                    });
+                } else {
+                    skips.incr(SkipReason::InsertionInvalidLength);
+                }
             } else if alt_allele[0] == b'<' {
                 // skip any other special alleles
             } else if alt_allele.len() == 1 && ref_allele.len() == 1 {
@@ -217,3 +343,120 @@ pub(crate) fn collect_variants(
 
     Ok(variants)
 }
+
+/// Index of SNV candidates that are close to each other. Each cluster of such SNVs is
+/// additionally considered as a combined MNV candidate.
+#[derive(Default, Debug)]
+pub(crate) struct AdjacentSnvIndex {
+    clusters: HashMap<String, HashMap<u64, Vec<(u64, u8)>>>,
+}
+
+impl AdjacentSnvIndex {
+    /// Index SNVs of the given BCF that are at most `max_distance` bases apart.
+    /// If regions are given, only records within these are considered.
+    pub(crate) fn new<P: AsRef<Path>>(
+        inbcf: P,
+        regions: Option<&Regions>,
+        max_distance: u64,
+    ) -> Result<Self> {
+        let mut bcf_reader = RegionReader::new(inbcf, regions)?;
+        let mut skips = SimpleCounter::default();
+
+        let mut snvs: HashMap<String, Vec<(u64, u8)>> = HashMap::new();
+        loop {
+            let mut record = bcf_reader.empty_record();
+            match bcf_reader.read(&mut record) {
+                None => break,
+                Some(res) => res?,
+            }
+
+            let pos = record.pos() as u64;
+            let chrom = String::from_utf8(
+                bcf_reader
+                    .header()
+                    .rid2name(record.rid().unwrap())?
+                    .to_owned(),
+            )?;
+            for variant in collect_variants(&mut record, true, &mut skips)? {
+                if let model::Variant::SNV(alt) = variant {
+                    snvs.entry(chrom.clone()).or_default().push((pos, alt));
+                }
+            }
+        }
+
+        let clusters = snvs
+            .into_iter()
+            .map(|(chrom, mut snvs)| {
+                snvs.sort();
+                let clusters = cluster_snvs(&snvs, max_distance)
+                    .into_iter()
+                    .map(|cluster| (cluster[0].0, cluster))
+                    .collect();
+                (chrom, clusters)
+            })
+            .collect();
+
+        Ok(AdjacentSnvIndex { clusters })
+    }
+
+    /// Combined MNV of the SNV cluster starting at the given position (if any).
+    /// Bases between the SNVs are taken from the given reference sequence.
+    pub(crate) fn mnv(&self, chrom: &str, pos: u64, ref_seq: &[u8]) -> Option<model::Variant> {
+        let cluster = self.clusters.get(chrom)?.get(&pos)?;
+        let end = cluster.last().unwrap().0 + 1;
+        let mut alt = ref_seq[pos as usize..end as usize].to_ascii_uppercase();
+        for (snv_pos, snv_alt) in cluster {
+            alt[(snv_pos - pos) as usize] = *snv_alt;
+        }
+        Some(model::Variant::MNV(alt))
+    }
+}
+
+/// Group the given sorted SNVs into clusters of at least two SNVs, where consecutive SNVs
+/// are at most `max_distance` bases apart. Positions with multiple alternative alleles
+/// are ambiguous and therefore not merged.
+fn cluster_snvs(snvs: &[(u64, u8)], max_distance: u64) -> Vec<Vec<(u64, u8)>> {
+    let mut clusters = Vec::new();
+    let mut cluster: Vec<(u64, u8)> = Vec::new();
+    for (pos, group) in &snvs.iter().group_by(|(pos, _)| *pos) {
+        let group = group.collect_vec();
+        let extends = cluster
+            .last()
+            .is_some_and(|(last_pos, _)| pos - last_pos <= max_distance);
+        if !extends || group.len() > 1 {
+            if cluster.len() > 1 {
+                clusters.push(cluster);
+            }
+            cluster = Vec::new();
+        }
+        if group.len() == 1 {
+            cluster.push(*group[0]);
+        }
+    }
+    if cluster.len() > 1 {
+        clusters.push(cluster);
+    }
+
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster_snvs() {
+        let snvs = vec![
+            (10, b'A'),
+            (11, b'C'),
+            (13, b'G'),
+            (20, b'T'),
+            (30, b'A'),
+            (31, b'C'),
+            (31, b'G'),
+            (32, b'T'),
+        ];
+        let clusters = cluster_snvs(&snvs, 2);
+        assert_eq!(clusters, vec![vec![(10, b'A'), (11, b'C'), (13, b'G')]]);
+    }
+}
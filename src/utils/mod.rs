@@ -25,7 +25,9 @@ use rust_htslib::{bam, bcf};
 use crate::variants::model;
 use crate::Event;
 
+pub(crate) mod alignments;
 pub(crate) mod collect_variants;
+pub(crate) mod regions;
 
 pub(crate) use collect_variants::collect_variants;
 
@@ -45,8 +47,8 @@ pub(crate) fn aux_tag_strand_info(record: &bam::Record) -> Option<&[u8]> {
     }
 }
 
-pub(crate) fn is_sv_bcf(reader: &bcf::Reader) -> bool {
-    for rec in reader.header().header_records() {
+pub(crate) fn is_sv_bcf(header: &bcf::header::HeaderView) -> bool {
+    for rec in header.header_records() {
         if let bcf::header::HeaderRecord::Info { values, .. } = rec {
             if values.get("ID").map_or(false, |id| id == "SVTYPE") {
                 return true;
@@ -0,0 +1,235 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bio::io::bed;
+use bio_types::genome::{self, AbstractInterval};
+use itertools::Itertools;
+use rust_htslib::bcf::{self, Read};
+
+use crate::errors;
+
+/// Maximum position in a BCF file (positions are stored as 32-bit integers).
+const MAX_POS: u64 = i32::MAX as u64;
+
+/// Genomic regions to restrict processing to.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Regions {
+    intervals: Vec<genome::Interval>,
+}
+
+impl Regions {
+    /// Parse regions from the given specifications. Each specification is either
+    /// a path to a BED file or a region string of the form CHROM, CHROM:START-END
+    /// (1-based, inclusive, as used by samtools and bcftools).
+    pub(crate) fn new(specs: &[String]) -> Result<Self> {
+        let mut intervals = Vec::new();
+        for spec in specs {
+            if Path::new(spec).exists() {
+                let mut reader = bed::Reader::from_file(spec)
+                    .context(format!("Unable to read regions from BED file {}.", spec))?;
+                for record in reader.records() {
+                    let record = record?;
+                    intervals.push(genome::Interval::new(
+                        record.chrom().to_owned(),
+                        record.start()..record.end(),
+                    ));
+                }
+            } else {
+                intervals.push(parse_region(spec)?);
+            }
+        }
+
+        Ok(Regions { intervals })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &genome::Interval> {
+        self.intervals.iter()
+    }
+}
+
+fn parse_region(spec: &str) -> Result<genome::Interval> {
+    let invalid = || errors::Error::InvalidRegion {
+        spec: spec.to_owned(),
+    };
+
+    if let Some((contig, range)) = spec.rsplitn(2, ':').collect_tuple::<(&str, &str)>() {
+        // rsplitn yields the parts in reverse order
+        let (contig, range) = (range, contig);
+        let (start, end) = range
+            .replace(",", "")
+            .split('-')
+            .map(|pos| pos.parse::<u64>())
+            .collect_tuple()
+            .ok_or_else(invalid)?;
+        let (start, end) = (start.map_err(|_| invalid())?, end.map_err(|_| invalid())?);
+        if start == 0 || start > end {
+            return Err(invalid().into());
+        }
+        Ok(genome::Interval::new(contig.to_owned(), start - 1..end))
+    } else if !spec.is_empty() {
+        Ok(genome::Interval::new(spec.to_owned(), 0..MAX_POS))
+    } else {
+        Err(invalid().into())
+    }
+}
+
+/// A BCF reader that optionally restricts the returned records to a set of regions.
+/// Restriction uses the BCF index, such that only the relevant parts of the file are read.
+/// Records are reported once, for the region they start in.
+pub(crate) enum RegionReader {
+    Full(bcf::Reader),
+    Restricted {
+        reader: bcf::IndexedReader,
+        regions: Vec<(u32, genome::Interval)>,
+        current: Option<usize>,
+    },
+}
+
+impl RegionReader {
+    pub(crate) fn new<P: AsRef<Path>>(path: P, regions: Option<&Regions>) -> Result<Self> {
+        match regions {
+            Some(regions) if !regions.is_empty() => {
+                let reader = bcf::IndexedReader::from_path(&path).context(format!(
+                    "Unable to read indexed BCF/VCF {}. Restricting to regions requires an index \
+                     (create it with bcftools index).",
+                    path.as_ref().display()
+                ))?;
+
+                let mut restricted = Vec::new();
+                for interval in regions.iter() {
+                    match reader.header().name2rid(interval.contig().as_bytes()) {
+                        Ok(rid) => restricted.push((rid, interval.to_owned())),
+                        Err(_) => warn!(
+                            "Contig {} of given region not found in {}, skipping.",
+                            interval.contig(),
+                            path.as_ref().display()
+                        ),
+                    }
+                }
+                // METHOD: sort by contig order in the header and merge overlapping regions,
+                // such that each record is reported exactly once and in the order of the file.
+                restricted.sort_by_key(|(rid, interval)| (*rid, interval.range().start));
+                let mut merged: Vec<(u32, genome::Interval)> = Vec::new();
+                for (rid, interval) in restricted {
+                    if let Some((last_rid, last)) = merged.last_mut() {
+                        if *last_rid == rid && interval.range().start <= last.range().end {
+                            if interval.range().end > last.range().end {
+                                last.range_mut().end = interval.range().end;
+                            }
+                            continue;
+                        }
+                    }
+                    merged.push((rid, interval));
+                }
+
+                Ok(RegionReader::Restricted {
+                    reader,
+                    regions: merged,
+                    current: None,
+                })
+            }
+            _ => Ok(RegionReader::Full(bcf::Reader::from_path(&path)?)),
+        }
+    }
+
+    pub(crate) fn header(&self) -> &bcf::header::HeaderView {
+        match self {
+            RegionReader::Full(reader) => reader.header(),
+            RegionReader::Restricted { reader, .. } => reader.header(),
+        }
+    }
+
+    pub(crate) fn empty_record(&self) -> bcf::Record {
+        match self {
+            RegionReader::Full(reader) => reader.empty_record(),
+            RegionReader::Restricted { reader, .. } => reader.empty_record(),
+        }
+    }
+
+    pub(crate) fn set_threads(&mut self, n_threads: usize) -> Result<()> {
+        match self {
+            RegionReader::Full(reader) => reader.set_threads(n_threads)?,
+            RegionReader::Restricted { reader, .. } => reader.set_threads(n_threads)?,
+        }
+        Ok(())
+    }
+
+    /// Read next record. Returns `None` if no record is left.
+    pub(crate) fn read(&mut self, record: &mut bcf::Record) -> Option<Result<()>> {
+        match self {
+            RegionReader::Full(reader) => reader.read(record).map(|res| res.map_err(|e| e.into())),
+            RegionReader::Restricted {
+                reader,
+                regions,
+                current,
+            } => loop {
+                if let Some(i) = current {
+                    match reader.read(record) {
+                        Some(Ok(())) => {
+                            // Skip records that start before the region (they belong to the previous one).
+                            if (record.pos() as u64) < regions[*i].1.range().start {
+                                continue;
+                            }
+                            return Some(Ok(()));
+                        }
+                        Some(Err(e)) => return Some(Err(e.into())),
+                        None => (),
+                    }
+                }
+
+                // Current region is exhausted, move to the next one.
+                let next = current.map_or(0, |i| i + 1);
+                if next >= regions.len() {
+                    return None;
+                }
+                *current = Some(next);
+                let (rid, interval) = &regions[next];
+                if let Err(e) = reader.fetch(
+                    *rid,
+                    interval.range().start,
+                    interval.range().end.saturating_sub(1),
+                ) {
+                    return Some(Err(e.into()));
+                }
+            },
+        }
+    }
+}
+
+/// Return regions if any are given, `None` otherwise.
+pub(crate) fn regions(specs: &[String]) -> Result<Option<Regions>> {
+    if specs.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(Regions::new(specs)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_region() {
+        let interval = parse_region("chr1:1,001-2000").unwrap();
+        assert_eq!(interval.contig(), "chr1");
+        assert_eq!(interval.range(), 1000..2000);
+
+        let interval = parse_region("chrX").unwrap();
+        assert_eq!(interval.contig(), "chrX");
+        assert_eq!(interval.range().start, 0);
+
+        assert!(parse_region("chr1:0-10").is_err());
+        assert!(parse_region("chr1:20-10").is_err());
+        assert!(parse_region("chr1:a-b").is_err());
+    }
+}
@@ -0,0 +1,333 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Refinement of imprecise breakpoints (IMPRECISE records with CIPOS/CIEND) by searching
+//! over their confidence intervals.
+
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use bio::stats::{LogProb, Prob};
+use itertools::Itertools;
+use rust_htslib::bcf;
+
+use crate::variants::evidence::observation::{Observation, ReadPosition};
+
+/// Maximum number of breakpoint combinations that are evaluated for an imprecise variant
+/// when increasing the resolution beyond the minimal one (see `MIN_RESOLUTION`).
+pub(crate) const MAX_CANDIDATES: usize = 49;
+
+/// Minimal number of candidate offsets per confidence interval: its boundaries and the
+/// given breakpoint.
+const MIN_RESOLUTION: usize = 3;
+
+/// Confidence interval around a breakpoint, given as offsets relative to it (both inclusive).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, new)]
+pub(crate) struct ConfidenceInterval {
+    start: i64,
+    end: i64,
+}
+
+impl Default for ConfidenceInterval {
+    fn default() -> Self {
+        ConfidenceInterval::new(0, 0)
+    }
+}
+
+impl ConfidenceInterval {
+    /// Read the confidence interval from the given INFO tag (CIPOS or CIEND).
+    /// Returns `None` if the tag is not present.
+    pub(crate) fn from_record(record: &mut bcf::Record, tag: &[u8]) -> Option<Self> {
+        match record.info(tag).integer() {
+            Ok(Some(values)) if values.len() == 2 => Some(ConfidenceInterval::new(
+                values[0].min(0) as i64,
+                values[1].max(0) as i64,
+            )),
+            _ => None,
+        }
+    }
+
+    /// At most `n` evenly spaced candidate offsets, always containing the original
+    /// breakpoint (offset zero).
+    pub(crate) fn offsets(&self, n: usize) -> Vec<i64> {
+        let width = self.end - self.start;
+        if n <= 1 || width == 0 {
+            return vec![0];
+        }
+        let step = ((width as f64 / (n - 1) as f64).ceil() as i64).max(1);
+        // METHOD: use multiples of the step size, such that the original breakpoint is
+        // always contained and at most n offsets fit into the interval.
+        (-(-self.start / step)..=self.end / step)
+            .map(|i| i * step)
+            .collect()
+    }
+}
+
+/// Breakpoints of an imprecise variant, refined within their confidence intervals.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RefinedBreakpoints {
+    /// Offsets of the refined breakpoints relative to the given ones.
+    pub(crate) offsets: Vec<i64>,
+    /// Posterior probability of the refined breakpoints, given that the variant exists.
+    pub(crate) posterior: LogProb,
+}
+
+/// Combinations of candidate offsets for the given confidence intervals.
+///
+/// METHOD: each interval is searched at least at its boundaries and the given breakpoint.
+/// Then, the resolution is increased for one interval at a time, as long as the number of
+/// combinations does not exceed `MAX_CANDIDATES`. If already the minimal resolution exceeds
+/// `MAX_CANDIDATES` (i.e., for many imprecise breakpoints), each interval is searched
+/// separately instead (see `separate_candidate_offsets`).
+pub(crate) fn candidate_offsets(intervals: &[ConfidenceInterval]) -> Vec<Vec<i64>> {
+    let mut resolutions = vec![MIN_RESOLUTION; intervals.len()];
+    let mut offsets = intervals
+        .iter()
+        .map(|interval| interval.offsets(MIN_RESOLUTION))
+        .collect_vec();
+    let n_combinations = offsets
+        .iter()
+        .try_fold(1usize, |n, offsets| n.checked_mul(offsets.len()));
+    if n_combinations.is_none_or(|n| n > MAX_CANDIDATES) {
+        return separate_candidate_offsets(intervals);
+    }
+    let mut refined = true;
+    while refined {
+        refined = false;
+        for (i, interval) in intervals.iter().enumerate() {
+            let n_others: usize = offsets
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, offsets)| offsets.len())
+                .product();
+            // find the next resolution that actually yields additional offsets
+            for n in resolutions[i] + 1..=MAX_CANDIDATES {
+                let candidates = interval.offsets(n);
+                if candidates.len() > offsets[i].len() {
+                    if candidates.len() * n_others <= MAX_CANDIDATES {
+                        offsets[i] = candidates;
+                        resolutions[i] = n;
+                        refined = true;
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    offsets.into_iter().multi_cartesian_product().collect()
+}
+
+/// Candidate offsets that move one breakpoint at a time, while keeping all others at their
+/// given positions. Together with the given breakpoints, these are at most `MAX_CANDIDATES`.
+///
+/// METHOD: the number of combinations grows exponentially with the number of intervals.
+/// Searching each interval separately keeps the number of candidates linear, at the expense
+/// of not capturing dependencies between the breakpoints.
+fn separate_candidate_offsets(intervals: &[ConfidenceInterval]) -> Vec<Vec<i64>> {
+    // each interval contributes its offsets except zero, which is shared by all
+    let resolution = (MAX_CANDIDATES - 1) / intervals.len() + 1;
+    let mut candidates = vec![vec![0; intervals.len()]];
+    for (i, interval) in intervals.iter().enumerate() {
+        for offset in interval.offsets(resolution) {
+            if offset != 0 {
+                let mut offsets = vec![0; intervals.len()];
+                offsets[i] = offset;
+                candidates.push(offsets);
+            }
+        }
+    }
+    candidates
+}
+
+/// Log-likelihood ratio of the given observations between a heterozygous alt allele and
+/// the reference allele.
+fn support(observations: &[Observation<ReadPosition>]) -> LogProb {
+    let half = LogProb::from(Prob(0.5));
+    LogProb(
+        observations
+            .iter()
+            .map(|obs| {
+                // METHOD: a mismapped read does not tell anything about the breakpoints, hence it
+                // is explained equally well by both hypotheses.
+                let prob_het = obs.prob_alt.ln_add_exp(obs.prob_ref) + half;
+                let prob_ref = (obs.prob_mapping() + obs.prob_ref)
+                    .ln_add_exp(obs.prob_mismapping() + prob_het);
+                *prob_het - *prob_ref
+            })
+            .sum(),
+    )
+}
+
+/// Refine breakpoints with the given confidence intervals. The given closure extracts
+/// observations for the variant with breakpoints moved by the given offsets, or returns `None`
+/// if such a variant is not valid (e.g. a deletion of negative length).
+/// Returns the refined breakpoints together with their observations.
+///
+/// METHOD: each combination of candidate breakpoints is scored by the likelihood ratio of all
+/// observations between a heterozygous alt allele and the reference allele. With a uniform
+/// prior over all combinations, this yields a posterior, and the maximum a posteriori
+/// combination is reported. Ties are resolved in favor of the given breakpoints.
+/// The observations of the reported combination are subsequently used for calling the variant.
+/// Since they were selected as the best supported among all candidates, they overstate the
+/// evidence for the variant. With a uniform prior over the candidates, the evidence is given
+/// by the mean likelihood ratio over all candidates, which is lower than the one of the best
+/// candidate by the factor n * posterior (at most n, the number of candidates). Hence, the
+/// alt likelihoods of the returned observations are reduced by that factor, spread evenly
+/// over all observations.
+pub(crate) fn refine<F>(
+    intervals: &[ConfidenceInterval],
+    mut extract_observations: F,
+) -> Result<Option<(RefinedBreakpoints, Vec<Observation<ReadPosition>>)>>
+where
+    F: FnMut(&[i64]) -> Result<Option<Vec<Observation<ReadPosition>>>>,
+{
+    let mut candidates = Vec::new();
+    for offsets in candidate_offsets(intervals) {
+        if let Some(observations) = extract_observations(&offsets)? {
+            candidates.push((support(&observations), offsets, observations));
+        }
+    }
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+
+    let n_candidates = candidates.len();
+    let marginal =
+        LogProb::ln_sum_exp(&candidates.iter().map(|(score, _, _)| *score).collect_vec());
+    let distance = |offsets: &[i64]| offsets.iter().map(|offset| offset.abs()).sum::<i64>();
+    let (score, offsets, mut observations) = candidates
+        .into_iter()
+        .max_by(|(a, a_offsets, _), (b, b_offsets, _)| {
+            a.partial_cmp(b)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| distance(b_offsets).cmp(&distance(a_offsets)))
+        })
+        .unwrap();
+
+    let posterior = score - marginal;
+    let penalty = *posterior + (n_candidates as f64).ln();
+    if penalty > 0.0 && !observations.is_empty() {
+        let penalty = LogProb(-penalty / observations.len() as f64);
+        for obs in &mut observations {
+            obs.prob_alt += penalty;
+        }
+    }
+
+    Ok(Some((
+        RefinedBreakpoints { offsets, posterior },
+        observations,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    use crate::variants::model;
+
+    #[test]
+    fn test_offsets() {
+        assert_eq!(
+            ConfidenceInterval::new(-10, 10).offsets(5),
+            vec![-10, -5, 0, 5, 10]
+        );
+        assert_eq!(ConfidenceInterval::new(-3, 4).offsets(3), vec![0, 4]);
+        assert_eq!(
+            ConfidenceInterval::new(-2, 1).offsets(10),
+            vec![-2, -1, 0, 1]
+        );
+        assert_eq!(ConfidenceInterval::default().offsets(7), vec![0]);
+    }
+
+    fn observation(prob_alt: f64) -> Observation<ReadPosition> {
+        model::tests::observation(
+            LogProb(0.999f64.ln()),
+            LogProb(prob_alt.ln()),
+            LogProb((1.0 - prob_alt).ln()),
+        )
+    }
+
+    #[test]
+    fn test_candidate_offsets_many_intervals() {
+        let intervals = [ConfidenceInterval::new(-100, 100); 6];
+        let candidates = candidate_offsets(&intervals);
+        assert!(candidates.len() <= MAX_CANDIDATES);
+        assert!(candidates.contains(&vec![0; 6]));
+        for i in 0..intervals.len() {
+            let offsets: HashSet<i64> = candidates.iter().map(|offsets| offsets[i]).collect();
+            assert!(offsets.len() > 2);
+        }
+        // one breakpoint is moved at a time
+        for offsets in &candidates {
+            assert!(offsets.iter().filter(|offset| **offset != 0).count() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_refine() {
+        let intervals = [ConfidenceInterval::new(-100, 100); 2];
+        let truth = [100, -100];
+        let (refined, observations) = refine(&intervals, |offsets| {
+            let prob_alt = if offsets == truth { 0.99 } else { 0.1 };
+            Ok(Some(vec![observation(prob_alt); 10]))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(refined.offsets, truth);
+        assert!(refined.posterior > LogProb(0.99f64.ln()));
+        assert_eq!(observations.len(), 10);
+        // the selection among the candidates is penalized
+        for obs in &observations {
+            assert!(obs.prob_alt < LogProb(0.99f64.ln()));
+        }
+
+        // no evidence, hence the given breakpoints are kept without penalty
+        let (refined, observations) = refine(&intervals, |_| Ok(Some(vec![observation(0.5); 10])))
+            .unwrap()
+            .unwrap();
+        assert_eq!(refined.offsets, vec![0; 2]);
+        assert_relative_eq!(observations[0].prob_alt.exp(), 0.5, epsilon = 1e-9);
+
+        // invalid candidates are not considered
+        assert!(refine(&intervals, |_| Ok(None)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_refine_many_intervals() {
+        // breakends of a group with four imprecise breakpoints, only one of them being off
+        let intervals = [ConfidenceInterval::new(-100, 100); 4];
+        let mut truth = vec![0; 4];
+        truth[2] = *intervals[2]
+            .offsets((MAX_CANDIDATES - 1) / 4 + 1)
+            .last()
+            .unwrap();
+        let (refined, _) = refine(&intervals, |offsets| {
+            let prob_alt = if offsets == truth.as_slice() {
+                0.99
+            } else {
+                0.1
+            };
+            Ok(Some(vec![observation(prob_alt); 10]))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(refined.offsets, truth);
+    }
+
+    #[test]
+    fn test_candidate_offsets() {
+        let intervals = [
+            ConfidenceInterval::new(-100, 100),
+            ConfidenceInterval::new(-100, 100),
+        ];
+        let candidates = candidate_offsets(&intervals);
+        assert!(candidates.len() <= MAX_CANDIDATES);
+        assert!(candidates.contains(&vec![0, 0]));
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Read depth evidence for large deletions and duplications. The number of reads within the
+//! affected region is compared to the number expected from control samples (a matched normal
+//! or a panel of normals).
+
+use std::f64::consts::PI;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use bio::stats::LogProb;
+use rust_htslib::bam::{self, Read as BAMRead};
+use rust_htslib::bcf;
+
+use crate::utils;
+
+/// Minimum length of deletions and duplications for which read depth evidence is considered.
+/// Below, breakpoint evidence dominates and depth is too noisy.
+pub(crate) const MIN_DEPTH_EVIDENCE_LEN: u64 = 1000;
+
+/// Maximum length of the flanks on each side of a variant that are used for normalization.
+const MAX_FLANK_LEN: u64 = 50_000;
+
+/// Maximum number of bases within a variant in which reads are counted. Within larger
+/// variants, reads are only counted in evenly spaced windows of length `WINDOW_LEN`.
+const MAX_COUNTED_LEN: u64 = 100_000;
+
+/// Length of the windows in which reads are counted within variants longer than
+/// `MAX_COUNTED_LEN`.
+const WINDOW_LEN: u64 = 10_000;
+
+/// Lower bound for the variance of the read count, avoiding degenerate distributions
+/// (e.g. for homozygous deletions).
+const MIN_DEPTH_VARIANCE: f64 = 1.0;
+
+/// Read depth within the region affected by a deletion or duplication.
+#[derive(Debug, Clone, Copy, PartialEq, new)]
+pub(crate) struct DepthObservation {
+    /// Number of reads starting within the region.
+    pub(crate) observed: u32,
+    /// Expected number of reads if the sample carries only reference haplotypes.
+    pub(crate) expected: f64,
+    /// Variance of the number of reads if the sample carries only reference haplotypes.
+    pub(crate) variance: f64,
+    /// Depth of alt haplotypes relative to reference haplotypes within the region
+    /// (0 for deletions, 2 for duplications).
+    pub(crate) alt_depth_ratio: f64,
+}
+
+impl DepthObservation {
+    /// Likelihood of the observed read count, given the allele frequency of the variant.
+    ///
+    /// METHOD: the expected read count scales linearly with the allele frequency between the
+    /// reference and the alt depth. The read count is approximated by a normal distribution,
+    /// with the Poisson part of the variance scaling with the mean and the part stemming from
+    /// the controls scaling with its square.
+    pub(crate) fn likelihood(&self, allele_freq: f64) -> LogProb {
+        let depth_ratio = 1.0 - allele_freq + allele_freq * self.alt_depth_ratio;
+        let mean = self.expected * depth_ratio;
+        let variance = (mean + (self.variance - self.expected).max(0.0) * depth_ratio.powi(2))
+            .max(MIN_DEPTH_VARIANCE);
+        LogProb(
+            -0.5 * (2.0 * PI * variance).ln()
+                - (self.observed as f64 - mean).powi(2) / (2.0 * variance),
+        )
+    }
+
+    /// Read the depth observation from the given record of an observation BCF.
+    pub(crate) fn read(record: &mut bcf::Record) -> Option<Self> {
+        let float = |record: &mut bcf::Record, tag: &[u8]| match record.info(tag).float() {
+            Ok(Some(values)) => Some(values[0] as f64),
+            _ => None,
+        };
+        let observed = match record.info(b"DEPTH_OBS").integer() {
+            Ok(Some(values)) => values[0] as u32,
+            _ => return None,
+        };
+        Some(DepthObservation {
+            observed,
+            expected: float(record, b"DEPTH_EXP")?,
+            variance: float(record, b"DEPTH_VAR")?,
+            alt_depth_ratio: float(record, b"DEPTH_ALT_RATIO")?,
+        })
+    }
+
+    /// Write the depth observation into the given record of an observation BCF.
+    pub(crate) fn write(&self, record: &mut bcf::Record) -> Result<()> {
+        record.push_info_integer(b"DEPTH_OBS", &[self.observed as i32])?;
+        record.push_info_float(b"DEPTH_EXP", &[self.expected as f32])?;
+        record.push_info_float(b"DEPTH_VAR", &[self.variance as f32])?;
+        record.push_info_float(b"DEPTH_ALT_RATIO", &[self.alt_depth_ratio as f32])?;
+        Ok(())
+    }
+}
+
+/// Number of reads starting within the region and within its flanks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RegionCounts {
+    region: u64,
+    flanks: u64,
+}
+
+/// Read counts of a sample with one or more BAM files.
+#[derive(Debug)]
+struct DepthCounter {
+    bams: Vec<bam::IndexedReader>,
+}
+
+impl DepthCounter {
+    fn new<P: AsRef<Path>>(paths: &[P], reference: &Path) -> Result<Self> {
+        let bams: Result<Vec<_>> = paths
+            .iter()
+            .map(|path| utils::alignments::indexed_reader(path, reference))
+            .collect();
+        Ok(DepthCounter { bams: bams? })
+    }
+
+    /// Count the primary, uniquely placed reads starting within the given interval.
+    fn count(&mut self, contig: &str, interval: &Range<u64>) -> Result<Option<u64>> {
+        let mut count = 0;
+        for bam in &mut self.bams {
+            let tid = if let Some(tid) = bam.header().tid(contig.as_bytes()) {
+                tid
+            } else {
+                return Ok(None);
+            };
+            bam.fetch((tid, interval.start, interval.end))?;
+            for record in bam.records() {
+                let record = record?;
+                if (record.pos() as u64) < interval.start
+                    || record.is_unmapped()
+                    || record.is_secondary()
+                    || record.is_supplementary()
+                    || record.is_duplicate()
+                    || record.is_quality_check_failed()
+                    || record.mapq() == 0
+                {
+                    continue;
+                }
+                count += 1;
+            }
+        }
+        Ok(Some(count))
+    }
+
+    /// Count reads in the given (possibly windowed) region and its flanks.
+    fn region_counts(
+        &mut self,
+        contig: &str,
+        region: &[Range<u64>],
+        flanks: &[Range<u64>],
+    ) -> Result<Option<RegionCounts>> {
+        let mut total = |intervals: &[Range<u64>]| -> Result<Option<u64>> {
+            let mut total = 0;
+            for interval in intervals {
+                if let Some(count) = self.count(contig, interval)? {
+                    total += count;
+                } else {
+                    return Ok(None);
+                }
+            }
+            Ok(Some(total))
+        };
+        Ok(match (total(region)?, total(flanks)?) {
+            (Some(region), Some(flanks)) => Some(RegionCounts { region, flanks }),
+            _ => None,
+        })
+    }
+}
+
+/// Intervals within the given region in which reads are counted. These are the entire region
+/// if it is at most `MAX_COUNTED_LEN` long, and evenly spaced windows otherwise.
+fn counted_windows(region: &Range<u64>) -> Vec<Range<u64>> {
+    let len = region.end - region.start;
+    if len <= MAX_COUNTED_LEN {
+        return vec![region.clone()];
+    }
+    let n = MAX_COUNTED_LEN / WINDOW_LEN;
+    let step = len / n;
+    (0..n)
+        .map(|i| {
+            // center the window within its part of the region
+            let start = region.start + i * step + (step - WINDOW_LEN) / 2;
+            start..start + WINDOW_LEN
+        })
+        .collect()
+}
+
+/// Read counts of the control samples. They are opened once and shared between all
+/// samples of which depth evidence is collected in parallel.
+#[derive(Debug, Default)]
+pub(crate) struct DepthControls {
+    counters: Mutex<Vec<DepthCounter>>,
+}
+
+impl DepthControls {
+    /// Controls given by `control_bams` (one BAM file per control sample).
+    pub(crate) fn new<P: AsRef<Path>>(control_bams: &[P], reference: &Path) -> Result<Self> {
+        let counters: Result<Vec<_>> = control_bams
+            .iter()
+            .map(|path| DepthCounter::new(&[path], reference))
+            .collect();
+        Ok(DepthControls {
+            counters: Mutex::new(counters?),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.counters.lock().unwrap().is_empty()
+    }
+}
+
+/// Read depth evidence of a sample, compared to control samples.
+#[derive(Debug)]
+pub(crate) struct DepthEvidence {
+    sample: DepthCounter,
+    controls: Arc<DepthControls>,
+}
+
+impl DepthEvidence {
+    /// Depth evidence for the sample given by the BAM files `sample_bams`, compared to the
+    /// given controls.
+    pub(crate) fn new<P: AsRef<Path>>(
+        sample_bams: &[P],
+        controls: Arc<DepthControls>,
+        reference: &Path,
+    ) -> Result<Self> {
+        Ok(DepthEvidence {
+            sample: DepthCounter::new(sample_bams, reference)?,
+            controls,
+        })
+    }
+
+    /// Observe the read depth in the given region of the given contig (0-based, half-open).
+    /// Returns `None` if the depth is not informative, e.g. because there are no reads in the
+    /// flanks of the region.
+    ///
+    /// METHOD: the region and its flanks are compared between sample and controls. This
+    /// normalizes for both library size and local coverage biases (e.g. GC content or
+    /// mappability) shared with the controls. The expected count is the mean over all controls.
+    /// Its variance is estimated from the spread between the controls if there are at least
+    /// two of them, and from the Poisson noise of the counts otherwise.
+    /// In order to bound the runtime for large variants (up to several megabases), reads are
+    /// only counted in evenly spaced windows of the region (see `counted_windows`). Since
+    /// sample and controls are counted in the same windows, the normalization is unaffected.
+    pub(crate) fn observation(
+        &mut self,
+        contig: &str,
+        region: Range<u64>,
+        alt_depth_ratio: f64,
+    ) -> Result<Option<DepthObservation>> {
+        let flank_len = ((region.end - region.start) / 2).min(MAX_FLANK_LEN);
+        let flanks = [
+            region.start.saturating_sub(flank_len)..region.start,
+            region.end..region.end + flank_len,
+        ];
+
+        let windows = counted_windows(&region);
+
+        let sample = match self.sample.region_counts(contig, &windows, &flanks)? {
+            Some(counts) if counts.flanks > 0 => counts,
+            _ => return Ok(None),
+        };
+
+        let mut controls = self.controls.counters.lock().unwrap();
+        if controls.is_empty() {
+            return Ok(None);
+        }
+        let mut expected = Vec::with_capacity(controls.len());
+        let mut poisson_variance = 0.0;
+        for control in controls.iter_mut() {
+            match control.region_counts(contig, &windows, &flanks)? {
+                Some(counts) if counts.flanks > 0 && counts.region > 0 => {
+                    let e = sample.flanks as f64 * counts.region as f64 / counts.flanks as f64;
+                    expected.push(e);
+                    poisson_variance += e.powi(2)
+                        * (1.0 / counts.region as f64
+                            + 1.0 / counts.flanks as f64
+                            + 1.0 / sample.flanks as f64);
+                }
+                _ => (),
+            }
+        }
+        if expected.is_empty() {
+            return Ok(None);
+        }
+
+        let n = expected.len() as f64;
+        let mean = expected.iter().sum::<f64>() / n;
+        let control_variance = if expected.len() > 1 {
+            expected.iter().map(|e| (e - mean).powi(2)).sum::<f64>() / (n - 1.0)
+        } else {
+            poisson_variance
+        };
+
+        Ok(Some(DepthObservation::new(
+            sample.region as u32,
+            mean,
+            mean + control_variance,
+            alt_depth_ratio,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depth_likelihood() {
+        // heterozygous deletion
+        let obs = DepthObservation::new(500, 1000.0, 1100.0, 0.0);
+        assert!(obs.likelihood(0.5) > obs.likelihood(0.0));
+        assert!(obs.likelihood(0.5) > obs.likelihood(1.0));
+
+        // no duplication
+        let obs = DepthObservation::new(1010, 1000.0, 1100.0, 2.0);
+        assert!(obs.likelihood(0.0) > obs.likelihood(0.5));
+
+        // homozygous deletion does not lead to a degenerate likelihood
+        let obs = DepthObservation::new(0, 1000.0, 1100.0, 0.0);
+        assert!(obs.likelihood(1.0) > obs.likelihood(0.5));
+        assert!(!obs.likelihood(1.0).is_nan());
+    }
+
+    #[test]
+    fn test_counted_windows() {
+        assert_eq!(counted_windows(&(1000..51000)), vec![1000..51000]);
+
+        let region = 1000..5_001_000;
+        let windows = counted_windows(&region);
+        assert_eq!(
+            windows.iter().map(|w| w.end - w.start).sum::<u64>(),
+            MAX_COUNTED_LEN
+        );
+        assert!(windows
+            .iter()
+            .all(|w| w.start >= region.start && w.end <= region.end));
+        assert!(windows.windows(2).all(|w| w[0].end <= w[1].start));
+    }
+}
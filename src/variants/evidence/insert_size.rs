@@ -6,6 +6,15 @@
 use anyhow::Result;
 use rust_htslib::bam;
 
+/// Read group (RG tag) of the given record, if any.
+pub(crate) fn read_group(record: &bam::Record) -> Option<&str> {
+    if let Some(bam::record::Aux::String(rg)) = record.aux(b"RG") {
+        std::str::from_utf8(rg).ok()
+    } else {
+        None
+    }
+}
+
 /// Estimate the insert size from read pair projected on reference sequence including clips.
 /// Note that this is is not the insert size of the real fragment but rather the insert size of
 /// the alignment on the reference sequence.
@@ -4,6 +4,9 @@
 // except according to those terms.
 
 pub(crate) mod bases;
+pub(crate) mod breakpoints;
+pub(crate) mod depth;
 pub(crate) mod insert_size;
 pub(crate) mod observation;
 pub(crate) mod realignment;
+pub(crate) mod split_reads;
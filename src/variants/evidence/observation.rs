@@ -4,6 +4,8 @@
 // except according to those terms.
 
 use std::char;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::ops;
 use std::ops::Deref;
@@ -24,6 +26,7 @@ use itertools::Itertools;
 use crate::errors::{self, Error};
 use crate::estimation::alignment_properties::AlignmentProperties;
 use crate::utils;
+use crate::variants::evidence::insert_size::estimate_insert_size;
 use crate::variants::sample;
 use crate::variants::types::Variant;
 
@@ -198,6 +201,22 @@ where
     pub(crate) paired: bool,
     /// Read position of the variant in the read (for SNV and MNV)
     pub(crate) read_position: P,
+    /// Length of the sequenced fragment (if known, i.e. for properly paired reads)
+    #[builder(default = "None")]
+    pub(crate) fragment_length: Option<u32>,
+    /// Number of reads (or read pairs) of the UMI family this observation has been obtained from
+    #[builder(default = "1")]
+    pub(crate) family_size: u32,
+    /// True if the UMI family contains reads from both strands of the original molecule
+    #[builder(default = "false")]
+    pub(crate) duplex: bool,
+    /// Probability that a UMI family of the sample contains reads from both strands of the
+    /// original molecule (the duplex recovery rate, estimated per sample during calling)
+    #[builder(default = "LogProb(0.5f64.ln())")]
+    pub(crate) prob_duplex_recovery: LogProb,
+    /// Identifier of the fragment (i.e. a hash of the read name), used for read-backed phasing
+    #[builder(default = "None")]
+    pub(crate) fragment_id: Option<u64>,
 }
 
 impl<P: Clone> ObservationBuilder<P> {
@@ -233,6 +252,11 @@ impl Observation<Option<u32>> {
             read_orientation: self.read_orientation,
             softclipped: self.softclipped,
             paired: self.paired,
+            fragment_length: self.fragment_length,
+            family_size: self.family_size,
+            duplex: self.duplex,
+            prob_duplex_recovery: self.prob_duplex_recovery,
+            fragment_id: self.fragment_id,
             read_position: self.read_position.map_or(ReadPosition::Some, |pos| {
                 if let Some(major_pos) = major_read_position {
                     if pos == major_pos {
@@ -248,6 +272,54 @@ impl Observation<Option<u32>> {
     }
 }
 
+/// Probability that a molecule shows the other allele in all members of its UMI family,
+/// because of an error that happened before amplification.
+const PROB_PRE_AMPLIFICATION_ERROR: f64 = 0.001;
+
+impl Observation<Option<u32>> {
+    /// Combine the observations of the members of a UMI family into a single observation.
+    pub(crate) fn from_family(members: &[Self], duplex: bool) -> Self {
+        assert!(!members.is_empty(), "bug: empty UMI family");
+        let mut obs = members[0].clone();
+        for member in &members[1..] {
+            // METHOD: like for read pairs, we take the maximum mapping probability.
+            if member.prob_mapping > obs.prob_mapping {
+                obs.prob_mapping = member.prob_mapping;
+                obs.prob_mismapping = member.prob_mismapping;
+            }
+            obs.strand |= member.strand;
+            obs.softclipped |= member.softclipped;
+            obs.paired |= member.paired;
+        }
+        // METHOD: members of a family are copies of the same molecule. Sequencing errors
+        // happen independently in each copy, hence the member likelihoods are multiplied.
+        // In contrast, errors that happened before amplification (e.g. during end repair or
+        // in the first PCR cycle) are shared by all members: with probability
+        // PROB_PRE_AMPLIFICATION_ERROR, the molecule shows the other allele in all copies.
+        // This bounds the evidence a single family can provide, no matter how large it is.
+        let product = |prob: fn(&Self) -> LogProb| {
+            members
+                .iter()
+                .fold(LogProb::ln_one(), |acc, member| acc + prob(member))
+        };
+        let prob_alt = product(|member| member.prob_alt);
+        let prob_ref = product(|member| member.prob_ref);
+        let prob_error = LogProb(PROB_PRE_AMPLIFICATION_ERROR.ln());
+        let prob_no_error = prob_error.ln_one_minus_exp();
+        obs.prob_alt = (prob_no_error + prob_alt).ln_add_exp(prob_error + prob_ref);
+        obs.prob_ref = (prob_no_error + prob_ref).ln_add_exp(prob_error + prob_alt);
+        obs.prob_missed_allele = product(|member| member.prob_missed_allele);
+        if obs.strand == Strand::Both {
+            obs.prob_double_overlap = LogProb::ln_one();
+            obs.prob_single_overlap = LogProb::ln_zero();
+        }
+        obs.family_size = members.len() as u32;
+        obs.duplex = duplex;
+
+        obs
+    }
+}
+
 impl<P: Clone> Observation<P> {
     pub(crate) fn bayes_factor_alt(&self) -> BayesFactor {
         BayesFactor::new(self.prob_alt, self.prob_ref)
@@ -327,6 +399,84 @@ where
     /// correctly.
     fn prob_mapping(&self, evidence: &E) -> LogProb;
 
+    /// Calculate observations from the given evidence. If `umi_families` is true, evidence is
+    /// grouped into UMI families first, such that each family yields a single observation.
+    /// The result is subsampled to the given maximum depth (if `subsample` is true).
+    fn evidences_to_observations(
+        &self,
+        evidences: &[E],
+        alignment_properties: &AlignmentProperties,
+        umi_families: bool,
+        subsample: bool,
+        max_depth: usize,
+    ) -> Result<Vec<Observation>> {
+        let mut observations = Vec::new();
+        if umi_families {
+            // Use a BTreeMap in order to have a deterministic order for subsampling.
+            let mut families: BTreeMap<Vec<u8>, Vec<&E>> = BTreeMap::new();
+            let mut singletons = Vec::new();
+            for evidence in evidences {
+                if let Some(umi) = evidence.umi() {
+                    families.entry(umi.molecule).or_default().push(evidence);
+                } else {
+                    singletons.push(evidence);
+                }
+            }
+
+            let n_families = families.len() + singletons.len();
+            let mut subsampler = sample::SubsampleCandidates::new(max_depth, n_families);
+            for (molecule, family) in families
+                .into_iter()
+                .map(|(molecule, members)| (Some(molecule), members))
+                .chain(
+                    singletons
+                        .into_iter()
+                        .map(|evidence| (None, vec![evidence])),
+                )
+            {
+                if subsample && !subsampler.keep() {
+                    continue;
+                }
+                let strands: HashSet<_> = family
+                    .iter()
+                    .filter_map(|evidence| evidence.umi().and_then(|umi| umi.duplex_strand))
+                    .collect();
+                let mut members = Vec::with_capacity(family.len());
+                for evidence in family {
+                    if let Some(obs) =
+                        self.evidence_to_observation(evidence, alignment_properties)?
+                    {
+                        members.push(obs);
+                    }
+                }
+                if !members.is_empty() {
+                    let mut obs = Observation::from_family(&members, strands.len() > 1);
+                    // METHOD: the members of a family are different reads (or read pairs)
+                    // of the same molecule. Identifying the fragment by the molecule ensures
+                    // that the family is recognized at each locus, regardless of which of
+                    // its members cover it.
+                    if let Some(molecule) = molecule {
+                        obs.fragment_id = Some(fragment_id(&molecule));
+                    }
+                    observations.push(obs);
+                }
+            }
+        } else {
+            let mut subsampler = sample::SubsampleCandidates::new(max_depth, evidences.len());
+            for evidence in evidences {
+                if !subsample || subsampler.keep() {
+                    if let Some(obs) =
+                        self.evidence_to_observation(evidence, alignment_properties)?
+                    {
+                        observations.push(obs);
+                    }
+                }
+            }
+        }
+
+        Ok(observations)
+    }
+
     /// Calculate an observation from the given evidence.
     fn evidence_to_observation(
         &self,
@@ -354,6 +504,8 @@ where
                     .softclipped(evidence.softclipped())
                     .read_position(allele_support.read_position())
                     .paired(evidence.is_paired())
+                    .fragment_length(evidence.fragment_length())
+                    .fragment_id(Some(fragment_id(evidence)))
                     .prob_hit_base(LogProb::ln_one() - LogProb((evidence.len() as f64).ln()))
                     .build()
                     .unwrap();
@@ -364,6 +516,14 @@ where
     }
 }
 
+/// Identifier of the fragment the given evidence stems from. Evidence is hashed via
+/// the read name, hence all reads of a fragment yield the same identifier.
+pub(crate) fn fragment_id<E: Hash>(evidence: &E) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    evidence.hash(&mut hasher);
+    hasher.finish()
+}
+
 pub(crate) trait Evidence {
     fn read_orientation(&self) -> Result<SequenceReadPairOrientation>;
 
@@ -372,6 +532,64 @@ pub(crate) trait Evidence {
     fn is_paired(&self) -> bool;
 
     fn len(&self) -> usize;
+
+    /// Length of the sequenced fragment, if it can be determined.
+    fn fragment_length(&self) -> Option<u32>;
+
+    /// Molecular identifier of the UMI family this evidence belongs to, if any.
+    fn umi(&self) -> Option<Umi>;
+}
+
+/// Molecular identifier of a read, as given in the MI tag (e.g. by fgbio).
+/// For duplex sequencing, the identifier of each read ends with /A or /B, denoting
+/// the strand of the original molecule.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct Umi {
+    pub(crate) molecule: Vec<u8>,
+    pub(crate) duplex_strand: Option<u8>,
+}
+
+impl Umi {
+    pub(crate) fn from_record(record: &bam::Record) -> Option<Self> {
+        let mi = match record.aux(b"MI")? {
+            bam::record::Aux::String(mi) => mi.to_owned(),
+            bam::record::Aux::Integer(mi) => mi.to_string().into_bytes(),
+            _ => return None,
+        };
+        let duplex = match mi
+            .rsplitn(2, |c| *c == b'/')
+            .collect_tuple::<(&[u8], &[u8])>()
+        {
+            Some((strand @ b"A", molecule)) | Some((strand @ b"B", molecule)) => {
+                Some((strand[0], molecule.to_owned()))
+            }
+            _ => None,
+        };
+        Some(if let Some((strand, molecule)) = duplex {
+            Umi {
+                molecule,
+                duplex_strand: Some(strand),
+            }
+        } else {
+            Umi {
+                molecule: mi,
+                duplex_strand: None,
+            }
+        })
+    }
+}
+
+/// Fragment length of a single read, as given by the template length of the alignment.
+fn read_fragment_length(record: &bam::Record) -> Option<u32> {
+    if record.is_paired()
+        && !record.is_mate_unmapped()
+        && record.tid() == record.mtid()
+        && record.insert_size() != 0
+    {
+        Some(record.insert_size().unsigned_abs() as u32)
+    } else {
+        None
+    }
 }
 
 #[derive(new, Clone, Eq, Debug)]
@@ -406,6 +624,14 @@ impl Evidence for SingleEndEvidence {
     fn len(&self) -> usize {
         self.inner.seq_len()
     }
+
+    fn fragment_length(&self) -> Option<u32> {
+        read_fragment_length(self.inner.as_ref())
+    }
+
+    fn umi(&self) -> Option<Umi> {
+        Umi::from_record(self.inner.as_ref())
+    }
 }
 
 impl PartialEq for SingleEndEvidence {
@@ -467,6 +693,22 @@ impl Evidence for PairedEndEvidence {
             PairedEndEvidence::PairedEnd { left, right } => left.seq_len() + right.seq_len(),
         }
     }
+
+    fn fragment_length(&self) -> Option<u32> {
+        match self {
+            PairedEndEvidence::SingleEnd(rec) => read_fragment_length(rec.as_ref()),
+            PairedEndEvidence::PairedEnd { left, right } => estimate_insert_size(left, right)
+                .ok()
+                .map(|insert_size| insert_size as u32),
+        }
+    }
+
+    fn umi(&self) -> Option<Umi> {
+        match self {
+            PairedEndEvidence::SingleEnd(rec) => Umi::from_record(rec.as_ref()),
+            PairedEndEvidence::PairedEnd { left, .. } => Umi::from_record(left.as_ref()),
+        }
+    }
 }
 
 impl PartialEq for PairedEndEvidence {
@@ -492,3 +734,59 @@ impl Hash for PairedEndEvidence {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variants::model;
+
+    fn observation(prob_alt: f64, prob_ref: f64, strand: Strand) -> Observation {
+        model::tests::observation_builder(
+            LogProb(0.99f64.ln()),
+            LogProb(prob_alt.ln()),
+            LogProb(prob_ref.ln()),
+        )
+        .strand(strand)
+        .read_position(None)
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_family() {
+        let members = vec![
+            observation(0.9, 0.1, Strand::Forward),
+            observation(0.8, 0.2, Strand::Reverse),
+        ];
+        let family = Observation::from_family(&members, true);
+
+        let (alt, ref_) = (0.72, 0.02);
+        let e = PROB_PRE_AMPLIFICATION_ERROR;
+        assert_relative_eq!(
+            family.prob_alt.exp(),
+            (1.0 - e) * alt + e * ref_,
+            epsilon = 1e-9
+        );
+        assert_relative_eq!(
+            family.prob_ref.exp(),
+            (1.0 - e) * ref_ + e * alt,
+            epsilon = 1e-9
+        );
+        assert_eq!(family.strand, Strand::Both);
+        assert_eq!(family.prob_double_overlap, LogProb::ln_one());
+        assert_eq!(family.family_size, 2);
+        assert!(family.duplex);
+    }
+
+    #[test]
+    fn test_from_family_size() {
+        // Larger families provide stronger evidence, bounded by pre-amplification errors.
+        let single = Observation::from_family(&[observation(0.9, 0.1, Strand::Forward)], false);
+        let members = vec![observation(0.9, 0.1, Strand::Forward); 10];
+        let family = Observation::from_family(&members, false);
+
+        assert!(*family.bayes_factor_alt() > *single.bayes_factor_alt());
+        assert!(*family.bayes_factor_alt() <= 1.0 / PROB_PRE_AMPLIFICATION_ERROR);
+        assert_eq!(family.family_size, 10);
+    }
+}
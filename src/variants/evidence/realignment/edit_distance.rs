@@ -13,6 +13,11 @@ use bio::stats::pairhmm;
 
 use crate::variants::evidence::realignment::pairhmm::{RefBaseEmission, EDIT_BAND};
 
+/// Maximum pattern length that can be handled by the single word (128 bit) variant of Myers'
+/// algorithm. Longer patterns (e.g. realignment windows of long reads) are handled by the
+/// block-based variant, which splits the pattern into multiple 64 bit words.
+const MAX_SINGLE_WORD_PATTERN_LEN: usize = 128;
+
 enum Myers {
     Short(myers::Myers<u128>),
     Long(long::Myers<u64>),
@@ -24,21 +29,17 @@ pub(crate) struct EditDistanceCalculation {
 }
 
 impl EditDistanceCalculation {
-    pub(crate) fn max_pattern_len() -> usize {
-        128
-    }
-
     /// Create new instance.
     ///
     /// # Arguments
-    /// * `read_seq` - read sequence in window (may not exceed 128 bases).
+    /// * `read_seq` - read sequence in window (of arbitrary length).
     pub(crate) fn new<P>(read_seq: P) -> Self
     where
         P: Iterator<Item = u8> + DoubleEndedIterator + ExactSizeIterator,
     {
         let l = read_seq.len();
 
-        let myers = if l <= 128 {
+        let myers = if l <= MAX_SINGLE_WORD_PATTERN_LEN {
             Myers::Short(myers::Myers::new(read_seq))
         } else {
             Myers::Long(long::Myers::new(read_seq))
@@ -122,7 +123,7 @@ impl EditDistanceCalculation {
             let start = alignments[0].start();
             // take the last (aka first because we are mapping backwards) position for an upper bound of the putative end
             let end = cmp::min(
-                alignments.last().unwrap().start() + self.read_seq_len + best_dist as usize,
+                alignments.last().unwrap().start() + self.read_seq_len + best_dist,
                 emission_params.len_x(),
             );
             Some(EditDistanceHit {
@@ -157,6 +158,61 @@ pub(crate) struct EditDistanceHit {
 
 impl EditDistanceHit {
     pub(crate) fn dist_upper_bound(&self) -> usize {
-        self.dist as usize + EDIT_BAND
+        self.dist + EDIT_BAND
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use rust_htslib::bam;
+
+    use crate::variants::evidence::realignment::pairhmm::{
+        GapParams, ReadEmission, ReferenceEmissionParams,
+    };
+    use crate::variants::sample::Platform;
+
+    #[test]
+    fn test_long_pattern() {
+        // pseudo-random reference sequence
+        let mut state: u32 = 42;
+        let ref_seq: Vec<u8> = (0..1000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b"ACGT"[(state >> 16) as usize % 4]
+            })
+            .collect();
+        // read of 500 bases with a single substitution, exceeding a single machine word
+        let mut read_seq = ref_seq[200..700].to_owned();
+        read_seq[250] = if read_seq[250] == b'A' { b'C' } else { b'A' };
+        let qual = vec![30; read_seq.len()];
+        let mut record = bam::Record::new();
+        record.set(b"read", None, &read_seq, &qual);
+
+        let read_emission = Rc::new(ReadEmission::new(
+            record.seq(),
+            record.qual(),
+            0,
+            500,
+            &GapParams::preset(Platform::Illumina),
+        ));
+        let params = ReferenceEmissionParams {
+            ref_seq: Arc::new(ref_seq),
+            ref_offset: 0,
+            ref_end: 1000,
+            read_emission,
+        };
+
+        let mut edit_dist = EditDistanceCalculation::new(read_seq.iter().cloned());
+        let hit = edit_dist.calc_best_hit(&params, None).unwrap();
+        assert_eq!(hit.dist(), 1);
+        assert!(hit
+            .alignments()
+            .iter()
+            .any(|alignment| alignment.start() == 200));
     }
 }
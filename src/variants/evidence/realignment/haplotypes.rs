@@ -0,0 +1,256 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Local haplotypes for joint realignment of nearby candidate variants.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::utils;
+use crate::utils::regions::{RegionReader, Regions};
+use crate::variants::model;
+use crate::variants::types::insertion::known_flanks;
+
+/// Maximum number of nearby candidate variants that are combined into local haplotypes.
+/// The number of haplotypes grows exponentially with this number.
+pub(crate) const MAX_HAPLOTYPE_NEIGHBORS: usize = 3;
+
+/// A small variant, represented as an edit of the reference sequence.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, new, Getters, CopyGetters)]
+pub(crate) struct HaplotypeVariant {
+    /// Start of the replaced reference sequence (0-based).
+    #[getset(get_copy = "pub(crate)")]
+    pos: u64,
+    /// Length of the replaced reference sequence.
+    ref_len: u64,
+    /// Sequence the reference sequence is replaced with.
+    #[getset(get = "pub(crate)")]
+    alt_seq: Vec<u8>,
+}
+
+impl HaplotypeVariant {
+    /// Represent the given variant, located at the given position of a VCF record,
+    /// as an edit of the reference sequence. Returns `None` for variant types that
+    /// cannot be represented this way (e.g. breakends, inversions or insertions of
+    /// unknown sequence).
+    pub(crate) fn from_variant(variant: &model::Variant, pos: u64) -> Option<Self> {
+        match variant {
+            model::Variant::SNV(alt) => Some(HaplotypeVariant::new(pos, 1, vec![*alt])),
+            model::Variant::MNV(alt) => {
+                Some(HaplotypeVariant::new(pos, alt.len() as u64, alt.to_owned()))
+            }
+            // Deletions and insertions happen behind the leading reference base.
+            model::Variant::Deletion(len) => Some(HaplotypeVariant::new(pos + 1, *len, vec![])),
+            model::Variant::Insertion(seq) if known_flanks(seq).is_none() => {
+                Some(HaplotypeVariant::new(pos + 1, 0, seq.to_owned()))
+            }
+            model::Variant::Replacement {
+                ref_allele,
+                alt_allele,
+            } => Some(HaplotypeVariant::new(
+                pos,
+                ref_allele.len() as u64,
+                alt_allele.to_owned(),
+            )),
+            // The repeat tract starts behind the leading reference base.
+            model::Variant::TandemRepeat {
+                unit,
+                ref_count,
+                alt_count,
+            } => Some(HaplotypeVariant::new(
+                pos + 1,
+                unit.len() as u64 * ref_count,
+                unit.repeat(*alt_count as usize),
+            )),
+            _ => None,
+        }
+    }
+
+    /// End of the replaced reference sequence (exclusive).
+    pub(crate) fn end(&self) -> u64 {
+        self.pos + self.ref_len
+    }
+
+    /// Whether the two variants cannot be part of the same haplotype. Insertions are
+    /// treated as if they would replace the following reference base, such that variants
+    /// that touch each other are never combined.
+    pub(crate) fn conflicts(&self, other: &HaplotypeVariant) -> bool {
+        let end = |variant: &HaplotypeVariant| cmp::max(variant.end(), variant.pos + 1);
+        self.pos < end(other) && other.pos < end(self)
+    }
+
+    /// Whether the variant is located within the given reference interval.
+    pub(crate) fn is_within(&self, interval: &Range<usize>) -> bool {
+        self.pos >= interval.start as u64 && self.end() <= interval.end as u64
+    }
+}
+
+/// Build the sequence of a local haplotype by applying the given (non-conflicting) variants
+/// to the given interval of the reference sequence.
+pub(crate) fn haplotype_seq(
+    ref_seq: &[u8],
+    interval: &Range<usize>,
+    variants: &[&HaplotypeVariant],
+) -> Vec<u8> {
+    let mut variants = variants.to_owned();
+    variants.sort_by_key(|variant| variant.pos());
+
+    let mut seq = Vec::with_capacity(interval.end - interval.start);
+    let mut pos = interval.start;
+    for variant in variants {
+        seq.extend(&ref_seq[pos..variant.pos() as usize]);
+        seq.extend(variant.alt_seq());
+        pos = variant.end() as usize;
+    }
+    seq.extend(&ref_seq[pos..interval.end]);
+
+    seq
+}
+
+/// Combinations of the given variants that can occur together on a haplotype,
+/// i.e., that do not contain conflicting variants.
+pub(crate) fn compatible_combinations(
+    variants: &[HaplotypeVariant],
+) -> Vec<Vec<&HaplotypeVariant>> {
+    (0..1usize << variants.len())
+        .filter_map(|mask| {
+            let combination: Vec<_> = variants
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, variant)| variant)
+                .collect();
+            let compatible = combination
+                .iter()
+                .enumerate()
+                .all(|(i, a)| combination[i + 1..].iter().all(|b| !a.conflicts(b)));
+            if compatible {
+                Some(combination)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Index of all small candidate variants of a BCF file, used to find the neighbors of a
+/// candidate variant for building local haplotypes.
+#[derive(Default, Debug)]
+pub(crate) struct HaplotypeIndex {
+    variants: HashMap<String, Vec<HaplotypeVariant>>,
+}
+
+impl HaplotypeIndex {
+    /// Index small variants of the given BCF. If regions are given, only records within these
+    /// are considered.
+    pub(crate) fn new<P: AsRef<Path>>(inbcf: P, regions: Option<&Regions>) -> Result<Self> {
+        let mut bcf_reader = RegionReader::new(inbcf, regions)?;
+        let mut skips = utils::SimpleCounter::default();
+
+        let mut variants: HashMap<String, Vec<HaplotypeVariant>> = HashMap::new();
+        loop {
+            let mut record = bcf_reader.empty_record();
+            match bcf_reader.read(&mut record) {
+                None => break,
+                Some(res) => res?,
+            }
+
+            let pos = record.pos() as u64;
+            let chrom = String::from_utf8(
+                bcf_reader
+                    .header()
+                    .rid2name(record.rid().unwrap())?
+                    .to_owned(),
+            )?;
+            for variant in utils::collect_variants(&mut record, true, &mut skips)? {
+                if let Some(variant) = HaplotypeVariant::from_variant(&variant, pos) {
+                    variants.entry(chrom.clone()).or_default().push(variant);
+                }
+            }
+        }
+
+        for contig_variants in variants.values_mut() {
+            contig_variants.sort_by_key(|variant| variant.pos());
+        }
+
+        Ok(HaplotypeIndex { variants })
+    }
+
+    /// Candidate variants within the given distance of the given variant, that do not
+    /// conflict with it. At most `MAX_HAPLOTYPE_NEIGHBORS` are returned, preferring the closest.
+    pub(crate) fn neighbors(
+        &self,
+        contig: &str,
+        variant: &HaplotypeVariant,
+        distance: u64,
+    ) -> Vec<HaplotypeVariant> {
+        let contig_variants = if let Some(contig_variants) = self.variants.get(contig) {
+            contig_variants
+        } else {
+            return Vec::new();
+        };
+
+        let start = variant.pos().saturating_sub(distance);
+        let end = variant.end() + distance;
+        let first = contig_variants.partition_point(|other| other.pos() < start);
+
+        let mut neighbors: Vec<_> = contig_variants[first..]
+            .iter()
+            .take_while(|other| other.pos() < end)
+            .filter(|other| other.end() <= end && !other.conflicts(variant))
+            .cloned()
+            .collect();
+        // METHOD: keep the closest neighbors, since they are most likely to be covered by
+        // the same reads.
+        let dist = |other: &HaplotypeVariant| {
+            if other.pos() >= variant.end() {
+                other.pos() - variant.end()
+            } else {
+                variant.pos().saturating_sub(other.end())
+            }
+        };
+        neighbors.sort_by_key(|other| dist(other));
+        neighbors.truncate(MAX_HAPLOTYPE_NEIGHBORS);
+        neighbors.sort_by_key(|other| other.pos());
+
+        neighbors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_haplotype_seq() {
+        let ref_seq = b"ACGTACGTACGT";
+        let snv = HaplotypeVariant::from_variant(&model::Variant::SNV(b'T'), 2).unwrap();
+        let del = HaplotypeVariant::from_variant(&model::Variant::Deletion(2), 4).unwrap();
+        let ins =
+            HaplotypeVariant::from_variant(&model::Variant::Insertion(b"GG".to_vec()), 8).unwrap();
+
+        assert_eq!(haplotype_seq(ref_seq, &(0..12), &[]), ref_seq.to_vec());
+        assert_eq!(
+            haplotype_seq(ref_seq, &(1..12), &[&ins, &snv, &del]),
+            b"CTTATAGGCGT".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_compatible_combinations() {
+        let snv = HaplotypeVariant::new(5, 1, b"A".to_vec());
+        let mnv = HaplotypeVariant::new(4, 2, b"GG".to_vec());
+        let ins = HaplotypeVariant::new(10, 0, b"T".to_vec());
+
+        assert!(snv.conflicts(&mnv));
+        assert!(!snv.conflicts(&ins));
+        // all combinations except those containing both the SNV and the MNV
+        assert_eq!(compatible_combinations(&[snv, mnv, ins]).len(), 6);
+    }
+}
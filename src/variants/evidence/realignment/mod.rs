@@ -5,6 +5,7 @@
 
 use std::cmp;
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::ops::Range;
 use std::rc::Rc;
 use std::str;
@@ -13,7 +14,8 @@ use std::usize;
 
 use anyhow::Result;
 use bio::alignment::AlignmentOperation;
-use bio::stats::{self, pairhmm::GapParameters, pairhmm::PairHMM, LogProb, Prob};
+use bio::stats::pairhmm::{EmissionParameters, GapParameters, PairHMM};
+use bio::stats::{self, LogProb, Prob};
 use bio_types::genome;
 use bio_types::genome::AbstractInterval;
 use rust_htslib::bam;
@@ -21,15 +23,23 @@ use rust_htslib::bam;
 use crate::errors::Error;
 use crate::reference;
 use crate::utils;
+use crate::variants::evidence::insert_size::read_group;
 use crate::variants::evidence::observation::Strand;
 use crate::variants::evidence::realignment::edit_distance::EditDistanceCalculation;
-use crate::variants::evidence::realignment::pairhmm::{ReadEmission, ReferenceEmissionParams};
+use crate::variants::evidence::realignment::pairhmm::{
+    HomopolymerEmissionParams, ReadEmission, ReferenceEmissionParams,
+};
 use crate::variants::types::{AlleleSupport, AlleleSupportBuilder, SingleLocus};
 
 pub(crate) mod edit_distance;
+pub(crate) mod haplotypes;
 pub(crate) mod pairhmm;
+pub(crate) mod simd;
+pub(crate) mod stutter;
 
 use crate::variants::evidence::realignment::edit_distance::EditDistanceHit;
+use crate::variants::evidence::realignment::haplotypes::HaplotypeVariant;
+use crate::variants::evidence::realignment::stutter::RepeatAlleles;
 
 pub(crate) struct CandidateRegion {
     overlap: bool,
@@ -55,6 +65,19 @@ pub(crate) trait Realignable<'a> {
     fn maybe_revcomp(&self) -> bool {
         false
     }
+
+    /// Representation of the alt allele as an edit of the reference sequence.
+    /// If given, the alt allele can be combined with nearby candidate variants
+    /// into local haplotypes during realignment.
+    fn haplotype_variant(&self) -> Option<HaplotypeVariant> {
+        None
+    }
+
+    /// Ref and alt allele of a tandem repeat. If given, both alleles are marginalized
+    /// over stutter during realignment.
+    fn repeat_alleles(&self) -> Option<RepeatAlleles> {
+        None
+    }
 }
 
 pub(crate) trait Realigner {
@@ -92,9 +115,9 @@ pub(crate) trait Realigner {
                     let mut read_offset = qstart.saturating_sub(max_window);
                     let mut read_end = cmp::min(qend + max_window as usize, record.seq_len());
 
-                    // correct for reads that enclose the entire variant while that exceeds the maximum pattern len
-                    let exceed = (read_end - read_offset)
-                        .saturating_sub(EditDistanceCalculation::max_pattern_len());
+                    // correct for reads that enclose the entire variant while that exceeds the maximum window
+                    let exceed =
+                        (read_end - read_offset).saturating_sub(2 * self.max_window() as usize);
                     if exceed > 0 {
                         read_offset += exceed / 2;
                         read_end -= (exceed as f64 / 2.0).ceil() as usize;
@@ -240,6 +263,7 @@ pub(crate) trait Realigner {
                 read_qual,
                 region.read_interval.start,
                 region.read_interval.end,
+                self.gap_params(),
             ));
             let mut edit_dist =
                 EditDistanceCalculation::new(region.read_interval.clone().map(|i| read_seq[i]));
@@ -268,6 +292,49 @@ pub(crate) trait Realigner {
                 &mut edit_dist,
             );
 
+            // METHOD: Reads from tandem repeats frequently show a few repeat units more or less
+            // than their allele due to polymerase slippage. Hence, we marginalize both alleles
+            // over the repeat counts that can arise from stutter.
+            // If there are nearby candidate variants in the considered window, reads
+            // carrying them would be penalized on both alleles. Hence, we build local haplotypes
+            // from all compatible combinations of them and marginalize over those.
+            if let Some(repeat) = variant
+                .repeat_alleles()
+                .filter(|repeat| repeat.is_within(&region.ref_interval))
+            {
+                let (prob_ref_repeat, prob_alt_repeat) = self.prob_repeat_alleles(
+                    &ref_seq,
+                    &region.ref_interval,
+                    &repeat,
+                    &read_emission,
+                    &mut edit_dist,
+                );
+                prob_ref = prob_ref_repeat;
+                prob_alt = prob_alt_repeat;
+            } else if let Some(target) = variant
+                .haplotype_variant()
+                .filter(|target| target.is_within(&region.ref_interval))
+            {
+                let neighbors: Vec<_> = self
+                    .haplotype_variants()
+                    .iter()
+                    .filter(|neighbor| neighbor.is_within(&region.ref_interval))
+                    .cloned()
+                    .collect();
+                if !neighbors.is_empty() {
+                    let (prob_ref_haplotypes, prob_alt_haplotypes) = self.prob_haplotypes(
+                        &ref_seq,
+                        &region.ref_interval,
+                        &target,
+                        &neighbors,
+                        &read_emission,
+                        &mut edit_dist,
+                    );
+                    prob_ref = prob_ref_haplotypes;
+                    prob_alt = prob_alt_haplotypes;
+                }
+            }
+
             assert!(!prob_ref.is_nan());
             assert!(!prob_alt.is_nan());
 
@@ -330,6 +397,95 @@ pub(crate) trait Realigner {
             .unwrap())
     }
 
+    /// Calculate probabilities of the ref and the alt allele (given by the target variant),
+    /// marginalized over all local haplotypes that can be built from the given neighboring
+    /// variants.
+    fn prob_haplotypes<'a>(
+        &mut self,
+        ref_seq: &Arc<Vec<u8>>,
+        ref_interval: &Range<usize>,
+        target: &HaplotypeVariant,
+        neighbors: &[HaplotypeVariant],
+        read_emission: &Rc<ReadEmission<'a>>,
+        edit_dist: &mut edit_distance::EditDistanceCalculation,
+    ) -> (LogProb, LogProb) {
+        let mut prob_haplotype = |variants: &[&HaplotypeVariant]| {
+            let seq = haplotypes::haplotype_seq(ref_seq, ref_interval, variants);
+            let len = seq.len();
+            self.prob_allele(
+                &mut [ReferenceEmissionParams {
+                    ref_seq: Arc::new(seq),
+                    ref_offset: 0,
+                    ref_end: len,
+                    read_emission: Rc::clone(read_emission),
+                }],
+                edit_dist,
+            )
+        };
+
+        let mut probs_ref = Vec::new();
+        let mut probs_alt = Vec::new();
+        for mut combination in haplotypes::compatible_combinations(neighbors) {
+            probs_ref.push(prob_haplotype(&combination));
+            combination.push(target);
+            probs_alt.push(prob_haplotype(&combination));
+        }
+
+        // METHOD: all haplotypes are considered equally likely a priori. Since the same
+        // haplotypes are considered for ref and alt allele, the prior does not affect
+        // the ratio between the two probabilities.
+        let n = LogProb((probs_ref.len() as f64).ln());
+        (
+            LogProb::ln_sum_exp(&probs_ref) - n,
+            LogProb::ln_sum_exp(&probs_alt) - n,
+        )
+    }
+
+    /// Calculate probabilities of the ref and the alt allele of a tandem repeat, each
+    /// marginalized over the repeat counts that reads may show due to stutter.
+    fn prob_repeat_alleles<'a>(
+        &mut self,
+        ref_seq: &Arc<Vec<u8>>,
+        ref_interval: &Range<usize>,
+        repeat: &RepeatAlleles,
+        read_emission: &Rc<ReadEmission<'a>>,
+        edit_dist: &mut edit_distance::EditDistanceCalculation,
+    ) -> (LogProb, LogProb) {
+        // Probabilities of the read given a haplotype with a certain repeat count.
+        // Ref and alt allele share most of their stutter counts, hence we cache them.
+        let mut probs_count = HashMap::new();
+        let mut prob_repeat_allele = |count: u64| {
+            let probs: Vec<_> = stutter::stutter_counts(repeat.unit().len(), count)
+                .into_iter()
+                .map(|(observed_count, prob_stutter)| {
+                    let prob_observed = *probs_count.entry(observed_count).or_insert_with(|| {
+                        let seq = haplotypes::haplotype_seq(
+                            ref_seq,
+                            ref_interval,
+                            &[&repeat.allele(observed_count)],
+                        );
+                        let len = seq.len();
+                        self.prob_allele(
+                            &mut [ReferenceEmissionParams {
+                                ref_seq: Arc::new(seq),
+                                ref_offset: 0,
+                                ref_end: len,
+                                read_emission: Rc::clone(read_emission),
+                            }],
+                            edit_dist,
+                        )
+                    });
+                    prob_stutter + prob_observed
+                })
+                .collect();
+            LogProb::ln_sum_exp(&probs)
+        };
+
+        let prob_ref = prob_repeat_allele(repeat.ref_count());
+        let prob_alt = prob_repeat_allele(repeat.alt_count());
+        (prob_ref, prob_alt)
+    }
+
     /// Calculate probability of a certain allele.
     fn prob_allele<E>(
         &mut self,
@@ -381,19 +537,138 @@ pub(crate) trait Realigner {
     where
         E: stats::pairhmm::EmissionParameters + pairhmm::RefBaseEmission;
 
-    fn ref_buffer(&self) -> &Arc<reference::Buffer>;
+    /// State that is shared by all realigners.
+    fn base(&self) -> &RealignerBase;
+
+    fn base_mut(&mut self) -> &mut RealignerBase;
+
+    fn ref_buffer(&self) -> &Arc<reference::Buffer> {
+        &self.base().ref_buffer
+    }
+
+    fn gap_params(&self) -> &pairhmm::GapParams {
+        &self.base().gap_params
+    }
+
+    fn max_window(&self) -> u64 {
+        self.base().max_window
+    }
+
+    /// Nearby candidate variants to consider for building local haplotypes.
+    fn haplotype_variants(&self) -> &[HaplotypeVariant] {
+        &self.base().haplotype_variants
+    }
 
-    fn max_window(&self) -> u64;
+    fn set_haplotype_variants(&mut self, variants: Vec<HaplotypeVariant>) {
+        self.base_mut().haplotype_variants = variants;
+    }
 }
 
+/// State that is shared by all realigners.
 #[derive(Clone)]
-pub(crate) struct PairHMMRealigner {
+pub(crate) struct RealignerBase {
     gap_params: pairhmm::GapParams,
-    pairhmm: PairHMM,
+    haplotype_variants: Vec<HaplotypeVariant>,
     max_window: u64,
     ref_buffer: Arc<reference::Buffer>,
 }
 
+impl RealignerBase {
+    pub(crate) fn new(
+        ref_buffer: Arc<reference::Buffer>,
+        gap_params: pairhmm::GapParams,
+        max_window: u64,
+    ) -> Self {
+        RealignerBase {
+            gap_params,
+            haplotype_variants: Vec::new(),
+            max_window,
+            ref_buffer,
+        }
+    }
+}
+
+/// Realigner that delegates reads to read group specific realigners (e.g. using error rates
+/// estimated per read group). Reads of other read groups or without read group are handled
+/// by the default realigner.
+#[derive(Clone)]
+pub(crate) struct ReadGroupRealigner<R> {
+    default: R,
+    read_groups: HashMap<String, R>,
+}
+
+impl<R: Realigner> ReadGroupRealigner<R> {
+    /// Create a new instance, using the given function to build the default realigner and
+    /// one realigner for each read group with specific gap parameters.
+    pub(crate) fn new<F>(
+        gap_params: pairhmm::GapParams,
+        read_group_gap_params: HashMap<String, pairhmm::GapParams>,
+        new_realigner: F,
+    ) -> Self
+    where
+        F: Fn(pairhmm::GapParams) -> R,
+    {
+        ReadGroupRealigner {
+            default: new_realigner(gap_params),
+            read_groups: read_group_gap_params
+                .into_iter()
+                .map(|(rg, gap_params)| (rg, new_realigner(gap_params)))
+                .collect(),
+        }
+    }
+
+    fn realigner(&mut self, record: &bam::Record) -> &mut R {
+        match read_group(record) {
+            Some(rg) if self.read_groups.contains_key(rg) => self.read_groups.get_mut(rg).unwrap(),
+            _ => &mut self.default,
+        }
+    }
+}
+
+impl<R: Realigner> Realigner for ReadGroupRealigner<R> {
+    fn allele_support<'a, V, L>(
+        &mut self,
+        record: &'a bam::Record,
+        loci: L,
+        variant: &V,
+    ) -> Result<AlleleSupport>
+    where
+        V: Realignable<'a>,
+        L: IntoIterator,
+        L::Item: AsRef<SingleLocus>,
+    {
+        self.realigner(record).allele_support(record, loci, variant)
+    }
+
+    fn base(&self) -> &RealignerBase {
+        self.default.base()
+    }
+
+    fn base_mut(&mut self) -> &mut RealignerBase {
+        self.default.base_mut()
+    }
+
+    fn set_haplotype_variants(&mut self, variants: Vec<HaplotypeVariant>) {
+        for realigner in self.read_groups.values_mut() {
+            realigner.set_haplotype_variants(variants.clone());
+        }
+        self.default.set_haplotype_variants(variants);
+    }
+
+    fn calculate_prob_allele<E>(&mut self, hit: &EditDistanceHit, allele_params: &mut E) -> LogProb
+    where
+        E: stats::pairhmm::EmissionParameters + pairhmm::RefBaseEmission,
+    {
+        self.default.calculate_prob_allele(hit, allele_params)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct PairHMMRealigner {
+    base: RealignerBase,
+    pairhmm: PairHMM,
+}
+
 impl PairHMMRealigner {
     /// Create a new instance.
     pub(crate) fn new(
@@ -403,21 +678,19 @@ impl PairHMMRealigner {
     ) -> Self {
         let pairhmm = PairHMM::new(&gap_params);
         PairHMMRealigner {
-            gap_params,
+            base: RealignerBase::new(ref_buffer, gap_params, max_window),
             pairhmm,
-            max_window,
-            ref_buffer,
         }
     }
 }
 
 impl Realigner for PairHMMRealigner {
-    fn ref_buffer(&self) -> &Arc<reference::Buffer> {
-        &self.ref_buffer
+    fn base(&self) -> &RealignerBase {
+        &self.base
     }
 
-    fn max_window(&self) -> u64 {
-        self.max_window
+    fn base_mut(&mut self) -> &mut RealignerBase {
+        &mut self.base
     }
 
     fn calculate_prob_allele<E>(&mut self, hit: &EditDistanceHit, allele_params: &mut E) -> LogProb
@@ -427,21 +700,80 @@ impl Realigner for PairHMMRealigner {
         // METHOD: We shrink the area to run the HMM against to an environment around the best
         // edit distance hits.
         allele_params.shrink_to_hit(&hit);
+        let allele_params = HomopolymerEmissionParams::new(allele_params, &self.base.gap_params);
 
         // METHOD: Further, we run the HMM on a band around the best edit distance.
         self.pairhmm.prob_related(
-            allele_params,
-            &self.gap_params,
+            &allele_params,
+            &self.base.gap_params,
             Some(hit.dist_upper_bound()),
         )
     }
 }
 
+/// Realigner that computes the same probabilities as the `PairHMMRealigner`, but uses
+/// a vectorized forward algorithm (see `simd::SimdPairHMM`).
+#[derive(Clone)]
+pub(crate) struct SimdPairHMMRealigner {
+    base: RealignerBase,
+    pairhmm: PairHMM,
+    simd_pairhmm: simd::SimdPairHMM,
+}
+
+impl SimdPairHMMRealigner {
+    /// Create a new instance.
+    pub(crate) fn new(
+        ref_buffer: Arc<reference::Buffer>,
+        gap_params: pairhmm::GapParams,
+        max_window: u64,
+    ) -> Self {
+        let pairhmm = PairHMM::new(&gap_params);
+        let simd_pairhmm = simd::SimdPairHMM::new(&gap_params);
+        SimdPairHMMRealigner {
+            base: RealignerBase::new(ref_buffer, gap_params, max_window),
+            pairhmm,
+            simd_pairhmm,
+        }
+    }
+}
+
+impl Realigner for SimdPairHMMRealigner {
+    fn base(&self) -> &RealignerBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut RealignerBase {
+        &mut self.base
+    }
+
+    fn calculate_prob_allele<E>(&mut self, hit: &EditDistanceHit, allele_params: &mut E) -> LogProb
+    where
+        E: stats::pairhmm::EmissionParameters + pairhmm::RefBaseEmission,
+    {
+        // METHOD: We shrink the area to run the HMM against to an environment around the best
+        // edit distance hits.
+        allele_params.shrink_to_hit(hit);
+        let allele_params = HomopolymerEmissionParams::new(allele_params, &self.base.gap_params);
+
+        // METHOD: Further, we run the HMM on a band around the best edit distance.
+        // If the probability underflows in linear space (e.g. for very long reads with
+        // many mismatches), we fall back to the log space implementation.
+        let max_edit_dist = Some(hit.dist_upper_bound());
+        if let Some(prob) = self
+            .simd_pairhmm
+            .prob_related(&allele_params, max_edit_dist)
+        {
+            prob
+        } else {
+            self.pairhmm
+                .prob_related(&allele_params, &self.base.gap_params, max_edit_dist)
+        }
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct PathHMMRealigner {
-    gap_params: pairhmm::GapParams,
-    max_window: u64,
-    ref_buffer: Arc<reference::Buffer>,
+    base: RealignerBase,
     prob_no_gap: LogProb,
     prob_close_gap_x: LogProb,
     prob_close_gap_y: LogProb,
@@ -468,9 +800,7 @@ impl PathHMMRealigner {
             .prob_gap_y_extend()
             .ln_add_exp(prob_close_gap_y + gap_params.prob_gap_y());
         PathHMMRealigner {
-            gap_params,
-            max_window,
-            ref_buffer,
+            base: RealignerBase::new(ref_buffer, gap_params, max_window),
             prob_no_gap,
             prob_close_gap_x,
             prob_close_gap_y,
@@ -481,18 +811,19 @@ impl PathHMMRealigner {
 }
 
 impl Realigner for PathHMMRealigner {
-    fn ref_buffer(&self) -> &Arc<reference::Buffer> {
-        &self.ref_buffer
+    fn base(&self) -> &RealignerBase {
+        &self.base
     }
 
-    fn max_window(&self) -> u64 {
-        self.max_window
+    fn base_mut(&mut self) -> &mut RealignerBase {
+        &mut self.base
     }
 
     fn calculate_prob_allele<E>(&mut self, hit: &EditDistanceHit, allele_params: &mut E) -> LogProb
     where
         E: stats::pairhmm::EmissionParameters + pairhmm::RefBaseEmission,
     {
+        let allele_params = HomopolymerEmissionParams::new(allele_params, &self.base.gap_params);
         let mut best_prob = None;
         for alignment in hit.alignments() {
             let mut prob = LogProb::ln_one();
@@ -528,10 +859,10 @@ impl Realigner for PathHMMRealigner {
                                 prob += self.prob_extend_or_reopen_gap_y;
                             },
                             Some(&AlignmentOperation::Ins) => {
-                                prob += self.prob_close_gap_x + self.gap_params.prob_gap_y();
+                                prob += self.prob_close_gap_x + self.base.gap_params.prob_gap_y();
                             },
                             None | Some(&AlignmentOperation::Match) | Some(&AlignmentOperation::Subst) => {
-                                prob += self.gap_params.prob_gap_y();
+                                prob += self.base.gap_params.prob_gap_y();
                             },
                             _ => (),
                         }
@@ -545,10 +876,10 @@ impl Realigner for PathHMMRealigner {
                                 prob += self.prob_extend_or_reopen_gap_x;
                             },
                             Some(&AlignmentOperation::Del) => {
-                                prob += self.prob_close_gap_y + self.gap_params.prob_gap_x();
+                                prob += self.prob_close_gap_y + self.base.gap_params.prob_gap_x();
                             },
                             None | Some(&AlignmentOperation::Match) | Some(&AlignmentOperation::Subst) => {
-                                prob += self.gap_params.prob_gap_x();
+                                prob += self.base.gap_params.prob_gap_x();
                             },
                             _ => (),
                         }
@@ -578,3 +909,211 @@ pub(crate) trait AltAlleleEmissionBuilder {
         ref_seq: &'a [u8],
     ) -> Self::EmissionParams;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::Write;
+
+    use bio::io::fasta;
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+
+    use crate::utils::NUMERICAL_EPSILON;
+    use crate::variants::sample::Platform;
+    use crate::variants::types::Deletion;
+
+    const CONTIG: &str = "chr1";
+    const MAX_WINDOW: u64 = 100;
+
+    /// Pseudo-random reference sequence.
+    fn ref_seq() -> Vec<u8> {
+        let mut state: u32 = 7;
+        (0..400)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b"ACGT"[(state >> 16) as usize % 4]
+            })
+            .collect()
+    }
+
+    fn ref_buffer(dir: &tempfile::TempDir) -> Arc<reference::Buffer> {
+        let seq = ref_seq();
+        let path = dir.path().join("ref.fa");
+        let mut fasta = fs::File::create(&path).unwrap();
+        writeln!(fasta, ">{}", CONTIG).unwrap();
+        fasta.write_all(&seq).unwrap();
+        writeln!(fasta).unwrap();
+        let mut fai = fs::File::create(dir.path().join("ref.fa.fai")).unwrap();
+        let offset = CONTIG.len() + 2;
+        writeln!(
+            fai,
+            "{}\t{}\t{}\t{}\t{}",
+            CONTIG,
+            seq.len(),
+            offset,
+            seq.len(),
+            seq.len() + 1
+        )
+        .unwrap();
+
+        Arc::new(reference::Buffer::new(
+            fasta::IndexedReader::from_file(&path).unwrap(),
+            1,
+        ))
+    }
+
+    fn record(pos: i64, cigar: Vec<Cigar>, seq: &[u8], read_group: Option<&[u8]>) -> bam::Record {
+        let mut header = bam::Header::new();
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", &CONTIG)
+                .push_tag(b"LN", &ref_seq().len()),
+        );
+        let qual = vec![30; seq.len()];
+        let mut record = bam::Record::new();
+        record.set(b"read", Some(&CigarString(cigar)), seq, &qual);
+        record.set_header(Rc::new(bam::HeaderView::from_header(&header)));
+        record.set_tid(0);
+        record.set_pos(pos);
+        record.set_mapq(60);
+        if let Some(read_group) = read_group {
+            record.push_aux(b"RG", &Aux::String(read_group));
+        }
+        record.cache_cigar();
+        record
+    }
+
+    /// A deletion of 5 bases after position 150.
+    fn deletion<R: Realigner>(realigner: R) -> Deletion<R> {
+        Deletion::new(
+            genome::Interval::new(CONTIG.to_owned(), 150..155),
+            realigner,
+        )
+    }
+
+    /// Read that carries the deletion.
+    fn alt_read(read_group: Option<&[u8]>) -> bam::Record {
+        let seq = ref_seq();
+        let read_seq = [&seq[90..151], &seq[156..216]].concat();
+        record(
+            90,
+            vec![Cigar::Match(61), Cigar::Del(5), Cigar::Match(60)],
+            &read_seq,
+            read_group,
+        )
+    }
+
+    /// Read that carries the reference allele.
+    fn ref_read() -> bam::Record {
+        let seq = ref_seq();
+        record(90, vec![Cigar::Match(121)], &seq[90..211], None)
+    }
+
+    fn allele_support<R: Realigner + Clone>(
+        realigner: &mut R,
+        record: &bam::Record,
+    ) -> AlleleSupport {
+        let variant = deletion(realigner.clone());
+        let locus = SingleLocus::new(genome::Interval::new(CONTIG.to_owned(), 150..155));
+        realigner
+            .allele_support(record, &[&locus], &variant)
+            .unwrap()
+    }
+
+    fn assert_supports_deletion<R: Realigner + Clone>(mut realigner: R) {
+        let alt = allele_support(&mut realigner, &alt_read(None));
+        assert!(alt.prob_alt_allele() > alt.prob_ref_allele());
+        let reference = allele_support(&mut realigner, &ref_read());
+        assert!(reference.prob_ref_allele() > reference.prob_alt_allele());
+    }
+
+    #[test]
+    fn test_pairhmm_realigner() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_supports_deletion(PairHMMRealigner::new(
+            ref_buffer(&dir),
+            pairhmm::GapParams::preset(Platform::Illumina),
+            MAX_WINDOW,
+        ));
+    }
+
+    #[test]
+    fn test_path_hmm_realigner() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_supports_deletion(PathHMMRealigner::new(
+            pairhmm::GapParams::preset(Platform::Illumina),
+            MAX_WINDOW,
+            ref_buffer(&dir),
+        ));
+    }
+
+    #[test]
+    fn test_simd_realigner_same_as_pairhmm() {
+        let dir = tempfile::tempdir().unwrap();
+        let ref_buffer = ref_buffer(&dir);
+        let gap_params = pairhmm::GapParams::preset(Platform::PacBioHiFi);
+        let mut pairhmm =
+            PairHMMRealigner::new(Arc::clone(&ref_buffer), gap_params.clone(), MAX_WINDOW);
+        let mut simd = SimdPairHMMRealigner::new(ref_buffer, gap_params, MAX_WINDOW);
+        assert_supports_deletion(simd.clone());
+
+        for record in &[alt_read(None), ref_read()] {
+            let expected = allele_support(&mut pairhmm, record);
+            let support = allele_support(&mut simd, record);
+            assert_relative_eq!(
+                *support.prob_alt_allele(),
+                *expected.prob_alt_allele(),
+                epsilon = NUMERICAL_EPSILON
+            );
+            assert_relative_eq!(
+                *support.prob_ref_allele(),
+                *expected.prob_ref_allele(),
+                epsilon = NUMERICAL_EPSILON
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_group_realigner() {
+        let dir = tempfile::tempdir().unwrap();
+        let ref_buffer = ref_buffer(&dir);
+        let gap_params = pairhmm::GapParams::preset(Platform::Illumina);
+        let mut read_group_gap_params = HashMap::new();
+        read_group_gap_params.insert(
+            "lib1".to_owned(),
+            pairhmm::GapParams::preset(Platform::Nanopore),
+        );
+        let new_realigner =
+            |gap_params| PairHMMRealigner::new(Arc::clone(&ref_buffer), gap_params, MAX_WINDOW);
+        let mut realigner =
+            ReadGroupRealigner::new(gap_params.clone(), read_group_gap_params, new_realigner);
+        assert_supports_deletion(realigner.clone());
+
+        // reads are realigned with the gap parameters of their read group
+        let mut default = new_realigner(gap_params);
+        let mut lib1 = new_realigner(pairhmm::GapParams::preset(Platform::Nanopore));
+        for (read_group, is_lib1) in &[
+            (None, false),
+            (Some(&b"lib1"[..]), true),
+            (Some(&b"lib2"[..]), false),
+        ] {
+            let expected = if *is_lib1 { &mut lib1 } else { &mut default };
+            let record = alt_read(*read_group);
+            let support = allele_support(&mut realigner, &record);
+            let expected = allele_support(expected, &record);
+            assert_eq!(support.prob_alt_allele(), expected.prob_alt_allele());
+            assert_eq!(support.prob_ref_allele(), expected.prob_ref_allele());
+        }
+
+        // haplotype variants are shared with all read group specific realigners
+        let variants = vec![HaplotypeVariant::new(160, 1, b"A".to_vec())];
+        realigner.set_haplotype_variants(variants.clone());
+        assert_eq!(realigner.haplotype_variants(), &variants[..]);
+        assert_eq!(
+            realigner.read_groups["lib1"].haplotype_variants(),
+            &variants[..]
+        );
+    }
+}
@@ -14,6 +14,7 @@ use rust_htslib::bam;
 
 use crate::variants::evidence::bases::prob_read_base_miscall;
 use crate::variants::evidence::realignment::edit_distance::EditDistanceHit;
+use crate::variants::sample::Platform;
 
 /// Width of band around alignment with optimal edit distance.
 pub(crate) const EDIT_BAND: usize = 2;
@@ -22,9 +23,19 @@ lazy_static! {
     static ref PROB_CONFUSION: LogProb = LogProb::from(Prob(0.3333));
 }
 
+/// Probability to emit a particular base from an unknown base.
+pub(crate) const PROB_RANDOM_BASE: f64 = 0.25;
+
 pub(crate) trait RefBaseEmission {
     fn ref_base(&self, i: usize) -> u8;
 
+    /// Whether the allele base at position i is unknown (e.g. within an insertion of which
+    /// only the flanks are known). Unknown bases are treated as random bases, emitting any
+    /// read base with the same probability.
+    fn is_unknown_base(&self, _i: usize) -> bool {
+        false
+    }
+
     fn ref_offset(&self) -> usize;
 
     fn ref_end(&self) -> usize;
@@ -76,6 +87,113 @@ pub(crate) struct GapParams {
     pub(crate) prob_deletion_artifact: LogProb,
     pub(crate) prob_insertion_extend_artifact: LogProb,
     pub(crate) prob_deletion_extend_artifact: LogProb,
+    /// Model for homopolymer length dependent indel errors (if considered by the platform).
+    pub(crate) homopolymer_indel_model: Option<HomopolymerIndelModel>,
+    /// Empirical mismatch rate (if estimated from the data). Miscall probabilities derived
+    /// from base qualities are not allowed to fall below it.
+    pub(crate) prob_mismatch_artifact: Option<LogProb>,
+}
+
+impl GapParams {
+    /// Gap parameters as typically observed for the given sequencing platform.
+    pub(crate) fn preset(platform: Platform) -> Self {
+        // METHOD: Illumina rates are taken from Schirmer et al. BMC Bioinformatics 2016.
+        // For the other platforms, rates reflect the typical indel error rates of current
+        // chemistries and basecallers. For Nanopore and Ion Torrent, indel errors are
+        // dominated by miscounted homopolymer lengths, which is captured by the
+        // homopolymer indel model.
+        let (ins, del, insext, delext, homopolymer_indel_model) = match platform {
+            Platform::Illumina => (2.8e-6, 5.1e-6, 0.0, 0.0, None),
+            Platform::PacBioHiFi => (5.0e-4, 5.0e-4, 0.1, 0.1, None),
+            Platform::Nanopore => (
+                1.0e-2,
+                1.5e-2,
+                0.2,
+                0.2,
+                Some(HomopolymerIndelModel::new(1.0, 20)),
+            ),
+            Platform::IonTorrent => (
+                1.0e-3,
+                1.0e-3,
+                0.1,
+                0.1,
+                Some(HomopolymerIndelModel::new(0.5, 15)),
+            ),
+        };
+        GapParams {
+            prob_insertion_artifact: LogProb::from(Prob(ins)),
+            prob_deletion_artifact: LogProb::from(Prob(del)),
+            prob_insertion_extend_artifact: LogProb::from(Prob(insext)),
+            prob_deletion_extend_artifact: LogProb::from(Prob(delext)),
+            homopolymer_indel_model,
+            prob_mismatch_artifact: None,
+        }
+    }
+}
+
+/// Model for indel errors that depend on the length of the homopolymer they occur in.
+/// Within a homopolymer of length l, the odds of a gap (extension) against the match
+/// transition are increased by the factor 1 + slope * (l - 1), where l is capped at the given
+/// maximum length.
+#[derive(Debug, Clone, Copy, new)]
+pub(crate) struct HomopolymerIndelModel {
+    slope: f64,
+    max_len: usize,
+}
+
+impl HomopolymerIndelModel {
+    /// Factor for the gap probability in a homopolymer of the given length, given the gap
+    /// extension probability.
+    pub(crate) fn prob_factor(&self, homopolymer_len: usize, prob_extend: LogProb) -> LogProb {
+        let len = cmp::min(cmp::max(homopolymer_len, 1), self.max_len);
+        let odds_factor = 1.0 + self.slope * (len - 1) as f64;
+        // METHOD: scaling the extension probability by the odds factor alone would yield
+        // values above 1 for long homopolymers and high extension probabilities (e.g. with
+        // Nanopore reads). Hence, we renormalize against the match transition, such that the
+        // effective extension probability p * f / (1 - p + p * f) stays below 1.
+        let prob_extend = prob_extend.exp();
+        LogProb((odds_factor / (1.0 - prob_extend + prob_extend * odds_factor)).ln())
+    }
+
+    /// Lengths of the homopolymers containing each position of the given sequence.
+    /// Lengths are capped at the maximum length.
+    pub(crate) fn homopolymer_lens<I>(&self, seq: I) -> Vec<usize>
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let mut lens: Vec<usize> = Vec::new();
+        let mut run_start = 0;
+        let mut run_base = None;
+        for (i, base) in seq.into_iter().enumerate() {
+            let base = base.to_ascii_uppercase();
+            if run_base != Some(base) {
+                self.finish_run(&mut lens, run_start);
+                run_start = i;
+                run_base = Some(base);
+            }
+            lens.push(0);
+        }
+        self.finish_run(&mut lens, run_start);
+        lens
+    }
+
+    fn finish_run(&self, lens: &mut [usize], run_start: usize) {
+        let run_len = cmp::min(lens.len() - run_start, self.max_len);
+        for len in &mut lens[run_start..] {
+            *len = run_len;
+        }
+    }
+
+    /// Gap probability factors for each position of the given sequence.
+    pub(crate) fn prob_factors<I>(&self, seq: I, prob_extend: LogProb) -> Vec<LogProb>
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        self.homopolymer_lens(seq)
+            .into_iter()
+            .map(|len| self.prob_factor(len, prob_extend))
+            .collect()
+    }
 }
 
 impl pairhmm::GapParameters for GapParams {
@@ -125,8 +243,12 @@ macro_rules! default_emission {
     () => {
         #[inline]
         fn prob_emit_xy(&self, i: usize, j: usize) -> bio::stats::pairhmm::XYEmission {
-            let r = self.ref_base(i);
-            self.read_emission.prob_match_mismatch(j, r)
+            if self.is_unknown_base(i) {
+                self.read_emission.prob_match_unknown(j)
+            } else {
+                let r = self.ref_base(i);
+                self.read_emission.prob_match_mismatch(j, r)
+            }
         }
 
         #[inline]
@@ -154,6 +276,8 @@ pub(crate) struct ReadEmission<'a> {
     no_miscall: Vec<LogProb>,
     read_offset: usize,
     read_end: usize,
+    /// Homopolymer dependent factors for insertions at each read position.
+    insertion_factors: Option<Vec<LogProb>>,
 }
 
 impl<'a> ReadEmission<'a> {
@@ -162,20 +286,38 @@ impl<'a> ReadEmission<'a> {
         qual: &[u8],
         read_offset: usize,
         read_end: usize,
+        gap_params: &GapParams,
     ) -> Self {
         let mut any_miscall = vec![LogProb::ln_zero(); read_end - read_offset];
         let mut no_miscall = any_miscall.clone();
         for (j, j_) in (read_offset..read_end).enumerate() {
-            let prob_miscall = prob_read_base_miscall(*unsafe { qual.get_unchecked(j_) });
+            let mut prob_miscall = prob_read_base_miscall(*unsafe { qual.get_unchecked(j_) });
+            // METHOD: basecallers tend to overestimate high base qualities. The empirical
+            // mismatch rate of the sample (if known) is hence used as a lower bound for the
+            // miscall probability of each base.
+            if let Some(prob_mismatch) = gap_params.prob_mismatch_artifact {
+                if prob_miscall < prob_mismatch {
+                    prob_miscall = prob_mismatch;
+                }
+            }
             any_miscall[j] = prob_miscall;
             no_miscall[j] = prob_miscall.ln_one_minus_exp();
         }
+        // METHOD: inserted bases are located in the read, hence the homopolymer
+        // context of insertions is taken from the read sequence (within the considered window).
+        let insertion_factors = gap_params.homopolymer_indel_model.map(|model| {
+            model.prob_factors(
+                (read_offset..read_end).map(|k| read_seq[k]),
+                gap_params.prob_insertion_extend_artifact,
+            )
+        });
         ReadEmission {
             read_seq,
             any_miscall,
             no_miscall,
             read_offset,
             read_end,
+            insertion_factors,
         }
     }
 
@@ -195,8 +337,21 @@ impl<'a> ReadEmission<'a> {
         }
     }
 
+    /// Calculate probability of read_base given an unknown ref_base.
+    /// METHOD: an unknown base is a random base, hence any read base is emitted with the
+    /// same probability, regardless of miscalls. It is reported as a match, such that
+    /// unknown sequence does not count towards the edit distance of banded alignment.
+    pub(crate) fn prob_match_unknown(&self, _j: usize) -> pairhmm::XYEmission {
+        pairhmm::XYEmission::Match(LogProb(PROB_RANDOM_BASE.ln()))
+    }
+
     pub(crate) fn prob_insertion(&self, j: usize) -> LogProb {
-        *unsafe { self.any_miscall.get_unchecked(j) }
+        let prob = *unsafe { self.any_miscall.get_unchecked(j) };
+        if let Some(ref insertion_factors) = self.insertion_factors {
+            prob + insertion_factors[j]
+        } else {
+            prob
+        }
     }
 
     #[inline]
@@ -208,6 +363,19 @@ impl<'a> ReadEmission<'a> {
     pub(crate) fn certainty_est(&self) -> LogProb {
         self.no_miscall.iter().sum()
     }
+
+    /// Decoded read base at position j of the window.
+    pub(crate) fn read_base(&self, j: usize) -> u8 {
+        self.read_seq[self.project_j(j)]
+    }
+
+    /// Probabilities (in linear space) of each read base in the window, given that it matches
+    /// or mismatches the reference base. This yields the same values as `prob_match_mismatch`.
+    pub(crate) fn linear_match_mismatch_probs(&self) -> (Vec<f64>, Vec<f64>) {
+        (0..self.no_miscall.len())
+            .map(|j| (self.no_miscall[j].exp(), self.particular_miscall(j).exp()))
+            .unzip()
+    }
 }
 
 /// Emission parameters for PairHMM over reference allele.
@@ -235,3 +403,142 @@ impl<'a> pairhmm::EmissionParameters for ReferenceEmissionParams<'a> {
         self.ref_end - self.ref_offset
     }
 }
+
+/// Emission parameters that additionally consider homopolymer dependent deletion errors.
+/// The homopolymer context is determined once for the current window of the wrapped
+/// allele, instead of once per cell of the dynamic programming matrix. Hence, the window
+/// should not be changed after construction.
+pub(crate) struct HomopolymerEmissionParams<'e, E> {
+    inner: &'e mut E,
+    deletion_factors: Option<Vec<LogProb>>,
+}
+
+impl<'e, E> HomopolymerEmissionParams<'e, E>
+where
+    E: pairhmm::EmissionParameters + RefBaseEmission,
+{
+    pub(crate) fn new(inner: &'e mut E, gap_params: &GapParams) -> Self {
+        // METHOD: deleted bases are located in the allele sequence, hence the homopolymer
+        // context of deletions is taken from there.
+        let deletion_factors = gap_params.homopolymer_indel_model.map(|model| {
+            model.prob_factors(
+                (0..inner.len_x()).map(|i| inner.ref_base(i)),
+                gap_params.prob_deletion_extend_artifact,
+            )
+        });
+        HomopolymerEmissionParams {
+            inner,
+            deletion_factors,
+        }
+    }
+}
+
+impl<'e, E> RefBaseEmission for HomopolymerEmissionParams<'e, E>
+where
+    E: pairhmm::EmissionParameters + RefBaseEmission,
+{
+    #[inline]
+    fn ref_base(&self, i: usize) -> u8 {
+        self.inner.ref_base(i)
+    }
+
+    #[inline]
+    fn is_unknown_base(&self, i: usize) -> bool {
+        self.inner.is_unknown_base(i)
+    }
+
+    fn ref_offset(&self) -> usize {
+        self.inner.ref_offset()
+    }
+
+    fn ref_end(&self) -> usize {
+        self.inner.ref_end()
+    }
+
+    fn set_ref_offset(&mut self, value: usize) {
+        self.inner.set_ref_offset(value)
+    }
+
+    fn set_ref_end(&mut self, value: usize) {
+        self.inner.set_ref_end(value)
+    }
+
+    fn read_emission(&self) -> &ReadEmission<'_> {
+        self.inner.read_emission()
+    }
+}
+
+impl<'e, E> pairhmm::EmissionParameters for HomopolymerEmissionParams<'e, E>
+where
+    E: pairhmm::EmissionParameters + RefBaseEmission,
+{
+    #[inline]
+    fn prob_emit_xy(&self, i: usize, j: usize) -> pairhmm::XYEmission {
+        self.inner.prob_emit_xy(i, j)
+    }
+
+    #[inline]
+    fn prob_emit_x(&self, i: usize) -> LogProb {
+        let prob = self.inner.prob_emit_x(i);
+        if let Some(ref deletion_factors) = self.deletion_factors {
+            prob + deletion_factors[i]
+        } else {
+            prob
+        }
+    }
+
+    #[inline]
+    fn prob_emit_y(&self, j: usize) -> LogProb {
+        self.inner.prob_emit_y(j)
+    }
+
+    #[inline]
+    fn len_x(&self) -> usize {
+        self.inner.len_x()
+    }
+
+    #[inline]
+    fn len_y(&self) -> usize {
+        self.inner.len_y()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_homopolymer_indel_model() {
+        let model = HomopolymerIndelModel::new(1.0, 5);
+        let seq = b"ACGTTTTGCAAAAAAAAT";
+        let lens = model.homopolymer_lens(seq.iter().cloned());
+        assert_eq!(lens.len(), seq.len());
+        assert_eq!(lens[0], 1);
+        assert_eq!(lens[3], 4);
+        assert_eq!(lens[6], 4);
+        // capped at the maximum length
+        assert_eq!(lens[12], 5);
+        assert_eq!(lens[17], 1);
+
+        // without gap extension, the factor is the odds factor itself
+        assert_eq!(model.prob_factor(1, LogProb::ln_zero()), LogProb::ln_one());
+        assert_relative_eq!(model.prob_factor(4, LogProb::ln_zero()).exp(), 4.0);
+        assert_relative_eq!(model.prob_factor(10, LogProb::ln_zero()).exp(), 5.0);
+    }
+
+    #[test]
+    fn test_homopolymer_indel_model_bounded() {
+        let gap_params = GapParams::preset(Platform::Nanopore);
+        let model = gap_params.homopolymer_indel_model.unwrap();
+        let prob_extend = gap_params.prob_deletion_extend_artifact;
+        assert_eq!(model.prob_factor(1, prob_extend), LogProb::ln_one());
+        let mut last = prob_extend;
+        for len in 2..30 {
+            let prob = prob_extend + model.prob_factor(len, prob_extend);
+            // increasing with the homopolymer length, but always a valid probability
+            assert!(prob >= last);
+            assert!(prob < LogProb::ln_one());
+            last = prob;
+        }
+    }
+}
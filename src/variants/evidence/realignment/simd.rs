@@ -0,0 +1,456 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A vectorized, banded implementation of the PairHMM forward algorithm.
+//!
+//! The algorithm computes the same probability as `bio::stats::pairhmm::PairHMM::prob_related`
+//! (semiglobal, i.e., with free start and end gaps in the reference), but works in linear
+//! instead of log space. Within a row of the dynamic programming matrix, match and deletion
+//! states only depend on the previous row, such that they can be computed with SIMD
+//! instructions. Depending on the CPU, an AVX2 or the default (SSE2 on x86_64) version of the
+//! inner loops is used, with a scalar fallback on other architectures.
+
+use std::cmp;
+
+use bio::stats::pairhmm::{EmissionParameters, GapParameters};
+use bio::stats::LogProb;
+
+use crate::variants::evidence::realignment::pairhmm::{
+    GapParams, RefBaseEmission, PROB_RANDOM_BASE,
+};
+
+lazy_static! {
+    /// Initial scaling of probabilities, such that they do not underflow in linear space.
+    /// Some headroom is left for summing up the end states over all reference positions.
+    static ref SCALE: f64 = 2.0f64.powi(1000);
+    static ref LN_SCALE: f64 = SCALE.ln();
+}
+
+/// Transition probabilities in linear space.
+#[derive(Debug, Clone, Copy)]
+struct Transitions {
+    no_gap: f64,
+    close_gap_x: f64,
+    close_gap_y: f64,
+    gap_x: f64,
+    gap_y: f64,
+    gap_x_extend: f64,
+    gap_y_extend: f64,
+}
+
+impl Transitions {
+    fn new(gap_params: &GapParams) -> Self {
+        Transitions {
+            no_gap: gap_params
+                .prob_gap_x()
+                .ln_add_exp(gap_params.prob_gap_y())
+                .ln_one_minus_exp()
+                .exp(),
+            close_gap_x: gap_params.prob_gap_x_extend().ln_one_minus_exp().exp(),
+            close_gap_y: gap_params.prob_gap_y_extend().ln_one_minus_exp().exp(),
+            gap_x: gap_params.prob_gap_x().exp(),
+            gap_y: gap_params.prob_gap_y().exp(),
+            gap_x_extend: gap_params.prob_gap_x_extend().exp(),
+            gap_y_extend: gap_params.prob_gap_y_extend().exp(),
+        }
+    }
+}
+
+/// Input of the forward algorithm, with all emissions in linear space.
+struct Input<'a> {
+    ref_bases: &'a [u8],
+    unknown_bases: &'a [bool],
+    prob_emit_x: &'a [f64],
+    read_bases: &'a [u8],
+    prob_match: &'a [f64],
+    prob_mismatch: &'a [f64],
+    prob_random_base: &'a [f64],
+    prob_emit_y: &'a [f64],
+    band: Option<usize>,
+}
+
+/// Rows of the dynamic programming matrix (match, deletion and insertion state).
+#[derive(Debug, Default, Clone)]
+struct Rows {
+    m: Vec<f64>,
+    x: Vec<f64>,
+    y: Vec<f64>,
+}
+
+impl Rows {
+    fn reset(&mut self, len: usize) {
+        for row in &mut [&mut self.m, &mut self.x, &mut self.y] {
+            row.clear();
+            row.resize(len, 0.0);
+        }
+    }
+}
+
+/// Vectorized PairHMM. Buffers are kept between invocations to avoid reallocation.
+#[derive(Debug, Clone)]
+pub(crate) struct SimdPairHMM {
+    transitions: Transitions,
+    prev: Rows,
+    curr: Rows,
+    ref_bases: Vec<u8>,
+    unknown_bases: Vec<bool>,
+    prob_emit_x: Vec<f64>,
+    read_bases: Vec<u8>,
+    prob_random_base: Vec<f64>,
+    prob_emit_y: Vec<f64>,
+}
+
+impl SimdPairHMM {
+    pub(crate) fn new(gap_params: &GapParams) -> Self {
+        SimdPairHMM {
+            transitions: Transitions::new(gap_params),
+            prev: Rows::default(),
+            curr: Rows::default(),
+            ref_bases: Vec::new(),
+            unknown_bases: Vec::new(),
+            prob_emit_x: Vec::new(),
+            read_bases: Vec::new(),
+            prob_random_base: Vec::new(),
+            prob_emit_y: Vec::new(),
+        }
+    }
+
+    /// Probability that the read is emitted from the given allele. The computation is
+    /// restricted to a band of the given edit distance around the diagonals of the possible
+    /// semiglobal alignments. Returns `None` if the probability cannot be represented in
+    /// linear space (i.e., it underflows), such that the caller can fall back to the
+    /// log space implementation.
+    pub(crate) fn prob_related<E>(
+        &mut self,
+        emission_params: &E,
+        max_edit_dist: Option<usize>,
+    ) -> Option<LogProb>
+    where
+        E: EmissionParameters + RefBaseEmission,
+    {
+        let len_x = emission_params.len_x();
+        let len_y = emission_params.len_y();
+        if len_y == 0 {
+            return Some(LogProb::ln_one());
+        }
+
+        let read_emission = emission_params.read_emission();
+        let (prob_match, prob_mismatch) = read_emission.linear_match_mismatch_probs();
+
+        self.ref_bases.clear();
+        self.ref_bases
+            .extend((0..len_x).map(|i| emission_params.ref_base(i).to_ascii_uppercase()));
+        self.unknown_bases.clear();
+        self.unknown_bases
+            .extend((0..len_x).map(|i| emission_params.is_unknown_base(i)));
+        self.prob_emit_x.clear();
+        self.prob_emit_x
+            .extend((0..len_x).map(|i| emission_params.prob_emit_x(i).exp()));
+        self.read_bases.clear();
+        self.read_bases
+            .extend((0..len_y).map(|j| read_emission.read_base(j)));
+        self.prob_random_base.clear();
+        self.prob_random_base.resize(len_y, PROB_RANDOM_BASE);
+        self.prob_emit_y.clear();
+        self.prob_emit_y
+            .extend((0..len_y).map(|j| emission_params.prob_emit_y(j).exp()));
+
+        self.prev.reset(len_y + 1);
+        self.curr.reset(len_y + 1);
+        self.prev.m[0] = *SCALE;
+
+        let input = Input {
+            ref_bases: &self.ref_bases,
+            unknown_bases: &self.unknown_bases,
+            prob_emit_x: &self.prob_emit_x,
+            read_bases: &self.read_bases,
+            prob_match: &prob_match,
+            prob_mismatch: &prob_mismatch,
+            prob_random_base: &self.prob_random_base,
+            prob_emit_y: &self.prob_emit_y,
+            band: max_edit_dist,
+        };
+
+        let prob = forward(&input, &self.transitions, &mut self.prev, &mut self.curr);
+
+        if prob > 0.0 && prob.is_finite() {
+            Some(LogProb(prob.ln() - *LN_SCALE))
+        } else {
+            None
+        }
+    }
+}
+
+/// Run the forward algorithm with the best available instruction set.
+fn forward(input: &Input, transitions: &Transitions, prev: &mut Rows, curr: &mut Rows) -> f64 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // This is safe because we have checked that the CPU supports AVX2.
+            return unsafe { forward_avx2(input, transitions, prev, curr) };
+        }
+    }
+    forward_default(input, transitions, prev, curr)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn forward_avx2(
+    input: &Input,
+    transitions: &Transitions,
+    prev: &mut Rows,
+    curr: &mut Rows,
+) -> f64 {
+    forward_impl(input, transitions, prev, curr)
+}
+
+fn forward_default(
+    input: &Input,
+    transitions: &Transitions,
+    prev: &mut Rows,
+    curr: &mut Rows,
+) -> f64 {
+    forward_impl(input, transitions, prev, curr)
+}
+
+/// Forward algorithm over rows of the reference (x) and columns of the read (y).
+/// The loops are written over equally sized slices, such that the compiler can vectorize
+/// them for the instruction set of the calling function.
+#[inline(always)]
+fn forward_impl(input: &Input, t: &Transitions, prev: &mut Rows, curr: &mut Rows) -> f64 {
+    let len_x = input.ref_bases.len();
+    let len_y = input.read_bases.len();
+    let mut prob = 0.0;
+
+    for i in 0..len_x {
+        // METHOD: free start gap in x, i.e., the alignment may start at any reference position.
+        curr.m[0] = *SCALE;
+        curr.x[0] = 0.0;
+        curr.y[0] = 0.0;
+
+        // METHOD: restrict to a band of diagonals, covering all semiglobal alignments
+        // with at most the given number of gaps.
+        let (lo, hi) = if let Some(band) = input.band {
+            let lo = (i + 1).saturating_sub(len_x.saturating_sub(len_y) + band);
+            let hi = cmp::min(i + 1 + band, len_y);
+            (cmp::max(lo, 1), hi)
+        } else {
+            (1, len_y)
+        };
+        for row in &mut [&mut curr.m, &mut curr.x, &mut curr.y] {
+            for v in &mut row[1..cmp::min(lo, len_y + 1)] {
+                *v = 0.0;
+            }
+            for v in &mut row[cmp::max(hi + 1, 1)..] {
+                *v = 0.0;
+            }
+        }
+
+        if lo <= hi {
+            // match and deletion states: only depend on the previous row
+            let n = hi - lo + 1;
+            let ref_base = input.ref_bases[i];
+            let prob_emit_x = input.prob_emit_x[i];
+            let read_bases = &input.read_bases[lo - 1..hi];
+            // unknown bases emit any read base with the same probability
+            let (prob_match, prob_mismatch) = if input.unknown_bases[i] {
+                let prob_random_base = &input.prob_random_base[lo - 1..hi];
+                (prob_random_base, prob_random_base)
+            } else {
+                (
+                    &input.prob_match[lo - 1..hi],
+                    &input.prob_mismatch[lo - 1..hi],
+                )
+            };
+            let prev_m_diag = &prev.m[lo - 1..hi];
+            let prev_x_diag = &prev.x[lo - 1..hi];
+            let prev_y_diag = &prev.y[lo - 1..hi];
+            let prev_m_up = &prev.m[lo..=hi];
+            let prev_x_up = &prev.x[lo..=hi];
+            let curr_m = &mut curr.m[lo..=hi];
+            let curr_x = &mut curr.x[lo..=hi];
+            assert!(
+                read_bases.len() == n
+                    && prob_match.len() == n
+                    && prob_mismatch.len() == n
+                    && prev_m_diag.len() == n
+                    && prev_x_diag.len() == n
+                    && prev_y_diag.len() == n
+                    && prev_m_up.len() == n
+                    && prev_x_up.len() == n
+                    && curr_m.len() == n
+                    && curr_x.len() == n
+            );
+            for k in 0..n {
+                let prob_emit_xy = if read_bases[k] == ref_base {
+                    prob_match[k]
+                } else {
+                    prob_mismatch[k]
+                };
+                curr_m[k] = prob_emit_xy
+                    * (t.no_gap * prev_m_diag[k]
+                        + t.close_gap_y * prev_x_diag[k]
+                        + t.close_gap_x * prev_y_diag[k]);
+                curr_x[k] = prob_emit_x * (t.gap_y * prev_m_up[k] + t.gap_y_extend * prev_x_up[k]);
+            }
+
+            // insertion states: depend on the previous column of the same row
+            for j in lo..=hi {
+                curr.y[j] = input.prob_emit_y[j - 1]
+                    * (t.gap_x * curr.m[j - 1] + t.gap_x_extend * curr.y[j - 1]);
+            }
+        }
+
+        // METHOD: free end gap in x, i.e., the alignment may end at any reference position.
+        prob += curr.m[len_y] + curr.x[len_y] + curr.y[len_y];
+
+        std::mem::swap(prev, curr);
+    }
+
+    prob
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use bio::stats::pairhmm::PairHMM;
+    use rust_htslib::bam;
+
+    use crate::{default_emission, default_ref_base_emission};
+
+    use crate::utils::NUMERICAL_EPSILON;
+    use crate::variants::evidence::realignment::pairhmm::{ReadEmission, ReferenceEmissionParams};
+
+    fn gap_params() -> GapParams {
+        GapParams {
+            prob_insertion_artifact: LogProb::from(bio::stats::Prob(2.8e-6)),
+            prob_deletion_artifact: LogProb::from(bio::stats::Prob(5.1e-6)),
+            prob_insertion_extend_artifact: LogProb::from(bio::stats::Prob(0.1)),
+            prob_deletion_extend_artifact: LogProb::from(bio::stats::Prob(0.1)),
+            homopolymer_indel_model: None,
+            prob_mismatch_artifact: None,
+        }
+    }
+
+    #[test]
+    fn test_same_as_pairhmm() {
+        let ref_seq = b"GATTACAGGCATCGATCGGCTAGCTACGATCGTTAGCGGCATCAGCTACGACTAGGCAT".to_vec();
+        let reads: Vec<&[u8]> = vec![
+            // exact match
+            b"ACAGGCATCGATCGGCTAGCTACGATCG",
+            // mismatch
+            b"ACAGGCATCGATCGGCTTGCTACGATCG",
+            // deletion
+            b"ACAGGCATCGATCGCTAGCTACGATCG",
+            // insertion
+            b"ACAGGCATCGATCGGGCTAGCTACGATCG",
+        ];
+        let gap_params = gap_params();
+        for read_seq in reads {
+            let qual = vec![30; read_seq.len()];
+            let mut record = bam::Record::new();
+            record.set(b"read", None, read_seq, &qual);
+            let read_emission = Rc::new(ReadEmission::new(
+                record.seq(),
+                record.qual(),
+                0,
+                read_seq.len(),
+                &gap_params,
+            ));
+            let params = ReferenceEmissionParams {
+                ref_seq: Arc::new(ref_seq.clone()),
+                ref_offset: 0,
+                ref_end: ref_seq.len(),
+                read_emission,
+            };
+
+            let mut pairhmm = PairHMM::new(&gap_params);
+            let expected = pairhmm.prob_related(&params, &gap_params, None);
+
+            let mut simd_pairhmm = SimdPairHMM::new(&gap_params);
+            let unbanded = simd_pairhmm.prob_related(&params, None).unwrap();
+            let banded = simd_pairhmm.prob_related(&params, Some(5)).unwrap();
+
+            assert_relative_eq!(*unbanded, *expected, epsilon = NUMERICAL_EPSILON);
+            assert_relative_eq!(*banded, *expected, epsilon = NUMERICAL_EPSILON);
+        }
+    }
+
+    /// Reference emission with unknown bases in the given range.
+    struct UnknownBasesEmissionParams<'a> {
+        ref_seq: Arc<Vec<u8>>,
+        ref_offset: usize,
+        ref_end: usize,
+        unknown: std::ops::Range<usize>,
+        read_emission: Rc<ReadEmission<'a>>,
+    }
+
+    impl<'a> RefBaseEmission for UnknownBasesEmissionParams<'a> {
+        fn ref_base(&self, i: usize) -> u8 {
+            self.ref_seq[i + self.ref_offset]
+        }
+
+        fn is_unknown_base(&self, i: usize) -> bool {
+            self.unknown.contains(&(i + self.ref_offset))
+        }
+
+        default_ref_base_emission!();
+    }
+
+    impl<'a> EmissionParameters for UnknownBasesEmissionParams<'a> {
+        default_emission!();
+
+        fn len_x(&self) -> usize {
+            self.ref_end - self.ref_offset
+        }
+    }
+
+    #[test]
+    fn test_unknown_bases() {
+        let ref_seq =
+            Arc::new(b"GATTACAGGCATCGATCGGCTAGCTACGATCGTTAGCGGCATCAGCTACGACTAGGCAT".to_vec());
+        let read_seq = b"ACAGGCATCGATCGGCTAGCTACGATCG";
+        let qual = vec![30; read_seq.len()];
+        let mut record = bam::Record::new();
+        record.set(b"read", None, read_seq, &qual);
+        let gap_params = gap_params();
+
+        let prob = |unknown: std::ops::Range<usize>| {
+            let params = UnknownBasesEmissionParams {
+                ref_seq: Arc::clone(&ref_seq),
+                ref_offset: 0,
+                ref_end: ref_seq.len(),
+                unknown,
+                read_emission: Rc::new(ReadEmission::new(
+                    record.seq(),
+                    record.qual(),
+                    0,
+                    read_seq.len(),
+                    &gap_params,
+                )),
+            };
+            let mut pairhmm = PairHMM::new(&gap_params);
+            let expected = pairhmm.prob_related(&params, &gap_params, None);
+            let mut simd_pairhmm = SimdPairHMM::new(&gap_params);
+            let prob = simd_pairhmm.prob_related(&params, None).unwrap();
+            assert_relative_eq!(*prob, *expected, epsilon = NUMERICAL_EPSILON);
+            prob
+        };
+
+        // METHOD: the read overlaps the 10 unknown bases, each of them emitting the read base
+        // with probability 1/4 instead of (almost) 1.
+        let known = prob(0..0);
+        let unknown = prob(10..20);
+        assert_relative_eq!(
+            *unknown - *known,
+            10.0 * PROB_RANDOM_BASE.ln(),
+            epsilon = 0.01
+        );
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Stutter model for tandem repeats (microsatellites). Polymerase slippage during PCR and
+//! sequencing yields reads that show a few repeat units more or less than the allele they
+//! stem from.
+
+use std::ops::Range;
+
+use bio::stats::{LogProb, Prob};
+use itertools::Itertools;
+
+use crate::variants::evidence::realignment::haplotypes::HaplotypeVariant;
+
+/// Maximum number of repeat units by which a read may differ from its allele due to stutter.
+pub(crate) const MAX_STUTTER_UNITS: u64 = 2;
+
+/// Logistic model for the probability of a stutter event: intercept, slope per base of tract
+/// length, and slope per base of unit length.
+const STUTTER_INTERCEPT: f64 = -5.0;
+const STUTTER_TRACT_SLOPE: f64 = 0.05;
+const STUTTER_UNIT_SLOPE: f64 = 0.5;
+
+/// Fraction of stutter events that remove repeat units.
+const STUTTER_CONTRACTION_FRAC: f64 = 2.0 / 3.0;
+
+/// Factor by which each additional unit of stutter becomes less likely.
+const STUTTER_DECAY: f64 = 0.1;
+
+/// The ref and the alt allele of a tandem repeat.
+#[derive(Debug, Clone, PartialEq, Eq, new, Getters, CopyGetters)]
+pub(crate) struct RepeatAlleles {
+    /// Start of the repeat tract in the reference (0-based).
+    #[getset(get_copy = "pub(crate)")]
+    start: u64,
+    #[getset(get = "pub(crate)")]
+    unit: Vec<u8>,
+    /// Number of repeat units in the reference.
+    #[getset(get_copy = "pub(crate)")]
+    ref_count: u64,
+    /// Number of repeat units of the alt allele.
+    #[getset(get_copy = "pub(crate)")]
+    alt_count: u64,
+}
+
+impl RepeatAlleles {
+    /// End of the repeat tract in the reference (exclusive).
+    pub(crate) fn ref_end(&self) -> u64 {
+        self.start + self.unit.len() as u64 * self.ref_count
+    }
+
+    /// Allele with the given number of repeat units, represented as an edit of the reference.
+    pub(crate) fn allele(&self, count: u64) -> HaplotypeVariant {
+        HaplotypeVariant::new(
+            self.start,
+            self.ref_end() - self.start,
+            self.unit.repeat(count as usize),
+        )
+    }
+
+    /// Whether the repeat tract (including a flanking base on each side) is located within
+    /// the given reference interval.
+    pub(crate) fn is_within(&self, interval: &Range<usize>) -> bool {
+        self.start > interval.start as u64 && self.ref_end() < interval.end as u64
+    }
+}
+
+/// Probability of a stutter event in a tract of the given number of units.
+///
+/// METHOD: stutter becomes more frequent with the length of the tract and less frequent with the
+/// length of the repeat unit (e.g. Fungtammasan et al. Genome Research 2015, Willems et al.
+/// Nature Methods 2017). This is modeled by a logistic function of both.
+fn prob_any_stutter(unit_len: usize, count: u64) -> f64 {
+    let tract_len = (unit_len as u64 * count) as f64;
+    let x =
+        STUTTER_INTERCEPT + STUTTER_TRACT_SLOPE * tract_len - STUTTER_UNIT_SLOPE * unit_len as f64;
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Probability that a read from an allele with the given number of units shows `delta` units
+/// more (or less, if negative).
+///
+/// METHOD: contractions are more frequent than expansions, and each additional unit of stutter
+/// is considerably less likely than the previous one.
+pub(crate) fn prob_stutter(unit_len: usize, count: u64, delta: i64) -> LogProb {
+    let prob_any = prob_any_stutter(unit_len, count);
+    let steps = delta.unsigned_abs();
+    if steps == 0 {
+        LogProb::from(Prob(1.0 - prob_any))
+    } else if steps > MAX_STUTTER_UNITS {
+        LogProb::ln_zero()
+    } else {
+        let direction = if delta < 0 {
+            STUTTER_CONTRACTION_FRAC
+        } else {
+            1.0 - STUTTER_CONTRACTION_FRAC
+        };
+        let normalization: f64 = (0..MAX_STUTTER_UNITS)
+            .map(|i| STUTTER_DECAY.powi(i as i32))
+            .sum();
+        LogProb::from(Prob(
+            prob_any * direction * STUTTER_DECAY.powi(steps as i32 - 1) / normalization,
+        ))
+    }
+}
+
+/// Repeat counts that may be observed in reads from an allele with the given number of units,
+/// together with their probabilities.
+pub(crate) fn stutter_counts(unit_len: usize, count: u64) -> Vec<(u64, LogProb)> {
+    let max = MAX_STUTTER_UNITS as i64;
+    let counts = (-max..=max)
+        .filter(|delta| count as i64 + delta >= 0)
+        .map(|delta| {
+            (
+                (count as i64 + delta) as u64,
+                prob_stutter(unit_len, count, delta),
+            )
+        })
+        .collect_vec();
+    // METHOD: short tracts cannot contract below zero units. The probability mass of such
+    // contractions is redistributed over the remaining counts by renormalization.
+    let total = LogProb::ln_sum_exp(&counts.iter().map(|(_, prob)| *prob).collect_vec());
+    counts
+        .into_iter()
+        .map(|(observed_count, prob)| (observed_count, prob - total))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prob_stutter() {
+        for count in &[0, 1, 20] {
+            let total = LogProb::ln_sum_exp(
+                &stutter_counts(2, *count)
+                    .into_iter()
+                    .map(|(_, prob)| prob)
+                    .collect::<Vec<_>>(),
+            );
+            assert_relative_eq!(total.exp(), 1.0, epsilon = 0.0000001);
+        }
+        assert_eq!(
+            stutter_counts(2, 1)
+                .into_iter()
+                .map(|(count, _)| count)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+
+        // longer tracts stutter more
+        assert!(prob_stutter(2, 30, -1) > prob_stutter(2, 10, -1));
+        // longer units stutter less
+        assert!(prob_stutter(4, 10, -1) < prob_stutter(2, 20, -1));
+        // contractions are more likely than expansions
+        assert!(prob_stutter(2, 20, -1) > prob_stutter(2, 20, 1));
+        assert!(prob_stutter(2, 20, 1) > prob_stutter(2, 20, 2));
+    }
+
+    #[test]
+    fn test_repeat_alleles() {
+        let repeat = RepeatAlleles::new(10, b"CA".to_vec(), 5, 7);
+        assert_eq!(repeat.ref_end(), 20);
+        assert_eq!(repeat.allele(7).alt_seq(), &b"CACACACACACACA".to_vec());
+        assert!(repeat.is_within(&(0..30)));
+        assert!(!repeat.is_within(&(10..30)));
+    }
+}
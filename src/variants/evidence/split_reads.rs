@@ -0,0 +1,157 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Split-read evidence from supplementary alignments of chimeric reads (SA tag).
+
+use std::str;
+
+use bio::stats::{LogProb, PHREDProb};
+use bio_types::genome::{self, AbstractLocus};
+use rust_htslib::bam;
+use rust_htslib::bam::record::Cigar;
+
+/// Maximum distance between an alignment end and a breakend for the alignment to be
+/// considered as split at the breakend.
+pub(crate) const MAX_SPLIT_DISTANCE: u64 = 10;
+
+/// A supplementary alignment of a read, as given by the SA tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SupplementaryAlignment {
+    pub(crate) contig: String,
+    /// Start position (0-based).
+    pub(crate) pos: u64,
+    /// End position (0-based, exclusive).
+    pub(crate) end: u64,
+    pub(crate) is_reverse: bool,
+    pub(crate) mapq: u8,
+}
+
+impl SupplementaryAlignment {
+    /// Parse a single entry of the SA tag (rname,pos,strand,CIGAR,mapQ,NM).
+    fn parse(entry: &str) -> Option<Self> {
+        let fields: Vec<_> = entry.split(',').collect();
+        if fields.len() != 6 {
+            return None;
+        }
+        let pos = fields[1].parse::<u64>().ok()?.checked_sub(1)?;
+        let is_reverse = match fields[2] {
+            "+" => false,
+            "-" => true,
+            _ => return None,
+        };
+        Some(SupplementaryAlignment {
+            contig: fields[0].to_owned(),
+            pos,
+            end: pos + cigar_ref_len(fields[3])?,
+            is_reverse,
+            mapq: fields[4].parse().ok()?,
+        })
+    }
+
+    /// Whether the alignment ends (if `at_end` is true) or starts (otherwise) at the given locus.
+    pub(crate) fn is_split_at(&self, locus: &genome::Locus, at_end: bool) -> bool {
+        self.contig == locus.contig() && is_near_boundary(self.pos, self.end, locus.pos(), at_end)
+    }
+
+    /// Probability that the supplementary alignment is wrong.
+    pub(crate) fn prob_mismapping(&self) -> LogProb {
+        LogProb::from(PHREDProb(self.mapq as f64))
+    }
+}
+
+/// Whether the end (if `at_end` is true) or the start (otherwise) of the given alignment
+/// interval is close to the given position.
+pub(crate) fn is_near_boundary(start: u64, end: u64, pos: u64, at_end: bool) -> bool {
+    if at_end {
+        end.abs_diff(pos + 1) <= MAX_SPLIT_DISTANCE
+    } else {
+        start.abs_diff(pos) <= MAX_SPLIT_DISTANCE
+    }
+}
+
+/// Whether the given read is clipped at its end (if `at_end` is true) or at its start
+/// (otherwise), in reference orientation.
+pub(crate) fn is_clipped(record: &bam::Record, at_end: bool) -> bool {
+    let cigar = record.cigar_cached().unwrap();
+    let op = if at_end { cigar.last() } else { cigar.first() };
+    matches!(op, Some(Cigar::SoftClip(_)) | Some(Cigar::HardClip(_)))
+}
+
+/// Supplementary alignments of the given read (empty if there is no SA tag).
+pub(crate) fn supplementary_alignments(record: &bam::Record) -> Vec<SupplementaryAlignment> {
+    if let Some(bam::record::Aux::String(sa)) = record.aux(b"SA") {
+        str::from_utf8(sa)
+            .map(|sa| {
+                sa.split(';')
+                    .filter(|entry| !entry.is_empty())
+                    .filter_map(SupplementaryAlignment::parse)
+                    .collect()
+            })
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Number of reference bases covered by the given CIGAR string.
+pub(crate) fn cigar_ref_len(cigar: &str) -> Option<u64> {
+    let mut len = 0;
+    let mut op_len = 0;
+    for c in cigar.chars() {
+        if let Some(d) = c.to_digit(10) {
+            op_len = op_len * 10 + d as u64;
+        } else {
+            if "MDN=X".contains(c) {
+                len += op_len;
+            } else if !"ISHP".contains(c) {
+                return None;
+            }
+            op_len = 0;
+        }
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_supplementary_alignment() {
+        let sa = SupplementaryAlignment::parse("chr2,1001,-,50S30M2D20M,60,3").unwrap();
+        assert_eq!(sa.contig, "chr2");
+        assert_eq!(sa.pos, 1000);
+        assert_eq!(sa.end, 1052);
+        assert!(sa.is_reverse);
+        assert_eq!(sa.mapq, 60);
+
+        assert!(sa.is_split_at(&genome::Locus::new("chr2".to_owned(), 1003), false));
+        assert!(!sa.is_split_at(&genome::Locus::new("chr2".to_owned(), 1003), true));
+        assert!(sa.is_split_at(&genome::Locus::new("chr2".to_owned(), 1051), true));
+        assert!(!sa.is_split_at(&genome::Locus::new("chr2".to_owned(), 1051), false));
+        assert!(!sa.is_split_at(&genome::Locus::new("chr2".to_owned(), 1025), false));
+        assert!(!sa.is_split_at(&genome::Locus::new("chr2".to_owned(), 1025), true));
+        assert!(!sa.is_split_at(&genome::Locus::new("chr1".to_owned(), 1000), false));
+
+        assert!(SupplementaryAlignment::parse("chr2,1001,-,50S50M").is_none());
+    }
+
+    #[test]
+    fn test_is_clipped() {
+        let mut record = bam::Record::new();
+        record.set(
+            b"read",
+            Some(&bam::record::CigarString(vec![
+                Cigar::Match(30),
+                Cigar::SoftClip(20),
+            ])),
+            &[b'A'; 50],
+            &[30; 50],
+        );
+        record.cache_cigar();
+        assert!(is_clipped(&record, true));
+        assert!(!is_clipped(&record, false));
+    }
+}
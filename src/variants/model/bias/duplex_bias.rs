@@ -0,0 +1,163 @@
+use bio::stats::probs::{LogProb, Prob};
+
+use crate::variants::evidence::observation::{Observation, ReadPosition};
+use crate::variants::model::bias::Bias;
+
+#[derive(Copy, Clone, PartialOrd, PartialEq, Eq, Debug, Ord, EnumIter, Default)]
+pub(crate) enum DuplexBias {
+    #[default]
+    None,
+    SingleStrand,
+}
+
+impl DuplexBias {
+    /// Probability to observe the duplex state of the given observation in the absence of
+    /// any bias.
+    fn prob_unbiased(observation: &Observation<ReadPosition>) -> LogProb {
+        match (observation.duplex, observation.family_size > 1) {
+            (true, _) => observation.prob_duplex_recovery,
+            (false, true) => observation.prob_duplex_recovery.ln_one_minus_exp(),
+            // Singletons cannot be seen on both strands, hence they do not tell anything.
+            (false, false) => LogProb::ln_one(),
+        }
+    }
+}
+
+impl Bias for DuplexBias {
+    fn prob(&self, observation: &Observation<ReadPosition>) -> LogProb {
+        match (self, observation.duplex, observation.family_size > 1) {
+            (DuplexBias::None, _, _) => Self::prob_unbiased(observation), // normal
+            (DuplexBias::SingleStrand, true, _) => LogProb::ln_zero(),    // no bias
+            (DuplexBias::SingleStrand, false, true) => LogProb::ln_one(), // bias
+            (DuplexBias::SingleStrand, false, false) => LogProb::ln_one(),
+        }
+    }
+
+    fn prob_any(&self, observation: &Observation<ReadPosition>) -> LogProb {
+        Self::prob_unbiased(observation)
+    }
+
+    fn is_artifact(&self) -> bool {
+        *self != DuplexBias::None
+    }
+
+    fn is_informative(&self, pileups: &[Vec<Observation<ReadPosition>>]) -> bool {
+        if let DuplexBias::None = *self {
+            return true;
+        }
+        // METHOD: duplex bias is only informative if the sample has been sequenced with a
+        // duplex protocol, i.e., if at least one UMI family has been seen on both strands
+        // of the original molecule. Otherwise, all families are single stranded by design
+        // and alt support on one strand only cannot be distinguished from a genuine variant.
+        pileups
+            .iter()
+            .flatten()
+            .any(|observation| observation.duplex)
+    }
+}
+
+/// Estimator for the duplex recovery rate of a sample, i.e. the probability that a UMI family
+/// (with more than one member) contains reads from both strands of the original molecule.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DuplexRecoveryEstimator {
+    families: u64,
+    duplex_families: u64,
+}
+
+impl DuplexRecoveryEstimator {
+    pub(crate) fn register(&mut self, observations: &[Observation<ReadPosition>]) {
+        for observation in observations.iter().filter(|obs| obs.family_size > 1) {
+            self.families += 1;
+            if observation.duplex {
+                self.duplex_families += 1;
+            }
+        }
+    }
+
+    /// Estimate the duplex recovery rate, or `None` if no UMI family has been observed.
+    pub(crate) fn estimate(&self) -> Option<LogProb> {
+        if self.families == 0 {
+            None
+        } else {
+            // METHOD: add a pseudocount for either state, such that neither of them becomes
+            // impossible in the absence of bias.
+            Some(LogProb::from(Prob(
+                (self.duplex_families + 1) as f64 / (self.families + 2) as f64,
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variants::model;
+
+    fn observation(
+        family_size: u32,
+        duplex: bool,
+        prob_duplex_recovery: f64,
+    ) -> Observation<ReadPosition> {
+        model::tests::observation_builder(LogProb::ln_one(), LogProb::ln_one(), LogProb::ln_zero())
+            .read_position(ReadPosition::Some)
+            .family_size(family_size)
+            .duplex(duplex)
+            .prob_duplex_recovery(LogProb(prob_duplex_recovery.ln()))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_prob_duplex_family() {
+        let obs = observation(4, true, 0.3);
+        assert_relative_eq!(DuplexBias::None.prob(&obs).exp(), 0.3, epsilon = 1e-9);
+        assert_relative_eq!(DuplexBias::None.prob_any(&obs).exp(), 0.3, epsilon = 1e-9);
+        assert_eq!(DuplexBias::SingleStrand.prob(&obs), LogProb::ln_zero());
+        assert_relative_eq!(
+            DuplexBias::SingleStrand.prob_any(&obs).exp(),
+            0.3,
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn test_prob_single_strand_family() {
+        let obs = observation(4, false, 0.3);
+        assert_relative_eq!(DuplexBias::None.prob(&obs).exp(), 0.7, epsilon = 1e-9);
+        assert_eq!(DuplexBias::SingleStrand.prob(&obs), LogProb::ln_one());
+        assert_relative_eq!(
+            DuplexBias::SingleStrand.prob_any(&obs).exp(),
+            0.7,
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn test_prob_singleton() {
+        // Singletons are uninformative, hence all states have the same likelihood.
+        let obs = observation(1, false, 0.3);
+        assert_eq!(DuplexBias::None.prob(&obs), LogProb::ln_one());
+        assert_eq!(DuplexBias::SingleStrand.prob(&obs), LogProb::ln_one());
+        assert_eq!(DuplexBias::SingleStrand.prob_any(&obs), LogProb::ln_one());
+    }
+
+    #[test]
+    fn test_duplex_recovery_estimator() {
+        let mut estimator = DuplexRecoveryEstimator::default();
+        assert!(estimator.estimate().is_none());
+
+        estimator.register(&[
+            observation(1, false, 0.5),
+            observation(3, true, 0.5),
+            observation(2, false, 0.5),
+            observation(5, true, 0.5),
+            observation(2, true, 0.5),
+        ]);
+        // 3 of 4 families are duplex, plus one pseudocount per state.
+        assert_relative_eq!(
+            estimator.estimate().unwrap().exp(),
+            4.0 / 6.0,
+            epsilon = 1e-9
+        );
+    }
+}
@@ -6,10 +6,12 @@ use strum::IntoEnumIterator;
 
 use crate::variants::evidence::observation::{Observation, ReadPosition};
 
+pub(crate) mod duplex_bias;
 pub(crate) mod read_orientation_bias;
 pub(crate) mod read_position_bias;
 pub(crate) mod strand_bias;
 
+pub(crate) use duplex_bias::{DuplexBias, DuplexRecoveryEstimator};
 pub(crate) use read_orientation_bias::ReadOrientationBias;
 pub(crate) use read_position_bias::ReadPositionBias;
 pub(crate) use strand_bias::StrandBias;
@@ -43,6 +45,8 @@ pub(crate) struct Biases {
     read_orientation_bias: ReadOrientationBias,
     #[getset(get = "pub(crate)")]
     read_position_bias: ReadPositionBias,
+    #[getset(get = "pub(crate)")]
+    duplex_bias: DuplexBias,
 }
 
 impl Biases {
@@ -50,8 +54,12 @@ impl Biases {
         consider_read_orientation_bias: bool,
         consider_strand_bias: bool,
         consider_read_position_bias: bool,
+        consider_duplex_bias: bool,
     ) -> Box<dyn Iterator<Item = Self>> {
-        if !consider_strand_bias && !consider_read_orientation_bias && !consider_read_position_bias
+        if !consider_strand_bias
+            && !consider_read_orientation_bias
+            && !consider_read_position_bias
+            && !consider_duplex_bias
         {
             return Box::new(std::iter::empty());
         }
@@ -71,23 +79,41 @@ impl Biases {
         } else {
             vec![ReadOrientationBias::None]
         };
+        let duplex_biases = if consider_duplex_bias {
+            DuplexBias::iter().collect_vec()
+        } else {
+            vec![DuplexBias::None]
+        };
 
         Box::new(
             strand_biases
                 .into_iter()
                 .cartesian_product(read_orientation_biases.into_iter())
                 .cartesian_product(read_position_biases.into_iter())
-                .filter_map(|((sb, rob), rpb)| {
-                    match (sb.is_artifact(), rob.is_artifact(), rpb.is_artifact()) {
-                        (true, false, false) | (false, true, false) | (false, false, true) => Some(
+                .cartesian_product(duplex_biases)
+                .filter_map(|(((sb, rob), rpb), db)| {
+                    // METHOD: exactly one artifact per combination.
+                    let n_artifacts = [
+                        sb.is_artifact(),
+                        rob.is_artifact(),
+                        rpb.is_artifact(),
+                        db.is_artifact(),
+                    ]
+                    .iter()
+                    .filter(|is_artifact| **is_artifact)
+                    .count();
+                    if n_artifacts == 1 {
+                        Some(
                             BiasesBuilder::default()
                                 .strand_bias(sb)
                                 .read_orientation_bias(rob)
                                 .read_position_bias(rpb)
+                                .duplex_bias(db)
                                 .build()
                                 .unwrap(),
-                        ),
-                        _ => None,
+                        )
+                    } else {
+                        None
                     }
                 }),
         )
@@ -98,6 +124,7 @@ impl Biases {
             .strand_bias(StrandBias::None)
             .read_orientation_bias(ReadOrientationBias::None)
             .read_position_bias(ReadPositionBias::None)
+            .duplex_bias(DuplexBias::None)
             .build()
             .unwrap()
     }
@@ -106,12 +133,14 @@ impl Biases {
         self.strand_bias.is_possible(pileups)
             && self.read_orientation_bias.is_possible(pileups)
             && self.read_position_bias.is_possible(pileups)
+            && self.duplex_bias.is_possible(pileups)
     }
 
     pub(crate) fn is_informative(&self, pileups: &[Vec<Observation<ReadPosition>>]) -> bool {
         self.strand_bias.is_informative(pileups)
             && self.read_orientation_bias.is_informative(pileups)
             && self.read_position_bias.is_informative(pileups)
+            && self.duplex_bias.is_informative(pileups)
     }
 
     pub(crate) fn prob(&self, observation: &Observation<ReadPosition>) -> LogProb {
@@ -119,17 +148,20 @@ impl Biases {
         self.strand_bias.prob(observation)
             + self.read_orientation_bias.prob(observation)
             + self.read_position_bias.prob(observation)
+            + self.duplex_bias.prob(observation)
     }
 
     pub(crate) fn prob_any(&self, observation: &Observation<ReadPosition>) -> LogProb {
         self.strand_bias.prob_any(observation)
             + self.read_orientation_bias.prob_any(observation)
             + self.read_position_bias.prob_any(observation)
+            + self.duplex_bias.prob_any(observation)
     }
 
     pub(crate) fn is_artifact(&self) -> bool {
         self.strand_bias.is_artifact()
             || self.read_orientation_bias.is_artifact()
             || self.read_position_bias.is_artifact()
+            || self.duplex_bias.is_artifact()
     }
 }
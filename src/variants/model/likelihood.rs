@@ -6,16 +6,30 @@
 use std::collections::BTreeMap;
 
 use bio::stats::{bayesian::model::Likelihood, LogProb};
+use itertools::Itertools;
 
 use crate::utils::NUMERICAL_EPSILON;
 use crate::variants::evidence::observation::{Observation, ReadPosition};
 use crate::variants::model::bias::Biases;
 use crate::variants::model::AlleleFreq;
 use crate::variants::sample::Pileup;
+use crate::variants::sampling_bias::fragments::isize_pmf;
 
 pub(crate) type ContaminatedSampleCache = BTreeMap<ContaminatedSampleEvent, LogProb>;
 pub(crate) type SingleSampleCache = BTreeMap<Event, LogProb>;
 
+/// Minimum standard deviation of learned fragment length distributions. This avoids
+/// degenerate distributions if only few fragments are available.
+const MIN_FRAGMENT_LENGTH_SD: f64 = 5.0;
+/// Minimum expected number of fragments needed for learning a fragment length distribution.
+const MIN_FRAGMENT_LENGTH_OBS: f64 = 2.0;
+/// Minimum posterior probability of a fragment to stem from an allele, such that it is used
+/// for learning the fragment length distribution of that allele.
+const MIN_PROB_FRAGMENT_ALLELE: f64 = 0.95;
+/// Maximum fraction of alt fragments at a locus, such that the alt allele is considered
+/// somatic when learning the alt fragment length distribution.
+const MAX_SOMATIC_ALT_FRACTION: f64 = 0.25;
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub(crate) struct Event {
     pub(crate) allele_freq: AlleleFreq,
@@ -34,6 +48,135 @@ fn prob_sample_alt(observation: &Observation<ReadPosition>, allele_freq: LogProb
     }
 }
 
+/// Normal distribution of fragment lengths.
+#[derive(Clone, Copy, Debug)]
+struct FragmentLengthDistribution {
+    mean: f64,
+    sd: f64,
+}
+
+impl FragmentLengthDistribution {
+    fn prob(&self, length: u32) -> LogProb {
+        isize_pmf(length as f64, self.mean, self.sd)
+    }
+}
+
+/// Weighted moments of fragment lengths, used to estimate a `FragmentLengthDistribution`.
+#[derive(Clone, Copy, Debug, Default)]
+struct FragmentLengthMoments {
+    total: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl FragmentLengthMoments {
+    fn register(&mut self, length: f64, weight: f64) {
+        self.total += weight;
+        self.sum += weight * length;
+        self.sum_squares += weight * length.powi(2);
+    }
+
+    fn distribution(&self) -> Option<FragmentLengthDistribution> {
+        if self.total < MIN_FRAGMENT_LENGTH_OBS {
+            return None;
+        }
+        let mean = self.sum / self.total;
+        let var = (self.sum_squares / self.total - mean.powi(2)).max(0.0);
+
+        Some(FragmentLengthDistribution {
+            mean,
+            sd: var.sqrt().max(MIN_FRAGMENT_LENGTH_SD),
+        })
+    }
+}
+
+/// Fragment length distributions of fragments supporting the alt and the ref allele.
+/// This is useful if alt fragments are expected to differ in length, e.g. tumor-derived
+/// cell-free DNA fragments being shorter than those from healthy cells.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FragmentLengthModel {
+    alt: FragmentLengthDistribution,
+    reference: FragmentLengthDistribution,
+}
+
+/// Learns a `FragmentLengthModel` from the pileups of all loci of a sample.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FragmentLengthModelEstimator {
+    alt: FragmentLengthMoments,
+    reference: FragmentLengthMoments,
+}
+
+impl FragmentLengthModelEstimator {
+    /// Register the fragments of the given pileup.
+    pub(crate) fn register(&mut self, pileup: &[Observation<ReadPosition>]) {
+        // METHOD: only fragments that confidently support one of the alleles (posterior
+        // assuming a flat prior, including the probability of being mapped correctly) carry
+        // information about the fragment lengths of that allele.
+        let prob_confident = LogProb(MIN_PROB_FRAGMENT_ALLELE.ln());
+        let confident = |obs: &Observation<ReadPosition>, prob_allele: LogProb| {
+            let total = obs.prob_alt.ln_add_exp(obs.prob_ref);
+            total != LogProb::ln_zero()
+                && obs.prob_mapping() + prob_allele - total >= prob_confident
+        };
+        let fragments = pileup
+            .iter()
+            .filter_map(|obs| {
+                obs.fragment_length.map(|length| {
+                    (
+                        length as f64,
+                        confident(obs, obs.prob_alt),
+                        confident(obs, obs.prob_ref),
+                    )
+                })
+            })
+            .collect_vec();
+
+        // METHOD: germline variants are carried by all cells, such that their alt fragments
+        // follow the ref fragment length distribution. Hence, the alt distribution is only
+        // learned from loci where a minority of fragments supports the alt allele, i.e.
+        // likely somatic variants.
+        let alt_count = fragments.iter().filter(|(_, is_alt, _)| *is_alt).count();
+        let ref_count = fragments.iter().filter(|(_, _, is_ref)| *is_ref).count();
+        let is_somatic = alt_count > 0
+            && (alt_count as f64 / (alt_count + ref_count) as f64) <= MAX_SOMATIC_ALT_FRACTION;
+
+        for (length, is_alt, is_ref) in fragments {
+            if is_alt && is_somatic {
+                self.alt.register(length, 1.0);
+            } else if is_ref {
+                self.reference.register(length, 1.0);
+            }
+        }
+    }
+
+    /// Estimate the model from all registered fragments. If there is not enough alt
+    /// evidence, the alt distribution is assumed to be the same as the ref distribution,
+    /// which renders the fragment length uninformative.
+    pub(crate) fn estimate(&self) -> Option<FragmentLengthModel> {
+        let reference = self.reference.distribution()?;
+        let alt = self.alt.distribution().unwrap_or(reference);
+
+        Some(FragmentLengthModel { alt, reference })
+    }
+}
+
+impl FragmentLengthModel {
+    /// Probabilities of the fragment length of the given observation, given that the fragment
+    /// comes from the alt or the ref allele, respectively.
+    fn probs(&self, observation: &Observation<ReadPosition>) -> (LogProb, LogProb) {
+        if let Some(length) = observation.fragment_length {
+            let prob_alt = self.alt.prob(length);
+            let prob_ref = self.reference.prob(length);
+            if prob_alt != LogProb::ln_zero() || prob_ref != LogProb::ln_zero() {
+                return (prob_alt, prob_ref);
+            }
+        }
+        // METHOD: unknown fragment length or fragment length outside of the numerical
+        // resolution of both distributions, hence uninformative.
+        (LogProb::ln_one(), LogProb::ln_one())
+    }
+}
+
 pub(crate) trait ContaminatedSamplePairView<T> {
     fn primary(&self) -> &T;
     fn secondary(&self) -> &T;
@@ -61,40 +204,60 @@ pub(crate) struct ContaminatedSampleLikelihoodModel {
     /// Purity of the case sample.
     purity: LogProb,
     impurity: LogProb,
+    /// Fragment lengths of the sample, if they shall be considered as evidence.
+    fragment_lengths: Option<FragmentLengthModel>,
 }
 
 impl Default for ContaminatedSampleLikelihoodModel {
     fn default() -> Self {
-        ContaminatedSampleLikelihoodModel::new(1.0)
+        ContaminatedSampleLikelihoodModel::new(1.0, None)
     }
 }
 
 impl ContaminatedSampleLikelihoodModel {
     /// Create new model.
-    pub(crate) fn new(purity: f64) -> Self {
+    pub(crate) fn new(purity: f64, fragment_lengths: Option<FragmentLengthModel>) -> Self {
         assert!(purity > 0.0 && purity <= 1.0);
         let purity = LogProb(purity.ln());
         ContaminatedSampleLikelihoodModel {
             purity,
             impurity: purity.ln_one_minus_exp(),
+            fragment_lengths,
         }
     }
 
+    /// Allele frequency among all haplotypes of the sample, given the allele frequencies
+    /// of the primary and the secondary (contaminating) sample.
+    pub(crate) fn allele_freq_mixture(&self, primary: f64, secondary: f64) -> f64 {
+        self.purity.exp() * primary + self.impurity.exp() * secondary
+    }
+
     fn likelihood_observation(
         &self,
         allele_freq_primary: LogProb,
         allele_freq_secondary: LogProb,
         biases_primary: &Biases,
         biases_secondary: &Biases,
+        fragment_lengths: Option<&FragmentLengthModel>,
         observation: &Observation<ReadPosition>,
     ) -> LogProb {
         // Step 1: likelihoods for the mapping case.
         // Case 1: read comes from primary sample and is correctly mapped
-        let prob_primary =
-            self.purity + likelihood_mapping(allele_freq_primary, biases_primary, observation);
+        let prob_primary = self.purity
+            + likelihood_mapping(
+                allele_freq_primary,
+                biases_primary,
+                fragment_lengths,
+                observation,
+            );
         // Case 2: read comes from secondary sample and is correctly mapped
         let prob_secondary = self.impurity
-            + likelihood_mapping(allele_freq_secondary, biases_secondary, observation);
+            + likelihood_mapping(
+                allele_freq_secondary,
+                biases_secondary,
+                fragment_lengths,
+                observation,
+            );
 
         // Step 4: total probability
         // Important note: we need to multiply a probability for a hypothetical missed allele
@@ -135,6 +298,7 @@ impl Likelihood<ContaminatedSampleCache> for ContaminatedSampleLikelihoodModel {
                     ln_af_secondary,
                     &events.primary.biases,
                     &events.secondary.biases,
+                    self.fragment_lengths.as_ref(),
                     obs,
                 );
                 prob + lh
@@ -150,12 +314,15 @@ impl Likelihood<ContaminatedSampleCache> for ContaminatedSampleLikelihoodModel {
 
 /// Likelihood model for single sample.
 #[derive(Clone, Copy, Debug, Default)]
-pub(crate) struct SampleLikelihoodModel {}
+pub(crate) struct SampleLikelihoodModel {
+    /// Fragment lengths of the sample, if they shall be considered as evidence.
+    fragment_lengths: Option<FragmentLengthModel>,
+}
 
 impl SampleLikelihoodModel {
     /// Create new model.
-    pub(crate) fn new() -> Self {
-        SampleLikelihoodModel {}
+    pub(crate) fn new(fragment_lengths: Option<FragmentLengthModel>) -> Self {
+        SampleLikelihoodModel { fragment_lengths }
     }
 
     /// Likelihood to observe a read given allele frequency for a single sample.
@@ -163,10 +330,11 @@ impl SampleLikelihoodModel {
         &self,
         allele_freq: LogProb,
         biases: &Biases,
+        fragment_lengths: Option<&FragmentLengthModel>,
         observation: &Observation<ReadPosition>,
     ) -> LogProb {
         // Step 1: likelihood for the mapping case.
-        let prob = likelihood_mapping(allele_freq, biases, observation);
+        let prob = likelihood_mapping(allele_freq, biases, fragment_lengths, observation);
 
         // Step 2: total probability
         // Important note: we need to multiply a probability for a hypothetical missed allele
@@ -188,6 +356,7 @@ impl SampleLikelihoodModel {
 fn likelihood_mapping(
     allele_freq: LogProb,
     biases: &Biases,
+    fragment_lengths: Option<&FragmentLengthModel>,
     observation: &Observation<ReadPosition>,
 ) -> LogProb {
     // Step 1: calculate probability to sample from alt allele
@@ -197,12 +366,19 @@ fn likelihood_mapping(
     let prob_bias = biases.prob(observation);
     let prob_any_bias = biases.prob_any(observation);
 
+    // METHOD: if enabled, the fragment length is an additional, independent piece of
+    // evidence for the allele the fragment stems from.
+    let (prob_length_alt, prob_length_ref) = fragment_lengths
+        .map_or((LogProb::ln_one(), LogProb::ln_one()), |fragment_lengths| {
+            fragment_lengths.probs(observation)
+        });
+
     // Step 2: read comes from case sample and is correctly mapped
     let prob = LogProb::ln_sum_exp(&[
         // alt allele
-        prob_sample_alt + prob_bias + observation.prob_alt,
+        prob_sample_alt + prob_bias + observation.prob_alt + prob_length_alt,
         // ref allele (we don't care about the strand)
-        prob_sample_ref + observation.prob_ref + prob_any_bias,
+        prob_sample_ref + observation.prob_ref + prob_any_bias + prob_length_ref,
     ]);
     assert!(!prob.is_nan());
 
@@ -222,7 +398,12 @@ impl Likelihood<SingleSampleCache> for SampleLikelihoodModel {
 
             // calculate product of per-read likelihoods in log space
             let likelihood = pileup.iter().fold(LogProb::ln_one(), |prob, obs| {
-                let lh = self.likelihood_observation(ln_af, &event.biases, obs);
+                let lh = self.likelihood_observation(
+                    ln_af,
+                    &event.biases,
+                    self.fragment_lengths.as_ref(),
+                    obs,
+                );
                 prob + lh
             });
 
@@ -259,16 +440,20 @@ mod tests {
     fn test_likelihood_observation_absent_single() {
         let observation = observation(LogProb::ln_one(), LogProb::ln_zero(), LogProb::ln_one());
 
-        let model = SampleLikelihoodModel::new();
+        let model = SampleLikelihoodModel::new(None);
 
-        let lh =
-            model.likelihood_observation(LogProb(AlleleFreq(0.0).ln()), &biases(), &observation);
+        let lh = model.likelihood_observation(
+            LogProb(AlleleFreq(0.0).ln()),
+            &biases(),
+            None,
+            &observation,
+        );
         assert_relative_eq!(*lh, *biases().prob_any(&observation));
     }
 
     #[test]
     fn test_likelihood_observation_absent() {
-        let model = ContaminatedSampleLikelihoodModel::new(1.0);
+        let model = ContaminatedSampleLikelihoodModel::new(1.0, None);
         let observation = observation(LogProb::ln_one(), LogProb::ln_zero(), LogProb::ln_one());
 
         let lh = model.likelihood_observation(
@@ -276,6 +461,7 @@ mod tests {
             LogProb(AlleleFreq(0.0).ln()),
             &biases(),
             &biases(),
+            None,
             &observation,
         );
         assert_relative_eq!(*lh, *biases().prob_any(&observation));
@@ -283,7 +469,7 @@ mod tests {
 
     #[test]
     fn test_likelihood_pileup_absent() {
-        let model = ContaminatedSampleLikelihoodModel::new(1.0);
+        let model = ContaminatedSampleLikelihoodModel::new(1.0, None);
         let mut observations = Vec::new();
         for _ in 0..10 {
             observations.push(observation(
@@ -313,7 +499,7 @@ mod tests {
 
     #[test]
     fn test_likelihood_pileup_absent_single() {
-        let model = SampleLikelihoodModel::new();
+        let model = SampleLikelihoodModel::new(None);
         let mut observations = Vec::new();
         for _ in 0..10 {
             observations.push(observation(
@@ -335,10 +521,49 @@ mod tests {
         assert!(cache.contains_key(&evt))
     }
 
+    #[test]
+    fn test_fragment_length_model() {
+        let fragment = |prob_alt: LogProb, prob_ref: LogProb, length: u32| {
+            let mut obs = observation(LogProb::ln_one(), prob_alt, prob_ref);
+            obs.fragment_length = Some(length);
+            obs
+        };
+        let alt = |length| fragment(LogProb::ln_one(), LogProb::ln_zero(), length);
+        let reference = |length| fragment(LogProb::ln_zero(), LogProb::ln_one(), length);
+
+        // somatic locus with short alt fragments and ambiguous fragments
+        let mut somatic = vec![alt(150); 3];
+        somatic.extend(vec![reference(300); 12]);
+        somatic.extend(vec![fragment(LogProb::ln_one(), LogProb::ln_one(), 50); 5]);
+        // germline locus, of which the alt fragments are not informative
+        let mut germline = vec![alt(300); 10];
+        germline.extend(vec![reference(300); 10]);
+
+        let mut estimator = FragmentLengthModelEstimator::default();
+        // fragments are pooled over loci
+        estimator.register(&somatic);
+        estimator.register(&germline);
+        let model = estimator.estimate().unwrap();
+        assert_relative_eq!(model.alt.mean, 150.0);
+        assert_relative_eq!(model.reference.mean, 300.0);
+        assert_relative_eq!(model.alt.sd, MIN_FRAGMENT_LENGTH_SD);
+
+        // A short fragment without allele specific read evidence is more likely to stem
+        // from the alt allele.
+        let mut obs = observation(LogProb::ln_one(), LogProb::ln_one(), LogProb::ln_one());
+        obs.fragment_length = Some(155);
+        let (prob_alt, prob_ref) = model.probs(&obs);
+        assert!(prob_alt > prob_ref);
+
+        // Observations without fragment length are uninformative.
+        obs.fragment_length = None;
+        assert_eq!(model.probs(&obs), (LogProb::ln_one(), LogProb::ln_one()));
+    }
+
     #[test]
     #[allow(clippy::float_cmp)]
     fn test_likelihood_pileup() {
-        let model = ContaminatedSampleLikelihoodModel::new(1.0);
+        let model = ContaminatedSampleLikelihoodModel::new(1.0, None);
         let mut observations = Vec::new();
         for _ in 0..5 {
             observations.push(observation(
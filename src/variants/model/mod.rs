@@ -162,6 +162,10 @@ pub enum VariantType {
     Duplication,
     #[strum(serialize = "REP")]
     Replacement,
+    #[strum(serialize = "MEI")]
+    MobileElementInsertion,
+    #[strum(serialize = "STR")]
+    TandemRepeat,
     #[strum(serialize = "REF")]
     None, // site with no suggested alternative allele
 }
@@ -177,6 +181,8 @@ impl From<&str> for VariantType {
             "DUP" => VariantType::Duplication,
             "REP" => VariantType::Replacement,
             "BND" => VariantType::Breakend,
+            "MEI" => VariantType::MobileElementInsertion,
+            "STR" => VariantType::TandemRepeat,
             _ => panic!("bug: given string does not describe a valid variant type"),
         }
     }
@@ -199,6 +205,15 @@ pub(crate) enum Variant {
         ref_allele: Vec<u8>,
         alt_allele: Vec<u8>,
     },
+    MobileElementInsertion {
+        family: Vec<u8>,
+        len: u64,
+    },
+    TandemRepeat {
+        unit: Vec<u8>,
+        ref_count: u64,
+        alt_count: u64,
+    },
     None,
 }
 
@@ -212,11 +227,14 @@ impl Variant {
             (&Variant::Deletion(l), &VariantType::Deletion(Some(ref range))) => {
                 l >= range.start && l < range.end
             }
-            (&Variant::Insertion(_), &VariantType::Insertion(Some(ref range))) => {
+            (&Variant::Insertion(_), &VariantType::Insertion(Some(ref range)))
+            | (&Variant::MobileElementInsertion { .. }, &VariantType::Insertion(Some(ref range))) => {
                 self.len() >= range.start && self.len() < range.end
             }
             (&Variant::Deletion(_), &VariantType::Deletion(None)) => true,
             (&Variant::Insertion(_), &VariantType::Insertion(None)) => true,
+            (&Variant::MobileElementInsertion { .. }, &VariantType::Insertion(None)) => true,
+            (&Variant::MobileElementInsertion { .. }, &VariantType::MobileElementInsertion) => true,
             (&Variant::SNV(_), &VariantType::SNV) => true,
             (&Variant::MNV(_), &VariantType::MNV) => true,
             (&Variant::None, &VariantType::None) => true,
@@ -224,6 +242,7 @@ impl Variant {
             (&Variant::Inversion { .. }, &VariantType::Inversion) => true,
             (&Variant::Duplication { .. }, &VariantType::Duplication) => true,
             (&Variant::Replacement { .. }, &VariantType::Replacement) => true,
+            (&Variant::TandemRepeat { .. }, &VariantType::TandemRepeat) => true,
             _ => false,
         }
     }
@@ -238,13 +257,19 @@ impl Variant {
             Variant::Inversion(l) => l,
             Variant::Duplication(l) => l,
             Variant::Replacement { ref alt_allele, .. } => alt_allele.len() as u64,
+            Variant::MobileElementInsertion { len, .. } => len,
+            Variant::TandemRepeat {
+                ref unit,
+                ref_count,
+                alt_count,
+            } => unit.len() as u64 * alt_count.abs_diff(ref_count),
             Variant::None => 1,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::variants::evidence::observation::{
         Observation, ObservationBuilder, ReadPosition, Strand,
     };
@@ -252,12 +277,15 @@ mod tests {
 
     use bio::stats::LogProb;
 
-    pub(crate) fn observation(
+    /// Builder for an observation with the given probabilities and neutral defaults for
+    /// everything else (except the read position, which has to be set by the caller).
+    pub(crate) fn observation_builder<P: Clone + Default>(
         prob_mapping: LogProb,
         prob_alt: LogProb,
         prob_ref: LogProb,
-    ) -> Observation<ReadPosition> {
-        ObservationBuilder::default()
+    ) -> ObservationBuilder<P> {
+        let mut builder = ObservationBuilder::default();
+        builder
             .prob_mapping_mismapping(prob_mapping)
             .prob_alt(prob_alt)
             .prob_ref(prob_ref)
@@ -265,11 +293,20 @@ mod tests {
             .prob_sample_alt(LogProb::ln_one())
             .prob_overlap(LogProb::ln_one())
             .read_orientation(SequenceReadPairOrientation::None)
-            .read_position(ReadPosition::Some)
             .strand(Strand::Both)
             .softclipped(false)
             .paired(true)
-            .prob_hit_base(LogProb::from(0.01f64.ln()))
+            .prob_hit_base(LogProb::from(0.01f64.ln()));
+        builder
+    }
+
+    pub(crate) fn observation(
+        prob_mapping: LogProb,
+        prob_alt: LogProb,
+        prob_ref: LogProb,
+    ) -> Observation<ReadPosition> {
+        observation_builder(prob_mapping, prob_alt, prob_ref)
+            .read_position(ReadPosition::Some)
             .build()
             .unwrap()
     }
@@ -8,6 +8,7 @@ use vec_map::VecMap;
 
 use crate::grammar;
 use crate::utils::PROB_05;
+use crate::variants::evidence::depth::DepthObservation;
 use crate::variants::model;
 use crate::variants::model::likelihood;
 use crate::variants::model::{bias::Biases, AlleleFreq, Contamination};
@@ -23,6 +24,8 @@ pub(crate) struct SNV {
 #[get = "pub"]
 pub(crate) struct Data {
     pileups: Vec<Pileup>,
+    /// Read depth within large deletions or duplications, per sample.
+    depth_observations: Vec<Option<DepthObservation>>,
     snv: Option<SNV>,
 }
 
@@ -57,6 +60,7 @@ where
 {
     resolutions: Option<grammar::SampleInfo<usize>>,
     contaminations: Option<grammar::SampleInfo<Option<Contamination>>>,
+    fragment_length_models: Option<grammar::SampleInfo<Option<likelihood::FragmentLengthModel>>>,
     prior: P,
 }
 
@@ -79,6 +83,15 @@ where
         self
     }
 
+    pub(crate) fn fragment_length_models(
+        mut self,
+        fragment_length_models: grammar::SampleInfo<Option<likelihood::FragmentLengthModel>>,
+    ) -> Self {
+        self.fragment_length_models = Some(fragment_length_models);
+
+        self
+    }
+
     pub(crate) fn prior(mut self, prior: P) -> Self {
         self.prior = prior;
 
@@ -95,6 +108,9 @@ where
         let likelihood = GenericLikelihood::new(
             self.contaminations
                 .expect("GenericModelBuilder: need to call contaminations() before build()"),
+            self.fragment_length_models.expect(
+                "GenericModelBuilder: need to call fragment_length_models() before build()",
+            ),
         );
         Ok(Model::new(likelihood, self.prior, posterior))
     }
@@ -293,19 +309,28 @@ pub(crate) struct GenericLikelihood {
 }
 
 impl GenericLikelihood {
-    pub(crate) fn new(contaminations: grammar::SampleInfo<Option<Contamination>>) -> Self {
-        let inner = contaminations.map(|contamination| {
-            if let Some(contamination) = contamination {
-                SampleModel::Contaminated {
-                    likelihood_model: likelihood::ContaminatedSampleLikelihoodModel::new(
-                        1.0 - contamination.fraction,
-                    ),
-                    by: contamination.by,
+    pub(crate) fn new(
+        contaminations: grammar::SampleInfo<Option<Contamination>>,
+        fragment_length_models: grammar::SampleInfo<Option<likelihood::FragmentLengthModel>>,
+    ) -> Self {
+        let inner: grammar::SampleInfo<SampleModel> = contaminations
+            .iter()
+            .zip(fragment_length_models.iter())
+            .map(|(contamination, fragment_lengths)| {
+                if let Some(contamination) = contamination {
+                    SampleModel::Contaminated {
+                        likelihood_model: likelihood::ContaminatedSampleLikelihoodModel::new(
+                            1.0 - contamination.fraction,
+                            *fragment_lengths,
+                        ),
+                        by: contamination.by,
+                    }
+                } else {
+                    SampleModel::Normal(likelihood::SampleLikelihoodModel::new(*fragment_lengths))
                 }
-            } else {
-                SampleModel::Normal(likelihood::SampleLikelihoodModel::new())
-            }
-        });
+            })
+            .collect_vec()
+            .into();
 
         GenericLikelihood { inner }
     }
@@ -354,6 +379,30 @@ impl Likelihood<Cache> for GenericLikelihood {
                         unreachable!();
                     }
                 }
+            };
+
+            // METHOD: read depth is an additional, independent piece of evidence for large
+            // deletions and duplications. It depends on the fraction of alt haplotypes among
+            // all haplotypes in the sample, including those of a contaminating sample.
+            // Artifacts do not change the copy number, hence the depth is expected to be the
+            // one of the reference.
+            if let Some(Some(depth_observation)) = data.depth_observations.get(sample) {
+                let allele_freq = |event: &likelihood::Event| {
+                    if event.biases.is_artifact() {
+                        0.0
+                    } else {
+                        *event.allele_freq
+                    }
+                };
+                let allele_freq = match *inner {
+                    SampleModel::Contaminated {
+                        ref likelihood_model,
+                        by,
+                    } => likelihood_model
+                        .allele_freq_mixture(allele_freq(event), allele_freq(&events[by])),
+                    SampleModel::Normal(_) => allele_freq(event),
+                };
+                p += depth_observation.likelihood(allele_freq);
             }
         }
 
@@ -5,32 +5,43 @@
 
 use std::f64;
 use std::hash::Hash;
+use std::ops::Range;
 use std::path::Path;
 use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
 
 use anyhow::Result;
 use bio_types::{genome, genome::AbstractInterval};
 use derive_builder::Builder;
+use itertools::Itertools;
 use rand::distributions;
 use rand::distributions::Distribution;
 use rand::{rngs::StdRng, SeedableRng};
 use rust_htslib::bam;
+use rust_htslib::bam::Read as BAMRead;
 
+use crate::errors;
 use crate::estimation::alignment_properties;
+use crate::utils;
+use crate::variants::evidence::depth::{DepthEvidence, DepthObservation};
 use crate::variants::evidence::observation::{
     self, major_read_position, Observable, Observation, ReadPosition,
 };
 use crate::variants::model::VariantType;
 use crate::variants::{self, types::Variant};
 
-#[derive(new, Getters, Debug)]
+/// Buffer of records from the BAM files of a sample.
+#[derive(new, Getters, CopyGetters, Debug)]
 pub(crate) struct RecordBuffer {
-    inner: bam::RecordBuffer,
+    inner: Vec<bam::RecordBuffer>,
     #[getset(get = "pub")]
     single_read_window: u64,
     #[getset(get = "pub")]
     read_pair_window: u64,
+    /// Group reads into UMI families (via the MI tag) instead of discarding duplicates.
+    #[getset(get_copy = "pub")]
+    umi_families: bool,
 }
 
 impl RecordBuffer {
@@ -49,14 +60,14 @@ impl RecordBuffer {
         interval: &genome::Interval,
         read_pair_mode: bool,
     ) -> Result<()> {
-        self.inner.fetch(
-            interval.contig().as_bytes(),
-            interval
-                .range()
-                .start
-                .saturating_sub(self.window(read_pair_mode, true)),
-            interval.range().end + self.window(read_pair_mode, false),
-        )?;
+        let start = interval
+            .range()
+            .start
+            .saturating_sub(self.window(read_pair_mode, true));
+        let end = interval.range().end + self.window(read_pair_mode, false);
+        for buffer in &mut self.inner {
+            buffer.fetch(interval.contig().as_bytes(), start, end)?;
+        }
 
         Ok(())
     }
@@ -69,9 +80,11 @@ impl RecordBuffer {
     }
 
     pub(crate) fn iter<'a>(&'a self) -> impl Iterator<Item = Rc<bam::Record>> + 'a {
+        let umi_families = self.umi_families;
         self.inner
             .iter()
-            .filter(|record| is_valid_record(record.as_ref()))
+            .flat_map(|buffer| buffer.iter())
+            .filter(move |record| is_valid_record(record.as_ref(), umi_families))
             .map(|record| Rc::clone(record))
     }
 }
@@ -126,6 +139,34 @@ impl Default for ProtocolStrandedness {
     }
 }
 
+/// Sequencing platform, determining the indel error model used during realignment.
+#[derive(
+    Display,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    EnumString,
+    EnumIter,
+    IntoStaticStr,
+    EnumVariantNames,
+    Default,
+)]
+pub enum Platform {
+    #[default]
+    #[strum(serialize = "illumina")]
+    Illumina,
+    #[strum(serialize = "pacbio-hifi")]
+    PacBioHiFi,
+    #[strum(serialize = "nanopore")]
+    Nanopore,
+    #[strum(serialize = "ion-torrent")]
+    IonTorrent,
+}
+
 pub(crate) type Pileup = Vec<Observation<ReadPosition>>;
 
 pub(crate) enum SubsampleCandidates {
@@ -162,17 +203,33 @@ impl SubsampleCandidates {
     }
 }
 
+/// Estimate alignment properties from the given BAM/CRAM files of a sample.
+/// CRAM files are decoded with the given reference genome.
+/// Properties are estimated from each file separately and combined afterwards.
 pub(crate) fn estimate_alignment_properties<P: AsRef<Path>>(
-    path: P,
+    paths: &[P],
+    reference: &Path,
     omit_insert_size: bool,
     allow_hardclips: bool,
+    empirical_insert_size: bool,
 ) -> Result<alignment_properties::AlignmentProperties> {
-    let mut bam = bam::Reader::from_path(path)?;
-    Ok(alignment_properties::AlignmentProperties::estimate(
-        &mut bam,
-        omit_insert_size,
-        allow_hardclips,
-    )?)
+    let mut properties: Option<alignment_properties::AlignmentProperties> = None;
+    for path in paths {
+        let mut bam = utils::alignments::reader(path, reference)?;
+        let bam_properties = alignment_properties::AlignmentProperties::estimate(
+            &mut bam,
+            omit_insert_size,
+            allow_hardclips,
+            empirical_insert_size,
+        )?;
+        if let Some(ref mut properties) = properties {
+            properties.merge(&bam_properties);
+        } else {
+            properties = Some(bam_properties);
+        }
+    }
+
+    Ok(properties.expect("bug: at least one BAM file has to be given"))
 }
 
 /// A sequenced sample, e.g., a tumor or a normal sample.
@@ -188,43 +245,100 @@ pub(crate) struct Sample {
     #[builder(default = "Vec::new()")]
     omit_repeat_regions: Vec<VariantType>,
     protocol_strandedness: ProtocolStrandedness,
+    /// Names of the target sequences of the BAM files, indexed by tid.
+    #[builder(private, default)]
+    contigs: Arc<Vec<String>>,
+    /// Read depth evidence against control samples, if any.
+    #[builder(default)]
+    depth_evidence: Option<DepthEvidence>,
 }
 
 impl SampleBuilder {
     /// Register alignment information.
     ///
     /// # Arguments
-    /// * `bam` - BAM file with the aligned and deduplicated sequence reads.
+    /// * `bams` - BAM files with the aligned and deduplicated sequence reads.
+    /// * `umi_families` - whether reads shall be grouped into UMI families (given by the MI tag)
+    ///   instead of discarding those marked as duplicates.
     pub(crate) fn alignments(
         self,
-        bam: bam::IndexedReader,
+        bams: Vec<bam::IndexedReader>,
         alignment_properties: alignment_properties::AlignmentProperties,
         min_refetch_distance: u64,
-    ) -> Self {
+        umi_families: bool,
+    ) -> Result<Self> {
         let single_read_window = alignment_properties.max_read_len as u64;
-        let read_pair_window = match alignment_properties.insert_size {
-            Some(isize) => (isize.mean + isize.sd * 6.0) as u64,
-            None => single_read_window,
+        // METHOD: the read pair window has to cover the widest insert size distribution
+        // among all read groups.
+        let read_pair_window = alignment_properties
+            .insert_sizes()
+            .map(|isize| isize.range().end)
+            .max()
+            .unwrap_or(single_read_window);
+        let target_names = |bam: &bam::IndexedReader| {
+            bam.header()
+                .target_names()
+                .iter()
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .collect_vec()
         };
-        let mut record_buffer = bam::RecordBuffer::new(bam, true);
-        record_buffer.set_min_refetch_distance(min_refetch_distance);
-        self.alignment_properties(alignment_properties)
+        let contigs = bams.first().map(target_names).unwrap_or_default();
+        // METHOD: tids (e.g. of mates) are resolved via the contigs of the first BAM file.
+        // Hence, all BAM files have to share the same reference sequences in the same order.
+        if bams.iter().skip(1).any(|bam| target_names(bam) != contigs) {
+            return Err(errors::Error::InconsistentBamContigs.into());
+        }
+        let record_buffers = bams
+            .into_iter()
+            .map(|bam| {
+                let mut record_buffer = bam::RecordBuffer::new(bam, true);
+                record_buffer.set_min_refetch_distance(min_refetch_distance);
+                record_buffer
+            })
+            .collect();
+        Ok(self
+            .alignment_properties(alignment_properties)
+            .contigs(Arc::new(contigs))
             .record_buffer(RecordBuffer::new(
-                record_buffer,
+                record_buffers,
                 single_read_window,
                 read_pair_window,
-            ))
+                umi_families,
+            )))
     }
 }
 
-fn is_valid_record(record: &bam::Record) -> bool {
+fn is_valid_record(record: &bam::Record, umi_families: bool) -> bool {
+    // METHOD: in UMI mode, duplicates are members of the same family and hence kept.
+    // Duplicates without a molecule identifier (MI tag) cannot be assigned to a family
+    // and are dropped as usual.
     !(record.is_secondary()
-        || record.is_duplicate()
+        || (record.is_duplicate() && !(umi_families && record.aux(b"MI").is_some()))
         || record.is_unmapped()
         || record.is_quality_check_failed())
 }
 
 impl Sample {
+    /// Names of the target sequences of the BAM files, indexed by tid.
+    pub(crate) fn contigs(&self) -> &Arc<Vec<String>> {
+        &self.contigs
+    }
+
+    /// Observe the read depth in the given region (0-based, half-open), if depth evidence
+    /// against control samples is available.
+    pub(crate) fn depth_observation(
+        &mut self,
+        contig: &str,
+        region: Range<u64>,
+        alt_depth_ratio: f64,
+    ) -> Result<Option<DepthObservation>> {
+        if let Some(ref mut depth_evidence) = self.depth_evidence {
+            depth_evidence.observation(contig, region, alt_depth_ratio)
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Extract observations for the given variant.
     pub(crate) fn extract_observations<V, E, L>(&mut self, variant: &V) -> Result<Pileup>
     where
@@ -16,27 +16,30 @@ use crate::variants::types::Variant;
 
 pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
     /// Get range of insert sizes with probability above zero.
-    /// We use 6 SDs around the mean.
-    fn isize_pmf_range(&self, alignment_properties: &AlignmentProperties) -> Range<u64> {
-        match alignment_properties.insert_size {
-            Some(isize) => {
-                let m = isize.mean.round() as u64;
-                let s = isize.sd.ceil() as u64 * 6;
-                m.saturating_sub(s)..m + s
-            },
+    /// For the normal distribution, we use 6 SDs around the mean.
+    fn isize_pmf_range(
+        &self,
+        read_group: Option<&str>,
+        alignment_properties: &AlignmentProperties,
+    ) -> Range<u64> {
+        match alignment_properties.read_group_insert_size(read_group) {
+            Some(isize) => isize.range(),
             None => panic!("Bug: Tried to create an isize_pmf_range(), but alignment_properties.insert_size was None.")
         }
     }
 
     /// Get probability of given insert size from distribution shifted by the given value.
+    /// The distribution of the given read group is used if known. If an empirical
+    /// histogram has been estimated, it is used instead of the normal distribution.
     fn isize_pmf(
         &self,
         insert_size: u64,
         shift: f64,
+        read_group: Option<&str>,
         alignment_properties: &AlignmentProperties,
     ) -> LogProb {
-        match alignment_properties.insert_size {
-            Some(isize) => isize_pmf(insert_size as f64, isize.mean + shift, isize.sd),
+        match alignment_properties.read_group_insert_size(read_group) {
+            Some(isize) => isize.pmf(insert_size, shift),
             None => panic!(
                 "Bug: Tried to get an isize_pmf(), but alignment_properties.insert_size was None."
             ),
@@ -53,6 +56,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
         delta_ref: u64,
         delta_alt: u64,
         enclose_only: bool,
+        read_group: Option<&str>,
         alignment_properties: &AlignmentProperties,
     ) -> LogProb {
         let mut infeasible_read_pos_left = left_read_len.saturating_sub(left_feasible);
@@ -71,7 +75,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
         // e.g., a ref fragment.
         let expected_p_alt = LogProb::ln_sum_exp(
             &self
-                .isize_pmf_range(alignment_properties)
+                .isize_pmf_range(read_group, alignment_properties)
                 .filter_map(|x| {
                     let internal_segment = x
                         .saturating_sub(left_read_len)
@@ -96,7 +100,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
                         None
                     } else {
                         // probability to sample a valid placement
-                        let p = self.isize_pmf(x, 0.0, alignment_properties)
+                        let p = self.isize_pmf(x, 0.0, read_group, alignment_properties)
                             + LogProb((valid_pos_alt as f64).ln() - (valid_pos_ref as f64).ln());
 
                         assert!(
@@ -119,6 +123,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
         &self,
         left_read_len: u64,
         right_read_len: u64,
+        read_group: Option<&str>,
         alignment_properties: &AlignmentProperties,
     ) -> LogProb {
         let left_feasible = self.feasible_bases(left_read_len, alignment_properties);
@@ -135,6 +140,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
                 delta_ref,
                 delta_alt,
                 true,
+                read_group,
                 alignment_properties,
             )
         } else {
@@ -146,9 +152,10 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
         &self,
         insert_size: u64,
         shift: f64,
+        read_group: Option<&str>,
         alignment_properties: &AlignmentProperties,
     ) -> bool {
-        match alignment_properties.insert_size {
+        match alignment_properties.read_group_insert_size(read_group) {
             Some(isize) => {
                 let m = isize.mean + shift;
                 (insert_size as f64 - m).abs() <= isize.sd
@@ -14,24 +14,29 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use bio::alphabets::dna;
+use bio::stats::bayesian::bayes_factors::{evidence::KassRaftery, BayesFactor};
 use bio::stats::pairhmm::EmissionParameters;
 use bio::stats::LogProb;
 use bio_types::genome::{self, AbstractInterval, AbstractLocus};
 use regex::Regex;
-use rust_htslib::bam;
-use rust_htslib::bcf::{self, Read};
+use rust_htslib::{bam, bcf};
 use vec_map::VecMap;
 
 use crate::errors::Error;
 use crate::estimation::alignment_properties::AlignmentProperties;
 use crate::reference;
 use crate::utils;
+use crate::utils::regions::{RegionReader, Regions};
+use crate::variants::evidence::breakpoints::ConfidenceInterval;
+use crate::variants::evidence::observation::Strand;
 use crate::variants::evidence::realignment::pairhmm::{ReadEmission, RefBaseEmission};
 use crate::variants::evidence::realignment::{Realignable, Realigner};
+use crate::variants::evidence::split_reads;
 use crate::variants::model;
 use crate::variants::sampling_bias::{ReadSamplingBias, SamplingBias};
 use crate::variants::types::{
-    AlleleSupport, MultiLocus, PairedEndEvidence, SingleLocus, SingleLocusBuilder, Variant,
+    AlleleSupport, AlleleSupportBuilder, MultiLocus, PairedEndEvidence, SingleLocus,
+    SingleLocusBuilder, Variant,
 };
 use crate::{default_emission, default_ref_base_emission};
 
@@ -42,6 +47,7 @@ pub(crate) struct BreakendGroup<R: Realigner> {
     enclosable_ref_interval: Option<genome::Interval>,
     // TODO consider making the right side a Vec<Breakend>!
     breakends: BTreeMap<genome::Locus, Breakend>,
+    confidence_intervals: BTreeMap<genome::Locus, ConfidenceInterval>,
     alt_alleles: RefCell<VecMap<Vec<Arc<AltAllele>>>>,
     realigner: RefCell<R>,
 }
@@ -58,6 +64,7 @@ pub(crate) struct BreakendGroupBuilder<R: Realigner> {
     loci: Option<MultiLocus>,
     enclosable_ref_interval: Option<genome::Interval>,
     breakends: Option<BTreeMap<genome::Locus, Breakend>>,
+    confidence_intervals: BTreeMap<genome::Locus, ConfidenceInterval>,
     realigner: Option<R>,
 }
 
@@ -67,6 +74,7 @@ impl<R: Realigner> BreakendGroupBuilder<R> {
             loci: Some(MultiLocus::default()),
             enclosable_ref_interval: None,
             breakends: Some(BTreeMap::default()),
+            confidence_intervals: BTreeMap::default(),
             realigner: None,
         }
     }
@@ -98,6 +106,17 @@ impl<R: Realigner> BreakendGroupBuilder<R> {
         self
     }
 
+    /// Register the confidence interval of an imprecise breakend at the given locus.
+    pub(crate) fn confidence_interval(
+        &mut self,
+        locus: genome::Locus,
+        interval: ConfidenceInterval,
+    ) -> &mut Self {
+        self.confidence_intervals.insert(locus, interval);
+
+        self
+    }
+
     pub(crate) fn build(&mut self) -> BreakendGroup<R> {
         // Calculate enclosable reference interval.
         let first = self.breakends.as_ref().unwrap().keys().next().unwrap();
@@ -129,6 +148,7 @@ impl<R: Realigner> BreakendGroupBuilder<R> {
             loci: self.loci.take().unwrap(),
             enclosable_ref_interval: self.enclosable_ref_interval.clone(),
             breakends: self.breakends.take().unwrap(),
+            confidence_intervals: std::mem::take(&mut self.confidence_intervals),
             alt_alleles: RefCell::new(VecMap::new()),
             realigner: RefCell::new(
                 self.realigner
@@ -144,6 +164,116 @@ impl<R: Realigner> BreakendGroup<R> {
         self.breakends.values()
     }
 
+    /// Confidence intervals of all breakends (in the order of `breakends()`), or `None` if
+    /// all breakends are precise.
+    pub(crate) fn confidence_intervals(&self) -> Option<Vec<ConfidenceInterval>> {
+        if self.confidence_intervals.is_empty() {
+            None
+        } else {
+            Some(
+                self.breakends
+                    .keys()
+                    .map(|locus| {
+                        self.confidence_intervals
+                            .get(locus)
+                            .cloned()
+                            .unwrap_or_default()
+                    })
+                    .collect(),
+            )
+        }
+    }
+
+    /// If the given read is split at a breakend of this group, with a supplementary alignment
+    /// (SA tag) at the join partner, return the index of the breakend locus and the probability
+    /// that the supplementary alignment is wrong.
+    fn split_read_support(&self, read: &bam::Record) -> Option<(usize, LogProb)> {
+        // METHOD: a supplementary alignment is listed in the SA tag of its primary alignment,
+        // which is part of the same fragment. Hence, the split is only considered via the
+        // primary alignment. Otherwise, it would be counted twice.
+        if read.is_supplementary() {
+            return None;
+        }
+        let supplementary_alignments = split_reads::supplementary_alignments(read);
+        if supplementary_alignments.is_empty() {
+            return None;
+        }
+        let read_end = read.cigar_cached().unwrap().end_pos() as u64;
+
+        for breakend in self.breakends.values() {
+            if let Some(ref join) = breakend.join {
+                // METHOD: the read has to be clipped at the breakend, on the side that is
+                // replaced by the joined sequence. I.e., it has to end at the breakend if the
+                // reference is kept left of it, and to start there otherwise. Likewise, the
+                // supplementary alignment has to start at the join partner if the joined
+                // sequence extends to the right of it, and to end there otherwise. Finally,
+                // it has to be on the opposite strand of the read if the joined sequence is
+                // reverse complemented, and on the same strand otherwise.
+                let at_end = breakend.is_left_to_right;
+                if read.contig() != breakend.locus.contig()
+                    || !split_reads::is_near_boundary(
+                        read.pos() as u64,
+                        read_end,
+                        breakend.locus.pos(),
+                        at_end,
+                    )
+                    || !split_reads::is_clipped(read, at_end)
+                {
+                    continue;
+                }
+                let join_at_end = join.side == Side::LeftOfPos;
+                let is_reverse = read.is_reverse()
+                    != (join.extension_modification == ExtensionModification::ReverseComplement);
+                if let Some(prob_mismapping) = supplementary_alignments
+                    .iter()
+                    .filter(|alignment| {
+                        alignment.is_reverse == is_reverse
+                            && alignment.is_split_at(&join.locus, join_at_end)
+                    })
+                    .map(|alignment| alignment.prob_mismapping())
+                    .min_by(|a, b| a.partial_cmp(b).unwrap())
+                {
+                    let locus_idx = self.loci.iter().position(|locus| {
+                        locus.contig() == breakend.locus.contig()
+                            && locus.range().start == breakend.locus.pos()
+                    })?;
+                    return Some((locus_idx, prob_mismapping));
+                }
+            }
+        }
+        None
+    }
+
+    /// Allele support of a single read, combining realignment with split read evidence.
+    fn read_allele_support(&self, read: &bam::Record) -> Result<AlleleSupport> {
+        let support = self
+            .realigner
+            .borrow_mut()
+            .allele_support(read, self.loci.iter(), self)?;
+
+        // METHOD: split read evidence is only used if the realignment is uninformative (e.g.
+        // because the realignment window misses a long soft clip). Otherwise, the realignment
+        // already captures the clipped sequence, and the split would be counted twice.
+        let is_informative =
+            |a, b| BayesFactor::new(a, b).evidence_kass_raftery() > KassRaftery::Barely;
+        if is_informative(support.prob_alt_allele(), support.prob_ref_allele())
+            || is_informative(support.prob_ref_allele(), support.prob_alt_allele())
+        {
+            return Ok(support);
+        }
+
+        if let Some((_, prob_mismapping)) = self.split_read_support(read) {
+            let strand = if support.strand() != Strand::None {
+                support.strand()
+            } else {
+                Strand::from_record(read)
+            };
+            return Ok(with_split_read_evidence(&support, prob_mismapping, strand));
+        }
+
+        Ok(support)
+    }
+
     fn upstream_bnd(&self, locus: &genome::Locus) -> Option<&Breakend> {
         for (l, bnd) in self.breakends.range(..locus).rev() {
             if l.contig() == locus.contig() {
@@ -216,6 +346,37 @@ impl<R: Realigner> BreakendGroup<R> {
     }
 }
 
+impl<R: Realigner + Clone> BreakendGroup<R> {
+    /// Breakend group with each breakend (in the order of `breakends()`) moved by the given
+    /// offset. Joins pointing to moved breakends of the group are moved along.
+    /// Returns `None` if a breakend would be moved outside of its contig.
+    pub(crate) fn shifted(
+        &self,
+        offsets: &[i64],
+        ref_buffer: &reference::Buffer,
+    ) -> Result<Option<Self>> {
+        let join_offsets: BTreeMap<_, _> = self.breakends.keys().zip(offsets).collect();
+
+        let mut builder = BreakendGroupBuilder::new();
+        builder.realigner(self.realigner.borrow().clone());
+        for (breakend, offset) in self.breakends().zip(offsets) {
+            let join_offset = breakend
+                .join()
+                .as_ref()
+                .and_then(|join| join_offsets.get(&join.locus))
+                .map_or(0, |offset| **offset);
+            let chrom_seq = ref_buffer.seq(breakend.locus().contig())?;
+            if let Some(breakend) = breakend.shifted(*offset, join_offset, &chrom_seq) {
+                builder.push_breakend(breakend);
+            } else {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(builder.build()))
+    }
+}
+
 impl<R: Realigner> Variant for BreakendGroup<R> {
     type Evidence = PairedEndEvidence;
     type Loci = MultiLocus;
@@ -246,10 +407,25 @@ impl<R: Realigner> Variant for BreakendGroup<R> {
             }
         };
 
-        let overlapping: Vec<_> = match evidence {
+        // METHOD: reads that are split at a breakend with a supplementary alignment at the
+        // join partner are valid evidence, even if they do not overlap a breakend locus
+        // (e.g. because the overlap is hidden in a long soft clip).
+        let split_loci: Vec<_> = match evidence {
+            PairedEndEvidence::SingleEnd(read) => {
+                self.split_read_support(read).into_iter().collect()
+            }
+            PairedEndEvidence::PairedEnd { left, right } => self
+                .split_read_support(left)
+                .into_iter()
+                .chain(self.split_read_support(right))
+                .collect(),
+        };
+        let mut split_loci: Vec<usize> = split_loci.into_iter().map(|(i, _)| i).collect();
+
+        let mut overlapping: Vec<_> = match evidence {
             PairedEndEvidence::SingleEnd(read) => {
                 if !is_valid_ref_bases(read) {
-                    return None;
+                    return Some(split_loci).filter(|loci| !loci.is_empty());
                 }
                 self.loci
                     .iter()
@@ -265,7 +441,7 @@ impl<R: Realigner> Variant for BreakendGroup<R> {
             }
             PairedEndEvidence::PairedEnd { left, right } => {
                 if !is_valid_ref_bases(left) && !is_valid_ref_bases(right) {
-                    return None;
+                    return Some(split_loci).filter(|loci| !loci.is_empty());
                 }
 
                 self.loci
@@ -281,6 +457,9 @@ impl<R: Realigner> Variant for BreakendGroup<R> {
                     .collect()
             }
         };
+        overlapping.append(&mut split_loci);
+        overlapping.sort_unstable();
+        overlapping.dedup();
 
         if overlapping.is_empty() {
             None
@@ -301,20 +480,10 @@ impl<R: Realigner> Variant for BreakendGroup<R> {
         _: &AlignmentProperties,
     ) -> Result<Option<AlleleSupport>> {
         match evidence {
-            PairedEndEvidence::SingleEnd(record) => Ok(Some(
-                self.realigner
-                    .borrow_mut()
-                    .allele_support(record, self.loci.iter(), self)?,
-            )),
+            PairedEndEvidence::SingleEnd(record) => Ok(Some(self.read_allele_support(record)?)),
             PairedEndEvidence::PairedEnd { left, right } => {
-                let left_support =
-                    self.realigner
-                        .borrow_mut()
-                        .allele_support(left, self.loci.iter(), self)?;
-                let right_support =
-                    self.realigner
-                        .borrow_mut()
-                        .allele_support(right, self.loci.iter(), self)?;
+                let left_support = self.read_allele_support(left)?;
+                let right_support = self.read_allele_support(right)?;
 
                 let mut support = left_support;
 
@@ -871,6 +1040,57 @@ impl Breakend {
         }
     }
 
+    /// Breakend moved by the given offset, with its join moved by the given join offset.
+    /// Reference bases are taken from the given sequence of the breakend's contig.
+    /// Returns `None` if the breakend would be moved outside of the contig.
+    pub(crate) fn shifted(&self, offset: i64, join_offset: i64, chrom_seq: &[u8]) -> Option<Self> {
+        let pos = self.locus.pos() as i64 + offset;
+        let ref_len = self.ref_allele.len() as i64;
+        if pos < MIN_REF_BASES as i64
+            || pos + ref_len + MIN_REF_BASES as i64 > chrom_seq.len() as i64
+        {
+            return None;
+        }
+        let pos = pos as u64;
+        let ref_allele =
+            chrom_seq[pos as usize..pos as usize + ref_len as usize].to_ascii_uppercase();
+
+        // METHOD: the replacement starts (left to right) or ends (right to left) with the
+        // reference bases, which have to be updated to the new position.
+        let mut replacement = self.replacement.clone();
+        let ref_range = if self.is_left_to_right {
+            0..self.ref_allele.len()
+        } else {
+            replacement.len().saturating_sub(self.ref_allele.len())..replacement.len()
+        };
+        if replacement.len() >= ref_allele.len()
+            && replacement[ref_range.clone()].eq_ignore_ascii_case(&self.ref_allele)
+        {
+            replacement[ref_range].copy_from_slice(&ref_allele);
+        }
+
+        let join = if let Some(ref join) = self.join {
+            let join_pos = join.locus.pos() as i64 + join_offset;
+            if join_pos < 0 {
+                return None;
+            }
+            Some(Join {
+                locus: genome::Locus::new(join.locus.contig().to_owned(), join_pos as u64),
+                ..join.clone()
+            })
+        } else {
+            None
+        };
+
+        Some(Breakend {
+            locus: genome::Locus::new(self.locus.contig().to_owned(), pos),
+            ref_allele,
+            replacement,
+            join,
+            ..self.clone()
+        })
+    }
+
     fn emits_revcomp(&self) -> bool {
         matches!(
             self.join(),
@@ -918,43 +1138,261 @@ pub(crate) struct Join {
     extension_modification: ExtensionModification,
 }
 
+/// Combine the given realignment based allele support of a read with the evidence from a
+/// supplementary alignment at the join partner that is wrong with the given probability.
+///
+/// METHOD: a supplementary alignment at the join partner is expected if the read stems from
+/// the alt allele. From the ref allele, it can only occur if the supplementary alignment is
+/// wrong. The likelihoods of both sources are multiplied. Thereby, the split read likelihoods
+/// are weighted by the MAPQ of the supplementary alignment: an unreliable one is almost equally
+/// likely under both alleles and hardly changes the realignment evidence. This helps in
+/// particular if the realignment window misses long soft clips.
+fn with_split_read_evidence(
+    support: &AlleleSupport,
+    prob_mismapping: LogProb,
+    strand: Strand,
+) -> AlleleSupport {
+    let normalization = LogProb::ln_one().ln_add_exp(prob_mismapping);
+    let prob_alt = LogProb::ln_one() - normalization;
+    let prob_ref = prob_mismapping - normalization;
+    AlleleSupportBuilder::default()
+        .prob_ref_allele(support.prob_ref_allele() + prob_ref)
+        .prob_alt_allele(support.prob_alt_allele() + prob_alt)
+        .strand(strand)
+        .read_position(support.read_position())
+        .build()
+        .unwrap()
+}
+
+/// Prefix of event names that are inferred from MATEID chains. Such names are additionally
+/// ensured to not collide with any EVENT given in the BCF (see `mate_events`).
+const MATE_EVENT_PREFIX: &[u8] = b"MATES_";
+
+/// Event name of a chain of breakends connected via MATEID, represented by the given record ID.
+pub(crate) fn mate_event_name(id: &[u8]) -> Vec<u8> {
+    [MATE_EVENT_PREFIX, id].concat()
+}
+
 #[derive(Default, Debug)]
 pub(crate) struct BreakendIndex {
     last_records: HashMap<Vec<u8>, usize>,
+    /// Events of breakends without EVENT tag, by record ID.
+    mate_events: HashMap<Vec<u8>, Vec<u8>>,
+    /// Events that have breakends outside of the given regions.
+    incomplete_events: HashSet<Vec<u8>>,
+}
+
+/// Breakend record as seen by the `BreakendIndex`.
+struct IndexedBreakend {
+    index: usize,
+    id: Vec<u8>,
+    event: Option<Vec<u8>>,
+    mateid: Option<Vec<u8>>,
 }
 
 impl BreakendIndex {
-    pub(crate) fn new<P: AsRef<Path>>(inbcf: P) -> Result<Self> {
-        let mut bcf_reader = bcf::Reader::from_path(inbcf)?;
-        if !utils::is_sv_bcf(&bcf_reader) {
-            return Ok(BreakendIndex::default());
-        }
+    /// Index breakends of the given BCF. If regions are given, only records within these
+    /// are considered, such that record indices match those of a region restricted reader.
+    /// Events with breakends outside of the regions are recorded as incomplete.
+    pub(crate) fn new<P: AsRef<Path>>(inbcf: P, regions: Option<&Regions>) -> Result<Self> {
+        let breakends = match indexed_breakends(inbcf.as_ref(), regions)? {
+            Some(breakends) => breakends,
+            None => return Ok(BreakendIndex::default()),
+        };
+        // METHOD: MATEID chains and event sizes are always inferred from the entire BCF.
+        // Otherwise, a chain could be broken by a breakend outside of the regions.
+        let all_breakends = if regions.is_some() {
+            indexed_breakends(inbcf.as_ref(), None)?
+        } else {
+            None
+        };
+        let all_breakends = all_breakends.as_ref().unwrap_or(&breakends);
+
+        // METHOD: breakends without EVENT tag are grouped by chains of MATEID references.
+        // Breakends with EVENT tag are part of these chains as well, such that a chain that
+        // touches them inherits their event.
+        let mates: Vec<_> = all_breakends
+            .iter()
+            .filter_map(|breakend| match &breakend.mateid {
+                Some(mateid) if breakend.id != b"." => {
+                    Some((breakend.index, breakend.id.clone(), mateid.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        let breakend_events: HashMap<Vec<u8>, Vec<u8>> = all_breakends
+            .iter()
+            .filter(|breakend| breakend.id != b".")
+            .filter_map(|breakend| {
+                breakend
+                    .event
+                    .clone()
+                    .map(|event| (breakend.id.clone(), event))
+            })
+            .collect();
+        let given_events: HashSet<Vec<u8>> = all_breakends
+            .iter()
+            .filter_map(|breakend| breakend.event.clone())
+            .collect();
+        let mate_events = mate_events(&mates, &breakend_events, &given_events);
+        let event = |breakend: &IndexedBreakend| {
+            breakend
+                .event
+                .clone()
+                .or_else(|| mate_events.get(&breakend.id).cloned())
+        };
 
         let mut last_records = HashMap::new();
-
-        let mut i = 0;
-        loop {
-            let mut record = bcf_reader.empty_record();
-            match bcf_reader.read(&mut record) {
-                None => return Ok(BreakendIndex { last_records }),
-                Some(res) => res?,
+        let mut counts: HashMap<Vec<u8>, usize> = HashMap::new();
+        for breakend in &breakends {
+            if let Some(event) = event(breakend) {
+                last_records.insert(event.clone(), breakend.index);
+                *counts.entry(event).or_insert(0) += 1;
             }
+        }
 
-            if utils::is_bnd(&mut record)? {
-                // TODO support records without EVENT tag.
-                if let Ok(Some(event)) = record.info(b"EVENT").string() {
-                    let event = event[0];
-                    last_records.insert(event.to_owned(), i);
+        let mut incomplete_events = HashSet::new();
+        if regions.is_some() {
+            let mut total_counts: HashMap<Vec<u8>, usize> = HashMap::new();
+            for breakend in all_breakends {
+                if let Some(event) = event(breakend) {
+                    *total_counts.entry(event).or_insert(0) += 1;
                 }
             }
-
-            i += 1;
+            for (event, count) in counts {
+                if total_counts[&event] > count {
+                    incomplete_events.insert(event);
+                }
+            }
+            if !incomplete_events.is_empty() {
+                warn!(
+                    "{} breakend events are only partially contained in the given regions. \
+                     They will be skipped. Extend the regions to include all their breakends.",
+                    incomplete_events.len()
+                );
+            }
         }
+
+        Ok(BreakendIndex {
+            last_records,
+            mate_events,
+            incomplete_events,
+        })
+    }
+
+    /// Whether all breakends of the given event are within the indexed regions.
+    pub(crate) fn is_complete(&self, event: &[u8]) -> bool {
+        !self.incomplete_events.contains(event)
     }
 
     pub(crate) fn last_record_index(&self, event: &[u8]) -> Option<usize> {
         self.last_records.get(event).cloned()
     }
+
+    /// Event of the given breakend record. This is either given by the EVENT tag, or, if
+    /// missing, inferred from the chain of breakends connected via MATEID.
+    pub(crate) fn event(&self, record: &mut bcf::Record) -> Result<Option<Vec<u8>>> {
+        if let Ok(Some(event)) = utils::info_tag_event(record) {
+            Ok(Some(event))
+        } else {
+            Ok(self.mate_events.get(&record.id()).cloned())
+        }
+    }
+}
+
+/// Read all breakends of the given BCF, optionally restricted to the given regions.
+/// Returns `None` if the BCF does not contain structural variants.
+fn indexed_breakends(
+    inbcf: &Path,
+    regions: Option<&Regions>,
+) -> Result<Option<Vec<IndexedBreakend>>> {
+    let mut bcf_reader = RegionReader::new(inbcf, regions)?;
+    if !utils::is_sv_bcf(bcf_reader.header()) {
+        return Ok(None);
+    }
+
+    let mut breakends = Vec::new();
+    let mut i = 0;
+    loop {
+        let mut record = bcf_reader.empty_record();
+        match bcf_reader.read(&mut record) {
+            None => break,
+            Some(res) => res?,
+        }
+
+        if utils::is_bnd(&mut record)? {
+            let event = utils::info_tag_event(&mut record).ok().flatten();
+            let mateid = utils::info_tag_mateid(&mut record).ok().flatten();
+            breakends.push(IndexedBreakend {
+                index: i,
+                id: record.id(),
+                event,
+                mateid,
+            });
+        }
+
+        i += 1;
+    }
+
+    Ok(Some(breakends))
+}
+
+/// Group the given breakends (record index, ID, MATEID) into events, such that breakends that
+/// are connected via MATEID belong to the same event. If a group contains breakends with an
+/// EVENT tag (given by ID in `breakend_events`), their event is used for the entire group
+/// (the smallest one if there are several). Otherwise, the event is named by the smallest ID
+/// among its breakends, prefixed with `MATE_EVENT_PREFIX`. Should such a name collide with one
+/// of the given events (from EVENT tags), underscores are prepended until it is unique.
+/// Returns the event for each ID.
+fn mate_events(
+    mates: &[(usize, Vec<u8>, Vec<u8>)],
+    breakend_events: &HashMap<Vec<u8>, Vec<u8>>,
+    given_events: &HashSet<Vec<u8>>,
+) -> HashMap<Vec<u8>, Vec<u8>> {
+    let mut neighbors: HashMap<&[u8], Vec<&[u8]>> = HashMap::new();
+    for (_, id, mateid) in mates {
+        let (id, mateid) = (id.as_slice(), mateid.as_slice());
+        neighbors.entry(id).or_default().push(mateid);
+        neighbors.entry(mateid).or_default().push(id);
+    }
+
+    let mut events = HashMap::new();
+    for (_, id, _) in mates {
+        if events.contains_key(id) {
+            continue;
+        }
+        // collect all breakends reachable from this one
+        let mut component = vec![id.as_slice()];
+        let mut visited: HashSet<&[u8]> = component.iter().cloned().collect();
+        let mut j = 0;
+        while j < component.len() {
+            for &neighbor in &neighbors[component[j]] {
+                if visited.insert(neighbor) {
+                    component.push(neighbor);
+                }
+            }
+            j += 1;
+        }
+
+        let event = if let Some(event) = component
+            .iter()
+            .filter_map(|member| breakend_events.get(*member))
+            .min()
+        {
+            event.clone()
+        } else {
+            let mut event = mate_event_name(component.iter().min().unwrap());
+            while given_events.contains(&event) {
+                event.insert(0, b'_');
+            }
+            event
+        };
+        for member in component {
+            events.insert(member.to_owned(), event.clone());
+        }
+    }
+
+    events
 }
 
 struct LocusPlusOne<'a>(&'a genome::Locus);
@@ -980,3 +1418,102 @@ impl<'a> AbstractLocus for LocusMinusOne<'a> {
         self.0.pos() - 1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bio::stats::PHREDProb;
+
+    fn support(prob_ref: f64, prob_alt: f64) -> AlleleSupport {
+        AlleleSupportBuilder::default()
+            .prob_ref_allele(LogProb(prob_ref.ln()))
+            .prob_alt_allele(LogProb(prob_alt.ln()))
+            .strand(Strand::Forward)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_split_read_evidence() {
+        let prob_mismapping = |mapq: f64| LogProb::from(PHREDProb(mapq));
+        let log_odds =
+            |support: &AlleleSupport| *(support.prob_alt_allele() - support.prob_ref_allele());
+
+        // realignment misses the soft clip, hence the supplementary alignment decides
+        let uninformative = support(0.5, 0.5);
+        let combined =
+            with_split_read_evidence(&uninformative, prob_mismapping(60.0), Strand::Forward);
+        assert_relative_eq!(
+            log_odds(&combined),
+            60.0 / 10.0 * 10.0f64.ln(),
+            epsilon = 1e-6
+        );
+
+        // both sources support the alt allele, hence the evidence accumulates
+        let alt = support(0.01, 0.99);
+        let combined = with_split_read_evidence(&alt, prob_mismapping(20.0), Strand::Forward);
+        assert!(log_odds(&combined) > log_odds(&alt));
+
+        // realignment clearly supports the ref allele, which is not overruled by an unreliable
+        // supplementary alignment
+        let reference = support(0.999, 0.001);
+        let combined = with_split_read_evidence(&reference, prob_mismapping(3.0), Strand::Forward);
+        assert!(log_odds(&combined) < 0.0);
+        assert!(log_odds(&combined) > log_odds(&reference));
+
+        // a supplementary alignment with MAPQ 0 does not change the evidence
+        let combined = with_split_read_evidence(&alt, prob_mismapping(0.0), Strand::Forward);
+        assert_relative_eq!(log_odds(&combined), log_odds(&alt), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn test_mate_events() {
+        let mates = vec![
+            (0, b"bnd_b".to_vec(), b"bnd_a".to_vec()),
+            (1, b"bnd_a".to_vec(), b"bnd_b".to_vec()),
+            (2, b"bnd_c".to_vec(), b"bnd_d".to_vec()),
+            (3, b"bnd_d".to_vec(), b"bnd_e".to_vec()),
+        ];
+        let events = mate_events(&mates, &HashMap::new(), &HashSet::new());
+        assert_eq!(events[&b"bnd_a"[..]], b"MATES_bnd_a");
+        assert_eq!(events[&b"bnd_b"[..]], b"MATES_bnd_a");
+        // chained via bnd_d
+        assert_eq!(events[&b"bnd_c"[..]], b"MATES_bnd_c");
+        assert_eq!(events[&b"bnd_e"[..]], b"MATES_bnd_c");
+    }
+
+    #[test]
+    fn test_mate_events_collision() {
+        let mates = vec![
+            (0, b"bnd_b".to_vec(), b"bnd_a".to_vec()),
+            (1, b"bnd_a".to_vec(), b"bnd_b".to_vec()),
+        ];
+        // other breakends in the BCF with EVENT tags that look like inferred names
+        let given_events = vec![b"MATES_bnd_a".to_vec(), b"_MATES_bnd_a".to_vec()]
+            .into_iter()
+            .collect();
+        let events = mate_events(&mates, &HashMap::new(), &given_events);
+        assert_eq!(events[&b"bnd_a"[..]], b"__MATES_bnd_a");
+        assert_eq!(events[&b"bnd_b"[..]], b"__MATES_bnd_a");
+    }
+
+    #[test]
+    fn test_mate_events_given_event() {
+        // bnd_c references bnd_b, which belongs to a given event
+        let mates = vec![
+            (0, b"bnd_a".to_vec(), b"bnd_b".to_vec()),
+            (1, b"bnd_b".to_vec(), b"bnd_a".to_vec()),
+            (2, b"bnd_c".to_vec(), b"bnd_b".to_vec()),
+        ];
+        let breakend_events = vec![
+            (b"bnd_a".to_vec(), b"event1".to_vec()),
+            (b"bnd_b".to_vec(), b"event1".to_vec()),
+        ]
+        .into_iter()
+        .collect();
+        let given_events = vec![b"event1".to_vec()].into_iter().collect();
+        let events = mate_events(&mates, &breakend_events, &given_events);
+        assert_eq!(events[&b"bnd_c"[..]], b"event1");
+    }
+}
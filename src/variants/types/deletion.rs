@@ -16,8 +16,9 @@ use rust_htslib::bam;
 
 use crate::estimation::alignment_properties::AlignmentProperties;
 use crate::reference;
-use crate::variants::evidence::insert_size::estimate_insert_size;
+use crate::variants::evidence::insert_size::{estimate_insert_size, read_group};
 use crate::variants::evidence::observation::Strand;
+use crate::variants::evidence::realignment::haplotypes::HaplotypeVariant;
 use crate::variants::evidence::realignment::pairhmm::{ReadEmission, RefBaseEmission};
 use crate::variants::evidence::realignment::{Realignable, Realigner};
 use crate::variants::sampling_bias::{FragmentSamplingBias, ReadSamplingBias, SamplingBias};
@@ -67,14 +68,25 @@ impl<R: Realigner> Deletion<R> {
         alignment_properties: &AlignmentProperties,
     ) -> Result<AlleleSupport> {
         let insert_size = estimate_insert_size(left_record, right_record)?;
+        let read_group = read_group(left_record);
 
-        let p_ref = self.isize_pmf(insert_size, 0.0, alignment_properties);
-        let p_alt = self.isize_pmf(insert_size, self.len() as f64, alignment_properties);
+        let p_ref = self.isize_pmf(insert_size, 0.0, read_group, alignment_properties);
+        let p_alt = self.isize_pmf(
+            insert_size,
+            self.len() as f64,
+            read_group,
+            alignment_properties,
+        );
 
         if (p_ref == LogProb::ln_zero()
-            && !self.is_within_sd(insert_size, self.len() as f64, alignment_properties))
+            && !self.is_within_sd(
+                insert_size,
+                self.len() as f64,
+                read_group,
+                alignment_properties,
+            ))
             || (p_alt == LogProb::ln_zero()
-                && !self.is_within_sd(insert_size, 0.0, alignment_properties))
+                && !self.is_within_sd(insert_size, 0.0, read_group, alignment_properties))
         {
             // METHOD: We cannot consider insert size as a reliable estimate here, because it is
             // outside of the numerical resolution for one of the alleles, and not within a
@@ -141,6 +153,15 @@ impl<'a, R: Realigner> Realignable<'a> for Deletion<R> {
             read_emission: read_emission_params,
         }])
     }
+
+    fn haplotype_variant(&self) -> Option<HaplotypeVariant> {
+        // deleted bases start behind the leading reference base
+        Some(HaplotypeVariant::new(
+            self.locus.range().start + 1,
+            self.len(),
+            Vec::new(),
+        ))
+    }
 }
 
 impl<R: Realigner> Variant for Deletion<R> {
@@ -161,7 +182,10 @@ impl<R: Realigner> Variant for Deletion<R> {
                 }
             }
             PairedEndEvidence::PairedEnd { left, right } => {
-                if alignment_properties.insert_size.is_some() {
+                if alignment_properties
+                    .read_group_insert_size(read_group(left))
+                    .is_some()
+                {
                     let right_cigar = right.cigar_cached().unwrap();
                     let encloses_centerpoint = (left.pos() as u64) < self.centerpoint()
                         && right_cigar.end_pos() as u64 > self.centerpoint();
@@ -233,7 +257,10 @@ impl<R: Realigner> Variant for Deletion<R> {
                 let mut support = left_support;
                 support.merge(&right_support);
 
-                if alignment_properties.insert_size.is_some() {
+                if alignment_properties
+                    .read_group_insert_size(read_group(left))
+                    .is_some()
+                {
                     let isize_support =
                         self.allele_support_isize(left, right, alignment_properties)?;
                     support.merge(&isize_support);
@@ -251,10 +278,15 @@ impl<R: Realigner> Variant for Deletion<R> {
     ) -> LogProb {
         match evidence {
             PairedEndEvidence::PairedEnd { left, right } => {
-                if alignment_properties.insert_size.is_some() {
+                let read_group = read_group(left);
+                if alignment_properties
+                    .read_group_insert_size(read_group)
+                    .is_some()
+                {
                     self.prob_sample_alt_fragment(
                         left.seq().len() as u64,
                         right.seq().len() as u64,
+                        read_group,
                         alignment_properties,
                     )
                 } else {
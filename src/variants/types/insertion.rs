@@ -15,12 +15,38 @@ use bio_types::genome::{self, AbstractInterval, AbstractLocus};
 
 use crate::estimation::alignment_properties::AlignmentProperties;
 use crate::reference;
+use crate::variants::evidence::realignment::haplotypes::HaplotypeVariant;
 use crate::variants::evidence::realignment::pairhmm::{ReadEmission, RefBaseEmission};
 use crate::variants::evidence::realignment::{Realignable, Realigner};
 use crate::variants::sampling_bias::{ReadSamplingBias, SamplingBias};
 use crate::variants::types::{AlleleSupport, MultiLocus, PairedEndEvidence, SingleLocus, Variant};
 use crate::{default_emission, default_ref_base_emission};
 
+/// Placeholder for inserted bases of unknown sequence, e.g. in symbolic `<INS>` alleles of
+/// which only the flanks (LEFT_SVINSSEQ, RIGHT_SVINSSEQ) are known.
+pub(crate) const UNKNOWN_BASE: u8 = b'N';
+
+/// Sequence of an insertion of the given length, of which only the given left and right
+/// flanks are known. Returns `None` if the flanks exceed the length of the insertion.
+pub(crate) fn partially_known_seq(len: usize, left: &[u8], right: &[u8]) -> Option<Vec<u8>> {
+    if left.len() + right.len() > len {
+        return None;
+    }
+    let mut seq = Vec::with_capacity(len);
+    seq.extend(left);
+    seq.resize(len - right.len(), UNKNOWN_BASE);
+    seq.extend(right);
+    Some(seq)
+}
+
+/// Known left and right flanks of an insertion sequence. Returns `None` if the entire
+/// sequence is known.
+pub(crate) fn known_flanks(seq: &[u8]) -> Option<(&[u8], &[u8])> {
+    let left = seq.iter().position(|b| *b == UNKNOWN_BASE)?;
+    let right = seq.iter().rposition(|b| *b == UNKNOWN_BASE).unwrap() + 1;
+    Some((&seq[..left], &seq[right..]))
+}
+
 pub(crate) struct Insertion<R: Realigner> {
     locus: MultiLocus,
     ins_seq: Rc<Vec<u8>>,
@@ -54,22 +80,26 @@ impl<'a, R: Realigner> Realignable<'a> for Insertion<R> {
         _: &genome::Interval,
         ref_window: usize,
     ) -> Result<Vec<InsertionEmissionParams<'a>>> {
-        let l = self.ins_seq.len() as usize;
-        let start = self.locus().range().start as usize;
-
-        let ref_seq = ref_buffer.seq(self.locus().contig())?;
+        Ok(vec![InsertionEmissionParams::new(
+            ref_buffer.seq(self.locus().contig())?,
+            self.locus().range().start as usize,
+            Rc::clone(&self.ins_seq),
+            ref_window,
+            read_emission_params,
+        )])
+    }
 
-        let ref_seq_len = ref_seq.len();
-        Ok(vec![InsertionEmissionParams {
-            ref_seq,
-            ref_offset: start.saturating_sub(ref_window),
-            ref_end: cmp::min(start + l + ref_window, ref_seq_len),
-            ins_start: start,
-            ins_len: l,
-            ins_end: start + l,
-            ins_seq: Rc::clone(&self.ins_seq),
-            read_emission: read_emission_params,
-        }])
+    fn haplotype_variant(&self) -> Option<HaplotypeVariant> {
+        if known_flanks(&self.ins_seq).is_some() {
+            // unknown sequence cannot be represented in local haplotypes
+            return None;
+        }
+        // inserted bases follow the leading reference base
+        Some(HaplotypeVariant::new(
+            self.locus().range().start + 1,
+            0,
+            self.ins_seq.as_ref().to_owned(),
+        ))
     }
 }
 
@@ -184,6 +214,31 @@ pub(crate) struct InsertionEmissionParams<'a> {
     read_emission: Rc<ReadEmission<'a>>,
 }
 
+impl<'a> InsertionEmissionParams<'a> {
+    /// Emission parameters for the given sequence, inserted behind the given position of
+    /// the reference sequence.
+    pub(crate) fn new(
+        ref_seq: Arc<Vec<u8>>,
+        start: usize,
+        ins_seq: Rc<Vec<u8>>,
+        ref_window: usize,
+        read_emission: Rc<ReadEmission<'a>>,
+    ) -> Self {
+        let l = ins_seq.len();
+        let ref_seq_len = ref_seq.len();
+        InsertionEmissionParams {
+            ref_seq,
+            ref_offset: start.saturating_sub(ref_window),
+            ref_end: cmp::min(start + l + ref_window, ref_seq_len),
+            ins_start: start,
+            ins_len: l,
+            ins_end: start + l,
+            ins_seq,
+            read_emission,
+        }
+    }
+}
+
 impl<'a> RefBaseEmission for InsertionEmissionParams<'a> {
     #[inline]
     fn ref_base(&self, i: usize) -> u8 {
@@ -197,6 +252,14 @@ impl<'a> RefBaseEmission for InsertionEmissionParams<'a> {
         }
     }
 
+    #[inline]
+    fn is_unknown_base(&self, i: usize) -> bool {
+        let i_ = i + self.ref_offset;
+        i_ > self.ins_start
+            && i_ <= self.ins_end
+            && self.ins_seq[i_ - (self.ins_start + 1)] == UNKNOWN_BASE
+    }
+
     default_ref_base_emission!();
 }
 
@@ -208,3 +271,24 @@ impl<'a> EmissionParameters for InsertionEmissionParams<'a> {
         self.ref_end - self.ref_offset + self.ins_len
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partially_known_seq() {
+        let seq = partially_known_seq(8, b"AC", b"GTT").unwrap();
+        assert_eq!(seq, b"ACNNNGTT".to_vec());
+        assert_eq!(known_flanks(&seq), Some((&b"AC"[..], &b"GTT"[..])));
+
+        assert_eq!(partially_known_seq(4, b"AC", b"GT"), Some(b"ACGT".to_vec()));
+        assert_eq!(known_flanks(b"ACGT"), None);
+
+        assert_eq!(partially_known_seq(3, b"AC", b"GT"), None);
+        assert_eq!(
+            known_flanks(&partially_known_seq(3, b"", b"").unwrap()),
+            Some((&b""[..], &b""[..]))
+        );
+    }
+}
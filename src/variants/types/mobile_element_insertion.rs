@@ -0,0 +1,637 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Mobile element insertions (e.g. `<INS:ME:ALU>`), genotyped against the consensus sequence
+//! of the element family.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::rc::Rc;
+use std::str;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bio::alphabets::dna;
+use bio::io::fasta;
+use bio::pattern_matching::myers::long;
+use bio::stats::{LogProb, PHREDProb, Prob};
+use bio_types::genome::{self, AbstractInterval, AbstractLocus};
+use rust_htslib::bam;
+
+use crate::estimation::alignment_properties::AlignmentProperties;
+use crate::reference;
+use crate::variants::evidence::insert_size::read_group;
+use crate::variants::evidence::observation::Strand;
+use crate::variants::evidence::realignment::pairhmm::ReadEmission;
+use crate::variants::evidence::realignment::{Realignable, Realigner};
+use crate::variants::evidence::split_reads::cigar_ref_len;
+use crate::variants::sampling_bias::{ReadSamplingBias, SamplingBias};
+use crate::variants::types::insertion::{partially_known_seq, InsertionEmissionParams};
+use crate::variants::types::{
+    AlleleSupport, AlleleSupportBuilder, MultiLocus, PairedEndEvidence, SingleLocus, Variant,
+};
+
+/// Maximum fraction of edits for the reference sequence at the mate of a discordant read pair
+/// to be considered a copy of the mobile element.
+const MAX_CONSENSUS_DIVERGENCE: f64 = 0.15;
+
+lazy_static! {
+    /// Probability that a reference fragment yields a discordant read pair with the mate
+    /// located in a copy of the mobile element (e.g. due to mismapping or chimeric fragments).
+    static ref PROB_DISCORDANT_ARTIFACT: LogProb = LogProb::from(Prob(0.01));
+}
+
+/// Consensus sequence of a mobile element family. The reverse complement is computed once,
+/// such that reference sequences can be matched against both orientations.
+#[derive(Debug)]
+pub(crate) struct Consensus {
+    seq: Vec<u8>,
+    revcomp: Vec<u8>,
+}
+
+impl Consensus {
+    pub(crate) fn new(seq: Vec<u8>) -> Self {
+        let revcomp = dna::revcomp(&seq);
+        Consensus { seq, revcomp }
+    }
+
+    pub(crate) fn seq(&self) -> &[u8] {
+        &self.seq
+    }
+
+    /// Whether the given sequence occurs in the consensus (in any orientation) with at most
+    /// the given fraction of edits.
+    fn contains(&self, seq: &[u8], max_divergence: f64) -> bool {
+        let max_dist = (seq.len() as f64 * max_divergence) as usize;
+        let myers = long::Myers::<u64>::new(seq.iter().cloned());
+        [&self.seq, &self.revcomp].iter().any(|consensus| {
+            myers
+                .find_all_end(consensus.iter().cloned(), max_dist)
+                .next()
+                .is_some()
+        })
+    }
+}
+
+/// Consensus sequences of mobile element families (e.g. ALU, L1, SVA), as given by a FASTA file.
+#[derive(Default, Debug)]
+pub(crate) struct MobileElementLibrary {
+    consensus: BTreeMap<String, Arc<Consensus>>,
+}
+
+impl MobileElementLibrary {
+    pub(crate) fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut consensus = BTreeMap::new();
+        for record in fasta::Reader::from_file(path.as_ref())?.records() {
+            let record = record?;
+            consensus.insert(
+                record.id().to_ascii_uppercase(),
+                Arc::new(Consensus::new(record.seq().to_ascii_uppercase())),
+            );
+        }
+        Ok(MobileElementLibrary { consensus })
+    }
+
+    /// Consensus sequence of the given family. If there is no consensus sequence with exactly
+    /// this name, the first one (in lexicographical order) that starts with the given name
+    /// is used (e.g. ALUY for ALU).
+    pub(crate) fn consensus(&self, family: &[u8]) -> Option<&Arc<Consensus>> {
+        let family = str::from_utf8(family).ok()?.to_ascii_uppercase();
+        self.consensus.get(&family).or_else(|| {
+            self.consensus
+                .range(family.clone()..)
+                .next()
+                .filter(|(id, _)| id.starts_with(&family))
+                .map(|(_, seq)| seq)
+        })
+    }
+}
+
+/// Family of the mobile element given by a symbolic ALT allele (e.g. ALU for `<INS:ME:ALU>`).
+pub(crate) fn family(alt_allele: &[u8]) -> Option<&[u8]> {
+    let family = alt_allele
+        .strip_prefix(b"<INS:ME:")?
+        .strip_suffix(b">")?
+        .split(|c| *c == b':')
+        .next()?;
+    if family.is_empty() {
+        None
+    } else {
+        Some(family)
+    }
+}
+
+/// Inserted sequence of a mobile element insertion of the given length.
+///
+/// METHOD: mobile element insertions are frequently 5' truncated, hence the inserted
+/// sequence is given by the 3' end of the consensus. Insertions that are longer than the
+/// consensus (e.g. due to extended polyA tails) are padded with unknown bases.
+pub(crate) fn inserted_seq(consensus: &[u8], len: usize) -> Vec<u8> {
+    if len <= consensus.len() {
+        consensus[consensus.len() - len..].to_owned()
+    } else {
+        partially_known_seq(len, consensus, b"").unwrap()
+    }
+}
+
+pub(crate) struct MobileElementInsertion<R: Realigner> {
+    locus: MultiLocus,
+    /// Inserted sequence, in forward and reverse complement orientation.
+    ins_seqs: Vec<Rc<Vec<u8>>>,
+    consensus: Arc<Consensus>,
+    /// Names of the target sequences of the BAM files, indexed by tid.
+    contigs: Arc<Vec<String>>,
+    realigner: RefCell<R>,
+    /// Whether the mate of a read (by name and whether it is the first in the template)
+    /// is located in a copy of the mobile element. Each read is considered multiple times
+    /// (validity and allele support), hence the result is cached.
+    mates_in_element: RefCell<HashMap<(Vec<u8>, bool), bool>>,
+}
+
+impl<R: Realigner> MobileElementInsertion<R> {
+    pub(crate) fn new(
+        locus: genome::Locus,
+        len: u64,
+        consensus: Arc<Consensus>,
+        contigs: Arc<Vec<String>>,
+        realigner: R,
+    ) -> Self {
+        let ins_seq = inserted_seq(consensus.seq(), len as usize);
+        // METHOD: mobile elements can be inserted in both orientations.
+        let ins_seqs = vec![Rc::new(dna::revcomp(&ins_seq)), Rc::new(ins_seq)];
+        MobileElementInsertion {
+            locus: MultiLocus::new(vec![SingleLocus::new(genome::Interval::new(
+                locus.contig().to_owned(),
+                locus.pos()..locus.pos() + 1,
+            ))]),
+            ins_seqs,
+            consensus,
+            contigs,
+            realigner: RefCell::new(realigner),
+            mates_in_element: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn locus(&self) -> &SingleLocus {
+        &self.locus[0]
+    }
+
+    fn len(&self) -> u64 {
+        self.ins_seqs[0].len() as u64
+    }
+
+    /// Whether the given read is the anchor of a discordant read pair, pointing towards the
+    /// insertion, while its mate is mapped elsewhere.
+    fn is_discordant_anchor(
+        &self,
+        read: &bam::Record,
+        alignment_properties: &AlignmentProperties,
+    ) -> bool {
+        if !read.is_paired() || read.is_mate_unmapped() || read.mtid() < 0 {
+            return false;
+        }
+        let max_dist = if let Some(insert_size) =
+            alignment_properties.read_group_insert_size(read_group(read))
+        {
+            insert_size.range().end
+        } else {
+            // no insert size information, e.g. for single end reads
+            return false;
+        };
+        if read.contig() != self.locus().contig() {
+            return false;
+        }
+        let pos = self.locus().range().start;
+        let read_start = read.pos() as u64;
+        let read_end = read.cigar_cached().unwrap().end_pos() as u64;
+        let points_to_locus = if read.is_reverse() {
+            read_end > pos + 1 && read_end - (pos + 1) <= max_dist
+        } else {
+            read_start <= pos && pos - read_start <= max_dist
+        };
+        let mate_dist = (read.mpos() - read.pos()).unsigned_abs();
+        points_to_locus && (read.mtid() != read.tid() || mate_dist > max_dist)
+    }
+
+    /// Whether the reference sequence at the mate position of the given read resembles
+    /// the consensus of the mobile element (in any orientation).
+    fn is_mate_in_element(&self, read: &bam::Record, ref_buffer: &reference::Buffer) -> bool {
+        let key = (read.qname().to_owned(), read.is_first_in_template());
+        if let Some(is_in_element) = self.mates_in_element.borrow().get(&key) {
+            return *is_in_element;
+        }
+
+        let is_in_element = self.is_in_element(
+            read.mtid(),
+            read.mpos() as usize,
+            mate_len(read) as usize,
+            ref_buffer,
+        );
+        self.mates_in_element
+            .borrow_mut()
+            .insert(key, is_in_element);
+        is_in_element
+    }
+
+    /// Whether the reference sequence of the given length at the given target and position
+    /// resembles the consensus of the mobile element.
+    fn is_in_element(
+        &self,
+        tid: i32,
+        start: usize,
+        len: usize,
+        ref_buffer: &reference::Buffer,
+    ) -> bool {
+        let contig = if let Some(contig) = self.contigs.get(tid as usize) {
+            contig
+        } else {
+            return false;
+        };
+        let seq = if let Ok(seq) = ref_buffer.seq(contig) {
+            seq
+        } else {
+            return false;
+        };
+        let end = start + len;
+        if end > seq.len() {
+            return false;
+        }
+        self.consensus.contains(
+            &seq[start..end].to_ascii_uppercase(),
+            MAX_CONSENSUS_DIVERGENCE,
+        )
+    }
+
+    /// Support from a discordant read pair with the mate in a copy of the mobile element.
+    ///
+    /// METHOD: if the insertion is present, the mate of a read pointing towards it is
+    /// expected to stem from the inserted element, and hence to be mapped to one of its copies
+    /// in the reference genome. For the reference allele, this can only happen by artifacts,
+    /// or if the mate is mismapped (as given by its MAPQ in the MQ tag), in which case its
+    /// location does not tell anything about the insertion.
+    /// Discordant read pairs with the mate elsewhere do not tell anything about the insertion.
+    fn discordant_support(
+        &self,
+        read: &bam::Record,
+        alignment_properties: &AlignmentProperties,
+    ) -> Option<AlleleSupport> {
+        if self.is_discordant_anchor(read, alignment_properties)
+            && self.is_mate_in_element(read, self.realigner.borrow().ref_buffer())
+        {
+            let prob_mate_mismapping = mate_prob_mismapping(read);
+            let prob_ref = prob_mate_mismapping
+                .ln_add_exp(prob_mate_mismapping.ln_one_minus_exp() + *PROB_DISCORDANT_ARTIFACT);
+            let normalization = LogProb::ln_one().ln_add_exp(prob_ref);
+            Some(
+                AlleleSupportBuilder::default()
+                    .prob_ref_allele(prob_ref - normalization)
+                    .prob_alt_allele(LogProb::ln_one() - normalization)
+                    .strand(Strand::from_record(read))
+                    .build()
+                    .unwrap(),
+            )
+        } else {
+            None
+        }
+    }
+}
+
+/// Number of reference bases covered by the mate of the given read, as given by its CIGAR
+/// string (MC tag). Without MC tag, the mate is assumed to cover as many bases as the read.
+fn mate_len(read: &bam::Record) -> u64 {
+    if let Some(bam::record::Aux::String(cigar)) = read.aux(b"MC") {
+        if let Some(len) = str::from_utf8(cigar).ok().and_then(cigar_ref_len) {
+            return len;
+        }
+    }
+    read.cigar_cached().unwrap().end_pos() as u64 - read.pos() as u64
+}
+
+/// Probability that the mate of the given read is mismapped, as given by its MAPQ (MQ tag).
+/// Without MQ tag, the mate is assumed to be mapped correctly.
+fn mate_prob_mismapping(read: &bam::Record) -> LogProb {
+    match read.aux(b"MQ") {
+        Some(bam::record::Aux::Integer(mapq)) => LogProb::from(PHREDProb(mapq as f64)),
+        _ => LogProb::ln_zero(),
+    }
+}
+
+impl<'a, R: Realigner> Realignable<'a> for MobileElementInsertion<R> {
+    type EmissionParams = InsertionEmissionParams<'a>;
+
+    fn alt_emission_params(
+        &self,
+        read_emission_params: Rc<ReadEmission<'a>>,
+        ref_buffer: Arc<reference::Buffer>,
+        _: &genome::Interval,
+        ref_window: usize,
+    ) -> Result<Vec<InsertionEmissionParams<'a>>> {
+        let ref_seq = ref_buffer.seq(self.locus().contig())?;
+        let start = self.locus().range().start as usize;
+
+        Ok(self
+            .ins_seqs
+            .iter()
+            .map(|ins_seq| {
+                InsertionEmissionParams::new(
+                    Arc::clone(&ref_seq),
+                    start,
+                    Rc::clone(ins_seq),
+                    ref_window,
+                    Rc::clone(&read_emission_params),
+                )
+            })
+            .collect())
+    }
+}
+
+impl<R: Realigner> SamplingBias for MobileElementInsertion<R> {
+    fn feasible_bases(&self, read_len: u64, alignment_properties: &AlignmentProperties) -> u64 {
+        if let Some(len) = self.enclosable_len() {
+            if len < (alignment_properties.max_ins_cigar_len as u64) {
+                return read_len;
+            }
+        }
+        (read_len as f64 * alignment_properties.frac_max_softclip) as u64
+    }
+
+    fn enclosable_len(&self) -> Option<u64> {
+        Some(self.len())
+    }
+}
+
+impl<R: Realigner> ReadSamplingBias for MobileElementInsertion<R> {}
+
+impl<R: Realigner> Variant for MobileElementInsertion<R> {
+    type Evidence = PairedEndEvidence;
+    type Loci = MultiLocus;
+
+    fn is_valid_evidence(
+        &self,
+        evidence: &Self::Evidence,
+        alignment_properties: &AlignmentProperties,
+    ) -> Option<Vec<usize>> {
+        if match evidence {
+            PairedEndEvidence::SingleEnd(read) => {
+                !self.locus().overlap(read, true).is_none()
+                    || self
+                        .discordant_support(read, alignment_properties)
+                        .is_some()
+            }
+            PairedEndEvidence::PairedEnd { left, right } => {
+                !self.locus().overlap(left, true).is_none()
+                    || !self.locus().overlap(right, true).is_none()
+            }
+        } {
+            Some(vec![0])
+        } else {
+            None
+        }
+    }
+
+    /// Return variant loci.
+    fn loci(&self) -> &Self::Loci {
+        &self.locus
+    }
+
+    /// Calculate probability for alt and reference allele.
+    fn allele_support(
+        &self,
+        evidence: &Self::Evidence,
+        alignment_properties: &AlignmentProperties,
+    ) -> Result<Option<AlleleSupport>> {
+        match evidence {
+            PairedEndEvidence::SingleEnd(record) => {
+                let mut support =
+                    self.realigner
+                        .borrow_mut()
+                        .allele_support(record, self.locus.iter(), self)?;
+                // METHOD: the mate sequence is independent of the realigned read sequence,
+                // hence discordant read pair evidence is combined with the realignment.
+                if let Some(discordant_support) =
+                    self.discordant_support(record, alignment_properties)
+                {
+                    support.merge(&discordant_support);
+                }
+                Ok(Some(support))
+            }
+            PairedEndEvidence::PairedEnd { left, right } => {
+                let left_support =
+                    self.realigner
+                        .borrow_mut()
+                        .allele_support(left, self.locus.iter(), self)?;
+                let right_support =
+                    self.realigner
+                        .borrow_mut()
+                        .allele_support(right, self.locus.iter(), self)?;
+
+                let mut support = left_support;
+
+                support.merge(&right_support);
+
+                Ok(Some(support))
+            }
+        }
+    }
+
+    fn prob_sample_alt(
+        &self,
+        evidence: &Self::Evidence,
+        alignment_properties: &AlignmentProperties,
+    ) -> LogProb {
+        match evidence {
+            PairedEndEvidence::PairedEnd { left, right } => {
+                // METHOD: we do not require the fragment to enclose the variant.
+                // Hence, we treat both reads independently.
+                (self
+                    .prob_sample_alt_read(left.seq().len() as u64, alignment_properties)
+                    .ln_one_minus_exp()
+                    + self
+                        .prob_sample_alt_read(right.seq().len() as u64, alignment_properties)
+                        .ln_one_minus_exp())
+                .ln_one_minus_exp()
+            }
+            PairedEndEvidence::SingleEnd(read) => {
+                self.prob_sample_alt_read(read.seq().len() as u64, alignment_properties)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::Write;
+
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+
+    use crate::estimation::alignment_properties::InsertSize;
+    use crate::variants::evidence::realignment::pairhmm::GapParams;
+    use crate::variants::evidence::realignment::PairHMMRealigner;
+    use crate::variants::sample::Platform;
+
+    const CONTIG: &str = "chr1";
+    /// Position of the insertion.
+    const POS: u64 = 500;
+    /// Position of a copy of the mobile element in the reference.
+    const ELEMENT_POS: usize = 1200;
+
+    /// Pseudo-random sequence of the given length.
+    fn random_seq(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b"ACGT"[(state >> 16) as usize % 4]
+            })
+            .collect()
+    }
+
+    fn consensus() -> Vec<u8> {
+        random_seq(300, 11)
+    }
+
+    /// Reference sequence with a copy of the mobile element.
+    fn ref_seq() -> Vec<u8> {
+        let mut seq = random_seq(2000, 7);
+        seq.splice(
+            ELEMENT_POS..ELEMENT_POS + consensus().len(),
+            consensus().into_iter(),
+        );
+        seq
+    }
+
+    fn ref_buffer(dir: &tempfile::TempDir) -> Arc<reference::Buffer> {
+        let seq = ref_seq();
+        let path = dir.path().join("ref.fa");
+        let mut fasta = fs::File::create(&path).unwrap();
+        writeln!(fasta, ">{}", CONTIG).unwrap();
+        fasta.write_all(&seq).unwrap();
+        writeln!(fasta).unwrap();
+        let mut fai = fs::File::create(dir.path().join("ref.fa.fai")).unwrap();
+        let offset = CONTIG.len() + 2;
+        writeln!(
+            fai,
+            "{}\t{}\t{}\t{}\t{}",
+            CONTIG,
+            seq.len(),
+            offset,
+            seq.len(),
+            seq.len() + 1
+        )
+        .unwrap();
+
+        Arc::new(reference::Buffer::new(
+            fasta::IndexedReader::from_file(&path).unwrap(),
+            1,
+        ))
+    }
+
+    fn alignment_properties() -> AlignmentProperties {
+        AlignmentProperties {
+            insert_size: Some(InsertSize {
+                mean: 300.0,
+                sd: 10.0,
+                histogram: None,
+                count: 0,
+            }),
+            max_del_cigar_len: 10,
+            max_ins_cigar_len: 10,
+            frac_max_softclip: 0.1,
+            max_read_len: 50,
+            read_groups: HashMap::new(),
+            error_rates: None,
+        }
+    }
+
+    /// Read in front of the insertion, pointing towards it, with its mate at the given
+    /// position (with the given MAPQ).
+    fn anchor_read(qname: &[u8], mpos: i64, mate_mapq: i64) -> bam::Record {
+        let mut header = bam::Header::new();
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", &CONTIG)
+                .push_tag(b"LN", &ref_seq().len()),
+        );
+        let seq = &ref_seq()[350..400];
+        let qual = vec![30; seq.len()];
+        let mut record = bam::Record::new();
+        record.set(
+            qname,
+            Some(&CigarString(vec![Cigar::Match(50)])),
+            seq,
+            &qual,
+        );
+        record.set_header(Rc::new(bam::HeaderView::from_header(&header)));
+        // paired, mate reverse, first in template
+        record.set_flags(0x1 | 0x20 | 0x40);
+        record.set_tid(0);
+        record.set_pos(350);
+        record.set_mapq(60);
+        record.set_mtid(0);
+        record.set_mpos(mpos);
+        record.push_aux(b"MC", &Aux::String(b"50M"));
+        record.push_aux(b"MQ", &Aux::Integer(mate_mapq));
+        record.cache_cigar();
+        record
+    }
+
+    #[test]
+    fn test_discordant_read_pairs() {
+        let dir = tempfile::tempdir().unwrap();
+        let ref_buffer = ref_buffer(&dir);
+        let variant = MobileElementInsertion::new(
+            genome::Locus::new(CONTIG.to_owned(), POS),
+            consensus().len() as u64,
+            Arc::new(Consensus::new(consensus())),
+            Arc::new(vec![CONTIG.to_owned()]),
+            PairHMMRealigner::new(
+                Arc::clone(&ref_buffer),
+                GapParams::preset(Platform::Illumina),
+                100,
+            ),
+        );
+        let alignment_properties = alignment_properties();
+        let support = |read: &bam::Record| {
+            let evidence = PairedEndEvidence::SingleEnd(Rc::new(read.clone()));
+            let support = variant
+                .is_valid_evidence(&evidence, &alignment_properties)
+                .and_then(|_| {
+                    variant
+                        .allele_support(&evidence, &alignment_properties)
+                        .unwrap()
+                });
+            support.map(|support| *(support.prob_alt_allele() - support.prob_ref_allele()))
+        };
+
+        // mate in the copy of the mobile element, hence supporting the insertion
+        let log_odds = support(&anchor_read(b"in_element", ELEMENT_POS as i64 + 100, 60)).unwrap();
+        assert!(log_odds > Prob(0.99).ln() - Prob(0.01).ln() - 0.1);
+
+        // mate elsewhere, hence no evidence
+        assert!(support(&anchor_read(b"unrelated", 1700, 60)).is_none());
+
+        // mate in the element but likely mismapped, hence hardly any evidence
+        let log_odds = support(&anchor_read(b"mismapped", ELEMENT_POS as i64 + 100, 0)).unwrap();
+        assert!(log_odds.abs() < 0.01);
+    }
+
+    #[test]
+    fn test_family() {
+        assert_eq!(family(b"<INS:ME:ALU>"), Some(&b"ALU"[..]));
+        assert_eq!(family(b"<INS:ME:L1:HS>"), Some(&b"L1"[..]));
+        assert_eq!(family(b"<INS:ME>"), None);
+        assert_eq!(family(b"<INS>"), None);
+    }
+
+    #[test]
+    fn test_inserted_seq() {
+        assert_eq!(inserted_seq(b"ACGTAAAA", 5), b"TAAAA".to_vec());
+        assert_eq!(inserted_seq(b"ACGT", 6), b"ACGTNN".to_vec());
+    }
+}
@@ -24,18 +24,22 @@ pub(crate) mod duplication;
 pub(crate) mod insertion;
 pub(crate) mod inversion;
 pub(crate) mod mnv;
+pub(crate) mod mobile_element_insertion;
 pub(crate) mod none;
 pub(crate) mod replacement;
 pub(crate) mod snv;
+pub(crate) mod tandem_repeat;
 
 pub(crate) use deletion::Deletion;
 pub(crate) use duplication::Duplication;
 pub(crate) use insertion::Insertion;
 pub(crate) use inversion::Inversion;
 pub(crate) use mnv::MNV;
+pub(crate) use mobile_element_insertion::MobileElementInsertion;
 pub(crate) use none::None;
 pub(crate) use replacement::Replacement;
 pub(crate) use snv::SNV;
+pub(crate) use tandem_repeat::TandemRepeat;
 
 #[derive(Debug, CopyGetters, Builder)]
 #[getset(get_copy = "pub")]
@@ -137,17 +141,13 @@ where
             })
             .collect();
 
-        let mut subsampler = sample::SubsampleCandidates::new(max_depth, candidates.len());
-
-        let mut observations = Vec::new();
-        for evidence in candidates {
-            if subsampler.keep() {
-                if let Some(obs) = self.evidence_to_observation(&evidence, alignment_properties)? {
-                    observations.push(obs);
-                }
-            }
-        }
-        Ok(observations)
+        self.evidences_to_observations(
+            &candidates,
+            alignment_properties,
+            buffer.umi_families(),
+            true,
+            max_depth,
+        )
     }
 }
 
@@ -266,18 +266,14 @@ where
         // METHOD: if all loci exceed the maximum depth, we subsample the evidence.
         // We cannot decide this per locus, because we risk adding more biases if loci have different alt allele sampling biases.
         let subsample = locus_depth.values().all(|depth| *depth > max_depth);
-        let mut subsampler = sample::SubsampleCandidates::new(max_depth, candidates.len());
-
-        let mut observations = Vec::new();
-        for evidence in &candidates {
-            if !subsample || subsampler.keep() {
-                if let Some(obs) = self.evidence_to_observation(evidence, alignment_properties)? {
-                    observations.push(obs);
-                }
-            }
-        }
 
-        Ok(observations)
+        self.evidences_to_observations(
+            &candidates,
+            alignment_properties,
+            buffer.umi_families(),
+            subsample,
+            max_depth,
+        )
     }
 }
 
@@ -0,0 +1,396 @@
+// Copyright 2021 Johannes Köster.
+// Licensed under the GNU GPLv3 license (https://opensource.org/licenses/GPL-3.0)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bio::stats::bayesian::model::Likelihood;
+use bio::stats::LogProb;
+use bio_types::genome::{self, AbstractInterval, AbstractLocus};
+use itertools::Itertools;
+use itertools_num::linspace;
+
+use crate::estimation::alignment_properties::AlignmentProperties;
+use crate::reference;
+use crate::variants::evidence::realignment::haplotypes::{self, HaplotypeVariant};
+use crate::variants::evidence::realignment::pairhmm::{ReadEmission, ReferenceEmissionParams};
+use crate::variants::evidence::realignment::stutter::RepeatAlleles;
+use crate::variants::evidence::realignment::{Realignable, Realigner};
+use crate::variants::model::bias::Biases;
+use crate::variants::model::likelihood::{self, SampleLikelihoodModel};
+use crate::variants::model::AlleleFreq;
+use crate::variants::sample::Pileup;
+use crate::variants::sampling_bias::{ReadSamplingBias, SamplingBias};
+use crate::variants::types::{AlleleSupport, MultiLocus, PairedEndEvidence, SingleLocus, Variant};
+
+/// Number of repeat units in the given sequence, or `None` if the sequence is not a
+/// repetition of the given unit (compared case-insensitively).
+pub(crate) fn repeat_count(seq: &[u8], unit: &[u8]) -> Option<u64> {
+    if unit.is_empty() || !seq.len().is_multiple_of(unit.len()) {
+        return None;
+    }
+    if seq
+        .chunks(unit.len())
+        .all(|chunk| chunk.eq_ignore_ascii_case(unit))
+    {
+        Some((seq.len() / unit.len()) as u64)
+    } else {
+        None
+    }
+}
+
+/// Number of repeat units of a symbolic tandem repeat allele like `<STR12>`
+/// (as written by e.g. ExpansionHunter).
+pub(crate) fn symbolic_repeat_count(alt: &[u8]) -> Option<u64> {
+    let alt = std::str::from_utf8(alt).ok()?;
+    alt.strip_prefix("<STR")?.strip_suffix('>')?.parse().ok()
+}
+
+/// Whether the given number of repeat units, starting at the given position, spans the full
+/// repeat tract of the reference, i.e. the units match the reference and the unit does not
+/// continue behind them.
+pub(crate) fn is_full_tract(ref_seq: &[u8], start: u64, unit: &[u8], count: u64) -> bool {
+    let start = start as usize;
+    let end = start + unit.len() * count as usize;
+    end <= ref_seq.len()
+        && repeat_count(&ref_seq[start..end], unit) == Some(count)
+        && ref_seq
+            .get(end..end + unit.len())
+            .is_none_or(|next| !next.eq_ignore_ascii_case(unit))
+}
+
+/// Number of allele frequencies between zero and one at which repeat count posteriors are
+/// evaluated.
+const REPEAT_COUNT_AF_STEPS: usize = 101;
+
+/// Posterior probabilities that a sample does not carry the ref and the alt repeat count,
+/// given its observations. Returns `None` if there are no observations.
+///
+/// METHOD: the ref repeat count is absent if the allele frequency of the alt repeat count in the
+/// sample is one, the alt repeat count is absent if it is zero. A priori, both cases and the
+/// presence of both repeat counts are equally likely, the latter with a uniform distribution
+/// over the allele frequency.
+pub(crate) fn prob_repeat_counts_absent(pileup: &Pileup) -> Option<(LogProb, LogProb)> {
+    if pileup.is_empty() {
+        return None;
+    }
+    let model = SampleLikelihoodModel::new(None);
+    let mut cache = likelihood::SingleSampleCache::default();
+    let mut likelihood = |allele_freq: f64| {
+        model.compute(
+            &likelihood::Event {
+                allele_freq: AlleleFreq(allele_freq),
+                biases: Biases::none(),
+            },
+            pileup,
+            &mut cache,
+        )
+    };
+
+    let prob_case = LogProb((1.0f64 / 3.0).ln());
+    let prob_alt_absent = prob_case + likelihood(0.0);
+    let prob_ref_absent = prob_case + likelihood(1.0);
+    let inner_afs = linspace(0.0, 1.0, REPEAT_COUNT_AF_STEPS)
+        .skip(1)
+        .take(REPEAT_COUNT_AF_STEPS - 2)
+        .collect_vec();
+    let prob_inner_af = LogProb((1.0 / inner_afs.len() as f64).ln());
+    let prob_both = prob_case
+        + LogProb::ln_sum_exp(
+            &inner_afs
+                .into_iter()
+                .map(|allele_freq| prob_inner_af + likelihood(allele_freq))
+                .collect_vec(),
+        );
+
+    let marginal = LogProb::ln_sum_exp(&[prob_ref_absent, prob_alt_absent, prob_both]);
+    if marginal == LogProb::ln_zero() {
+        None
+    } else {
+        Some((prob_ref_absent - marginal, prob_alt_absent - marginal))
+    }
+}
+
+pub(crate) struct TandemRepeat<R: Realigner> {
+    locus: MultiLocus,
+    repeat: RepeatAlleles,
+    realigner: RefCell<R>,
+}
+
+impl<R: Realigner> TandemRepeat<R> {
+    /// Tandem repeat with the given unit, of which the tract starts at the given locus.
+    pub(crate) fn new(
+        locus: genome::Locus,
+        unit: Vec<u8>,
+        ref_count: u64,
+        alt_count: u64,
+        realigner: R,
+    ) -> Self {
+        let repeat = RepeatAlleles::new(locus.pos(), unit, ref_count, alt_count);
+        // An empty reference tract (e.g. an insertion of a new repeat) is represented by the
+        // base in front of which the units are inserted.
+        let end = cmp::max(repeat.ref_end(), locus.pos() + 1);
+        TandemRepeat {
+            locus: MultiLocus::new(vec![SingleLocus::new(genome::Interval::new(
+                locus.contig().to_owned(),
+                locus.pos()..end,
+            ))]),
+            repeat,
+            realigner: RefCell::new(realigner),
+        }
+    }
+
+    pub(crate) fn locus(&self) -> &SingleLocus {
+        &self.locus[0]
+    }
+
+    /// Number of bases by which the alt allele differs in length from the reference.
+    fn len_diff(&self) -> u64 {
+        let unit_len = self.repeat.unit().len() as u64;
+        if self.repeat.alt_count() > self.repeat.ref_count() {
+            (self.repeat.alt_count() - self.repeat.ref_count()) * unit_len
+        } else {
+            (self.repeat.ref_count() - self.repeat.alt_count()) * unit_len
+        }
+    }
+
+    fn is_expansion(&self) -> bool {
+        self.repeat.alt_count() > self.repeat.ref_count()
+    }
+}
+
+impl<'a, R: Realigner> Realignable<'a> for TandemRepeat<R> {
+    type EmissionParams = ReferenceEmissionParams<'a>;
+
+    fn alt_emission_params(
+        &self,
+        read_emission_params: Rc<ReadEmission<'a>>,
+        ref_buffer: Arc<reference::Buffer>,
+        _: &genome::Interval,
+        ref_window: usize,
+    ) -> Result<Vec<ReferenceEmissionParams<'a>>> {
+        let ref_seq = ref_buffer.seq(self.locus().contig())?;
+        let interval = self.repeat.start().saturating_sub(ref_window as u64) as usize
+            ..cmp::min(self.repeat.ref_end() as usize + ref_window, ref_seq.len());
+        let alt_seq = haplotypes::haplotype_seq(
+            &ref_seq,
+            &interval,
+            &[&self.repeat.allele(self.repeat.alt_count())],
+        );
+        let alt_seq_len = alt_seq.len();
+
+        Ok(vec![ReferenceEmissionParams {
+            ref_seq: Arc::new(alt_seq),
+            ref_offset: 0,
+            ref_end: alt_seq_len,
+            read_emission: read_emission_params,
+        }])
+    }
+
+    fn haplotype_variant(&self) -> Option<HaplotypeVariant> {
+        Some(self.repeat.allele(self.repeat.alt_count()))
+    }
+
+    fn repeat_alleles(&self) -> Option<RepeatAlleles> {
+        Some(self.repeat.clone())
+    }
+}
+
+impl<R: Realigner> SamplingBias for TandemRepeat<R> {
+    fn feasible_bases(&self, read_len: u64, alignment_properties: &AlignmentProperties) -> u64 {
+        if let Some(len) = self.enclosable_len() {
+            let max_cigar_len = if self.is_expansion() {
+                alignment_properties.max_ins_cigar_len
+            } else {
+                alignment_properties.max_del_cigar_len
+            };
+            if len < (max_cigar_len as u64) {
+                return read_len;
+            }
+        }
+        (read_len as f64 * alignment_properties.frac_max_softclip) as u64
+    }
+
+    fn enclosable_len(&self) -> Option<u64> {
+        Some(self.len_diff())
+    }
+}
+
+impl<R: Realigner> ReadSamplingBias for TandemRepeat<R> {}
+
+impl<R: Realigner> Variant for TandemRepeat<R> {
+    type Evidence = PairedEndEvidence;
+    type Loci = MultiLocus;
+
+    fn is_valid_evidence(
+        &self,
+        evidence: &Self::Evidence,
+        _: &AlignmentProperties,
+    ) -> Option<Vec<usize>> {
+        if match evidence {
+            PairedEndEvidence::SingleEnd(read) => !self.locus().overlap(read, true).is_none(),
+            PairedEndEvidence::PairedEnd { left, right } => {
+                !self.locus().overlap(left, true).is_none()
+                    || !self.locus().overlap(right, true).is_none()
+            }
+        } {
+            Some(vec![0])
+        } else {
+            None
+        }
+    }
+
+    /// Return variant loci.
+    fn loci(&self) -> &Self::Loci {
+        &self.locus
+    }
+
+    /// Calculate probability for alt and reference allele.
+    fn allele_support(
+        &self,
+        evidence: &Self::Evidence,
+        _alignment_properties: &AlignmentProperties,
+    ) -> Result<Option<AlleleSupport>> {
+        match evidence {
+            PairedEndEvidence::SingleEnd(record) => Ok(Some(
+                self.realigner
+                    .borrow_mut()
+                    .allele_support(record, self.locus.iter(), self)?,
+            )),
+            PairedEndEvidence::PairedEnd { left, right } => {
+                let left_support =
+                    self.realigner
+                        .borrow_mut()
+                        .allele_support(left, self.locus.iter(), self)?;
+                let right_support =
+                    self.realigner
+                        .borrow_mut()
+                        .allele_support(right, self.locus.iter(), self)?;
+
+                let mut support = left_support;
+
+                support.merge(&right_support);
+
+                Ok(Some(support))
+            }
+        }
+    }
+
+    fn prob_sample_alt(
+        &self,
+        evidence: &Self::Evidence,
+        alignment_properties: &AlignmentProperties,
+    ) -> LogProb {
+        match evidence {
+            PairedEndEvidence::PairedEnd { left, right } => {
+                // METHOD: we do not require the fragment to enclose the repeat.
+                // Hence, we treat both reads independently.
+                (self
+                    .prob_sample_alt_read(left.seq().len() as u64, alignment_properties)
+                    .ln_one_minus_exp()
+                    + self
+                        .prob_sample_alt_read(right.seq().len() as u64, alignment_properties)
+                        .ln_one_minus_exp())
+                .ln_one_minus_exp()
+            }
+            PairedEndEvidence::SingleEnd(read) => {
+                self.prob_sample_alt_read(read.seq().len() as u64, alignment_properties)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::variants::model::tests::observation;
+
+    #[test]
+    fn test_repeat_count() {
+        assert_eq!(repeat_count(b"CACACA", b"CA"), Some(3));
+        assert_eq!(repeat_count(b"cacaCA", b"CA"), Some(3));
+        assert_eq!(repeat_count(b"", b"CA"), Some(0));
+        assert_eq!(repeat_count(b"CACAC", b"CA"), None);
+        assert_eq!(repeat_count(b"CAGACA", b"CA"), None);
+    }
+
+    #[test]
+    fn test_symbolic_repeat_count() {
+        assert_eq!(symbolic_repeat_count(b"<STR12>"), Some(12));
+        assert_eq!(symbolic_repeat_count(b"<STR>"), None);
+        assert_eq!(symbolic_repeat_count(b"<INS>"), None);
+    }
+
+    #[test]
+    fn test_is_full_tract() {
+        let ref_seq = b"GTCACACAGT";
+        assert!(is_full_tract(ref_seq, 2, b"CA", 3));
+        // the tract continues behind the given units
+        assert!(!is_full_tract(ref_seq, 2, b"CA", 2));
+        assert!(!is_full_tract(ref_seq, 2, b"CA", 4));
+        assert!(!is_full_tract(ref_seq, 1, b"CA", 3));
+        // no repeat unit in the reference
+        assert!(is_full_tract(ref_seq, 8, b"CA", 0));
+    }
+
+    #[test]
+    fn test_prob_repeat_counts_absent() {
+        let prob_absent = |pileup: &Pileup| {
+            let (prob_ref_absent, prob_alt_absent) = prob_repeat_counts_absent(pileup).unwrap();
+            (prob_ref_absent.exp(), prob_alt_absent.exp())
+        };
+
+        assert!(prob_repeat_counts_absent(&Vec::new()).is_none());
+
+        // only the alt repeat count
+        let (prob_ref_absent, prob_alt_absent) = prob_absent(&vec![
+            observation(
+                LogProb::ln_one(),
+                LogProb(0.9f64.ln()),
+                LogProb(0.1f64.ln())
+            );
+            20
+        ]);
+        assert!(prob_ref_absent > 0.9);
+        assert!(prob_alt_absent < 0.01);
+
+        // only the ref repeat count
+        let (prob_ref_absent, prob_alt_absent) = prob_absent(&vec![
+            observation(
+                LogProb::ln_one(),
+                LogProb(0.1f64.ln()),
+                LogProb(0.9f64.ln())
+            );
+            20
+        ]);
+        assert!(prob_ref_absent < 0.01);
+        assert!(prob_alt_absent > 0.9);
+
+        // both repeat counts
+        let mut pileup = vec![
+            observation(
+                LogProb::ln_one(),
+                LogProb(0.9f64.ln()),
+                LogProb(0.1f64.ln())
+            );
+            10
+        ];
+        pileup.extend(vec![
+            observation(
+                LogProb::ln_one(),
+                LogProb(0.1f64.ln()),
+                LogProb(0.9f64.ln())
+            );
+            10
+        ]);
+        let (prob_ref_absent, prob_alt_absent) = prob_absent(&pileup);
+        assert!(prob_ref_absent < 0.01);
+        assert!(prob_alt_absent < 0.01);
+    }
+}
@@ -162,6 +162,53 @@ pub(crate) trait Testcase {
         self.yaml()["purity"].as_f64()
     }
 
+    /// Preprocess the given sample, writing observations to the given output path.
+    fn preprocess(
+        &self,
+        sample_name: &str,
+        temp_ref: &Path,
+        output_path: &Path,
+        pairhmm_mode_override: &str,
+        num_threads: usize,
+    ) -> Result<()> {
+        let mut options = serde_json::from_str(&self.preprocess_options(sample_name))?;
+        match &mut options {
+            Varlociraptor::Preprocess {
+                kind:
+                    PreprocessKind::Variants {
+                        ref mut reference,
+                        ref mut candidates,
+                        ref mut output,
+                        ref mut bams,
+                        ref mut alignment_properties,
+                        ref mut pairhmm_mode,
+                        ref mut threads,
+                        ..
+                    },
+            } => {
+                // prepare test bam
+                let test_bam = self.sample_bam(sample_name);
+                bam::index::build(&test_bam, None, bam::index::Type::BAI, 1).unwrap();
+
+                // prepare alignment properties
+                let props =
+                    self.alignment_properties(&self.sample_alignment_properties(sample_name))?;
+
+                // replace options
+                *bams = vec![test_bam];
+                *reference = temp_ref.to_owned();
+                *candidates = self.candidates();
+                *output = Some(output_path.to_owned());
+                *alignment_properties = Some(props.path().to_owned());
+                *pairhmm_mode = pairhmm_mode_override.to_owned();
+                *threads = num_threads;
+
+                run(options)
+            }
+            _ => panic!("bug: unsupported options"),
+        }
+    }
+
     fn run(&self, pairhmm_mode_override: &str) -> Result<()> {
         let temp_ref = self.reference()?;
 
@@ -169,40 +216,13 @@ pub(crate) trait Testcase {
 
         // Step 1: preprocess all samples
         for sample_name in &self.samples() {
-            let mut options = serde_json::from_str(&self.preprocess_options(sample_name))?;
-            match &mut options {
-                Varlociraptor::Preprocess {
-                    kind:
-                        PreprocessKind::Variants {
-                            ref mut reference,
-                            ref mut candidates,
-                            ref mut output,
-                            ref mut bam,
-                            ref mut alignment_properties,
-                            ref mut pairhmm_mode,
-                            ..
-                        },
-                } => {
-                    // prepare test bam
-                    let test_bam = self.sample_bam(sample_name);
-                    bam::index::build(&test_bam, None, bam::index::Type::BAI, 1).unwrap();
-
-                    // prepare alignment properties
-                    let props =
-                        self.alignment_properties(&self.sample_alignment_properties(sample_name))?;
-
-                    // replace options
-                    *bam = test_bam;
-                    *reference = PathBuf::from((*temp_ref).as_ref());
-                    *candidates = self.candidates();
-                    *output = Some(self.sample_preprocessed_path(sample_name, &temp_preprocess));
-                    *alignment_properties = Some(props.path().to_owned());
-                    *pairhmm_mode = pairhmm_mode_override.to_owned();
-
-                    run(options)?;
-                }
-                _ => panic!("bug: unsupported options"),
-            }
+            self.preprocess(
+                sample_name,
+                (*temp_ref).as_ref(),
+                &self.sample_preprocessed_path(sample_name, &temp_preprocess),
+                pairhmm_mode_override,
+                1,
+            )?;
         }
 
         // Step 2: run calling
@@ -215,7 +235,9 @@ pub(crate) trait Testcase {
                         omit_strand_bias: self.omit_strand_bias(),
                         omit_read_orientation_bias: self.omit_read_orientation_bias(),
                         omit_read_position_bias: self.omit_read_position_bias(),
+                        omit_duplex_bias: false,
                         output: Some(self.output()),
+                        regions: Vec::new(),
                         mode: VariantCallMode::Generic {
                             scenario: self.scenario().unwrap(),
                             sample_observations: self
@@ -248,7 +270,9 @@ pub(crate) trait Testcase {
                         omit_strand_bias: self.omit_strand_bias(),
                         omit_read_orientation_bias: self.omit_read_orientation_bias(),
                         omit_read_position_bias: self.omit_read_position_bias(),
+                        omit_duplex_bias: false,
                         output: Some(self.output()),
+                        regions: Vec::new(),
                         mode: VariantCallMode::TumorNormal {
                             tumor_observations: self
                                 .sample_preprocessed_path("tumor", &temp_preprocess),
@@ -7,6 +7,7 @@ use yaml_rust::Yaml;
 use crate::common::Testcase;
 use varlociraptor::cli::{PreprocessKind, Varlociraptor};
 use varlociraptor::testcase::Mode;
+use varlociraptor::variants::sample::Platform;
 
 #[derive(Debug)]
 pub(crate) struct TestcaseVersion0 {
@@ -87,22 +88,32 @@ impl Testcase for TestcaseVersion0 {
                 let options = Varlociraptor::Preprocess {
                     kind: PreprocessKind::Variants {
                         reference,
-                        spurious_ins_rate,
-                        spurious_del_rate,
-                        spurious_insext_rate,
-                        spurious_delext_rate,
+                        platform: Platform::Illumina,
+                        spurious_ins_rate: Some(spurious_ins_rate),
+                        spurious_del_rate: Some(spurious_del_rate),
+                        spurious_insext_rate: Some(spurious_insext_rate),
+                        spurious_delext_rate: Some(spurious_delext_rate),
                         protocol_strandedness,
                         realignment_window: indel_window as u64,
                         max_depth,
                         // The rest will be overwritten.
                         alignment_properties: None,
-                        bam: PathBuf::from("dummy"),
+                        bams: vec![PathBuf::from("dummy")],
                         candidates: self.candidates(),
                         output: None,
                         omit_insert_size: false,
+                        empirical_insert_size: false,
+                        umi_families: false,
+                        phasing: false,
+                        joint_realignment: false,
+                        merge_adjacent_snvs: None,
+                        mobile_elements: None,
+                        depth_controls: Vec::new(),
                         reference_buffer_size: 10,
                         min_bam_refetch_distance: 1,
                         pairhmm_mode: "exact".to_owned(),
+                        threads: 1,
+                        regions: Vec::new(),
                     },
                 };
 
@@ -1,14 +1,16 @@
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::Mutex;
 
+use bio::io::fasta;
 use bio::stats::{LogProb, Prob};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use paste::paste;
-use rust_htslib::bcf;
+use rust_htslib::bam::record::{Cigar, CigarString};
 use rust_htslib::bcf::Read as BCFRead;
+use rust_htslib::{bam, bcf};
 
 mod common;
 
@@ -198,3 +200,379 @@ fn test_fdr_control4() {
 fn test_fdr_control5() {
     control_fdr("test_fdr_control_out_of_bounds", "PRESENT", 0.05);
 }
+
+#[test]
+fn test_call_cnvs() {
+    let basedir = basedir("test_cnvs");
+    let output = format!("{}/cnvs.bcf", basedir);
+    cleanup_file(&output);
+
+    varlociraptor::cli::run(varlociraptor::cli::Varlociraptor::Call {
+        kind: varlociraptor::cli::CallKind::CNVs {
+            calls: Some(format!("{}/calls.vcf", basedir).into()),
+            output: Some(output.clone().into()),
+            purity: 0.8,
+            min_bayes_factor: 100.0,
+            max_dist: 1000,
+            threads: 2,
+        },
+    })
+    .unwrap();
+
+    // the calls contain a loss of one copy between 5000 and 8900
+    let mut reader = bcf::Reader::from_path(&output).unwrap();
+    let mut losses = reader
+        .records()
+        .map(|r| r.unwrap())
+        .filter_map(|rec| {
+            if rec.info(b"CN").integer().unwrap().unwrap()[0] == 1 {
+                Some(rec)
+            } else {
+                None
+            }
+        })
+        .collect_vec();
+    assert_eq!(losses.len(), 1, "unexpected number of losses");
+    let loss = &mut losses[0];
+    assert_eq!(loss.pos(), 4999);
+    assert_eq!(loss.info(b"END").integer().unwrap().unwrap()[0], 8900);
+    assert_eq!(loss.info(b"LOCI").integer().unwrap().unwrap()[0], 40);
+    assert!(loss.qual() > 100.0);
+}
+
+/// Observation records of the given preprocessed BCF, with all their INFO fields.
+fn preprocessed_records(path: &Path) -> Vec<(i64, Vec<Vec<u8>>, Vec<u8>, Vec<Vec<i32>>)> {
+    let mut reader = bcf::Reader::from_path(path).unwrap();
+    let info_tags = reader
+        .header()
+        .header_records()
+        .into_iter()
+        .filter_map(|rec| match rec {
+            bcf::HeaderRecord::Info { values, .. }
+                if values.get("Type").map_or(false, |t| t == "Integer") =>
+            {
+                Some(values.get("ID").unwrap().clone())
+            }
+            _ => None,
+        })
+        .collect_vec();
+    reader
+        .records()
+        .map(|rec| {
+            let rec = rec.unwrap();
+            let info = info_tags
+                .iter()
+                .map(|tag| {
+                    rec.info(tag.as_bytes())
+                        .integer()
+                        .unwrap()
+                        .map_or_else(Vec::new, |values| values.to_vec())
+                })
+                .collect_vec();
+            (
+                rec.pos(),
+                rec.alleles()
+                    .into_iter()
+                    .map(|a| a.to_owned())
+                    .collect_vec(),
+                rec.id(),
+                info,
+            )
+        })
+        .collect_vec()
+}
+
+#[test]
+fn test_parallel_preprocessing() {
+    // test42 contains breakends, the others contain regular variants.
+    for (name, mutex) in &[
+        ("test01", &*TEST01_MUTEX),
+        ("test05", &*TEST05_MUTEX),
+        ("test42", &*TEST42_MUTEX),
+    ] {
+        // Poison error can be ignored here, see above.
+        let _guard = mutex.lock();
+        let testcase = load_testcase(
+            &Path::new(file!())
+                .parent()
+                .unwrap()
+                .join("resources/testcases")
+                .join(name),
+        )
+        .unwrap();
+        let temp_ref = testcase.reference().unwrap();
+        let temp_preprocess = tempfile::tempdir().unwrap();
+
+        for sample_name in &testcase.samples() {
+            let outputs = [1, 4]
+                .iter()
+                .map(|&threads| {
+                    let output = temp_preprocess
+                        .path()
+                        .join(format!("{}.{}.bcf", sample_name, threads));
+                    testcase
+                        .preprocess(sample_name, (*temp_ref).as_ref(), &output, "exact", threads)
+                        .unwrap();
+                    preprocessed_records(&output)
+                })
+                .collect_vec();
+            assert!(!outputs[0].is_empty());
+            assert_eq!(
+                outputs[0], outputs[1],
+                "threaded preprocessing of {} in {} differs",
+                sample_name, name
+            );
+        }
+    }
+}
+
+/// Write the given records of the observation BCF at `path` into a new observation BCF.
+fn write_observation_shard(path: &Path, shard: &Path, records: std::ops::Range<usize>) {
+    let mut reader = bcf::Reader::from_path(path).unwrap();
+    let header = bcf::Header::from_template(reader.header());
+    let mut writer = bcf::Writer::from_path(shard, &header, false, bcf::Format::BCF).unwrap();
+    for mut record in reader
+        .records()
+        .map(|rec| rec.unwrap())
+        .skip(records.start)
+        .take(records.end - records.start)
+    {
+        writer.translate(&mut record);
+        writer.write(&record).unwrap();
+    }
+}
+
+/// Preprocess the given sample of the given testcase with a single thread.
+fn preprocess_testcase(name: &str, sample_name: &str, output: &Path) {
+    let testcase = load_testcase(
+        &Path::new(file!())
+            .parent()
+            .unwrap()
+            .join("resources/testcases")
+            .join(name),
+    )
+    .unwrap();
+    let temp_ref = testcase.reference().unwrap();
+    testcase
+        .preprocess(sample_name, (*temp_ref).as_ref(), output, "exact", 1)
+        .unwrap();
+}
+
+fn merge_observations(observations: Vec<PathBuf>, output: &Path) -> anyhow::Result<()> {
+    varlociraptor::cli::run(varlociraptor::cli::Varlociraptor::MergeObservations {
+        observations,
+        output: Some(output.to_owned()),
+    })
+}
+
+#[test]
+fn test_merge_observations() {
+    // Poison error can be ignored here, see above.
+    let _guard = TEST42_MUTEX.lock();
+    let temp = tempfile::tempdir().unwrap();
+    let observations = temp.path().join("normal.bcf");
+    preprocess_testcase("test42", "normal", &observations);
+    let records = preprocessed_records(&observations);
+    assert_eq!(records.len(), 2);
+
+    let shards = vec![
+        temp.path().join("shard2.bcf"),
+        temp.path().join("shard1.bcf"),
+    ];
+    write_observation_shard(&observations, &shards[1], 0..1);
+    write_observation_shard(&observations, &shards[0], 1..2);
+
+    let merged = temp.path().join("merged.bcf");
+    merge_observations(shards, &merged).unwrap();
+    assert_eq!(preprocessed_records(&merged), records);
+}
+
+#[test]
+fn test_merge_overlapping_observations() {
+    // Poison error can be ignored here, see above.
+    let _guard = TEST01_MUTEX.lock();
+    let temp = tempfile::tempdir().unwrap();
+    let observations = temp.path().join("tumor.bcf");
+    preprocess_testcase("test01", "tumor", &observations);
+
+    let merged = temp.path().join("merged.bcf");
+    let err = merge_observations(vec![observations.clone(), observations], &merged).unwrap_err();
+    assert!(err.to_string().starts_with("overlapping observations"));
+}
+
+#[test]
+fn test_merge_overlapping_breakend_observations() {
+    // Poison error can be ignored here, see above.
+    let _guard = TEST42_MUTEX.lock();
+    let temp = tempfile::tempdir().unwrap();
+    let observations = temp.path().join("normal.bcf");
+    preprocess_testcase("test42", "normal", &observations);
+
+    let merged = temp.path().join("merged.bcf");
+    let err = merge_observations(vec![observations.clone(), observations], &merged).unwrap_err();
+    assert!(err.to_string().starts_with("overlapping observations"));
+}
+
+/// Length of the synthetic reads used for testing read depth evidence.
+const DEPTH_READ_LEN: usize = 100;
+
+/// Write an indexed BAM file with single end reads of the given reference sequence
+/// (contig chr1), starting at the given positions.
+fn write_depth_bam(path: &Path, ref_seq: &[u8], mut starts: Vec<u64>) {
+    starts.sort();
+    let mut header = bam::Header::new();
+    let mut contig = bam::header::HeaderRecord::new(b"SQ");
+    contig.push_tag(b"SN", &"chr1");
+    contig.push_tag(b"LN", &ref_seq.len());
+    header.push_record(&contig);
+    {
+        let mut writer = bam::Writer::from_path(path, &header, bam::Format::BAM).unwrap();
+        for (i, start) in starts.into_iter().enumerate() {
+            let start = start as usize;
+            let mut record = bam::Record::new();
+            record.set(
+                format!("read{}", i).as_bytes(),
+                Some(&CigarString(vec![Cigar::Match(DEPTH_READ_LEN as u32)])),
+                &ref_seq[start..start + DEPTH_READ_LEN],
+                &[40; DEPTH_READ_LEN],
+            );
+            record.set_tid(0);
+            record.set_pos(start as i64);
+            record.set_mapq(60);
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+            writer.write(&record).unwrap();
+        }
+    }
+    bam::index::build(path, None, bam::index::Type::BAI, 1).unwrap();
+}
+
+#[test]
+fn test_depth_evidence() {
+    let temp = tempfile::tempdir().unwrap();
+
+    // reference with a pseudo-random sequence
+    let ref_len = 100_000;
+    let mut state: u64 = 42;
+    let ref_seq = (0..ref_len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize]
+        })
+        .collect_vec();
+    let reference = temp.path().join("ref.fa");
+    fasta::Writer::to_file(&reference)
+        .unwrap()
+        .write("chr1", None, &ref_seq)
+        .unwrap();
+    fs::write(
+        temp.path().join("ref.fa.fai"),
+        format!("chr1\t{}\t6\t{}\t{}\n", ref_len, ref_len, ref_len + 1),
+    )
+    .unwrap();
+
+    // Candidates: a heterozygous deletion of 20000..30000, a heterozygous duplication of
+    // 40000..50000, and a deletion of 70000..80000 that is not present in the sample.
+    let candidates = temp.path().join("candidates.vcf");
+    fs::write(
+        &candidates,
+        format!(
+            "##fileformat=VCFv4.2\n\
+             ##contig=<ID=chr1,length={}>\n\
+             ##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"Type of structural variant\">\n\
+             ##INFO=<ID=SVLEN,Number=.,Type=Integer,Description=\"Length of structural variant\">\n\
+             ##INFO=<ID=END,Number=1,Type=Integer,Description=\"End position\">\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+             chr1\t20000\tdel\t{}\t<DEL>\t.\t.\tSVTYPE=DEL;SVLEN=-10000;END=30000\n\
+             chr1\t40001\tdup\t{}\t<DUP>\t.\t.\tSVTYPE=DUP;SVLEN=10000;END=50000\n\
+             chr1\t70000\tnodel\t{}\t<DEL>\t.\t.\tSVTYPE=DEL;SVLEN=-10000;END=80000\n",
+            ref_len, ref_seq[19999] as char, ref_seq[40000] as char, ref_seq[69999] as char,
+        ),
+    )
+    .unwrap();
+
+    // Reads start every 10 bases. In the sample, the deletion halves and the duplication
+    // increases the depth by half.
+    let max_start = (ref_len - DEPTH_READ_LEN) as u64;
+    let sample_starts = (0..max_start)
+        .step_by(10)
+        .filter(|pos| !(20000..30000).contains(pos) || pos % 20 == 0)
+        .chain((40005..50000).step_by(20))
+        .collect_vec();
+    let sample = temp.path().join("sample.bam");
+    write_depth_bam(&sample, &ref_seq, sample_starts);
+    let controls = (0..2)
+        .map(|i| {
+            let control = temp.path().join(format!("control{}.bam", i));
+            write_depth_bam(
+                &control,
+                &ref_seq,
+                (i * 3..max_start).step_by(10).collect_vec(),
+            );
+            control
+        })
+        .collect_vec();
+
+    let alignment_properties = temp.path().join("alignment-properties.json");
+    fs::write(
+        &alignment_properties,
+        "{\"insert_size\":null,\"max_del_cigar_len\":30,\"max_ins_cigar_len\":30,\
+         \"frac_max_softclip\":0.5,\"max_read_len\":100}",
+    )
+    .unwrap();
+
+    let output = temp.path().join("sample.bcf");
+    let options = serde_json::json!({
+        "Preprocess": {
+            "kind": {
+                "Variants": {
+                    "reference": reference,
+                    "candidates": candidates,
+                    "bam": [sample],
+                    "alignment_properties": alignment_properties,
+                    "output": output,
+                    "protocol_strandedness": "Opposite",
+                    "realignment_window": 64,
+                    "max_depth": 200,
+                    "omit_insert_size": true,
+                    "depth_controls": controls,
+                    "pairhmm_mode": "exact",
+                    "threads": 2,
+                }
+            }
+        }
+    });
+    varlociraptor::cli::run(serde_json::from_value(options).unwrap()).unwrap();
+
+    let mut reader = bcf::Reader::from_path(&output).unwrap();
+    let depth_ratios = reader
+        .records()
+        .map(|rec| {
+            let rec = rec.unwrap();
+            let observed = rec.info(b"DEPTH_OBS").integer().unwrap().unwrap()[0] as f64;
+            let expected = rec.info(b"DEPTH_EXP").float().unwrap().unwrap()[0] as f64;
+            let alt_ratio = rec.info(b"DEPTH_ALT_RATIO").float().unwrap().unwrap()[0] as f64;
+            (rec.id(), observed / expected, alt_ratio)
+        })
+        .collect_vec();
+    assert_eq!(depth_ratios.len(), 3);
+    for ((id, depth_ratio, alt_ratio), (expected_id, expected_depth_ratio, expected_alt_ratio)) in
+        depth_ratios.into_iter().zip(vec![
+            (b"del".to_vec(), 0.5, 0.0),
+            (b"dup".to_vec(), 1.5, 2.0),
+            (b"nodel".to_vec(), 1.0, 0.0),
+        ])
+    {
+        assert_eq!(id, expected_id);
+        assert!(
+            (depth_ratio - expected_depth_ratio).abs() < 0.05,
+            "unexpected depth ratio for {} ({} vs {})",
+            str::from_utf8(&id).unwrap(),
+            depth_ratio,
+            expected_depth_ratio
+        );
+        assert_eq!(alt_ratio, expected_alt_ratio);
+    }
+}